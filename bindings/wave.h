@@ -10,35 +10,133 @@
 #include <stdint.h>
 #include <stdlib.h>
 
-#define MAX_SBOX_FDS 8
+/**
+ * Raw fds of in-memory files start here, far above anything the host hands out
+ */
+#define MEM_FD_BASE (1 << 30)
+
+/**
+ * Bytes of file data a `MemFs::new` tree may hold, see `MemFs::with_capacity`
+ */
+#define DEFAULT_CAPACITY (1 << 30)
+
+#define MAX_SBOX_FDS (1 << 16)
+
+#define DEFAULT_SBOX_FDS 1024
 
 #define MAX_HOST_FDS 1024
 
-#define PATH_MAX 1024
+#define PATH_MAX 4096
 
 #define PAGE_SIZE 4096
 
 #define LINEAR_MEM_SIZE 4294965096
 
+#define SBOX_SOCKADDR_IN_LEN 16
+
+#define SBOX_SOCKADDR_IN6_LEN 28
+
+/**
+ * The most `SymlinkPolicy::max_hops` may be, like Linux's own limit
+ */
+#define MAX_SYMLINK_HOPS 40
+
+#define STOP_CODE 0x100
+
 typedef enum WasiProto {
+  Unknown,
   Tcp,
   Udp,
-  Unknown,
 } WasiProto;
 
 typedef struct VmCtx VmCtx;
 
 typedef struct NetEndpoint {
+  /**
+   * ipv6 address as a native-endian integer (::1 is 1), not in network byte order.
+   * ipv4 addresses are stored ipv4-mapped (::ffff:a.b.c.d)
+   */
+  unsigned __int128 addr;
   enum WasiProto protocol;
-  uint32_t addr;
-  uint32_t port;
+  /**
+   * 0..=128, so an ipv4 /8 is a prefix of 96 + 8
+   */
+  uint32_t prefix_len;
+  uint32_t port_lo;
+  uint32_t port_hi;
 } NetEndpoint;
 
-typedef struct NetEndpoint Netlist[4];
+typedef uint64_t Rights;
+
+#define RIGHTS_FD_DATASYNC (1 << 0)
+
+#define RIGHTS_FD_READ (1 << 1)
+
+#define RIGHTS_FD_SEEK (1 << 2)
+
+#define RIGHTS_FD_FDSTAT_SET_FLAGS (1 << 3)
+
+#define RIGHTS_FD_SYNC (1 << 4)
+
+#define RIGHTS_FD_TELL (1 << 5)
+
+#define RIGHTS_FD_WRITE (1 << 6)
+
+#define RIGHTS_FD_ADVISE (1 << 7)
+
+#define RIGHTS_FD_ALLOCATE (1 << 8)
+
+#define RIGHTS_PATH_CREATE_DIRECTORY (1 << 9)
+
+#define RIGHTS_PATH_CREATE_FILE (1 << 10)
+
+#define RIGHTS_PATH_LINK_SOURCE (1 << 11)
+
+#define RIGHTS_PATH_LINK_TARGET (1 << 12)
+
+#define RIGHTS_PATH_OPEN (1 << 13)
+
+#define RIGHTS_FD_READDIR (1 << 14)
+
+#define RIGHTS_PATH_READLINK (1 << 15)
+
+#define RIGHTS_PATH_RENAME_SOURCE (1 << 16)
+
+#define RIGHTS_PATH_RENAME_TARGET (1 << 17)
+
+#define RIGHTS_PATH_FILESTAT_GET (1 << 18)
+
+#define RIGHTS_PATH_FILESTAT_SET_SIZE (1 << 19)
+
+#define RIGHTS_PATH_FILESTAT_SET_TIMES (1 << 20)
+
+#define RIGHTS_FD_FILESTAT_GET (1 << 21)
+
+#define RIGHTS_FD_FILESTAT_SET_SIZE (1 << 22)
 
-typedef uint32_t SboxFd;
+#define RIGHTS_FD_FILESTAT_SET_TIMES (1 << 23)
 
-#define HOMEDIR_FD 3
+#define RIGHTS_PATH_SYMLINK (1 << 24)
+
+#define RIGHTS_PATH_REMOVE_DIRECTORY (1 << 25)
+
+#define RIGHTS_PATH_UNLINK_FILE (1 << 26)
+
+#define RIGHTS_POLL_FD_READWRITE (1 << 27)
+
+#define RIGHTS_SOCK_SHUTDOWN (1 << 28)
+
+#define RIGHTS_SOCK_ACCEPT (1 << 29)
+
+#define RIGHTS_SOCK_BIND (1 << 30)
+
+#define RIGHTS_SOCK_LISTEN (1 << 31)
+
+#define RIGHTS_ALL ((1 << 32) - 1)
+
+#define RIGHTS_SOCK_SERVE (RIGHTS_SOCK_BIND | RIGHTS_SOCK_LISTEN | RIGHTS_SOCK_ACCEPT)
+
+#define RIGHTS_FD_MUTATE (RIGHTS_FD_WRITE | RIGHTS_FD_ALLOCATE | RIGHTS_FD_FILESTAT_SET_SIZE | RIGHTS_FD_FILESTAT_SET_TIMES)
 
 #ifdef __cplusplus
 extern "C" {
@@ -212,7 +310,7 @@ int32_t hostcall_wasi_snapshot_preview1_path_open_wave(void *ctx,
                                                        int32_t path_len,
                                                        int32_t oflags,
                                                        int64_t fs_rights_base,
-                                                       int64_t _fs_rights_inheriting,
+                                                       int64_t fs_rights_inheriting,
                                                        int32_t fdflags,
                                                        int32_t out);
 
@@ -279,10 +377,34 @@ int32_t hostcall_wasi_snapshot_preview1_sock_send_wave(void *ctx,
                                                        int32_t si_flags,
                                                        int32_t out);
 
+int32_t hostcall_wasi_snapshot_preview1_sock_recv_from_wave(void *ctx,
+                                                            int32_t fd,
+                                                            int32_t buf,
+                                                            int32_t buf_len,
+                                                            int32_t ri_flags,
+                                                            int32_t addr_buf,
+                                                            int32_t addr_buf_len,
+                                                            int32_t out0,
+                                                            int32_t out1);
+
+int32_t hostcall_wasi_snapshot_preview1_sock_send_to_wave(void *ctx,
+                                                          int32_t fd,
+                                                          int32_t buf,
+                                                          int32_t buf_len,
+                                                          int32_t si_flags,
+                                                          int32_t addr,
+                                                          int32_t addrlen,
+                                                          int32_t out);
+
 int32_t hostcall_wasi_snapshot_preview1_sock_shutdown_wave(void *ctx,
                                                            int32_t fd,
                                                            int32_t how);
 
+int32_t hostcall_wasi_snapshot_preview1_sock_accept_wave(void *ctx,
+                                                         int32_t fd,
+                                                         int32_t flags,
+                                                         int32_t retptr);
+
 int32_t hostcall_wasi_snapshot_preview1_socket_wave(void *ctx,
                                                     int32_t domain,
                                                     int32_t ty,
@@ -294,6 +416,34 @@ int32_t hostcall_wasi_snapshot_preview1_sock_connect_wave(void *ctx,
                                                           int32_t addr,
                                                           int32_t addrlen);
 
+int32_t hostcall_wasi_snapshot_preview1_sock_bind_wave(void *ctx,
+                                                       int32_t sockfd,
+                                                       int32_t addr,
+                                                       int32_t addrlen);
+
+int32_t hostcall_wasi_snapshot_preview1_sock_listen_wave(void *ctx,
+                                                         int32_t sockfd,
+                                                         int32_t backlog);
+
+int32_t hostcall_wasi_snapshot_preview1_sock_setsockopt_wave(void *ctx,
+                                                             int32_t fd,
+                                                             int32_t opt,
+                                                             int32_t value,
+                                                             int32_t value_len);
+
+int32_t hostcall_wasi_snapshot_preview1_sock_getsockopt_wave(void *ctx,
+                                                             int32_t fd,
+                                                             int32_t opt,
+                                                             int32_t value,
+                                                             int32_t value_len,
+                                                             int32_t out);
+
+/**
+ * Set up a sandbox for a module whose linear memory is at `memptr`, with `homedir`
+ * preopened as fd 3 and the `netlist_len` endpoints at `netlist` as its netlist.
+ * Returns NULL if the sandbox can't be set up (e.g. `homedir` can't be opened), so
+ * callers must check the result before running the module.
+ */
 struct VmCtx *wave_init(uint8_t *memptr,
                         intptr_t memsize,
                         const char *homedir,
@@ -302,7 +452,8 @@ struct VmCtx *wave_init(uint8_t *memptr,
                         uint8_t *env,
                         uintptr_t envc,
                         char *log_path,
-                        const Netlist *netlist);
+                        const struct NetEndpoint *netlist,
+                        uintptr_t netlist_len);
 
 void wave_cleanup(void *ctx);
 
@@ -381,10 +532,10 @@ uint32_t Z_wasi_snapshot_preview1Z_fd_fdstat_set_flagsZ_iii(void *ctx,
                                                             uint32_t v_fd,
                                                             uint32_t flags);
 
-uint32_t _Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(void *ctx,
-                                                               uint32_t a,
-                                                               uint64_t b,
-                                                               uint64_t c);
+uint32_t Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(void *ctx,
+                                                              uint32_t v_fd,
+                                                              uint64_t fs_rights_base,
+                                                              uint64_t fs_rights_inheriting);
 
 uint32_t Z_wasi_snapshot_preview1Z_fd_filestat_getZ_iii(void *ctx,
                                                         uint32_t v_fd,
@@ -474,7 +625,7 @@ uint32_t Z_wasi_snapshot_preview1Z_path_openZ_iiiiiijjii(void *ctx,
                                                          uint32_t path_len,
                                                          uint32_t oflags,
                                                          uint64_t fs_rights_base,
-                                                         uint64_t _fs_rights_inheriting,
+                                                         uint64_t fs_rights_inheriting,
                                                          uint32_t fdflags,
                                                          uint32_t out);
 
@@ -541,10 +692,34 @@ uint32_t Z_wasi_snapshot_preview1Z_sock_sendZ_iiiiii(void *ctx,
                                                      uint32_t si_flags,
                                                      uint32_t out);
 
+uint32_t Z_wasi_snapshot_preview1Z_sock_recv_fromZ_iiiiiiiii(void *ctx,
+                                                             uint32_t fd,
+                                                             uint32_t buf,
+                                                             uint32_t buf_len,
+                                                             uint32_t ri_flags,
+                                                             uint32_t addr_buf,
+                                                             uint32_t addr_buf_len,
+                                                             uint32_t out0,
+                                                             uint32_t out1);
+
+uint32_t Z_wasi_snapshot_preview1Z_sock_send_toZ_iiiiiiii(void *ctx,
+                                                          uint32_t fd,
+                                                          uint32_t buf,
+                                                          uint32_t buf_len,
+                                                          uint32_t si_flags,
+                                                          uint32_t addr,
+                                                          uint32_t addrlen,
+                                                          uint32_t out);
+
 uint32_t Z_wasi_snapshot_preview1Z_sock_shutdownZ_iii(void *ctx,
                                                       uint32_t fd,
                                                       uint32_t how);
 
+uint32_t Z_wasi_snapshot_preview1Z_sock_acceptZ_iiii(void *ctx,
+                                                     uint32_t fd,
+                                                     uint32_t flags,
+                                                     uint32_t retptr);
+
 uint32_t Z_wasi_snapshot_preview1Z_socketZ_iiiii(void *ctx,
                                                  uint32_t domain,
                                                  uint32_t ty,
@@ -556,6 +731,28 @@ uint32_t Z_wasi_snapshot_preview1Z_sock_connectZ_iiii(void *ctx,
                                                       uint32_t addr,
                                                       uint32_t addrlen);
 
+uint32_t Z_wasi_snapshot_preview1Z_sock_bindZ_iiii(void *ctx,
+                                                   uint32_t sockfd,
+                                                   uint32_t addr,
+                                                   uint32_t addrlen);
+
+uint32_t Z_wasi_snapshot_preview1Z_sock_listenZ_iii(void *ctx,
+                                                    uint32_t sockfd,
+                                                    uint32_t backlog);
+
+uint32_t Z_wasi_snapshot_preview1Z_sock_setsockoptZ_iiiii(void *ctx,
+                                                          uint32_t fd,
+                                                          uint32_t opt,
+                                                          uint32_t value,
+                                                          uint32_t value_len);
+
+uint32_t Z_wasi_snapshot_preview1Z_sock_getsockoptZ_iiiiii(void *ctx,
+                                                           uint32_t fd,
+                                                           uint32_t opt,
+                                                           uint32_t value,
+                                                           uint32_t value_len,
+                                                           uint32_t out);
+
 #ifdef __cplusplus
 } // extern "C"
 #endif // __cplusplus
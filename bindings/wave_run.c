@@ -0,0 +1,24 @@
+// Entry and exit points for running a guest under wave (see run_start in
+// src/wasm2c_frontend.rs). This is compiled into the module together with the
+// wasm2c runtime, so that it shares the runtime's jmp_buf: traps (wasm_rt_trap)
+// and wave's own stops (proc_exit, timeouts, ...) both longjmp back to wave_run
+// instead of unwinding through the guest's frames.
+#include "wasm-rt.h"
+#include "wasm-rt-impl.h"
+
+// Returns 0 if `start` returned, otherwise the code the guest was stopped with:
+// a wasm_rt_trap_t, or a code of wave's own that is larger than all of them.
+int wave_run(int (*start)(void)) {
+  int code = wasm_rt_impl_try();
+  if (code == 0) {
+    start();
+  }
+  return code;
+}
+
+// Also called from signal handlers, which is why the handlers that call it are
+// installed with SA_NODEFER: the runtime only restores the signal mask on its
+// way back when it is built to use sigsetjmp.
+void wave_exit(int code) {
+  WASM_RT_LONGJMP(g_jmp_buf, code);
+}
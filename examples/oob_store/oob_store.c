@@ -0,0 +1,10 @@
+#include <stdio.h>
+
+// Stores past the end of linear memory. Run under waverunner (`make waverun`), the
+// sandbox stops with an out of bounds trap before "Done!" is printed.
+int main() {
+   volatile char *past_end = (volatile char *)0xfffffff0;
+   *past_end = 1;
+   printf("Done!\n");
+   return 0;
+}
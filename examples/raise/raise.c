@@ -2,7 +2,8 @@
 //#include <signal.h>
 //int raise(int sig);
 
-// This test doesn't really do much, all I'm expecting is that it doesn't crash
+// With the default (ignore) signal policy this prints "Done!"; run with
+// `waverunner --on-raise terminate` the sandbox stops with a signaled outcome instead
 int main() {
    __wasi_proc_raise(11);
    __wasi_sched_yield();
@@ -1,10 +1,11 @@
-use crate::tcb::misc::vec_checked_lookup;
+use crate::tcb::misc::{rights_subset, vec_checked_lookup};
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::external_specs::vec::*;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
 use crate::types::*;
 use prusti_contracts::*;
+use std::collections::HashMap;
 use std::io::{stderr, stdin, stdout};
 use std::os::unix::io::AsRawFd;
 use wave_macros::{external_calls, external_methods, with_ghost_var};
@@ -17,22 +18,39 @@ Data structure to map sandbox file descriptors to host file descriptors.
 We will prove things about it's API as necessary.
 */
 
-//TODO: should not be able to close stdin,stdout,stderr,homedir
-// homedir is hardcoded to 3.
+//TODO: should not be able to close stdin,stdout,stderr,preopens
+// preopens are allocated consecutively starting at 3.
 
 impl FdMap {
-    // #[ensures (result.m.len() == MAX_SBOX_FDS)]
+    // The table starts out empty and grows as fds are handed out (see `pop_fd`),
+    // so a large `max_fds` costs nothing until the sandbox actually uses it.
+    #[requires(max_fds <= MAX_SBOX_FDS)]
+    #[ensures (result.m.len() == 0)]
     #[ensures (result.reserve.len() == 0)]
     #[ensures (result.counter == 0)]
-    pub fn new() -> Self {
+    #[ensures (result.max_fds == max_fds)]
+    pub fn new(max_fds: SboxFd) -> Self {
         FdMap {
-            m: vec![Err(Ebadf); MAX_SBOX_FDS as usize],
-            sockinfo: vec![Err(Enotsock); MAX_SBOX_FDS as usize], // these are the host protocol domain/ty/family numbers
+            m: Vec::new(),
+            sockinfo: Vec::new(), // these are the host protocol domain/ty/family numbers
+            pathinfo: Vec::new(),
+            rights: Vec::new(),
+            bound: Vec::new(),
+            inodes: Vec::new(),
+            open_inodes: HashMap::new(),
             reserve: Vec::new(),
             counter: 0,
+            max_fds,
         }
     }
 
+    /// Number of slots currently in the table. Every sandbox fd below this is
+    /// safe to index with.
+    #[pure]
+    pub fn len(&self) -> usize {
+        self.m.len()
+    }
+
     // #[with_ghost_var(trace: &mut Trace)]
     // #[external_call(stdin)]
     // #[external_call(stdout)]
@@ -56,7 +74,7 @@ impl FdMap {
     }
 
     #[pure]
-    #[requires (index < MAX_SBOX_FDS )]
+    #[requires ((index as usize) < self.len())]
     pub fn lookup(&self, index: SboxFd) -> RuntimeResult<HostFd> {
         vec_checked_lookup(&self.m, index)
     }
@@ -64,17 +82,27 @@ impl FdMap {
     #[with_ghost_var(trace: &Trace)]
     #[external_calls(vec_checked_lookup)]
     // #[pure]
-    #[ensures(result.is_ok() ==> old(v_fd) < MAX_SBOX_FDS)]
+    #[ensures(result.is_ok() ==> (old(v_fd) as usize) < self.len())]
     pub fn fd_to_native(&self, v_fd: SboxFd) -> RuntimeResult<HostFd> {
-        if v_fd >= MAX_SBOX_FDS {
+        if v_fd as usize >= self.len() {
             return Err(Ebadf);
         }
         // self.m[idx as usize]
         vec_checked_lookup(&self.m, v_fd)
     }
 
+    /// The host fd of the socket `v_fd`. Anything else fails with Enotsock, including
+    /// files of in-memory preopens, whose fds the host doesn't know about.
+    #[with_ghost_var(trace: &Trace)]
+    #[ensures(result.is_ok() ==> (old(v_fd) as usize) < self.len())]
+    pub fn fd_to_sock(&self, v_fd: SboxFd) -> RuntimeResult<HostFd> {
+        let fd = self.fd_to_native(v_fd)?;
+        self.sockinfo[v_fd as usize]?;
+        Ok(fd)
+    }
+
     #[pure]
-    #[requires(index < MAX_SBOX_FDS)]
+    #[requires((index as usize) < self.len())]
     #[ensures(result == true ==> self.lookup(index).is_ok())]
     pub fn contains(&self, index: SboxFd) -> bool {
         matches!(self.lookup(index), Ok(_))
@@ -84,11 +112,19 @@ impl FdMap {
     // #[external_call(Ok)]
     // #[external_call(Err)]
     // #[external_method(pop)]
+    // Fresh fds are always the next slot past the end of the table, so growing
+    // by one keeps every handed out fd in bounds.
     fn pop_fd(&mut self) -> RuntimeResult<SboxFd> {
         match self.reserve.pop() {
             Some(fd) => Ok(fd),
             None => {
-                if self.counter < MAX_SBOX_FDS {
+                if self.counter < self.max_fds && self.counter < MAX_SBOX_FDS {
+                    self.m.push(Err(Ebadf));
+                    self.sockinfo.push(Err(Enotsock));
+                    self.pathinfo.push(Err(Enotdir));
+                    self.rights.push(FdRights::empty());
+                    self.bound.push(false);
+                    self.inodes.push(None);
                     self.counter += 1;
                     return Ok(self.counter - 1);
                 }
@@ -108,17 +144,116 @@ impl FdMap {
     pub fn create(&mut self, k: HostFd) -> RuntimeResult<SboxFd> {
         let s_fd = self.pop_fd()?;
         self.m[s_fd as usize] = Ok(k);
+        self.rights[s_fd as usize] = FdRights::all();
         Ok(s_fd)
     }
 
-    pub fn create_sock(&mut self, k: HostFd, proto: WasiProto) -> RuntimeResult<SboxFd> {
+    pub fn create_sock(
+        &mut self,
+        k: HostFd,
+        proto: WasiProto,
+        rights: FdRights,
+    ) -> RuntimeResult<SboxFd> {
         let s_fd = self.pop_fd()?;
         self.m[s_fd as usize] = Ok(k);
         self.sockinfo[s_fd as usize] = Ok(proto);
+        self.rights[s_fd as usize] = rights;
+        Ok(s_fd)
+    }
+
+    // Used for preopens and for fds created by path_open, so that later *at calls
+    // can be resolved relative to them
+    pub fn create_at(
+        &mut self,
+        k: HostFd,
+        pos: PathPos,
+        rights: FdRights,
+    ) -> RuntimeResult<SboxFd> {
+        let s_fd = self.pop_fd()?;
+        self.m[s_fd as usize] = Ok(k);
+        self.pathinfo[s_fd as usize] = Ok(pos);
+        self.rights[s_fd as usize] = rights;
         Ok(s_fd)
     }
 
-    #[requires(k < MAX_SBOX_FDS)]
+    /// Fails with Ebadf if `v_fd` is not open, or Enotcapable if it does not
+    /// hold every right in `needed`.
+    #[with_ghost_var(trace: &Trace)]
+    #[external_calls(rights_subset)]
+    #[ensures(result.is_ok() ==> (old(v_fd) as usize) < self.len())]
+    pub fn check_rights(&self, v_fd: SboxFd, needed: Rights) -> RuntimeResult<()> {
+        if v_fd as usize >= self.len() {
+            return Err(Ebadf);
+        }
+        if !self.contains(v_fd) {
+            return Err(Ebadf);
+        }
+        if !rights_subset(needed, self.rights[v_fd as usize].base) {
+            return Err(Enotcapable);
+        }
+        Ok(())
+    }
+
+    #[requires((index as usize) < self.len())]
+    pub fn fd_to_rights(&self, index: SboxFd) -> FdRights {
+        self.rights[index as usize]
+    }
+
+    /// Replace the rights of `index`. Rights may only ever be dropped.
+    #[requires((index as usize) < self.len())]
+    pub fn narrow_rights(&mut self, index: SboxFd, rights: FdRights) -> RuntimeResult<()> {
+        if !rights.within(&self.rights[index as usize]) {
+            return Err(Enotcapable);
+        }
+        self.rights[index as usize] = rights;
+        Ok(())
+    }
+
+    #[requires((index as usize) < self.len())]
+    pub fn mark_bound(&mut self, index: SboxFd) {
+        self.bound[index as usize] = true;
+    }
+
+    #[requires((index as usize) < self.len())]
+    pub fn is_bound(&self, index: SboxFd) -> bool {
+        self.bound[index as usize]
+    }
+
+    /// Record that `index` refers to the file with inode `ino` on device `dev`
+    #[requires((index as usize) < self.len())]
+    pub fn set_inode(&mut self, index: SboxFd, dev: u64, ino: u64) {
+        self.release_inode(index);
+        self.inodes[index as usize] = Some((dev, ino));
+        *self.open_inodes.entry((dev, ino)).or_insert(0) += 1;
+    }
+
+    /// Whether any fd was recorded (see `set_inode`) to refer to the file with inode
+    /// `ino` on device `dev`
+    pub fn inode_open(&self, dev: u64, ino: u64) -> bool {
+        self.open_inodes.contains_key(&(dev, ino))
+    }
+
+    #[requires((index as usize) < self.len())]
+    fn release_inode(&mut self, index: SboxFd) {
+        if let Some(key) = self.inodes[index as usize].take() {
+            match self.open_inodes.get_mut(&key) {
+                Some(count) if *count > 1 => *count -= 1,
+                _ => {
+                    self.open_inodes.remove(&key);
+                }
+            }
+        }
+    }
+
+    #[requires((index as usize) < self.len())]
+    pub fn fd_to_pos(&self, index: SboxFd) -> RuntimeResult<PathPos> {
+        match &self.pathinfo[index as usize] {
+            Ok(pos) => Ok(pos.clone()),
+            Err(e) => Err(*e),
+        }
+    }
+
+    #[requires((k as usize) < self.len())]
     // #[with_ghost_var(trace: &mut Trace)]
     // #[external_call(Err)]
     // #[external_call(init_std_fds)]
@@ -132,16 +267,31 @@ impl FdMap {
         if let Ok(oldfd) = self.m[k as usize] {
             self.reserve.push(k);
         }
+        self.release_inode(k);
         self.m[k as usize] = Err(Ebadf);
+        self.sockinfo[k as usize] = Err(Enotsock);
+        self.pathinfo[k as usize] = Err(Enotdir);
+        self.rights[k as usize] = FdRights::empty();
+        self.bound[k as usize] = false;
     }
 
-    #[requires(from < MAX_SBOX_FDS)]
-    #[requires(to < MAX_SBOX_FDS)]
+    #[requires((from as usize) < self.len())]
+    #[requires((to as usize) < self.len())]
     pub fn shift(&mut self, from: SboxFd, to: SboxFd) {
         if let Ok(hostfd) = self.m[from as usize] {
-            self.m[to as usize] = Ok(hostfd)
+            self.release_inode(to);
+            self.m[to as usize] = Ok(hostfd);
+            self.sockinfo[to as usize] = self.sockinfo[from as usize];
+            self.pathinfo[to as usize] = self.pathinfo[from as usize].clone();
+            self.rights[to as usize] = self.rights[from as usize];
+            self.bound[to as usize] = self.bound[from as usize];
+            self.inodes[to as usize] = self.inodes[from as usize].take();
         }
         self.m[from as usize] = Err(Ebadf);
+        self.sockinfo[from as usize] = Err(Enotsock);
+        self.pathinfo[from as usize] = Err(Enotdir);
+        self.rights[from as usize] = FdRights::empty();
+        self.bound[from as usize] = false;
     }
 
     // // auto drop open file descriptors and shutdown sockets
@@ -153,7 +303,7 @@ impl FdMap {
     // #[external_methods(lookup)]
     // fn drop(&mut self) {
     //     let mut idx = 3; // not stdin,stdout,stderr
-    //     while idx < MAX_SBOX_FDS {
+    //     while idx < self.len() {
     //         // body_invariant!(ctx_safe(self));
     //         // body_invariant!(trace_safe(trace, self));
     //         match self.lookup(idx) {
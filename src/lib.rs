@@ -30,5 +30,6 @@ mod writeback;
 mod iov;
 mod path_resolution;
 mod poll;
+mod quota;
 mod wrappers;
 //pub mod setup_teardown;
@@ -55,10 +55,7 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_args_sizes_get_wave(
 #[no_mangle]
 //#[trace(logging)]
 pub extern "C" fn hostcall_wasi_snapshot_preview1_proc_exit_wave(ctx: *const *mut VmCtx, x: i32) {
-    std::panic::panic_any(3);
-    //panic!(x);
-    //panic::resume_unwind(Box::new("this is an exit!"))
-    //Z_wasi_snapshot_preview1Z_proc_exitZ_vi(ctx, x as u32)
+    Z_wasi_snapshot_preview1Z_proc_exitZ_vi(ctx, x as u32)
 }
 
 #[no_mangle]
@@ -241,7 +238,7 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_fd_fdstat_set_rights_wave(
     b: i64,
     c: i64,
 ) -> i32 {
-    _Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(ctx, a as u32, b as u64, c as u64) as i32
+    Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(ctx, a as u32, b as u64, c as u64) as i32
 }
 
 #[no_mangle]
@@ -483,7 +480,7 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_path_open_wave(
     path_len: i32,
     oflags: i32,
     fs_rights_base: i64,
-    _fs_rights_inheriting: i64,
+    fs_rights_inheriting: i64,
     fdflags: i32,
     out: i32,
 ) -> i32 {
@@ -495,7 +492,7 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_path_open_wave(
         path_len as u32,
         oflags as u32,
         fs_rights_base as u64,
-        _fs_rights_inheriting as u64,
+        fs_rights_inheriting as u64,
         fdflags as u32,
         out as u32,
     ) as i32
@@ -678,6 +675,56 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_send_wave(
     ) as i32
 }
 
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_recv_from_wave(
+    ctx: *const *mut VmCtx,
+    fd: i32,
+    buf: i32,
+    buf_len: i32,
+    ri_flags: i32,
+    addr_buf: i32,
+    addr_buf_len: i32,
+    out0: i32,
+    out1: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_recv_fromZ_iiiiiiiii(
+        ctx,
+        fd as u32,
+        buf as u32,
+        buf_len as u32,
+        ri_flags as u32,
+        addr_buf as u32,
+        addr_buf_len as u32,
+        out0 as u32,
+        out1 as u32,
+    ) as i32
+}
+
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_send_to_wave(
+    ctx: *const *mut VmCtx,
+    fd: i32,
+    buf: i32,
+    buf_len: i32,
+    si_flags: i32,
+    addr: i32,
+    addrlen: i32,
+    out: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_send_toZ_iiiiiiii(
+        ctx,
+        fd as u32,
+        buf as u32,
+        buf_len as u32,
+        si_flags as u32,
+        addr as u32,
+        addrlen as u32,
+        out as u32,
+    ) as i32
+}
+
 #[no_mangle]
 //#[trace(logging)]
 pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_shutdown_wave(
@@ -688,6 +735,17 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_shutdown_wave(
     Z_wasi_snapshot_preview1Z_sock_shutdownZ_iii(ctx, fd as u32, how as u32) as i32
 }
 
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_accept_wave(
+    ctx: *const *mut VmCtx,
+    fd: i32,
+    flags: i32,
+    retptr: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_acceptZ_iiii(ctx, fd as u32, flags as u32, retptr as u32) as i32
+}
+
 /*
  New Calls
 */
@@ -721,3 +779,63 @@ pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_connect_wave(
     Z_wasi_snapshot_preview1Z_sock_connectZ_iiii(ctx, sockfd as u32, addr as u32, addrlen as u32)
         as i32
 }
+
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_bind_wave(
+    ctx: *const *mut VmCtx,
+    sockfd: i32,
+    addr: i32,
+    addrlen: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_bindZ_iiii(ctx, sockfd as u32, addr as u32, addrlen as u32)
+        as i32
+}
+
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_listen_wave(
+    ctx: *const *mut VmCtx,
+    sockfd: i32,
+    backlog: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_listenZ_iii(ctx, sockfd as u32, backlog as u32) as i32
+}
+
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_setsockopt_wave(
+    ctx: *const *mut VmCtx,
+    fd: i32,
+    opt: i32,
+    value: i32,
+    value_len: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_setsockoptZ_iiiii(
+        ctx,
+        fd as u32,
+        opt as u32,
+        value as u32,
+        value_len as u32,
+    ) as i32
+}
+
+#[no_mangle]
+//#[trace(logging)]
+pub extern "C" fn hostcall_wasi_snapshot_preview1_sock_getsockopt_wave(
+    ctx: *const *mut VmCtx,
+    fd: i32,
+    opt: i32,
+    value: i32,
+    value_len: i32,
+    out: i32,
+) -> i32 {
+    Z_wasi_snapshot_preview1Z_sock_getsockoptZ_iiiiii(
+        ctx,
+        fd as u32,
+        opt as u32,
+        value as u32,
+        value_len as u32,
+        out as u32,
+    ) as i32
+}
@@ -0,0 +1,59 @@
+use crate::types::*;
+
+// The filesystem a preopen is backed by. Host directories are served by the os_* calls
+// directly, so the verifier keeps seeing their specs; other backends implement this
+// trait with the same syscall-shaped interface: raw fds in, negative errnos out.
+// Every fd a backend hands out must be one only it owns, since trace_* picks the
+// backend by fd alone (see `backend_for` in tcb/memfs.rs).
+pub trait FsBackend {
+    /// Whether `fd` is one of this backend's open files
+    fn owns(&self, fd: usize) -> bool;
+    fn openat(&self, dirfd: usize, path: HostPath, flags: i32, mode: i32) -> isize;
+    fn close(&self, fd: usize) -> isize;
+    fn read(&self, fd: usize, buf: &mut [u8], cnt: usize) -> isize;
+    fn pread(&self, fd: usize, buf: &mut [u8], cnt: usize, offset: usize) -> isize;
+    fn readv(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize) -> isize;
+    fn preadv(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize, offset: usize) -> isize;
+    fn write(&self, fd: usize, buf: &[u8], cnt: usize) -> isize;
+    fn pwrite(&self, fd: usize, buf: &[u8], cnt: usize, offset: usize) -> isize;
+    fn writev(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize) -> isize;
+    fn pwritev(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize, offset: usize) -> isize;
+    fn lseek(&self, fd: usize, offset: i64, whence: i32) -> isize;
+    fn sync(&self, fd: usize) -> isize;
+    fn fdatasync(&self, fd: usize) -> isize;
+    fn fstat(&self, fd: usize, stat: &mut libc::stat) -> isize;
+    fn fstatat(&self, dirfd: usize, path: HostPath, stat: &mut libc::stat, flags: i32) -> isize;
+    fn fcntl(&self, fd: usize, cmd: i32, arg: libc::c_int) -> isize;
+    fn ftruncate(&self, fd: usize, length: libc::off_t) -> isize;
+    fn allocate(&self, fd: usize, offset: i64, len: i64) -> isize;
+    fn advise(&self, fd: usize, offset: i64, len: i64, advice: i32) -> isize;
+    fn linkat(
+        &self,
+        old_fd: usize,
+        old_path: HostPath,
+        new_fd: usize,
+        new_path: HostPath,
+        flags: i32,
+    ) -> isize;
+    fn mkdirat(&self, dirfd: usize, path: HostPath, mode: libc::mode_t) -> isize;
+    fn readlinkat(&self, dirfd: usize, path: HostPath, buf: &mut [u8], cnt: usize) -> isize;
+    fn unlinkat(&self, dirfd: usize, path: HostPath, flags: libc::c_int) -> isize;
+    fn renameat(
+        &self,
+        old_fd: usize,
+        old_path: HostPath,
+        new_fd: usize,
+        new_path: HostPath,
+    ) -> isize;
+    fn symlinkat(&self, target: HostPath, dirfd: usize, path: HostPath) -> isize;
+    fn futimens(&self, fd: usize, specs: &Vec<libc::timespec>) -> isize;
+    fn utimensat(
+        &self,
+        dirfd: usize,
+        path: HostPath,
+        specs: &Vec<libc::timespec>,
+        flags: libc::c_int,
+    ) -> isize;
+    /// Fill `dirp` (up to `count` bytes) with linux_dirent64 records, like the syscall
+    fn getdents64(&self, fd: usize, dirp: &mut Vec<u8>, count: usize) -> isize;
+}
@@ -1,12 +1,14 @@
-use crate::tcb::misc::flag_set;
+use crate::tcb::memfs::backend_for;
+use crate::tcb::misc::{flag_set, is_preopen_fd, sockaddr_ip, sockaddr_port};
 use crate::tcb::os_specs::*;
 #[cfg(feature = "verify")]
 use crate::tcb::path::path_safe;
 use crate::tcb::sbox_mem::{raw_ptr, valid_linmem};
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
+use crate::tcb::walk::pin_parent;
 use crate::types::*;
-use crate::{effect, effects};
+use crate::{effect, effects, net_effect};
 use prusti_contracts::*;
 use syscall::syscall;
 use wave_macros::with_ghost_var;
@@ -16,13 +18,16 @@ use wave_macros::with_ghost_var;
 mod platform;
 pub use platform::*;
 
+pub mod backend;
+use backend::FsBackend;
+
 // Common implementations between operating systems
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&path, !flag_set(flags, libc::O_NOFOLLOW) ))] // path_safe is parameterized by `should_follow`, so we need to reverse it
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 // #[ensures(one_effect!(old(trace), trace, effect!(PathAccessAt, os_fd, p)))]
@@ -33,7 +38,19 @@ pub fn trace_openat(
     flags: i32,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = dir_fd.to_raw();
-    let r = os_openat(os_fd, path, flags, 0o666);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.openat(os_fd, path, flags, 0o666),
+        // walks are not needed in memory, nothing else can change it mid-call
+        None if ctx.path_resolution == PathResolution::Walk => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &path)?;
+            let flags = flags | libc::O_NOFOLLOW;
+            os_openat(pinned.dirfd.to_raw(), pinned.leaf, flags, 0o666)
+        }
+        None if ctx.path_resolution == PathResolution::Beneath => {
+            return trace_openat_beneath(ctx, dir_fd, path, flags);
+        }
+        None => os_openat(os_fd, path, flags, 0o666),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -45,7 +62,10 @@ pub fn trace_openat(
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_close(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_close(os_fd);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.close(os_fd),
+        None => os_close(os_fd),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -59,13 +79,38 @@ pub fn trace_close(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
 // read writes `cnt` bytes to sandbox memory
 // #[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(WriteMem, addr, count)))]
 pub fn trace_read(ctx: &mut VmCtx, fd: HostFd, ptr: SboxPtr, cnt: usize) -> RuntimeResult<usize> {
+    let backend = backend_for(ctx, fd.to_raw());
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_read(os_fd, slice, cnt);
+    let r = match backend {
+        Some(fs) => fs.read(os_fd, slice, cnt),
+        None => os_read(os_fd, slice, cnt),
+    };
 
     RuntimeError::from_syscall_ret(r)
 }
 
+// Like trace_read, but into a buffer of the runtime's own (e.g. for overlay copy-ups)
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(buf.len() >= cnt)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_read_host(
+    ctx: &VmCtx,
+    fd: HostFd,
+    buf: &mut [u8],
+    cnt: usize,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = fd.to_raw();
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.read(os_fd, buf, cnt),
+        None => os_read(os_fd, buf, cnt),
+    };
+    RuntimeError::from_syscall_ret(r)
+}
+
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(
     iovs.len() >= 0 &&
@@ -99,7 +144,10 @@ pub fn trace_readv(
     let mut native_iovs = ctx.translate_iovs(iovs);
     // native_iovs
     let os_fd: usize = fd.to_raw();
-    let r = os_readv(os_fd, &mut native_iovs, iovcnt);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.readv(os_fd, &native_iovs, iovcnt),
+        None => os_readv(os_fd, &mut native_iovs, iovcnt),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -119,9 +167,13 @@ pub fn trace_pread(
     cnt: usize,
     offset: usize,
 ) -> RuntimeResult<usize> {
+    let backend = backend_for(ctx, fd.to_raw());
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_pread(os_fd, slice, cnt, offset);
+    let r = match backend {
+        Some(fs) => fs.pread(os_fd, slice, cnt, offset),
+        None => os_pread(os_fd, slice, cnt, offset),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -152,7 +204,10 @@ pub fn trace_preadv(
     let native_iovs = ctx.translate_iovs(iovs);
     // native_iovs
     let os_fd: usize = fd.to_raw();
-    let r = os_preadv(os_fd, &native_iovs, iovcnt, offset);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.preadv(os_fd, &native_iovs, iovcnt, offset),
+        None => os_preadv(os_fd, &native_iovs, iovcnt, offset),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -166,9 +221,29 @@ pub fn trace_preadv(
 // write reads `cnt` bytes to the sandbox
 // #[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(ReadMem, addr, count)))]
 pub fn trace_write(ctx: &mut VmCtx, fd: HostFd, ptr: SboxPtr, cnt: usize) -> RuntimeResult<usize> {
+    let backend = backend_for(ctx, fd.to_raw());
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_write(os_fd, slice, cnt);
+    let r = match backend {
+        Some(fs) => fs.write(os_fd, slice, cnt),
+        None => os_write(os_fd, slice, cnt),
+    };
+    RuntimeError::from_syscall_ret(r)
+}
+
+// Like trace_write, but from a buffer of the runtime's own
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(buf.len() >= cnt)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_write_host(ctx: &VmCtx, fd: HostFd, buf: &[u8], cnt: usize) -> RuntimeResult<usize> {
+    let os_fd: usize = fd.to_raw();
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.write(os_fd, buf, cnt),
+        None => os_write(os_fd, buf, cnt),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -198,7 +273,10 @@ pub fn trace_writev(
     let native_iovs = ctx.translate_iovs(iovs);
     // native_iovs
     let os_fd: usize = fd.to_raw();
-    let r = os_writev(os_fd, &native_iovs, iovcnt);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.writev(os_fd, &native_iovs, iovcnt),
+        None => os_writev(os_fd, &native_iovs, iovcnt),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -229,7 +307,10 @@ pub fn trace_pwritev(
     let native_iovs = ctx.translate_iovs(iovs);
     // native_iovs
     let os_fd: usize = fd.to_raw();
-    let r = os_pwritev(os_fd, &native_iovs, iovcnt, offset);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.pwritev(os_fd, &native_iovs, iovcnt, offset),
+        None => os_pwritev(os_fd, &native_iovs, iovcnt, offset),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -249,9 +330,13 @@ pub fn trace_pwrite(
     cnt: usize,
     offset: usize,
 ) -> RuntimeResult<usize> {
+    let backend = backend_for(ctx, fd.to_raw());
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_pwrite(os_fd, slice, cnt, offset);
+    let r = match backend {
+        Some(fs) => fs.pwrite(os_fd, slice, cnt, offset),
+        None => os_pwrite(os_fd, slice, cnt, offset),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -263,7 +348,10 @@ pub fn trace_pwrite(
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_seek(ctx: &VmCtx, fd: HostFd, offset: i64, whence: i32) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_lseek(os_fd, offset, whence);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.lseek(os_fd, offset, whence),
+        None => os_lseek(os_fd, offset, whence),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -275,7 +363,10 @@ pub fn trace_seek(ctx: &VmCtx, fd: HostFd, offset: i64, whence: i32) -> RuntimeR
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_sync(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_sync(os_fd);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.sync(os_fd),
+        None => os_sync(os_fd),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -287,7 +378,10 @@ pub fn trace_sync(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_datasync(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fdatasync(os_fd);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.fdatasync(os_fd),
+        None => os_fdatasync(os_fd),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -299,13 +393,16 @@ pub fn trace_datasync(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_fstat(ctx: &VmCtx, fd: HostFd, stat: &mut libc::stat) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fstat(os_fd, stat);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.fstat(os_fd, stat),
+        None => os_fstat(os_fd, stat),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&path, !flag_set(flags, libc::AT_SYMLINK_NOFOLLOW) ))] // flags == 0 means that O_NOFOLLOW is not set and therefore that should_follow is true
-#[requires(fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -319,7 +416,15 @@ pub fn trace_fstatat(
     flags: i32,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fstatat(os_fd, path, stat, flags);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.fstatat(os_fd, path, stat, flags),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, fd, &path)?;
+            let flags = flags | libc::AT_SYMLINK_NOFOLLOW;
+            os_fstatat(pinned.dirfd.to_raw(), pinned.leaf, stat, flags)
+        }
+        None => os_fstatat(os_fd, path, stat, flags),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -331,7 +436,10 @@ pub fn trace_fstatat(
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_fgetfl(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fcntl(os_fd, libc::F_GETFL, 0);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.fcntl(os_fd, libc::F_GETFL, 0),
+        None => os_fcntl(os_fd, libc::F_GETFL, 0),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -343,7 +451,10 @@ pub fn trace_fgetfl(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<usize> {
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_fsetfl(ctx: &VmCtx, fd: HostFd, flags: libc::c_int) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fcntl(os_fd, libc::F_SETFL, flags);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.fcntl(os_fd, libc::F_SETFL, flags),
+        None => os_fcntl(os_fd, libc::F_SETFL, flags),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -355,15 +466,18 @@ pub fn trace_fsetfl(ctx: &VmCtx, fd: HostFd, flags: libc::c_int) -> RuntimeResul
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_ftruncate(ctx: &VmCtx, fd: HostFd, length: libc::off_t) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_ftruncate(os_fd, length);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.ftruncate(os_fd, length),
+        None => os_ftruncate(os_fd, length),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&old_path, flag_set(flags, libc::AT_SYMLINK_FOLLOW)))]
 #[requires(path_safe(&new_path, flag_set(flags, libc::AT_SYMLINK_FOLLOW)))]
-#[requires(old_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
-#[requires(new_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, old_fd.to_raw()))]
+#[requires(is_preopen_fd(&ctx.preopens, new_fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -381,13 +495,31 @@ pub fn trace_linkat(
     let os_new_fd: usize = new_fd.to_raw();
     // let os_old_path: Vec<u8> = old_path.into();
     // let os_new_path: Vec<u8> = new_path.into();
-    let r = os_linkat(os_old_fd, old_path, os_new_fd, new_path, flags);
+    let r = match backend_for(ctx, os_old_fd) {
+        // hard links can't cross backends, just like they can't cross mounts
+        Some(fs) if !fs.owns(os_new_fd) => -(libc::EXDEV as isize),
+        Some(fs) => fs.linkat(os_old_fd, old_path, os_new_fd, new_path, flags),
+        None if backend_for(ctx, os_new_fd).is_some() => -(libc::EXDEV as isize),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let old = pin_parent(ctx.path_resolution, old_fd, &old_path)?;
+            let new = pin_parent(ctx.path_resolution, new_fd, &new_path)?;
+            let flags = flags & !libc::AT_SYMLINK_FOLLOW;
+            os_linkat(
+                old.dirfd.to_raw(),
+                old.leaf,
+                new.dirfd.to_raw(),
+                new.leaf,
+                flags,
+            )
+        }
+        None => os_linkat(os_old_fd, old_path, os_new_fd, new_path, flags),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&path, true))]
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -401,13 +533,20 @@ pub fn trace_mkdirat(
 ) -> RuntimeResult<usize> {
     let os_fd: usize = dir_fd.to_raw();
     // let os_path: Vec<u8> = pathname.into();
-    let r = os_mkdirat(os_fd, path, mode);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.mkdirat(os_fd, path, mode),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &path)?;
+            os_mkdirat(pinned.dirfd.to_raw(), pinned.leaf, mode)
+        }
+        None => os_mkdirat(os_fd, path, mode),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&pathname, false))]
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[requires(ctx.fits_in_lin_mem(ptr, cnt as u32, trace))]
 #[requires(cnt < ctx.memlen)]
 #[requires(ctx_safe(ctx))]
@@ -422,16 +561,53 @@ pub fn trace_readlinkat(
     ptr: SboxPtr,
     cnt: usize,
 ) -> RuntimeResult<usize> {
+    let backend = backend_for(ctx, dir_fd.to_raw());
+    let mode = ctx.path_resolution;
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = dir_fd.to_raw();
     // let os_path: Vec<u8> = pathname.into();
-    let r = os_readlinkat(os_fd, pathname, slice, cnt);
+    let r = match backend {
+        Some(fs) => fs.readlinkat(os_fd, pathname, slice, cnt),
+        None if mode != PathResolution::Expand => {
+            let pinned = pin_parent(mode, dir_fd, &pathname)?;
+            os_readlinkat(pinned.dirfd.to_raw(), pinned.leaf, slice, cnt)
+        }
+        None => os_readlinkat(os_fd, pathname, slice, cnt),
+    };
+    RuntimeError::from_syscall_ret(r)
+}
+
+// Like trace_readlinkat, but into a buffer of the runtime's own (e.g. to expand symlinks)
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(path_safe(&pathname, false))]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
+#[requires(buf.len() >= cnt)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_readlinkat_host(
+    ctx: &VmCtx,
+    dir_fd: HostFd,
+    pathname: HostPath,
+    buf: &mut [u8],
+    cnt: usize,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = dir_fd.to_raw();
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.readlinkat(os_fd, pathname, buf, cnt),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &pathname)?;
+            os_readlinkat(pinned.dirfd.to_raw(), pinned.leaf, buf, cnt)
+        }
+        None => os_readlinkat(os_fd, pathname, buf, cnt),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&path, false))]
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -445,15 +621,22 @@ pub fn trace_unlinkat(
 ) -> RuntimeResult<usize> {
     let os_fd: usize = dir_fd.to_raw();
     // let os_path: Vec<u8> = pathname.into();
-    let r = os_unlinkat(os_fd, path, flags);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.unlinkat(os_fd, path, flags),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &path)?;
+            os_unlinkat(pinned.dirfd.to_raw(), pinned.leaf, flags)
+        }
+        None => os_unlinkat(os_fd, path, flags),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&old_path, false))]
 #[requires(path_safe(&new_path, false))]
-#[requires(old_dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
-#[requires(new_dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, old_dir_fd.to_raw()))]
+#[requires(is_preopen_fd(&ctx.preopens, new_dir_fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -470,14 +653,25 @@ pub fn trace_renameat(
     // let os_old_path: Vec<u8> = old_pathname.into();
     let os_new_fd: usize = new_dir_fd.to_raw();
     // let os_new_path: Vec<u8> = new_pathname.into();
-    let r = os_renameat(os_old_fd, old_path, os_new_fd, new_path);
+    let r = match backend_for(ctx, os_old_fd) {
+        // and neither can renames
+        Some(fs) if !fs.owns(os_new_fd) => -(libc::EXDEV as isize),
+        Some(fs) => fs.renameat(os_old_fd, old_path, os_new_fd, new_path),
+        None if backend_for(ctx, os_new_fd).is_some() => -(libc::EXDEV as isize),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let old = pin_parent(ctx.path_resolution, old_dir_fd, &old_path)?;
+            let new = pin_parent(ctx.path_resolution, new_dir_fd, &new_path)?;
+            os_renameat(old.dirfd.to_raw(), old.leaf, new.dirfd.to_raw(), new.leaf)
+        }
+        None => os_renameat(os_old_fd, old_path, os_new_fd, new_path),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&old_pathname, true))]
 #[requires(path_safe(&new_pathname, true))]
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -492,7 +686,14 @@ pub fn trace_symlinkat(
     let os_fd: usize = dir_fd.to_raw();
     // let os_old_path: Vec<u8> = old_pathname.into();
     // let os_new_path: Vec<u8> = new_pathname.into();
-    let r = os_symlinkat(old_pathname, os_fd, new_pathname);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.symlinkat(old_pathname, os_fd, new_pathname),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &new_pathname)?;
+            os_symlinkat(old_pathname, pinned.dirfd.to_raw(), pinned.leaf)
+        }
+        None => os_symlinkat(old_pathname, os_fd, new_pathname),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -509,13 +710,16 @@ pub fn trace_futimens(
     specs: &Vec<libc::timespec>,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_futimens(os_fd, specs);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.futimens(os_fd, specs),
+        None => os_futimens(os_fd, specs),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(path_safe(&path, !flag_set(flags, libc::AT_SYMLINK_NOFOLLOW) ))] // flags == 0 means that O_NOFOLLOW is not set and therefore that should_follow is true
-#[requires(dir_fd.to_raw() == ctx.homedir_host_fd.to_raw())]
+#[requires(is_preopen_fd(&ctx.preopens, dir_fd.to_raw()))]
 #[requires(specs.len() >= 2)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
@@ -531,7 +735,15 @@ pub fn trace_utimensat(
 ) -> RuntimeResult<usize> {
     let os_fd: usize = dir_fd.to_raw();
     // let os_path: Vec<u8> = pathname.into();
-    let r = os_utimensat(os_fd, path, specs, flags);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.utimensat(os_fd, path, specs, flags),
+        None if ctx.path_resolution != PathResolution::Expand => {
+            let pinned = pin_parent(ctx.path_resolution, dir_fd, &path)?;
+            let flags = flags | libc::AT_SYMLINK_NOFOLLOW;
+            os_utimensat(pinned.dirfd.to_raw(), pinned.leaf, specs, flags)
+        }
+        None => os_utimensat(os_fd, path, specs, flags),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -562,8 +774,8 @@ pub fn trace_getrandom(
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-// #[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(WriteMem, addr, count)))]
-pub fn trace_recv(
+// #[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(ReadMem, addr, count)))]
+pub fn trace_send(
     ctx: &mut VmCtx,
     fd: HostFd,
     ptr: SboxPtr,
@@ -572,7 +784,39 @@ pub fn trace_recv(
 ) -> RuntimeResult<usize> {
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_recvfrom(os_fd, slice, cnt, flags, 0, 0);
+    let r = os_sendto(os_fd, slice, cnt, flags, 0, 0);
+    RuntimeError::from_syscall_ret(r)
+}
+
+// recv into every iovec with a single recvmsg, so flags like MSG_PEEK apply to the
+// message as a whole. The kernel's msg_flags are returned through `msg_flags`.
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(
+    iovs.len() >= 0 &&
+    forall(|idx: usize|  (idx < iovs.len() && idx >= 0) ==> {
+        let iov = iovs.lookup(idx);
+        let buf = iov.iov_base;
+        let cnt = iov.iov_len;
+        (buf >= 0) && (cnt >= 0) &&
+        (buf as usize) + (cnt as usize) < LINEAR_MEM_SIZE &&
+        (buf <= buf + cnt)
+    })
+)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_recvmsg(
+    ctx: &mut VmCtx,
+    fd: HostFd,
+    iovs: &WasmIoVecs,
+    iovcnt: usize,
+    flags: i32,
+    msg_flags: &mut i32,
+) -> RuntimeResult<usize> {
+    let native_iovs = ctx.translate_iovs(iovs);
+    let os_fd: usize = fd.to_raw();
+    let r = os_recvmsg(os_fd, &native_iovs, iovcnt, flags, msg_flags);
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -583,17 +827,42 @@ pub fn trace_recv(
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-// #[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(ReadMem, addr, count)))]
-pub fn trace_send(
+pub fn trace_recvfrom(
     ctx: &mut VmCtx,
     fd: HostFd,
     ptr: SboxPtr,
     cnt: usize,
     flags: i32,
+    src: &mut libc::sockaddr_storage,
+    addrlen: &mut u32,
 ) -> RuntimeResult<usize> {
     let slice = ctx.slice_mem_mut(ptr, cnt as u32);
     let os_fd: usize = fd.to_raw();
-    let r = os_sendto(os_fd, slice, cnt, flags, 0, 0);
+    let r = os_recvfrom_addr(os_fd, slice, cnt, flags, src, addrlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx.fits_in_lin_mem(ptr, cnt as u32, trace))]
+#[requires(cnt < ctx.memlen)]
+#[requires(addr_in_netlist(&ctx.netlist, protocol, sockaddr_ip(dest), sockaddr_port(dest)))]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_sendto(
+    ctx: &mut VmCtx,
+    fd: HostFd,
+    protocol: WasiProto,
+    ptr: SboxPtr,
+    cnt: usize,
+    flags: i32,
+    dest: &libc::sockaddr_storage,
+    addrlen: u32,
+) -> RuntimeResult<usize> {
+    let slice = ctx.slice_mem_mut(ptr, cnt as u32);
+    let os_fd: usize = fd.to_raw();
+    let r = os_sendto_addr(os_fd, slice, cnt, flags, dest, addrlen);
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -638,12 +907,15 @@ pub fn trace_getdents64(
     count: usize,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_getdents64(os_fd, dirp, count);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.getdents64(os_fd, dirp, count),
+        None => os_getdents64(os_fd, dirp, count),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
-#[requires(domain == libc::AF_INET && (ty == libc::SOCK_STREAM || ty == libc::SOCK_DGRAM ))]
+#[requires((domain == libc::AF_INET || domain == libc::AF_INET6) && (ty == libc::SOCK_STREAM || ty == libc::SOCK_DGRAM ))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -655,16 +927,17 @@ pub fn trace_socket(ctx: &VmCtx, domain: i32, ty: i32, protocol: i32) -> Runtime
 }
 
 #[with_ghost_var(trace: &mut Trace)]
-#[requires(addr_in_netlist(&ctx.netlist, addr.sin_addr.s_addr, addr.sin_port as u32))]
+#[requires(addr_in_netlist(&ctx.netlist, protocol, sockaddr_ip(addr), sockaddr_port(addr)))]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-#[ensures(effects!(old(trace), trace, effect!(FdAccess), effect!(NetAccess, protocol, ip, port)))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess), net_effect!(NetAccess, protocol, ip, port)))]
 pub fn trace_connect(
     ctx: &VmCtx,
     sockfd: HostFd,
-    addr: &libc::sockaddr_in,
+    protocol: WasiProto,
+    addr: &libc::sockaddr_storage,
     addrlen: u32,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = sockfd.to_raw();
@@ -672,6 +945,130 @@ pub fn trace_connect(
     RuntimeError::from_syscall_ret(r)
 }
 
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(addr_in_netlist(&ctx.inbound.bindlist, protocol, sockaddr_ip(addr), sockaddr_port(addr)))]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess), net_effect!(SockBind, protocol, ip, port)))]
+pub fn trace_bind(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    protocol: WasiProto,
+    addr: &libc::sockaddr_storage,
+    addrlen: u32,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let r = os_bind(os_fd, addr, addrlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_listen(ctx: &VmCtx, sockfd: HostFd, backlog: i32) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let r = os_listen(os_fd, backlog);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_setsockopt_int(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    level: i32,
+    optname: i32,
+    optval: &i32,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let optlen = std::mem::size_of::<i32>() as u32;
+    let r = os_setsockopt_int(os_fd, level, optname, optval, optlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_setsockopt_timeval(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    level: i32,
+    optname: i32,
+    optval: &libc::timeval,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let optlen = std::mem::size_of::<libc::timeval>() as u32;
+    let r = os_setsockopt_timeval(os_fd, level, optname, optval, optlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_getsockopt_int(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    level: i32,
+    optname: i32,
+    optval: &mut i32,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let mut optlen = std::mem::size_of::<i32>() as u32;
+    let r = os_getsockopt_int(os_fd, level, optname, optval, &mut optlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_getsockopt_timeval(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    level: i32,
+    optname: i32,
+    optval: &mut libc::timeval,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let mut optlen = std::mem::size_of::<libc::timeval>() as u32;
+    let r = os_getsockopt_timeval(os_fd, level, optname, optval, &mut optlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
+pub fn trace_accept(
+    ctx: &VmCtx,
+    sockfd: HostFd,
+    addr: &mut libc::sockaddr_storage,
+    addrlen: &mut u32,
+) -> RuntimeResult<usize> {
+    let os_fd: usize = sockfd.to_raw();
+    let r = os_accept(os_fd, addr, addrlen);
+    RuntimeError::from_syscall_ret(r)
+}
+
 // TODO: I am not positive whether this returns the output value in its return
 //       or in its argument
 #[with_ghost_var(trace: &mut Trace)]
@@ -1,6 +1,9 @@
 //! Contains call implementations that are specific to Linux/Posix
 //! See src/tcb/os_specs for the raw system calls.
 
+use crate::os::backend::FsBackend;
+use crate::tcb::memfs::backend_for;
+use crate::tcb::misc::flag_set;
 use crate::tcb::os_specs::*;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
@@ -8,7 +11,7 @@ use crate::types::*;
 use crate::{effect, effects};
 use prusti_contracts::*;
 use syscall::syscall;
-use wave_macros::with_ghost_var;
+use wave_macros::{external_calls, with_ghost_var};
 
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(ctx_safe(ctx))]
@@ -24,7 +27,10 @@ pub fn trace_advise(
     advice: i32,
 ) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fadvise64(os_fd, offset, len, advice);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.advise(os_fd, offset, len, advice),
+        None => os_fadvise64(os_fd, offset, len, advice),
+    };
     RuntimeError::from_syscall_ret(r)
 }
 
@@ -81,6 +87,75 @@ pub fn trace_nanosleep(
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_allocate(ctx: &VmCtx, fd: HostFd, offset: i64, len: i64) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
-    let r = os_fallocate(os_fd, 0, offset, len);
+    let r = match backend_for(ctx, os_fd) {
+        Some(fs) => fs.allocate(os_fd, offset, len),
+        None => os_fallocate(os_fd, 0, offset, len),
+    };
+    RuntimeError::from_syscall_ret(r)
+}
+
+/// Whether the kernel finds no symlinks on `path` (from translate_path) under `dir_fd`,
+/// nor a way out of it. This only spares expanding the path in userspace, the access
+/// made with it is confined by the kernel on its own (see `pin_parent`). So paths that
+/// run into a missing file or a non-directory count as well.
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_symlink_free(
+    ctx: &VmCtx,
+    dir_fd: HostFd,
+    path: &HostPath,
+    should_follow: bool,
+) -> bool {
+    let mut flags = libc::O_PATH | libc::O_CLOEXEC;
+    if !should_follow {
+        // a trailing symlink is then opened itself rather than refused
+        flags |= libc::O_NOFOLLOW;
+    }
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS,
+    };
+    let r = os_openat2(dir_fd.to_raw(), *path, &how);
+    if r >= 0 {
+        os_close(r as usize);
+        return true;
+    }
+    r == -(libc::ENOENT as isize) || r == -(libc::ENOTDIR as isize)
+}
+
+/// openat under `PathResolution::Beneath`: the kernel, not the expanded path, keeps the
+/// open inside `dir_fd`, even if the tree changes after translate_path looked at it.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(flag_set)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_openat_beneath(
+    ctx: &VmCtx,
+    dir_fd: HostFd,
+    path: HostPath,
+    flags: i32,
+) -> RuntimeResult<usize> {
+    // unlike openat, a mode is refused unless the file may be created
+    let creates = flag_set(flags, libc::O_CREAT) || flag_set(flags, libc::O_TMPFILE);
+    let mut resolve = RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS;
+    if !ctx.symlinks.follow {
+        resolve |= RESOLVE_NO_SYMLINKS;
+    }
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: if creates { 0o666 } else { 0 },
+        resolve,
+    };
+    let r = os_openat2(dir_fd.to_raw(), path, &how);
+    // e.g. a seccomp filter that predates openat2
+    if r == -(libc::ENOSYS as isize) {
+        return RuntimeError::from_syscall_ret(os_openat(dir_fd.to_raw(), path, flags, 0o666));
+    }
     RuntimeError::from_syscall_ret(r)
 }
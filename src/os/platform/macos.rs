@@ -1,6 +1,8 @@
 //! Contains call implementations that are specific to MacOs
 //! See src/tcb/os_specs for the raw system calls.
 
+use crate::os::backend::FsBackend;
+use crate::tcb::memfs::backend_for;
 use crate::tcb::misc::{bitwise_or_u32, fresh_rusage};
 use crate::tcb::os_specs::*;
 #[cfg(feature = "verify")]
@@ -40,6 +42,9 @@ pub fn trace_advise(
 #[ensures(effects!(old(trace), trace, effect!(FdAccess)))]
 pub fn trace_allocate(ctx: &VmCtx, fd: HostFd, offset: i64, len: i64) -> RuntimeResult<usize> {
     let os_fd: usize = fd.to_raw();
+    if let Some(fs) = backend_for(ctx, os_fd) {
+        return RuntimeError::from_syscall_ret(fs.allocate(os_fd, offset, len));
+    }
     let fstore = libc::fstore_t {
         // we want to allocate contiguous space, and we want to allocate all space or none (TODO: CHECK THIS)
         fst_flags: bitwise_or_u32(libc::F_ALLOCATECONTIG, libc::F_ALLOCATEALL),
@@ -190,3 +195,31 @@ pub fn trace_nanosleep(
     os_wait_until(current_ticks + (mach_ticks as u64));
     Ok(0)
 }
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_symlink_free(
+    ctx: &VmCtx,
+    dir_fd: HostFd,
+    path: &HostPath,
+    should_follow: bool,
+) -> bool {
+    false
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn trace_openat_beneath(
+    ctx: &VmCtx,
+    dir_fd: HostFd,
+    path: HostPath,
+    flags: i32,
+) -> RuntimeResult<usize> {
+    RuntimeError::from_syscall_ret(os_openat(dir_fd.to_raw(), path, flags, 0o666))
+}
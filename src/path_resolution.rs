@@ -1,14 +1,15 @@
+use crate::tcb::overlay::is_reserved_path;
 use crate::tcb::path::*;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
 use crate::types::*;
-use owned_components::{readlinkat, OwnedComponent, OwnedComponents};
+use crate::unwrap_result;
+use owned_components::{OwnedComponent, OwnedComponents};
 use prusti_contracts::*;
 use std::ffi::OsString;
 use std::os::unix::ffi::OsStringExt;
 use std::path::{Component, Path, PathBuf};
-
-const MAXSYMLINKS: isize = 10;
+use wave_macros::{external_calls, external_methods, with_ghost_var};
 
 // #[pure]
 // #[ensures()]
@@ -17,6 +18,10 @@ fn to_pathbuf(v: Vec<u8>) -> PathBuf {
 }
 
 // #[ensures(!is_symlink(out_path))]
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(to_pathbuf, get_components, fresh_components, from_borrowed)]
+#[external_calls(apply_virtual_root)]
+#[external_methods(push)]
 #[ensures(
     match &result {
         Ok(v) => forall(|i: usize| (i < v.len() - 1) ==> !is_symlink(v.prefix(i)) ) && 
@@ -24,7 +29,14 @@ fn to_pathbuf(v: Vec<u8>) -> PathBuf {
         _ => true,
     }
 )]
-fn expand_path(vec: Vec<u8>, should_follow: bool, dirfd: HostFd) -> RuntimeResult<OwnedComponents> {
+fn expand_path(
+    ctx: &VmCtx,
+    vec: Vec<u8>,
+    should_follow: bool,
+    dirfd: HostFd,
+    virtual_root: bool,
+    max_symlinks: isize,
+) -> RuntimeResult<OwnedComponents> {
     let p = to_pathbuf(vec);
     let components = get_components(&p);
 
@@ -38,6 +50,10 @@ fn expand_path(vec: Vec<u8>, should_follow: bool, dirfd: HostFd) -> RuntimeResul
         body_invariant!(forall(|i: usize| i < out_path.len() ==> !is_symlink(out_path.prefix(i)) ) );
         let comp = components[idx];
         let c = OwnedComponent::from_borrowed(&comp);
+        if virtual_root && apply_virtual_root(&mut out_path, &c) {
+            idx += 1;
+            continue;
+        }
         // if this is the last element, and we are NO_FOLLOW, then don't expand
         if !should_follow && idx + 1 == components.len() {
             out_path.push(c);
@@ -45,12 +61,23 @@ fn expand_path(vec: Vec<u8>, should_follow: bool, dirfd: HostFd) -> RuntimeResul
         }
         // if comp is a symlink, return path + update num_symlinks
         // if not, just extend out_path
-        let maybe_linkpath = maybe_expand_component(dirfd, &mut out_path, c, &mut num_symlinks);
+        let maybe_linkpath =
+            maybe_expand_component(ctx, dirfd, &mut out_path, c, &mut num_symlinks);
+        unwrap_result!(maybe_linkpath);
 
         if let Some(linkpath) = maybe_linkpath {
-            expand_symlink(&mut out_path, linkpath, &mut num_symlinks, dirfd);
+            let expanded = expand_symlink(
+                ctx,
+                &mut out_path,
+                linkpath,
+                &mut num_symlinks,
+                dirfd,
+                virtual_root,
+                max_symlinks,
+            );
+            unwrap_result!(expanded);
         }
-        if num_symlinks >= MAXSYMLINKS {
+        if num_symlinks >= max_symlinks {
             return Err(RuntimeError::Eloop);
         }
         idx += 1;
@@ -59,28 +86,58 @@ fn expand_path(vec: Vec<u8>, should_follow: bool, dirfd: HostFd) -> RuntimeResul
     Ok(out_path)
 }
 
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(rebase_path, is_relative, min_depth, unparse, is_reserved_path)]
+#[external_methods(len, push, overlay_upper, is_some, hop_limit)]
 #[ensures(
     match &result {
         Ok(v) => path_safe(&v, should_follow),
         _ => true,
     }
 )]
-pub fn resolve_path(path: Vec<u8>, should_follow: bool, dirfd: HostFd) -> RuntimeResult<HostPath> {
+// Symlinks are read from `dirfd`'s preopen through `ctx`, from whichever layer has them
+// if it is copy-on-write (see tcb/overlay.rs).
+// `virtual_root` rebases absolute paths and symlink targets onto the preopen root.
+pub fn resolve_path(
+    ctx: &VmCtx,
+    path: Vec<u8>,
+    should_follow: bool,
+    dirfd: HostFd,
+    base: &Vec<u8>,
+    virtual_root: bool,
+    symlinks: SymlinkPolicy,
+) -> RuntimeResult<HostPath> {
+    let path = rebase_path(path, base, virtual_root);
+    unwrap_result!(path);
+
+    let max_symlinks = symlinks.hop_limit();
     // TODO: use ? when that works properly in Prusti
-    let c = expand_path(path, should_follow, dirfd);
+    let c = expand_path(ctx, path, should_follow, dirfd, virtual_root, max_symlinks);
 
-    let c = match c {
+    let mut c = match c {
         Ok(oc) => oc,
+        // the path went through a symlink, which the policy doesn't allow at all
+        Err(RuntimeError::Eloop) if !symlinks.follow => {
+            return Err(RuntimeError::Enotcapable);
+        }
         Err(e) => {
             return Err(e);
         }
     };
+    // e.g. `/` itself under a virtual root
+    if virtual_root && c.len() == 0 {
+        c.push(OwnedComponent::CurDir);
+    }
 
     if c.len() <= 0 || !is_relative(&c) || min_depth(&c) < 0 {
         return Err(RuntimeError::Enotcapable);
     }
 
     match OwnedComponents::unparse(c) {
+        // whiteouts are the overlay's own bookkeeping, not part of the guest's tree
+        Some(result_arr) if ctx.overlay_upper(dirfd).is_some() && is_reserved_path(&result_arr) => {
+            Err(RuntimeError::Enotcapable)
+        }
         Some(result_arr) => Ok(result_arr),
         _ => Err(RuntimeError::Enametoolong),
     }
@@ -88,29 +145,52 @@ pub fn resolve_path(path: Vec<u8>, should_follow: bool, dirfd: HostFd) -> Runtim
 
 // Recursively expands a symlink (without explicit recursion)
 // maintains a queue of path components to process
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(apply_virtual_root)]
+#[external_methods(lookup)]
 #[requires(forall(|i: usize| (i < out_path.len()) ==> !is_symlink(out_path.prefix(i)) ))]
 #[requires(!is_symlink(out_path) )]
 #[ensures(!is_symlink(out_path))]
 #[ensures(forall(|i: usize| (i < out_path.len()) ==> !is_symlink(out_path.prefix(i)) ))]
 fn expand_symlink(
+    ctx: &VmCtx,
     out_path: &mut OwnedComponents,
     linkpath_components: OwnedComponents,
     num_symlinks: &mut isize,
     dirfd: HostFd,
-) {
+    virtual_root: bool,
+    max_symlinks: isize,
+) -> RuntimeResult<()> {
     let mut idx = 0;
     while idx < linkpath_components.len() {
         body_invariant!(!is_symlink(out_path));
         // out_path should never contain symlinks
         body_invariant!(forall(|i: usize| i < out_path.len() ==> !is_symlink(out_path.prefix(i))));
-        if *num_symlinks >= MAXSYMLINKS {
-            return;
+        // this also bounds how deep links to links recurse
+        if *num_symlinks >= max_symlinks {
+            return Err(RuntimeError::Eloop);
         }
         let c = linkpath_components.lookup(idx);
-        let maybe_linkpath = maybe_expand_component(dirfd, out_path, c, num_symlinks);
+        // absolute targets start over at the root instead of failing the final checks
+        if virtual_root && apply_virtual_root(out_path, &c) {
+            idx += 1;
+            continue;
+        }
+        let maybe_linkpath = maybe_expand_component(ctx, dirfd, out_path, c, num_symlinks);
+        unwrap_result!(maybe_linkpath);
         if let Some(linkpath) = maybe_linkpath {
-            expand_symlink(out_path, linkpath, num_symlinks, dirfd);
+            let expanded = expand_symlink(
+                ctx,
+                out_path,
+                linkpath,
+                num_symlinks,
+                dirfd,
+                virtual_root,
+                max_symlinks,
+            );
+            unwrap_result!(expanded);
         }
         idx += 1;
     }
+    Ok(())
 }
@@ -1,6 +1,7 @@
 // use crate::os::trace_fionread;
 use crate::os::trace_fionread;
 use crate::runtime::*;
+use crate::tcb::memfs::backend_for;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::external_specs::result::*;
 #[cfg(feature = "verify")]
@@ -66,7 +67,8 @@ pub fn poll_parse_clock(
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-#[external_methods(push, to_posix)]
+#[external_calls(backend_for)]
+#[external_methods(push, to_posix, is_some)]
 pub fn poll_parse_fds(
     ctx: &VmCtx,
     pollfds: &mut Vec<libc::pollfd>,
@@ -74,8 +76,14 @@ pub fn poll_parse_fds(
     userdata: u64,
     subscription_readwrite: SubscriptionFdReadWrite,
 ) -> RuntimeResult<()> {
+    ctx.fdmap
+        .check_rights(subscription_readwrite.v_fd, RIGHTS_POLL_FD_READWRITE)?;
     let fd = ctx.fdmap.fd_to_native(subscription_readwrite.v_fd)?;
     let os_fd: usize = fd.to_raw();
+    // in-memory files have no host fd to poll
+    if backend_for(ctx, os_fd).is_some() {
+        return Err(Enotsup);
+    }
     // let event = match subscription_readwrite.typ {
     //     SubscriptionFdType::Read => libc::POLLIN,
     //     SubscriptionFdType::Write => libc::POLLOUT,
@@ -0,0 +1,238 @@
+use crate::os::{trace_fgetfl, trace_fstat, trace_fstatat, trace_openat, trace_seek};
+use crate::runtime::*;
+use crate::tcb::misc::{bitwise_and, bitwise_or, flag_set, fresh_stat};
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::external_specs::result::*;
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::*;
+use crate::types::*;
+use prusti_contracts::*;
+use wave_macros::{external_calls, external_methods, with_ghost_var};
+use RuntimeError::*;
+
+// How many times an O_CREAT open starts over when the file keeps appearing and
+// disappearing under it
+const CREATE_ATTEMPTS: u32 = 8;
+
+// Disk usage is the net change in the (apparent) size of the regular files the guest
+// writes to, grows, truncates or unlinks. Anything that is not a regular file
+// (sockets, pipes, ttys, ...) takes up no disk space and is not tracked.
+// Usage is never allowed below 0, so shrinking files the guest found on disk does not
+// buy it extra room.
+// Files are only ever inspected with the traced os calls, so PathResolution::Walk
+// covers these lookups like any other access.
+
+/// The size of the regular file behind `fd`, or None if it is not a regular file or the
+/// sandbox has no disk quota, in which case there is nothing to account for.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_stat, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn disk_file_size(ctx: &VmCtx, fd: HostFd) -> RuntimeResult<Option<u64>> {
+    if ctx.quotas.disk_bytes.is_none() {
+        return Ok(None);
+    }
+    let mut stat = fresh_stat();
+    trace_fstat(ctx, fd, &mut stat)?;
+    let filetype = Filetype::from(stat.st_mode as libc::mode_t);
+    if !matches!(filetype, Filetype::RegularFile) {
+        return Ok(None);
+    }
+    Ok(Some(stat.st_size as u64))
+}
+
+/// Like `disk_file_size` for `path` under `dirfd`. Used right before the file is
+/// truncated, so a file that isn't there is just None.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_stat, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn disk_path_size(ctx: &VmCtx, dirfd: HostFd, path: HostPath, flags: i32) -> Option<u64> {
+    if ctx.quotas.disk_bytes.is_none() {
+        return None;
+    }
+    let mut stat = fresh_stat();
+    if trace_fstatat(ctx, dirfd, path, &mut stat, flags).is_err() {
+        return None;
+    }
+    let filetype = Filetype::from(stat.st_mode as libc::mode_t);
+    if !matches!(filetype, Filetype::RegularFile) || stat.st_nlink != 1 {
+        return None;
+    }
+    Some(stat.st_size as u64)
+}
+
+/// Like `disk_path_size` for a file about to be unlinked. A file the sandbox still has
+/// open keeps its space until the last fd to it is closed (see `settle_close`), so it is
+/// None as well.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_stat, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn disk_unlink_size(ctx: &VmCtx, dirfd: HostFd, path: HostPath) -> Option<u64> {
+    if ctx.quotas.disk_bytes.is_none() {
+        return None;
+    }
+    let mut stat = fresh_stat();
+    if trace_fstatat(ctx, dirfd, path, &mut stat, libc::AT_SYMLINK_NOFOLLOW).is_err() {
+        return None;
+    }
+    let filetype = Filetype::from(stat.st_mode as libc::mode_t);
+    if !matches!(filetype, Filetype::RegularFile) || stat.st_nlink != 1 {
+        return None;
+    }
+    if ctx.fdmap.inode_open(stat.st_dev as u64, stat.st_ino as u64) {
+        return None;
+    }
+    Some(stat.st_size as u64)
+}
+
+/// Record which file the freshly opened sandbox fd `v_fd` (host fd `fd`) refers to, so
+/// that unlinking and closing can tell whether the sandbox still has it open without
+/// looking at every fd. Only regular files are recorded, and only under a disk quota.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_stat, from)]
+#[external_methods(set_inode)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn track_open(ctx: &mut VmCtx, v_fd: SboxFd, fd: HostFd) {
+    if ctx.quotas.disk_bytes.is_none() || v_fd as usize >= ctx.fdmap.len() {
+        return;
+    }
+    let mut stat = fresh_stat();
+    if trace_fstat(ctx, fd, &mut stat).is_err() {
+        return;
+    }
+    let filetype = Filetype::from(stat.st_mode as libc::mode_t);
+    if matches!(filetype, Filetype::RegularFile) {
+        ctx.fdmap
+            .set_inode(v_fd, stat.st_dev as u64, stat.st_ino as u64);
+    }
+}
+
+/// Right before `fd` is closed (after its sandbox fd was deleted), reclaim the space of
+/// the file behind it if it was unlinked while open and no other sandbox fd refers to it.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_stat, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn settle_close(ctx: &mut VmCtx, fd: HostFd) {
+    if ctx.quotas.disk_bytes.is_none() {
+        return;
+    }
+    let mut stat = fresh_stat();
+    if trace_fstat(ctx, fd, &mut stat).is_err() {
+        return;
+    }
+    let filetype = Filetype::from(stat.st_mode as libc::mode_t);
+    if !matches!(filetype, Filetype::RegularFile) || stat.st_nlink != 0 {
+        return;
+    }
+    if !ctx.fdmap.inode_open(stat.st_dev as u64, stat.st_ino as u64) {
+        ctx.reclaim_quota(QuotaKind::DiskBytes, stat.st_size as u64);
+    }
+}
+
+/// Fail with Edquot if growing a file of `size` bytes so that it ends at `end` would go
+/// over the disk quota.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_methods(saturating_sub)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn reserve_disk(ctx: &mut VmCtx, size: u64, end: u64) -> RuntimeResult<()> {
+    ctx.check_quota(QuotaKind::DiskBytes, end.saturating_sub(size))
+}
+
+/// Where a write of `len` bytes to the regular file behind `fd`, currently `size` bytes
+/// long, would end.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(flag_set)]
+#[external_methods(saturating_add)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn write_end(ctx: &VmCtx, fd: HostFd, size: u64, len: u64) -> RuntimeResult<u64> {
+    let flags = trace_fgetfl(ctx, fd)?;
+    // appends always land at the end of the file, wherever the offset is
+    let start = if flag_set(flags as i32, libc::O_APPEND) {
+        size
+    } else {
+        trace_seek(ctx, fd, 0, libc::SEEK_CUR)? as u64
+    };
+    Ok(start.saturating_add(len))
+}
+
+/// After a file that was `before` bytes long has been changed through `fd`, charge
+/// (or reclaim) how much it grew (or shrank). `before` comes from `disk_file_size`.
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn settle_disk(ctx: &mut VmCtx, fd: HostFd, before: Option<u64>) {
+    if let Some(before) = before {
+        if let Ok(Some(after)) = disk_file_size(ctx, fd) {
+            if after >= before {
+                ctx.charge_quota(QuotaKind::DiskBytes, after - before);
+            } else {
+                ctx.reclaim_quota(QuotaKind::DiskBytes, before - after);
+            }
+        }
+    }
+}
+
+/// Open `path` under `dirfd` with `flags` (which include O_CREAT), charging FilesCreated
+/// only if that creates the file. Without O_EXCL an existing file is opened without
+/// O_CREAT, and only if there is none is it created with O_EXCL, starting over if the
+/// file appears or disappears in between. Fails with Edquot only if a file would be created.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(flag_set, bitwise_and, bitwise_or)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn openat_creating(
+    ctx: &mut VmCtx,
+    dirfd: HostFd,
+    path: HostPath,
+    flags: i32,
+) -> RuntimeResult<usize> {
+    let existing = bitwise_and(flags, !(libc::O_CREAT | libc::O_EXCL));
+    let create = bitwise_or(flags, libc::O_EXCL);
+    let mut attempts = 0;
+    while attempts < CREATE_ATTEMPTS {
+        body_invariant!(ctx_safe(ctx));
+        body_invariant!(trace_safe(trace, ctx));
+
+        if !flag_set(flags, libc::O_EXCL) {
+            match trace_openat(ctx, dirfd, path, existing) {
+                Err(Enoent) => (),
+                result => return result,
+            }
+        }
+        ctx.check_quota(QuotaKind::FilesCreated, 1)?;
+        match trace_openat(ctx, dirfd, path, create) {
+            Ok(fd) => {
+                ctx.charge_quota(QuotaKind::FilesCreated, 1);
+                return Ok(fd);
+            }
+            Err(Eexist) if !flag_set(flags, libc::O_EXCL) => (),
+            Err(e) => return Err(e),
+        }
+        attempts += 1;
+    }
+    Err(Eexist)
+}
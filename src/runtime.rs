@@ -1,5 +1,16 @@
+use crate::os::trace_symlink_free;
 use crate::path_resolution::resolve_path;
-use crate::tcb::misc::{clone_vec_u8, empty_netlist, get_homedir_fd, string_to_vec_u8};
+use crate::tcb::access::preopen_modes;
+use crate::tcb::info_hiding::host_dir_path;
+use crate::tcb::memfs::MemFs;
+use crate::tcb::misc::{
+    clone_vec_u8, empty_inbound_policy, empty_netlist, is_preopen_fd, new_sockaddr,
+    normalized_components, open_host_dir, sockaddr_family, sockaddr_ip, sockaddr_port, sockaddr_v6_fields,
+    string_to_vec_u8,
+};
+use crate::tcb::os_specs::openat2_supported;
+use crate::tcb::overlay::{preopen_upper, unknown_overlay};
+use crate::tcb::path::lexical_path;
 #[cfg(feature = "verify")]
 use crate::tcb::path::path_safe;
 use crate::tcb::sbox_mem::{raw_ptr, valid_linmem};
@@ -19,24 +30,90 @@ use RuntimeError::*;
 
 // Exit codes for wasi-libc: https://github.com/WebAssembly/wasi-libc/blob/659ff414560721b1660a19685110e484a081c3d4/libc-top-half/musl/include/sysexits.h
 
+// Open each (host dir, guest name) pair and register it in the fdmap, failing if any
+// of them can't be opened or doesn't fit.
+// Preopens are created right after the std fds, so they get sandbox fds 3, 4, ...
+// `access` sets the access mode of the preopens and their subtrees (default read-write).
+// `overlays` makes the preopens it names, as (guest name, host upper dir) pairs,
+// copy-on-write: the guest's changes go to the upper dir instead.
+// `mem_preopens` are (guest name, in-memory filesystem) pairs, preopened after `dirs`.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_methods(create_at, push, clone, all, open_root)]
+#[external_calls(open_host_dir, from_raw, preopen_modes, preopen_upper, host_dir_path)]
+#[external_calls(unknown_overlay)]
+pub fn init_preopens(
+    fdmap: &mut FdMap,
+    dirs: &Vec<(String, String)>,
+    access: &Vec<AccessRule>,
+    overlays: &Vec<(String, String)>,
+    mem_preopens: &Vec<(String, MemFs)>,
+) -> RuntimeResult<Vec<Preopen>> {
+    // the guest would be writing to the real directory the overlay was meant to protect
+    if unknown_overlay(dirs, overlays) {
+        return Err(Enoent);
+    }
+    let mut preopens = Vec::new();
+    let mut idx = 0;
+    while idx < dirs.len() {
+        let (host_dir, guest_name) = &dirs[idx];
+        let host_fd = open_host_dir(host_dir)?;
+        let pos = PathPos {
+            preopen_fd: host_fd,
+            rel_path: Vec::new(),
+        };
+        // fds get their rights narrowed by access mode when opened (see path_open),
+        // since a read-only preopen may still have writable subtrees
+        let (mode, subtrees) = preopen_modes(guest_name, access);
+        let upper = preopen_upper(guest_name, overlays)?;
+        let sbox_fd = fdmap.create_at(host_fd, pos, FdRights::all())?;
+        preopens.push(Preopen {
+            sbox_fd,
+            host_fd,
+            guest_name: guest_name.clone(),
+            mode,
+            subtrees,
+            upper,
+            mem: None,
+            host_path: host_dir_path(host_dir),
+        });
+        idx += 1;
+    }
+    idx = 0;
+    while idx < mem_preopens.len() {
+        let (guest_name, fs) = &mem_preopens[idx];
+        let host_fd = HostFd::from_raw(fs.open_root());
+        let pos = PathPos {
+            preopen_fd: host_fd,
+            rel_path: Vec::new(),
+        };
+        let (mode, subtrees) = preopen_modes(guest_name, access);
+        let sbox_fd = fdmap.create_at(host_fd, pos, FdRights::all())?;
+        preopens.push(Preopen {
+            sbox_fd,
+            host_fd,
+            guest_name: guest_name.clone(),
+            mode,
+            subtrees,
+            upper: None,
+            mem: Some(fs.clone()),
+            host_path: None,
+        });
+        idx += 1;
+    }
+    Ok(preopens)
+}
+
 //#[ensures(safe(&result))]
 #[with_ghost_var(trace: &mut Trace)]
 #[external_methods(init_std_fds, unwrap, as_raw_fd, create, to_owned, clone)]
-#[external_calls(open, forget, get_homedir_fd, from)]
-pub fn fresh_ctx(homedir: String) -> VmCtx {
+#[external_calls(open, forget, init_preopens, from)]
+pub fn fresh_ctx(dirs: Vec<(String, String)>) -> VmCtx {
     let memlen = LINEAR_MEM_SIZE;
     let mem = vec![0; memlen];
-    let mut fdmap = FdMap::new();
+    let mut fdmap = FdMap::new(DEFAULT_SBOX_FDS);
     fdmap.init_std_fds();
-    let homedir_host_fd = get_homedir_fd(&homedir) as usize;
-    // let homedir_file = std::fs::File::open(&homedir).unwrap();
-    // let homedir_fd = homedir_file.as_raw_fd();
-    if homedir_host_fd >= 0 {
-        fdmap.create(HostFd::from_raw(homedir_host_fd));
-    }
-    // Need to forget file to make sure it does not get auto-closed
-    // when it gets out of scope
-    // std::mem::forget(homedir_file);
+    let preopens =
+        init_preopens(&mut fdmap, &dirs, &Vec::new(), &Vec::new(), &Vec::new()).unwrap();
     // let log_path = "".to_owned();
     // let log_path = String::new();
 
@@ -46,12 +123,13 @@ pub fn fresh_ctx(homedir: String) -> VmCtx {
     let envc = 0;
 
     let netlist = empty_netlist();
+    let inbound = empty_inbound_policy();
+    let signal_policy = SignalPolicy::Ignore;
     VmCtx {
         mem,
         memlen,
         fdmap,
-        homedir,
-        homedir_host_fd: HostFd::from_raw(homedir_host_fd),
+        preopens,
         // errno: Success,
         arg_buffer,
         argc,
@@ -59,6 +137,20 @@ pub fn fresh_ctx(homedir: String) -> VmCtx {
         envc,
         // log_path,
         netlist,
+        inbound,
+        violation: None,
+        guest_exit: None,
+        stop: None,
+        signal_policy,
+        cancel: CancelHandle::new(),
+        deadline: None,
+        quotas: Quotas::default(),
+        usage: Usage::default(),
+        quota_exceeded: None,
+        path_resolution: PathResolution::Expand,
+        virtual_root: false,
+        symlinks: SymlinkPolicy::default(),
+        info_hiding: None,
     }
 }
 
@@ -192,7 +284,8 @@ impl VmCtx {
     }
 
     #[with_ghost_var(trace: &mut Trace)]
-    #[external_calls(resolve_path)]
+    #[external_calls(lexical_path)]
+    #[external_methods(fd_to_pos, overlay_upper, mem_fs, is_none, clone)]
     #[requires(ctx_safe(self))]
     #[requires(trace_safe(trace, self))]
     #[ensures(trace_safe(trace, self))]
@@ -203,33 +296,124 @@ impl VmCtx {
             _ => true,
         }
     )]
+    /// Resolve a guest path relative to sandbox fd `v_dirfd`.
+    /// The result is relative to the root of the preopen `v_dirfd` lives under
+    /// (see `translate_dir_fd`).
     pub fn translate_path(
         &self,
         path: SboxPtr,
         path_len: u32,
         should_follow: bool,
-        dirfd: HostFd,
+        v_dirfd: SboxFd,
     ) -> RuntimeResult<HostPath> {
         if !self.fits_in_lin_mem(path, path_len) {
             return Err(Eoverflow);
         }
+        if v_dirfd as usize >= self.fdmap.len() {
+            return Err(Ebadf);
+        }
+        let pos = self.fdmap.fd_to_pos(v_dirfd);
+        let pos = match pos {
+            Ok(pos) => pos,
+            Err(_) => {
+                return Err(Enotcapable);
+            }
+        };
         let host_buffer = self.copy_buf_from_sandbox(path, path_len);
-        resolve_path(host_buffer, should_follow, dirfd)
+        // symlinks in copy-on-write preopens are read from whichever layer has them
+        let upper = self.overlay_upper(pos.preopen_fd);
+        let mem = self.mem_fs(pos.preopen_fd);
+        // most paths have no symlinks, which the kernel can tell in one openat2
+        if self.path_resolution == PathResolution::Beneath && upper.is_none() && mem.is_none() {
+            let lexical = lexical_path(
+                host_buffer.clone(),
+                should_follow,
+                &pos.rel_path,
+                self.virtual_root,
+            );
+            if let Ok(lexical) = lexical {
+                if trace_symlink_free(self, pos.preopen_fd, &lexical, should_follow) {
+                    return Ok(lexical);
+                }
+            }
+        }
+        resolve_path(
+            self,
+            host_buffer,
+            should_follow,
+            pos.preopen_fd,
+            &pos.rel_path,
+            self.virtual_root,
+            self.symlinks,
+        )
         // self.resolve_path(host_buffer)
     }
 
-    pub fn get_homedir(&self) -> Vec<u8> {
-        string_to_vec_u8(&self.homedir)
-        // self.homedir.as_bytes().to_vec()
+    /// Translate a sandbox fd that *at calls are based on to the host fd of the
+    /// preopen it lives under. All path accesses are made relative to a preopen.
+    #[with_ghost_var(trace: &Trace)]
+    #[external_calls(is_preopen_fd)]
+    #[external_methods(len, fd_to_pos, to_raw)]
+    #[ensures(
+        match &result {
+            Ok(fd) => is_preopen_fd(&self.preopens, fd.to_raw()),
+            _ => true,
+        }
+    )]
+    pub fn translate_dir_fd(&self, v_fd: SboxFd) -> RuntimeResult<HostFd> {
+        if v_fd as usize >= self.fdmap.len() {
+            return Err(Ebadf);
+        }
+        let pos = match self.fdmap.fd_to_pos(v_fd) {
+            Ok(pos) => pos,
+            Err(_) => return Err(Enotcapable),
+        };
+        if !is_preopen_fd(&self.preopens, pos.preopen_fd.to_raw()) {
+            return Err(Enotcapable);
+        }
+        Ok(pos.preopen_fd)
     }
 
-    // pub fn translate_homedir_fd() -> HostFd {
-    //     if v_fd != HOMEDIR_FD {
-    //         return Err(Enotcapable);
-    //     }
-    //     assert!(v_fd == HOMEDIR_FD);
-    //     let fd = ctx.homedir_host_fd;
-    // }
+    /// Fail if `path` under the preopen with host fd `dirfd` may not be accessed:
+    /// Enotcapable if it is hidden, Erofs if it is read-only and `write` is set.
+    #[with_ghost_var(trace: &Trace)]
+    #[external_methods(access_mode)]
+    pub fn check_access(
+        &self,
+        dirfd: HostFd,
+        path: &HostPath,
+        write: bool,
+    ) -> RuntimeResult<AccessMode> {
+        let mode = self.access_mode(dirfd, path);
+        match mode {
+            AccessMode::Hidden => Err(Enotcapable),
+            AccessMode::ReadOnly if write => Err(Erofs),
+            _ => Ok(mode),
+        }
+    }
+
+    /// Set how host paths are handed to the kernel. Without openat2 (before Linux 5.6,
+    /// or off Linux) `PathResolution::Beneath` falls back to `Expand`.
+    /// Returns the mode actually selected, so callers can tell when that happened.
+    pub fn set_path_resolution(&mut self, mode: PathResolution) -> PathResolution {
+        self.path_resolution = match mode {
+            PathResolution::Beneath if !openat2_supported() => PathResolution::Expand,
+            mode => mode,
+        };
+        self.path_resolution
+    }
+
+    /// Guest-visible name of the preopened directory at sandbox fd `v_fd`, if any
+    pub fn get_preopen_name(&self, v_fd: SboxFd) -> Option<Vec<u8>> {
+        let mut idx = 0;
+        while idx < self.preopens.len() {
+            if self.preopens[idx].sbox_fd == v_fd {
+                return Some(string_to_vec_u8(&self.preopens[idx].guest_name));
+            }
+            idx += 1;
+        }
+        None
+    }
 
     // TODO: replace read_x and write_x with faster raw ptr read/write
 
@@ -308,6 +492,132 @@ impl VmCtx {
         Ok((x1, x2))
     }
 
+    /// Fail with Edquot if using `amount` more of `kind` would go over the sandbox's quota.
+    /// The first quota to run out is remembered in `quota_exceeded`.
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_methods(limit, used)]
+    #[requires(ctx_safe(self))]
+    #[requires(trace_safe(trace, self))]
+    #[ensures(ctx_safe(self))]
+    #[ensures(trace_safe(trace, self))]
+    pub fn check_quota(&mut self, kind: QuotaKind, amount: u64) -> RuntimeResult<()> {
+        if let Some(limit) = self.quotas.limit(kind) {
+            if self.usage.used(kind).saturating_add(amount) > limit {
+                if self.quota_exceeded.is_none() {
+                    self.quota_exceeded = Some(kind);
+                }
+                return Err(Edquot);
+            }
+        }
+        Ok(())
+    }
+
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_methods(charge)]
+    #[requires(ctx_safe(self))]
+    #[requires(trace_safe(trace, self))]
+    #[ensures(ctx_safe(self))]
+    #[ensures(trace_safe(trace, self))]
+    pub fn charge_quota(&mut self, kind: QuotaKind, amount: u64) {
+        self.usage.charge(kind, amount);
+    }
+
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_methods(reclaim)]
+    #[requires(ctx_safe(self))]
+    #[requires(trace_safe(trace, self))]
+    #[ensures(ctx_safe(self))]
+    #[ensures(trace_safe(trace, self))]
+    pub fn reclaim_quota(&mut self, kind: QuotaKind, amount: u64) {
+        self.usage.reclaim(kind, amount);
+    }
+
+    /// read a sockaddr_in or sockaddr_in6 from wasm linear memory.
+    /// The guest uses the WASI address family numbers, but otherwise the layout
+    /// matches the host's (port and address in network byte order).
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_calls(sock_domain_to_posix, ipv4_mapped, new_sockaddr)]
+    #[external_methods(swap_bytes)]
+    #[requires(ctx_safe(self))]
+    #[requires(trace_safe(trace, self))]
+    #[ensures(ctx_safe(self))]
+    #[ensures(trace_safe(trace, self))]
+    pub fn read_sockaddr(
+        &self,
+        addr: SboxPtr,
+        addrlen: u32,
+    ) -> RuntimeResult<libc::sockaddr_storage> {
+        if !self.fits_in_lin_mem(addr, addrlen) {
+            return Err(Eoverflow);
+        }
+        if addrlen < 4 {
+            return Err(Einval);
+        }
+        let start = addr as usize;
+        let domain = sock_domain_to_posix(self.read_u16(start) as u32)?;
+        let port = self.read_u16(start + 2).swap_bytes();
+        if domain == libc::AF_INET {
+            if addrlen != SBOX_SOCKADDR_IN_LEN {
+                return Err(Einval);
+            }
+            let ip = self.read_u32(start + 4).swap_bytes();
+            return Ok(new_sockaddr(domain, ipv4_mapped(ip), port, 0, 0));
+        }
+        if addrlen != SBOX_SOCKADDR_IN6_LEN {
+            return Err(Einval);
+        }
+        let flowinfo = self.read_u32(start + 4);
+        let hi = self.read_u64(start + 8).swap_bytes();
+        let lo = self.read_u64(start + 16).swap_bytes();
+        let ip = (hi as u128) * (1 << 64) + lo as u128;
+        let scope_id = self.read_u32(start + 24);
+        Ok(new_sockaddr(domain, ip, port, flowinfo, scope_id))
+    }
+
+    /// write a host sockaddr to wasm linear memory, in the same layout `read_sockaddr`
+    /// accepts. Returns the number of bytes written.
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_calls(sockaddr_family, sockaddr_ip, sockaddr_port, sockaddr_v6_fields)]
+    #[external_methods(swap_bytes)]
+    #[requires(ctx_safe(self))]
+    #[requires(trace_safe(trace, self))]
+    #[ensures(ctx_safe(self))]
+    #[ensures(trace_safe(trace, self))]
+    pub fn write_sockaddr(
+        &mut self,
+        addr: SboxPtr,
+        addrlen: u32,
+        saddr: &libc::sockaddr_storage,
+    ) -> RuntimeResult<u32> {
+        if !self.fits_in_lin_mem(addr, addrlen) {
+            return Err(Eoverflow);
+        }
+        let start = addr as usize;
+        let ip = sockaddr_ip(saddr);
+        let port = sockaddr_port(saddr) as u16;
+        if sockaddr_family(saddr) == libc::AF_INET {
+            if addrlen < SBOX_SOCKADDR_IN_LEN {
+                return Err(Einval);
+            }
+            self.write_u16(start, 1);
+            self.write_u16(start + 2, port.swap_bytes());
+            self.write_u32(start + 4, (ip as u32).swap_bytes());
+            self.write_u64(start + 8, 0);
+            return Ok(SBOX_SOCKADDR_IN_LEN);
+        }
+        if addrlen < SBOX_SOCKADDR_IN6_LEN {
+            return Err(Einval);
+        }
+        let (flowinfo, scope_id) = sockaddr_v6_fields(saddr);
+        self.write_u16(start, 2);
+        self.write_u16(start + 2, port.swap_bytes());
+        self.write_u32(start + 4, flowinfo);
+        self.write_u64(start + 8, ((ip / (1 << 64)) as u64).swap_bytes());
+        self.write_u64(start + 16, (ip as u64).swap_bytes());
+        self.write_u32(start + 24, scope_id);
+        Ok(SBOX_SOCKADDR_IN6_LEN)
+    }
+
     /// write u16 to wasm linear memory
     // Not thrilled about this implementation, but it works
     #[with_ghost_var(trace: &mut Trace)]
@@ -0,0 +1,86 @@
+// Access modes of preopens and their subtrees (see `AccessMode`).
+// Subtrees are matched on paths from translate_path, which have no symlinks left to
+// expand, so comparing their components is exact.
+use crate::tcb::misc::normalized_components;
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::*;
+use crate::types::*;
+use prusti_contracts::*;
+use wave_macros::with_ghost_var;
+use RuntimeError::*;
+
+// The mode of the preopen named `guest_name` and its subtrees, from the rules for it
+#[trusted]
+pub fn preopen_modes(
+    guest_name: &String,
+    access: &Vec<AccessRule>,
+) -> (AccessMode, Vec<(Vec<u8>, AccessMode)>) {
+    let mut mode = AccessMode::ReadWrite;
+    let mut subtrees = Vec::new();
+    for rule in access.iter().filter(|rule| &rule.preopen == guest_name) {
+        if normalized_components(&rule.subtree).is_empty() {
+            mode = rule.mode;
+        } else {
+            subtrees.push((rule.subtree.clone(), rule.mode));
+        }
+    }
+    (mode, subtrees)
+}
+
+impl VmCtx {
+    /// The access mode of `path` (from `translate_path`) under the preopen with host fd
+    /// `dirfd`: that of the longest subtree containing it, otherwise the preopen's own.
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn access_mode(&self, dirfd: HostFd, path: &[u8]) -> AccessMode {
+        let preopen = match self.preopens.iter().find(|p| p.host_fd == dirfd) {
+            Some(preopen) => preopen,
+            None => return AccessMode::Hidden,
+        };
+        let path = normalized_components(path);
+        let mut mode = preopen.mode;
+        let mut longest = 0;
+        for (subtree, subtree_mode) in preopen.subtrees.iter() {
+            let subtree = normalized_components(subtree);
+            if subtree.len() >= longest && path.starts_with(&subtree) {
+                mode = *subtree_mode;
+                longest = subtree.len();
+            }
+        }
+        mode
+    }
+
+    /// Fail with Enotcapable if `path` under the preopen with host fd `dirfd` contains a
+    /// subtree whose mode differs from its own, since moving or linking it would carry
+    /// that subtree out from under its mode.
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn check_no_subtrees_below(&self, dirfd: HostFd, path: &HostPath) -> RuntimeResult<()> {
+        let preopen = match self.preopens.iter().find(|p| p.host_fd == dirfd) {
+            Some(preopen) => preopen,
+            None => return Err(Enotcapable),
+        };
+        let mode = self.access_mode(dirfd, path);
+        let path = normalized_components(path);
+        let differs = preopen.subtrees.iter().any(|(subtree, subtree_mode)| {
+            let subtree = normalized_components(subtree);
+            subtree.len() > path.len() && subtree.starts_with(&path) && *subtree_mode != mode
+        });
+        if differs {
+            return Err(Enotcapable);
+        }
+        Ok(())
+    }
+
+    /// Whether the entry `name` of the directory at `dir` (relative to the preopen with
+    /// host fd `dirfd`) is hidden, and so must not be listed
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn entry_hidden(&self, dirfd: HostFd, dir: &[u8], name: &[u8]) -> bool {
+        let end = dir.iter().position(|&b| b == 0).unwrap_or(dir.len());
+        let mut path = dir[..end].to_vec();
+        path.push(b'/');
+        path.extend_from_slice(name);
+        self.access_mode(dirfd, &path) == AccessMode::Hidden
+    }
+}
@@ -15,12 +15,39 @@ pub fn ptr_to_ref(ctx: *const *mut VmCtx) -> &'static mut VmCtx {
     unsafe { &mut **ctx }
 }
 
+/// Copy a C array of `len` endpoints into a netlist
 #[trusted]
-pub fn transmut_netlist(nl: *const Netlist) -> Netlist {
+pub fn transmut_netlist(nl: *const NetEndpoint, len: usize) -> Netlist {
+    if len == 0 {
+        return Vec::new();
+    }
     if nl.is_null() {
         panic!("null netlist")
     }
-    unsafe { *nl }
+    unsafe { std::slice::from_raw_parts(nl, len) }.to_vec()
+}
+
+/// How to start the guest and get back out of it. `run` and `exit` are `wave_run` and
+/// `wave_exit` from bindings/wave_run.c, which is compiled into the module together
+/// with the wasm2c runtime.
+#[derive(Clone, Copy)]
+pub struct GuestEntry {
+    pub start: unsafe extern "C" fn() -> i32,
+    pub run: unsafe extern "C" fn(unsafe extern "C" fn() -> i32) -> i32,
+    pub exit: unsafe extern "C" fn(i32),
+}
+
+/// Call the guest's entry point through `wave_run`, which returns 0 when it finishes
+/// and otherwise the code it was longjmp-ed out with
+#[trusted]
+pub fn run_guest(entry: GuestEntry) -> i32 {
+    unsafe { (entry.run)(entry.start) }
+}
+
+/// longjmp back to `run_guest` with `code` through `wave_exit`
+#[trusted]
+pub fn exit_guest(exit: unsafe extern "C" fn(i32), code: i32) {
+    unsafe { exit(code) }
 }
 
 #[trusted]
@@ -0,0 +1,167 @@
+// Information hiding (see `InfoHiding`): what the guest sees of host device ids, inode
+// numbers and symlink targets under the preopens.
+use crate::tcb::misc::normalized_components;
+use crate::tcb::path::climbs_out;
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::*;
+use crate::types::*;
+use prusti_contracts::*;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::os::unix::ffi::OsStringExt;
+use wave_macros::{external_calls, with_ghost_var};
+use RuntimeError::*;
+
+// The canonical path of the host directory `host_dir`
+#[trusted]
+pub fn host_dir_path(host_dir: &String) -> Option<Vec<u8>> {
+    let path = std::fs::canonicalize(host_dir).ok()?;
+    Some(path.into_os_string().into_vec())
+}
+
+impl InfoHiding {
+    #[trusted]
+    pub fn new(remap_inodes: bool) -> Self {
+        InfoHiding {
+            remap_inodes,
+            inode_key: RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    /// The inode number the guest sees for host inode `ino` in preopen number `dev`
+    #[trusted]
+    pub fn inode(&self, dev: u64, ino: u64) -> u64 {
+        if !self.remap_inodes {
+            return ino;
+        }
+        let mut hasher = DefaultHasher::new();
+        (self.inode_key, dev, ino).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl VmCtx {
+    /// The device id the guest sees for files under the preopen with host fd `dirfd`
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    fn virtual_dev(&self, dirfd: HostFd) -> u64 {
+        self.preopens
+            .iter()
+            .position(|p| p.host_fd == dirfd)
+            .map_or(0, |idx| idx as u64 + 1)
+    }
+
+    /// `stat` of a file under the preopen with host fd `preopen` (None for the std fds),
+    /// with the host details `info_hiding` keeps from the guest replaced
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn hide_stat(&self, preopen: Option<HostFd>, mut stat: FileStat) -> FileStat {
+        if let Some(hiding) = &self.info_hiding {
+            let dev = preopen.map_or(0, |fd| self.virtual_dev(fd));
+            stat.ino = hiding.inode(dev, stat.ino);
+            stat.dev = dev;
+        }
+        stat
+    }
+
+    /// `hide_stat` for the (inode, wasi filetype, name) entries of the directory at `dir`
+    /// under the preopen with host fd `dirfd`, leaving out those in hidden subtrees
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn hide_entries(
+        &self,
+        dirfd: HostFd,
+        dir: &[u8],
+        entries: Vec<(u64, u8, Vec<u8>)>,
+    ) -> Vec<(u64, u8, Vec<u8>)> {
+        entries
+            .into_iter()
+            .filter(|(_, _, name)| !self.entry_hidden(dirfd, dir, name))
+            .map(|(ino, filetype, name)| (self.hide_ino(Some(dirfd), ino), filetype, name))
+            .collect()
+    }
+
+    /// The inode number the guest sees for host inode `ino` under the preopen with host fd
+    /// `preopen`
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn hide_ino(&self, preopen: Option<HostFd>, ino: u64) -> u64 {
+        match &self.info_hiding {
+            Some(hiding) => hiding.inode(preopen.map_or(0, |fd| self.virtual_dev(fd)), ino),
+            None => ino,
+        }
+    }
+
+    /// The target the guest sees for symlink `link` (from `translate_path`) under the
+    /// preopen with host fd `dirfd`, given its `target` on the host. Absolute targets into
+    /// the preopen are made relative (or rooted at it under a virtual root); None if the
+    /// target leads out of the sandbox.
+    #[with_ghost_var(trace: &Trace)]
+    #[external_calls(climbs_out, normalized_components)]
+    #[trusted]
+    pub fn hide_link_target(
+        &self,
+        dirfd: HostFd,
+        link: &HostPath,
+        target: &[u8],
+    ) -> Option<Vec<u8>> {
+        if target.first() != Some(&b'/') {
+            // `..` stays at the root of a virtual root
+            if !self.virtual_root && climbs_out(link, target) {
+                return None;
+            }
+            return Some(target.to_vec());
+        }
+        let root = self
+            .preopens
+            .iter()
+            .find(|p| p.host_fd == dirfd)
+            .and_then(|p| p.host_path.as_ref());
+        // e.g. `/srv/data2` is not in `/srv/data`
+        let rest = root
+            .and_then(|root| target.strip_prefix(root.as_slice()))
+            .filter(|rest| rest.is_empty() || rest[0] == b'/');
+        let rest = match rest {
+            Some(rest) => rest,
+            // other absolute paths are in the preopen already under a virtual root
+            None if self.virtual_root => return Some(target.to_vec()),
+            None => return None,
+        };
+        let mut components: Vec<&[u8]> = Vec::new();
+        if self.virtual_root {
+            components.push(b"");
+        } else {
+            // back up from the directory the link is in to the root
+            let depth = normalized_components(link).len().saturating_sub(1);
+            components.resize(depth, b"..");
+        }
+        components.extend(rest.split(|&b| b == b'/').filter(|c| !c.is_empty()));
+        match components.as_slice() {
+            [] => Some(b".".to_vec()),
+            [root] if root.is_empty() => Some(b"/".to_vec()),
+            _ => Some(components.join(&b'/')),
+        }
+    }
+
+    /// Copy what `hide_link_target` makes of `target`, read from `link` under the preopen
+    /// with host fd `dirfd`, to `ptr` in the sandbox, returning its length (truncated to
+    /// `buf_len`, like readlink). Nothing is copied if the target may not be shown.
+    #[with_ghost_var(trace: &mut Trace)]
+    #[trusted]
+    pub fn hide_readlink(
+        &mut self,
+        dirfd: HostFd,
+        link: &HostPath,
+        target: &[u8],
+        ptr: SboxPtr,
+        buf_len: u32,
+    ) -> RuntimeResult<u32> {
+        let mut hidden = self
+            .hide_link_target(dirfd, link, target)
+            .ok_or(Enotcapable)?;
+        hidden.truncate(buf_len as usize);
+        let len = hidden.len() as u32;
+        self.copy_buf_to_sandbox(ptr, &hidden, len)?;
+        Ok(len)
+    }
+}
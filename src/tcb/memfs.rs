@@ -0,0 +1,1092 @@
+// An in-memory filesystem a preopen can be backed by instead of a host directory,
+// for tests and jobs that should never touch the host disk.
+//
+// It is an `FsBackend` (see os/backend.rs), so it is driven through the same
+// syscall-shaped calls as a host directory, with raw fds that it hands out itself.
+// Those come from a range no host fd can be in, so every fd belongs to one backend.
+// Paths are resolved like the kernel would resolve them under the preopen's root:
+// symlinks are followed, and `..` and absolute link targets stop at the root.
+use crate::os::backend::FsBackend;
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::*;
+use crate::types::*;
+use prusti_contracts::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use wave_macros::with_ghost_var;
+
+/// Raw fds of in-memory files start here, far above anything the host hands out
+pub const MEM_FD_BASE: usize = 1 << 30;
+// shared by all in-memory filesystems, so their fds never overlap
+static NEXT_MEM_FD: AtomicUsize = AtomicUsize::new(MEM_FD_BASE);
+
+const ROOT: usize = 0;
+const MAX_SYMLINKS: usize = 40;
+/// Bytes of file data a `MemFs::new` tree may hold, see `MemFs::with_capacity`
+pub const DEFAULT_CAPACITY: u64 = 1 << 30;
+// Past this offsets no longer fit in an off_t
+const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+type Errno = i32;
+type Timespec = (i64, i64);
+
+enum NodeKind {
+    File(Vec<u8>),
+    // entries, parent
+    Dir(BTreeMap<Vec<u8>, usize>, usize),
+    Symlink(Vec<u8>),
+}
+
+struct Node {
+    kind: NodeKind,
+    perm: u32,
+    nlink: u64,
+    // fds open on it, which keep it alive after its last link is gone
+    open: usize,
+    atime: Timespec,
+    mtime: Timespec,
+    ctime: Timespec,
+}
+
+struct OpenFile {
+    node: usize,
+    // a byte offset for files, an entry index for directories
+    offset: u64,
+    flags: i32,
+}
+
+// Where a path leads: the directory holding its last component, that component, and
+// the node it names if there is one. A path ending in `.` or `..` has no name.
+struct Walk {
+    dir: usize,
+    name: Option<Vec<u8>>,
+    node: Option<usize>,
+}
+
+struct MemTree {
+    // indexed by inode number, None once freed
+    nodes: Vec<Option<Node>>,
+    // the freed slots, which new nodes reuse so long runs don't grow the table forever
+    free: Vec<usize>,
+    fds: HashMap<usize, OpenFile>,
+    // bytes of file data the tree may hold, and holds now
+    capacity: u64,
+    used: u64,
+}
+
+/// An in-memory directory tree. Clones share the same tree, so an embedder can keep
+/// one to fill in before the run and read back after it.
+#[derive(Clone)]
+pub struct MemFs {
+    tree: Arc<Mutex<MemTree>>,
+}
+
+impl std::fmt::Debug for MemFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MemFs")
+    }
+}
+
+impl PartialEq for MemFs {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.tree, &other.tree)
+    }
+}
+
+impl Eq for MemFs {}
+
+fn now() -> Timespec {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() as i64, now.subsec_nanos() as i64)
+}
+
+// The bytes of a HostPath (or any NUL-terminated buffer) before the NUL
+fn path_bytes(path: &[u8]) -> &[u8] {
+    let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+    &path[..end]
+}
+
+fn ret(result: Result<usize, Errno>) -> isize {
+    match result {
+        Ok(n) => n as isize,
+        Err(errno) => -(errno as isize),
+    }
+}
+
+fn readable(flags: i32) -> bool {
+    flags & libc::O_ACCMODE != libc::O_WRONLY
+}
+
+fn writable(flags: i32) -> bool {
+    flags & libc::O_ACCMODE != libc::O_RDONLY
+}
+
+impl Node {
+    fn new(kind: NodeKind, perm: u32) -> Self {
+        let now = now();
+        Node {
+            kind,
+            perm,
+            nlink: 1,
+            open: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+        }
+    }
+
+    fn mode(&self) -> u32 {
+        let filetype = match self.kind {
+            NodeKind::File(_) => libc::S_IFREG,
+            NodeKind::Dir(..) => libc::S_IFDIR,
+            NodeKind::Symlink(_) => libc::S_IFLNK,
+        };
+        filetype as u32 | self.perm
+    }
+
+    fn size(&self) -> u64 {
+        match &self.kind {
+            NodeKind::File(data) => data.len() as u64,
+            NodeKind::Dir(entries, _) => entries.len() as u64,
+            NodeKind::Symlink(target) => target.len() as u64,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, NodeKind::Dir(..))
+    }
+
+    fn touch(&mut self) {
+        let now = now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+impl MemTree {
+    fn node(&self, ino: usize) -> &Node {
+        self.nodes[ino].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, ino: usize) -> &mut Node {
+        self.nodes[ino].as_mut().unwrap()
+    }
+
+    fn entries(&self, dir: usize) -> Result<&BTreeMap<Vec<u8>, usize>, Errno> {
+        match &self.node(dir).kind {
+            NodeKind::Dir(entries, _) => Ok(entries),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: usize) -> &mut BTreeMap<Vec<u8>, usize> {
+        match &mut self.node_mut(dir).kind {
+            NodeKind::Dir(entries, _) => entries,
+            _ => panic!("not a directory"),
+        }
+    }
+
+    fn parent(&self, dir: usize) -> usize {
+        match self.node(dir).kind {
+            NodeKind::Dir(_, parent) => parent,
+            _ => dir,
+        }
+    }
+
+    fn open_file(&self, fd: usize) -> Result<&OpenFile, Errno> {
+        self.fds.get(&fd).ok_or(libc::EBADF)
+    }
+
+    fn open_dir(&self, fd: usize) -> Result<usize, Errno> {
+        let dir = self.open_file(fd)?.node;
+        self.entries(dir)?;
+        Ok(dir)
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(ino) => {
+                self.nodes[ino] = Some(node);
+                ino
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn maybe_free(&mut self, ino: usize) {
+        let node = self.node(ino);
+        if node.nlink == 0 && node.open == 0 {
+            if let NodeKind::File(data) = &node.kind {
+                self.used -= data.len() as u64;
+            }
+            self.nodes[ino] = None;
+            self.free.push(ino);
+        }
+    }
+
+    // Resize the file `ino` to `len` bytes, zero filling, if the tree has room for it
+    fn set_file_len(&mut self, ino: usize, len: u64) -> Result<(), Errno> {
+        if len > MAX_FILE_SIZE {
+            return Err(libc::EFBIG);
+        }
+        let old = self.node(ino).size();
+        if len > old && len - old > self.capacity - self.used {
+            return Err(libc::ENOSPC);
+        }
+        match &mut self.node_mut(ino).kind {
+            NodeKind::File(data) => data.resize(len as usize, 0),
+            _ => return Err(libc::EINVAL),
+        }
+        self.used = self.used - old + len;
+        Ok(())
+    }
+
+    // Follow `path` from the directory `start`. Symlinks are followed everywhere but in
+    // the last component, which is only followed if `follow` is set.
+    // Like the kernel, an empty path (or symlink target) names nothing.
+    fn walk(&self, start: usize, path: &[u8], follow: bool) -> Result<Walk, Errno> {
+        let path = path_bytes(path);
+        if path.is_empty() {
+            return Err(libc::ENOENT);
+        }
+        let mut dir = if path.starts_with(b"/") { ROOT } else { start };
+        let mut queue: VecDeque<Vec<u8>> = path.split(|&b| b == b'/').map(|c| c.to_vec()).collect();
+        let mut symlinks = 0;
+        self.entries(dir)?;
+        while let Some(component) = queue.pop_front() {
+            let last = queue.iter().all(|c| c.is_empty() || c == b".");
+            match component.as_slice() {
+                b"" | b"." => continue,
+                b".." => {
+                    dir = self.parent(dir);
+                    continue;
+                }
+                _ => {}
+            }
+            let child = match self.entries(dir)?.get(&component) {
+                Some(&child) => child,
+                None if last => {
+                    return Ok(Walk {
+                        dir,
+                        name: Some(component),
+                        node: None,
+                    })
+                }
+                None => return Err(libc::ENOENT),
+            };
+            if let NodeKind::Symlink(target) = &self.node(child).kind {
+                if !last || follow {
+                    symlinks += 1;
+                    if symlinks > MAX_SYMLINKS {
+                        return Err(libc::ELOOP);
+                    }
+                    if target.is_empty() {
+                        return Err(libc::ENOENT);
+                    }
+                    if target.starts_with(b"/") {
+                        dir = ROOT;
+                    }
+                    for c in target.split(|&b| b == b'/').rev() {
+                        queue.push_front(c.to_vec());
+                    }
+                    continue;
+                }
+            }
+            if last {
+                return Ok(Walk {
+                    dir,
+                    name: Some(component),
+                    node: Some(child),
+                });
+            }
+            self.entries(child)?;
+            dir = child;
+        }
+        Ok(Walk {
+            dir,
+            name: None,
+            node: Some(dir),
+        })
+    }
+
+    fn walk_at(&self, dirfd: usize, path: &[u8], follow: bool) -> Result<Walk, Errno> {
+        let start = self.open_dir(dirfd)?;
+        self.walk(start, path, follow)
+    }
+
+    fn new_fd(&mut self, node: usize, flags: i32) -> usize {
+        let fd = NEXT_MEM_FD.fetch_add(1, Ordering::SeqCst);
+        self.node_mut(node).open += 1;
+        self.fds.insert(
+            fd,
+            OpenFile {
+                node,
+                offset: 0,
+                flags,
+            },
+        );
+        fd
+    }
+
+    // Add `node` to `dir` as `name`, which must not exist yet
+    fn link(&mut self, dir: usize, name: Vec<u8>, node: usize) {
+        self.entries_mut(dir).insert(name, node);
+        self.node_mut(dir).touch();
+    }
+
+    // Remove `name` from `dir`, freeing what it named once nothing else refers to it
+    fn unlink(&mut self, dir: usize, name: &[u8]) {
+        if let Some(node) = self.entries_mut(dir).remove(name) {
+            self.node_mut(dir).touch();
+            let target = self.node_mut(node);
+            target.nlink -= 1;
+            target.ctime = now();
+            self.maybe_free(node);
+        }
+    }
+
+    fn create(&mut self, walk: &Walk, kind: NodeKind, perm: u32) -> Result<usize, Errno> {
+        if walk.node.is_some() {
+            return Err(libc::EEXIST);
+        }
+        let name = walk.name.clone().ok_or(libc::EEXIST)?;
+        let node = self.alloc(Node::new(kind, perm & 0o7777));
+        self.link(walk.dir, name, node);
+        Ok(node)
+    }
+
+    fn openat(&mut self, dirfd: usize, path: &[u8], flags: i32, mode: i32) -> Result<usize, Errno> {
+        let walk = self.walk_at(dirfd, path, flags & libc::O_NOFOLLOW == 0)?;
+        let node = match walk.node {
+            Some(_) if flags & libc::O_CREAT != 0 && flags & libc::O_EXCL != 0 => {
+                return Err(libc::EEXIST)
+            }
+            Some(node) => node,
+            None if flags & libc::O_CREAT != 0 => {
+                self.create(&walk, NodeKind::File(Vec::new()), mode as u32)?
+            }
+            None => return Err(libc::ENOENT),
+        };
+        let truncate = match self.node(node).kind {
+            NodeKind::Symlink(_) => return Err(libc::ELOOP),
+            NodeKind::Dir(..) if writable(flags) || flags & libc::O_CREAT != 0 => {
+                return Err(libc::EISDIR)
+            }
+            NodeKind::Dir(..) => false,
+            NodeKind::File(_) if flags & libc::O_DIRECTORY != 0 => return Err(libc::ENOTDIR),
+            NodeKind::File(_) => flags & libc::O_TRUNC != 0 && writable(flags),
+        };
+        if truncate {
+            self.set_file_len(node, 0)?;
+            self.node_mut(node).touch();
+        }
+        Ok(self.new_fd(node, flags))
+    }
+
+    fn close(&mut self, fd: usize) -> Result<usize, Errno> {
+        let file = self.fds.remove(&fd).ok_or(libc::EBADF)?;
+        self.node_mut(file.node).open -= 1;
+        self.maybe_free(file.node);
+        Ok(0)
+    }
+
+    fn read_at(
+        &mut self,
+        fd: usize,
+        bufs: &mut [&mut [u8]],
+        offset: Option<u64>,
+    ) -> Result<usize, Errno> {
+        let file = self.open_file(fd)?;
+        if !readable(file.flags) {
+            return Err(libc::EBADF);
+        }
+        let (ino, start) = (file.node, offset.unwrap_or(file.offset));
+        let node = self.node_mut(ino);
+        let data = match &node.kind {
+            NodeKind::File(data) => data,
+            NodeKind::Dir(..) => return Err(libc::EISDIR),
+            NodeKind::Symlink(_) => return Err(libc::EINVAL),
+        };
+        let mut pos = std::cmp::min(start, data.len() as u64) as usize;
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let n = std::cmp::min(buf.len(), data.len() - pos);
+            buf[..n].copy_from_slice(&data[pos..pos + n]);
+            pos += n;
+            total += n;
+        }
+        node.atime = now();
+        if offset.is_none() {
+            self.fds.get_mut(&fd).unwrap().offset = start + total as u64;
+        }
+        Ok(total)
+    }
+
+    fn write_at(&mut self, fd: usize, bufs: &[&[u8]], offset: Option<u64>) -> Result<usize, Errno> {
+        let file = self.open_file(fd)?;
+        if !writable(file.flags) {
+            return Err(libc::EBADF);
+        }
+        let (ino, flags, cur) = (file.node, file.flags, file.offset);
+        let size = match &self.node(ino).kind {
+            NodeKind::File(data) => data.len() as u64,
+            _ => return Err(libc::EBADF),
+        };
+        // like the kernel, appends ignore both the fd's offset and pwrite's
+        let start = if flags & libc::O_APPEND != 0 {
+            size
+        } else {
+            offset.unwrap_or(cur)
+        };
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if total == 0 {
+            return Ok(0);
+        }
+        // the whole write fits, or none of it is done
+        let end = start.checked_add(total as u64).ok_or(libc::EFBIG)?;
+        if end > size {
+            self.set_file_len(ino, end)?;
+        }
+        let node = self.node_mut(ino);
+        if let NodeKind::File(data) = &mut node.kind {
+            let mut pos = start as usize;
+            for buf in bufs {
+                data[pos..pos + buf.len()].copy_from_slice(buf);
+                pos += buf.len();
+            }
+        }
+        node.touch();
+        if offset.is_none() {
+            self.fds.get_mut(&fd).unwrap().offset = end;
+        }
+        Ok(total)
+    }
+
+    fn lseek(&mut self, fd: usize, offset: i64, whence: i32) -> Result<usize, Errno> {
+        let file = self.open_file(fd)?;
+        let base = match whence {
+            libc::SEEK_SET => 0,
+            libc::SEEK_CUR => file.offset as i64,
+            libc::SEEK_END => self.node(file.node).size() as i64,
+            _ => return Err(libc::EINVAL),
+        };
+        let new_offset = base.checked_add(offset).ok_or(libc::EOVERFLOW)?;
+        if new_offset < 0 {
+            return Err(libc::EINVAL);
+        }
+        self.fds.get_mut(&fd).unwrap().offset = new_offset as u64;
+        Ok(new_offset as usize)
+    }
+
+    fn stat(&self, ino: usize, stat: &mut libc::stat) {
+        let node = self.node(ino);
+        *stat = unsafe { std::mem::zeroed() };
+        stat.st_ino = ino as _;
+        stat.st_mode = node.mode() as _;
+        stat.st_nlink = node.nlink as _;
+        stat.st_size = node.size() as _;
+        stat.st_blksize = 4096;
+        stat.st_blocks = ((node.size() + 511) / 512) as _;
+        stat.st_atime = node.atime.0 as _;
+        stat.st_atime_nsec = node.atime.1 as _;
+        stat.st_mtime = node.mtime.0 as _;
+        stat.st_mtime_nsec = node.mtime.1 as _;
+        stat.st_ctime = node.ctime.0 as _;
+        stat.st_ctime_nsec = node.ctime.1 as _;
+    }
+
+    fn fcntl(&mut self, fd: usize, cmd: i32, arg: libc::c_int) -> Result<usize, Errno> {
+        let file = self.fds.get_mut(&fd).ok_or(libc::EBADF)?;
+        match cmd {
+            libc::F_GETFL => Ok(file.flags as usize),
+            libc::F_SETFL => {
+                // only the status flags can be changed after open
+                let settable = libc::O_APPEND | libc::O_NONBLOCK;
+                file.flags = (file.flags & !settable) | (arg & settable);
+                Ok(0)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn resize(&mut self, fd: usize, len: u64, shrink: bool) -> Result<usize, Errno> {
+        let file = self.open_file(fd)?;
+        if !writable(file.flags) {
+            return Err(libc::EBADF);
+        }
+        let ino = file.node;
+        match &self.node(ino).kind {
+            NodeKind::File(data) if shrink || len > data.len() as u64 => {
+                self.set_file_len(ino, len)?
+            }
+            NodeKind::File(_) => {}
+            NodeKind::Dir(..) => return Err(libc::EISDIR),
+            NodeKind::Symlink(_) => return Err(libc::EINVAL),
+        }
+        self.node_mut(ino).touch();
+        Ok(0)
+    }
+
+    fn set_times(&mut self, ino: usize, specs: &[libc::timespec]) {
+        let now = now();
+        let time = |spec: &libc::timespec, old: Timespec| match spec.tv_nsec {
+            libc::UTIME_NOW => now,
+            libc::UTIME_OMIT => old,
+            _ => (spec.tv_sec as i64, spec.tv_nsec as i64),
+        };
+        let node = self.node_mut(ino);
+        node.atime = time(&specs[0], node.atime);
+        node.mtime = time(&specs[1], node.mtime);
+        node.ctime = now;
+    }
+
+    fn mkdirat(&mut self, dirfd: usize, path: &[u8], mode: libc::mode_t) -> Result<usize, Errno> {
+        let walk = self.walk_at(dirfd, path, false)?;
+        let dir = walk.dir;
+        let node = self.create(&walk, NodeKind::Dir(BTreeMap::new(), dir), mode as u32)?;
+        // a directory's own `.` is a link to it too
+        self.node_mut(node).nlink = 2;
+        Ok(0)
+    }
+
+    fn symlinkat(&mut self, target: &[u8], dirfd: usize, path: &[u8]) -> Result<usize, Errno> {
+        if path_bytes(target).is_empty() {
+            return Err(libc::ENOENT);
+        }
+        let walk = self.walk_at(dirfd, path, false)?;
+        self.create(&walk, NodeKind::Symlink(path_bytes(target).to_vec()), 0o777)?;
+        Ok(0)
+    }
+
+    fn readlinkat(&self, dirfd: usize, path: &[u8], buf: &mut [u8]) -> Result<usize, Errno> {
+        let walk = self.walk_at(dirfd, path, false)?;
+        let node = walk.node.ok_or(libc::ENOENT)?;
+        match &self.node(node).kind {
+            NodeKind::Symlink(target) => {
+                let n = std::cmp::min(buf.len(), target.len());
+                buf[..n].copy_from_slice(&target[..n]);
+                Ok(n)
+            }
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn linkat(
+        &mut self,
+        old_fd: usize,
+        old_path: &[u8],
+        new_fd: usize,
+        new_path: &[u8],
+        flags: i32,
+    ) -> Result<usize, Errno> {
+        let old = self.walk_at(old_fd, old_path, flags & libc::AT_SYMLINK_FOLLOW != 0)?;
+        let node = old.node.ok_or(libc::ENOENT)?;
+        if self.node(node).is_dir() {
+            return Err(libc::EPERM);
+        }
+        let new = self.walk_at(new_fd, new_path, false)?;
+        if new.node.is_some() {
+            return Err(libc::EEXIST);
+        }
+        let name = new.name.ok_or(libc::EEXIST)?;
+        let target = self.node_mut(node);
+        target.nlink += 1;
+        target.ctime = now();
+        self.link(new.dir, name, node);
+        Ok(0)
+    }
+
+    fn unlinkat(&mut self, dirfd: usize, path: &[u8], flags: libc::c_int) -> Result<usize, Errno> {
+        let walk = self.walk_at(dirfd, path, false)?;
+        let node = walk.node.ok_or(libc::ENOENT)?;
+        let name = walk.name.ok_or(libc::EBUSY)?;
+        let is_dir = self.node(node).is_dir();
+        if flags & libc::AT_REMOVEDIR != 0 {
+            if !is_dir {
+                return Err(libc::ENOTDIR);
+            }
+            if !self.entries(node)?.is_empty() {
+                return Err(libc::ENOTEMPTY);
+            }
+            // drop the link from its own `.`
+            self.node_mut(node).nlink -= 1;
+        } else if is_dir {
+            return Err(libc::EISDIR);
+        }
+        self.unlink(walk.dir, &name);
+        Ok(0)
+    }
+
+    fn renameat(
+        &mut self,
+        old_fd: usize,
+        old_path: &[u8],
+        new_fd: usize,
+        new_path: &[u8],
+    ) -> Result<usize, Errno> {
+        let old = self.walk_at(old_fd, old_path, false)?;
+        let node = old.node.ok_or(libc::ENOENT)?;
+        let old_name = old.name.ok_or(libc::EBUSY)?;
+        let new = self.walk_at(new_fd, new_path, false)?;
+        let new_name = new.name.ok_or(libc::EBUSY)?;
+        let is_dir = self.node(node).is_dir();
+        if is_dir {
+            // a directory can't be moved into itself
+            let mut dir = new.dir;
+            loop {
+                if dir == node {
+                    return Err(libc::EINVAL);
+                }
+                if dir == ROOT {
+                    break;
+                }
+                dir = self.parent(dir);
+            }
+        }
+        if let Some(existing) = new.node {
+            if existing == node {
+                return Ok(0);
+            }
+            match (is_dir, self.node(existing).is_dir()) {
+                (true, false) => return Err(libc::ENOTDIR),
+                (false, true) => return Err(libc::EISDIR),
+                (true, true) if !self.entries(existing)?.is_empty() => return Err(libc::ENOTEMPTY),
+                (true, true) => self.node_mut(existing).nlink -= 1,
+                _ => {}
+            }
+            self.unlink(new.dir, &new_name);
+        }
+        self.entries_mut(old.dir).remove(&old_name);
+        self.node_mut(old.dir).touch();
+        self.link(new.dir, new_name, node);
+        let dir = new.dir;
+        let moved = self.node_mut(node);
+        moved.ctime = now();
+        if let NodeKind::Dir(_, parent) = &mut moved.kind {
+            *parent = dir;
+        }
+        Ok(0)
+    }
+
+    // Entries of the open directory `fd` as linux_dirent64 records, from its offset on
+    fn getdents64(&mut self, fd: usize, dirp: &mut Vec<u8>, count: usize) -> Result<usize, Errno> {
+        let dir = self.open_dir(fd)?;
+        let start = self.open_file(fd)?.offset as usize;
+        let mut entries = vec![(dir, b".".to_vec()), (self.parent(dir), b"..".to_vec())];
+        for (name, &ino) in self.entries(dir)?.iter() {
+            entries.push((ino, name.clone()));
+        }
+        let mut idx = start;
+        while idx < entries.len() {
+            let (ino, name) = &entries[idx];
+            // header, name and its NUL, padded to 8 bytes
+            let reclen = (19 + name.len() + 1 + 7) & !7;
+            if dirp.len() + reclen > count {
+                if idx == start {
+                    return Err(libc::EINVAL);
+                }
+                break;
+            }
+            let d_type = match self.node(*ino).kind {
+                NodeKind::File(_) => libc::DT_REG,
+                NodeKind::Dir(..) => libc::DT_DIR,
+                NodeKind::Symlink(_) => libc::DT_LNK,
+            };
+            let record_start = dirp.len();
+            dirp.extend_from_slice(&(*ino as u64).to_le_bytes());
+            dirp.extend_from_slice(&(idx as i64 + 1).to_le_bytes());
+            dirp.extend_from_slice(&(reclen as u16).to_le_bytes());
+            dirp.push(d_type);
+            dirp.extend_from_slice(name);
+            dirp.resize(record_start + reclen, 0);
+            idx += 1;
+        }
+        self.fds.get_mut(&fd).unwrap().offset = idx as u64;
+        Ok(dirp.len())
+    }
+
+    // Like `walk` from the root, for the embedder-facing API
+    fn lookup(&self, path: &[u8]) -> Result<usize, Errno> {
+        self.walk(ROOT, path, true)?.node.ok_or(libc::ENOENT)
+    }
+}
+
+fn to_runtime<T>(result: Result<T, Errno>) -> RuntimeResult<T> {
+    result.map_err(|errno| {
+        RuntimeError::from_syscall_ret(-(errno as isize))
+            .err()
+            .unwrap_or(RuntimeError::Eio)
+    })
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    /// An empty tree that can hold `DEFAULT_CAPACITY` bytes
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// An empty tree whose files can hold `capacity` bytes between them. Writes past
+    /// that fail with ENOSPC.
+    pub fn with_capacity(capacity: u64) -> Self {
+        let root = Node {
+            nlink: 2,
+            ..Node::new(NodeKind::Dir(BTreeMap::new(), ROOT), 0o755)
+        };
+        MemFs {
+            tree: Arc::new(Mutex::new(MemTree {
+                nodes: vec![Some(root)],
+                free: Vec::new(),
+                fds: HashMap::new(),
+                capacity,
+                used: 0,
+            })),
+        }
+    }
+
+    fn tree(&self) -> std::sync::MutexGuard<'_, MemTree> {
+        // a panic while holding the lock can't leave the tree half-updated in a way
+        // that matters more than losing the whole run, so ignore poisoning
+        self.tree.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A new raw fd for the root directory, which a preopen is opened on
+    pub(crate) fn open_root(&self) -> usize {
+        self.tree().new_fd(ROOT, libc::O_RDONLY | libc::O_DIRECTORY)
+    }
+
+    /// Create the directory at `path` (relative to the root), and any missing parents
+    pub fn create_dir_all(&self, path: &[u8]) -> RuntimeResult<()> {
+        let mut tree = self.tree();
+        let mut dir = ROOT;
+        for component in path.split(|&b| b == b'/').filter(|c| !c.is_empty()) {
+            let walk = to_runtime(tree.walk(dir, component, true))?;
+            dir = match walk.node {
+                Some(node) => {
+                    to_runtime(tree.entries(node))?;
+                    node
+                }
+                None => {
+                    let kind = NodeKind::Dir(BTreeMap::new(), walk.dir);
+                    let node = to_runtime(tree.create(&walk, kind, 0o755))?;
+                    tree.node_mut(node).nlink = 2;
+                    node
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Create or replace the file at `path` with `contents`. Its directory must exist.
+    pub fn write_file(&self, path: &[u8], contents: &[u8]) -> RuntimeResult<()> {
+        let mut tree = self.tree();
+        let walk = to_runtime(tree.walk(ROOT, path, true))?;
+        let node = match walk.node {
+            Some(node) => node,
+            None => to_runtime(tree.create(&walk, NodeKind::File(Vec::new()), 0o644))?,
+        };
+        if !matches!(tree.node(node).kind, NodeKind::File(_)) {
+            return Err(RuntimeError::Eisdir);
+        }
+        to_runtime(tree.set_file_len(node, 0))?;
+        to_runtime(tree.set_file_len(node, contents.len() as u64))?;
+        if let NodeKind::File(data) = &mut tree.node_mut(node).kind {
+            data.copy_from_slice(contents);
+        }
+        tree.node_mut(node).touch();
+        Ok(())
+    }
+
+    /// Create a symlink at `path` pointing to `target`
+    pub fn symlink(&self, path: &[u8], target: &[u8]) -> RuntimeResult<()> {
+        if target.is_empty() {
+            return Err(RuntimeError::Enoent);
+        }
+        let mut tree = self.tree();
+        let walk = to_runtime(tree.walk(ROOT, path, false))?;
+        to_runtime(tree.create(&walk, NodeKind::Symlink(target.to_vec()), 0o777))?;
+        Ok(())
+    }
+
+    /// The contents of the file at `path`
+    pub fn read_file(&self, path: &[u8]) -> RuntimeResult<Vec<u8>> {
+        let tree = self.tree();
+        let node = to_runtime(tree.lookup(path))?;
+        match &tree.node(node).kind {
+            NodeKind::File(data) => Ok(data.clone()),
+            _ => Err(RuntimeError::Eisdir),
+        }
+    }
+
+    /// The names in the directory at `path`, in sorted order
+    pub fn read_dir(&self, path: &[u8]) -> RuntimeResult<Vec<Vec<u8>>> {
+        let tree = self.tree();
+        let node = to_runtime(tree.lookup(path))?;
+        Ok(to_runtime(tree.entries(node))?.keys().cloned().collect())
+    }
+
+    /// The target of the symlink at `path`
+    pub fn read_link(&self, path: &[u8]) -> RuntimeResult<Vec<u8>> {
+        let tree = self.tree();
+        let walk = to_runtime(tree.walk(ROOT, path, false))?;
+        let node = walk.node.ok_or(RuntimeError::Enoent)?;
+        match &tree.node(node).kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(RuntimeError::Einval),
+        }
+    }
+}
+
+// NativeIoVecs point into the sandbox's linear memory, which the caller has checked
+// (see `translate_iovs`)
+fn iov_slices(iovs: &NativeIoVecs, iovcnt: usize) -> Vec<&mut [u8]> {
+    iovs.iovs
+        .iter()
+        .take(iovcnt)
+        .map(|iov| unsafe { std::slice::from_raw_parts_mut(iov.iov_base as *mut u8, iov.iov_len) })
+        .collect()
+}
+
+/// The backend of the preopen `fd` was opened under, or None if that is a host directory
+#[trusted]
+pub fn backend_for(ctx: &VmCtx, fd: usize) -> Option<MemFs> {
+    // host fds are never this high, so they don't need to be looked up
+    if fd < MEM_FD_BASE {
+        return None;
+    }
+    ctx.preopens
+        .iter()
+        .filter_map(|p| p.mem.as_ref())
+        .find(|fs| fs.owns(fd))
+        .cloned()
+}
+
+impl VmCtx {
+    /// The in-memory filesystem behind the preopen with host fd `dirfd`, if it has one
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn mem_fs(&self, dirfd: HostFd) -> Option<MemFs> {
+        self.preopens
+            .iter()
+            .find(|p| p.host_fd == dirfd)
+            .and_then(|p| p.mem.clone())
+    }
+}
+
+impl FsBackend for MemFs {
+    #[trusted]
+    fn owns(&self, fd: usize) -> bool {
+        fd >= MEM_FD_BASE && self.tree().fds.contains_key(&fd)
+    }
+
+    #[trusted]
+    fn openat(&self, dirfd: usize, path: HostPath, flags: i32, mode: i32) -> isize {
+        ret(self.tree().openat(dirfd, &path, flags, mode))
+    }
+
+    #[trusted]
+    fn close(&self, fd: usize) -> isize {
+        ret(self.tree().close(fd))
+    }
+
+    #[trusted]
+    fn read(&self, fd: usize, buf: &mut [u8], cnt: usize) -> isize {
+        ret(self.tree().read_at(fd, &mut [&mut buf[..cnt]], None))
+    }
+
+    #[trusted]
+    fn pread(&self, fd: usize, buf: &mut [u8], cnt: usize, offset: usize) -> isize {
+        ret(self
+            .tree()
+            .read_at(fd, &mut [&mut buf[..cnt]], Some(offset as u64)))
+    }
+
+    #[trusted]
+    fn readv(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize) -> isize {
+        ret(self.tree().read_at(fd, &mut iov_slices(iovs, iovcnt), None))
+    }
+
+    #[trusted]
+    fn preadv(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize, offset: usize) -> isize {
+        let mut bufs = iov_slices(iovs, iovcnt);
+        ret(self.tree().read_at(fd, &mut bufs, Some(offset as u64)))
+    }
+
+    #[trusted]
+    fn write(&self, fd: usize, buf: &[u8], cnt: usize) -> isize {
+        ret(self.tree().write_at(fd, &[&buf[..cnt]], None))
+    }
+
+    #[trusted]
+    fn pwrite(&self, fd: usize, buf: &[u8], cnt: usize, offset: usize) -> isize {
+        ret(self
+            .tree()
+            .write_at(fd, &[&buf[..cnt]], Some(offset as u64)))
+    }
+
+    #[trusted]
+    fn writev(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize) -> isize {
+        let bufs: Vec<&[u8]> = iov_slices(iovs, iovcnt)
+            .into_iter()
+            .map(|b| b as &[u8])
+            .collect();
+        ret(self.tree().write_at(fd, &bufs, None))
+    }
+
+    #[trusted]
+    fn pwritev(&self, fd: usize, iovs: &NativeIoVecs, iovcnt: usize, offset: usize) -> isize {
+        let bufs: Vec<&[u8]> = iov_slices(iovs, iovcnt)
+            .into_iter()
+            .map(|b| b as &[u8])
+            .collect();
+        ret(self.tree().write_at(fd, &bufs, Some(offset as u64)))
+    }
+
+    #[trusted]
+    fn lseek(&self, fd: usize, offset: i64, whence: i32) -> isize {
+        ret(self.tree().lseek(fd, offset, whence))
+    }
+
+    #[trusted]
+    fn sync(&self, fd: usize) -> isize {
+        ret(self.tree().open_file(fd).map(|_| 0))
+    }
+
+    #[trusted]
+    fn fdatasync(&self, fd: usize) -> isize {
+        ret(self.tree().open_file(fd).map(|_| 0))
+    }
+
+    #[trusted]
+    fn fstat(&self, fd: usize, stat: &mut libc::stat) -> isize {
+        let tree = self.tree();
+        ret(tree
+            .open_file(fd)
+            .map(|file| tree.stat(file.node, stat))
+            .map(|_| 0))
+    }
+
+    #[trusted]
+    fn fstatat(&self, dirfd: usize, path: HostPath, stat: &mut libc::stat, flags: i32) -> isize {
+        let tree = self.tree();
+        let follow = flags & libc::AT_SYMLINK_NOFOLLOW == 0;
+        let node = tree
+            .walk_at(dirfd, &path, follow)
+            .and_then(|walk| walk.node.ok_or(libc::ENOENT));
+        ret(node.map(|node| tree.stat(node, stat)).map(|_| 0))
+    }
+
+    #[trusted]
+    fn fcntl(&self, fd: usize, cmd: i32, arg: libc::c_int) -> isize {
+        ret(self.tree().fcntl(fd, cmd, arg))
+    }
+
+    #[trusted]
+    fn ftruncate(&self, fd: usize, length: libc::off_t) -> isize {
+        if length < 0 {
+            return -(libc::EINVAL as isize);
+        }
+        ret(self.tree().resize(fd, length as u64, true))
+    }
+
+    #[trusted]
+    fn allocate(&self, fd: usize, offset: i64, len: i64) -> isize {
+        if offset < 0 || len <= 0 {
+            return -(libc::EINVAL as isize);
+        }
+        match offset.checked_add(len) {
+            Some(end) => ret(self.tree().resize(fd, end as u64, false)),
+            None => -(libc::EFBIG as isize),
+        }
+    }
+
+    #[trusted]
+    fn advise(&self, fd: usize, _offset: i64, _len: i64, _advice: i32) -> isize {
+        ret(self.tree().open_file(fd).map(|_| 0))
+    }
+
+    #[trusted]
+    fn linkat(
+        &self,
+        old_fd: usize,
+        old_path: HostPath,
+        new_fd: usize,
+        new_path: HostPath,
+        flags: i32,
+    ) -> isize {
+        ret(self
+            .tree()
+            .linkat(old_fd, &old_path, new_fd, &new_path, flags))
+    }
+
+    #[trusted]
+    fn mkdirat(&self, dirfd: usize, path: HostPath, mode: libc::mode_t) -> isize {
+        ret(self.tree().mkdirat(dirfd, &path, mode))
+    }
+
+    #[trusted]
+    fn readlinkat(&self, dirfd: usize, path: HostPath, buf: &mut [u8], cnt: usize) -> isize {
+        ret(self.tree().readlinkat(dirfd, &path, &mut buf[..cnt]))
+    }
+
+    #[trusted]
+    fn unlinkat(&self, dirfd: usize, path: HostPath, flags: libc::c_int) -> isize {
+        ret(self.tree().unlinkat(dirfd, &path, flags))
+    }
+
+    #[trusted]
+    fn renameat(
+        &self,
+        old_fd: usize,
+        old_path: HostPath,
+        new_fd: usize,
+        new_path: HostPath,
+    ) -> isize {
+        ret(self.tree().renameat(old_fd, &old_path, new_fd, &new_path))
+    }
+
+    #[trusted]
+    fn symlinkat(&self, target: HostPath, dirfd: usize, path: HostPath) -> isize {
+        ret(self.tree().symlinkat(&target, dirfd, &path))
+    }
+
+    #[trusted]
+    fn futimens(&self, fd: usize, specs: &Vec<libc::timespec>) -> isize {
+        let mut tree = self.tree();
+        let node = tree.open_file(fd).map(|file| file.node);
+        ret(node.map(|node| tree.set_times(node, specs)).map(|_| 0))
+    }
+
+    #[trusted]
+    fn utimensat(
+        &self,
+        dirfd: usize,
+        path: HostPath,
+        specs: &Vec<libc::timespec>,
+        flags: libc::c_int,
+    ) -> isize {
+        let mut tree = self.tree();
+        let follow = flags & libc::AT_SYMLINK_NOFOLLOW == 0;
+        let node = tree
+            .walk_at(dirfd, &path, follow)
+            .and_then(|walk| walk.node.ok_or(libc::ENOENT));
+        ret(node.map(|node| tree.set_times(node, specs)).map(|_| 0))
+    }
+
+    #[trusted]
+    fn getdents64(&self, fd: usize, dirp: &mut Vec<u8>, count: usize) -> isize {
+        dirp.clear();
+        ret(self.tree().getdents64(fd, dirp, count))
+    }
+}
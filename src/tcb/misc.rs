@@ -2,7 +2,8 @@
 use crate::tcb::verifier::*;
 use crate::types::*;
 use prusti_contracts::*;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
 use std::vec::Vec;
 use wave_macros::with_ghost_var;
 
@@ -12,7 +13,7 @@ use wave_macros::with_ghost_var;
 // Used in fdmap implementation
 #[trusted]
 #[pure]
-#[requires(index < MAX_SBOX_FDS )]
+#[requires((index as usize) < vec.len())]
 pub fn vec_checked_lookup(
     vec: &Vec<RuntimeResult<HostFd>>,
     index: SboxFd,
@@ -36,6 +37,12 @@ pub fn wasm_iovs_checked_lookup(vec: &WasmIoVecs, index: usize) -> WasmIoVec {
     vec.iovs[index]
 }
 
+// Total number of bytes the iovecs cover
+#[trusted]
+pub fn wasm_iovs_total_len(vec: &WasmIoVecs) -> u64 {
+    vec.iovs.iter().map(|iov| iov.iov_len as u64).sum()
+}
+
 // // Trusted because I can't convince the verifier tha tthis will never panic.
 // // Used in specification in src/os.rs
 // #[trusted]
@@ -105,6 +112,13 @@ pub fn bitwise_and_u64(bv1: u64, bv2: u64) -> u64 {
     bv1 & bv2
 }
 
+// true if every right in `sub` is also in `sup`
+#[trusted]
+#[pure]
+pub fn rights_subset(sub: Rights, sup: Rights) -> bool {
+    sub & sup == sub
+}
+
 #[trusted]
 #[pure]
 // this ensures is mostly to prove that or'ing a nonzero # returns a
@@ -144,6 +158,106 @@ pub fn fresh_rusage() -> libc::rusage {
     unsafe { std::mem::zeroed() }
 }
 
+// Trusted since the callback is embedder code
+#[trusted]
+pub fn call_signal_callback(callback: extern "C" fn(u32), signal: u32) {
+    callback(signal)
+}
+
+// Safety: Safe as libc::sockaddr_storage is valid with an all-zero byte-pattern
+#[trusted]
+pub fn fresh_sockaddr() -> libc::sockaddr_storage {
+    unsafe { std::mem::zeroed() }
+}
+
+// Build a host sockaddr_in (if `domain` is AF_INET) or sockaddr_in6 from its parts.
+// `ip` is an ipv6 address, or an ipv4-mapped one for AF_INET, and `port` is in host byte order.
+// Trusted because of the casts between the sockaddr types.
+#[trusted]
+#[ensures(sockaddr_ip(&result) == ip)]
+#[ensures(sockaddr_port(&result) == port as u32)]
+pub fn new_sockaddr(
+    domain: i32,
+    ip: u128,
+    port: u16,
+    flowinfo: u32,
+    scope_id: u32,
+) -> libc::sockaddr_storage {
+    let mut storage = fresh_sockaddr();
+    if domain == libc::AF_INET {
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+        #[cfg(target_os = "macos")]
+        {
+            addr.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+        }
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        addr.sin_addr.s_addr = (ip as u32).to_be();
+    } else {
+        let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+        #[cfg(target_os = "macos")]
+        {
+            addr.sin6_len = std::mem::size_of::<libc::sockaddr_in6>() as u8;
+        }
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = port.to_be();
+        addr.sin6_flowinfo = flowinfo;
+        addr.sin6_addr.s6_addr = ip.to_be_bytes();
+        addr.sin6_scope_id = scope_id;
+    }
+    storage
+}
+
+// The ip in a sockaddr_in or sockaddr_in6. ipv4 addresses are returned ipv4-mapped.
+#[trusted]
+#[pure]
+pub fn sockaddr_ip(addr: &libc::sockaddr_storage) -> u128 {
+    if addr.ss_family as i32 == libc::AF_INET {
+        let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+        return ipv4_mapped(u32::from_be(addr.sin_addr.s_addr));
+    }
+    let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+    u128::from_be_bytes(addr.sin6_addr.s6_addr)
+}
+
+// The port in a sockaddr_in or sockaddr_in6, in host byte order
+#[trusted]
+#[pure]
+pub fn sockaddr_port(addr: &libc::sockaddr_storage) -> u32 {
+    if addr.ss_family as i32 == libc::AF_INET {
+        let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+        return u16::from_be(addr.sin_port) as u32;
+    }
+    let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+    u16::from_be(addr.sin6_port) as u32
+}
+
+// The address family (AF_INET or AF_INET6) of a sockaddr_storage
+#[trusted]
+#[pure]
+pub fn sockaddr_family(addr: &libc::sockaddr_storage) -> i32 {
+    addr.ss_family as i32
+}
+
+// The flowinfo and scope_id of a sockaddr_in6, or (0, 0) for a sockaddr_in
+#[trusted]
+pub fn sockaddr_v6_fields(addr: &libc::sockaddr_storage) -> (u32, u32) {
+    if addr.ss_family as i32 == libc::AF_INET {
+        return (0, 0);
+    }
+    let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+    (addr.sin6_flowinfo, addr.sin6_scope_id)
+}
+
+#[trusted]
+#[pure]
+pub fn sockaddr_len(addr: &libc::sockaddr_storage) -> u32 {
+    if addr.ss_family as i32 == libc::AF_INET {
+        return std::mem::size_of::<libc::sockaddr_in>() as u32;
+    }
+    std::mem::size_of::<libc::sockaddr_in6>() as u32
+}
+
 #[trusted]
 #[requires(len >= offset)]
 #[requires(buf.len() >= start + len)]
@@ -169,16 +283,58 @@ pub fn clone_vec_u8(vec: &Vec<u8>) -> Vec<u8> {
     vec.clone()
 }
 
-// TODO: should probably fail more elegantly than this
+// Open the host directory `s`, keeping it open for as long as the sandbox runs
 #[trusted]
-pub fn get_homedir_fd(s: &String) -> i32 {
-    let homedir_file = std::fs::File::open(s).unwrap();
-    let homedir_fd = homedir_file.as_raw_fd();
+pub fn open_host_dir(s: &String) -> RuntimeResult<HostFd> {
+    let dir = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(s);
+    match dir {
+        Ok(dir) => Ok(HostFd::from_raw(dir.into_raw_fd() as usize)),
+        Err(e) => {
+            let errno = e.raw_os_error().unwrap_or(libc::EIO);
+            Err(RuntimeError::from_syscall_ret(-(errno as isize))
+                .err()
+                .unwrap_or(RuntimeError::Eio))
+        }
+    }
+}
 
-    //Need to forget file to make sure it does not get auto-closed
-    //when it gets out of scope
-    std::mem::forget(homedir_file);
-    homedir_fd
+// The components of a relative path (up to any NUL), with `.` and `..` folded away.
+// Paths from translate_path have no symlinks left to expand, so this is exact.
+#[trusted]
+pub fn normalized_components(path: &[u8]) -> Vec<&[u8]> {
+    let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+    let mut components = Vec::new();
+    for component in path[..end].split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    components
+}
+
+// Is `fd` the host fd of one of the sandbox's preopened directories (or of the upper
+// directory of a copy-on-write one)?
+// Used in trace_safe to restrict *at calls to preopened directories
+#[trusted]
+#[pure]
+pub fn is_preopen_fd(preopens: &Vec<Preopen>, fd: usize) -> bool {
+    preopens
+        .iter()
+        .any(|p| p.host_fd.to_raw() == fd || p.upper.map_or(false, |upper| upper.to_raw() == fd))
+}
+
+// Strip the trailing nulls off a HostPath
+#[trusted]
+pub fn hostpath_to_vec(path: &HostPath) -> Vec<u8> {
+    let len = path.iter().position(|c| *c == 0).unwrap_or(PATH_MAX);
+    path[..len].to_vec()
 }
 
 #[trusted]
@@ -189,13 +345,18 @@ pub fn string_to_vec_u8(s: &String) -> Vec<u8> {
 #[with_ghost_var(trace: &mut Trace)]
 #[trusted]
 pub fn empty_netlist() -> Netlist {
-    let empty = NetEndpoint {
-        protocol: WasiProto::Unknown,
-        addr: 0,
-        port: 0,
-    };
+    Vec::new()
+}
 
-    [empty, empty, empty, empty]
+// Nothing may be bound and no connections accepted
+#[with_ghost_var(trace: &mut Trace)]
+#[trusted]
+pub fn empty_inbound_policy() -> InboundPolicy {
+    InboundPolicy {
+        bindlist: Vec::new(),
+        peerlist: Vec::new(),
+        restrict_peers: true,
+    }
 }
 
 // this shouldn't need to be trusted, but prusti does not casting an enum to an int
@@ -215,3 +376,10 @@ pub fn as_u16(e: RuntimeError) -> u16 {
 pub fn netlist_unmodified(n: &Netlist) -> bool {
     unimplemented!();
 }
+
+// uninterpreted function
+#[trusted]
+#[pure]
+pub fn inbound_unmodified(p: &InboundPolicy) -> bool {
+    unimplemented!();
+}
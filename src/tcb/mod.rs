@@ -1,7 +1,12 @@
+pub mod access;
 pub mod ffi;
+pub mod info_hiding;
+pub mod memfs;
 pub mod misc;
 pub mod os_specs;
+pub mod overlay;
 pub mod path;
 pub mod sbox_mem;
 #[cfg(any(feature = "verify", test))]
 pub mod verifier;
+pub mod walk;
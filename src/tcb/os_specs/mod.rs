@@ -1,14 +1,14 @@
 use crate::iov::*;
 #[cfg(feature = "time_syscalls")]
 use crate::stats::timing::{push_syscall_result, start_timer, stop_timer};
-use crate::tcb::misc::flag_set;
+use crate::tcb::misc::{flag_set, sockaddr_ip, sockaddr_port};
 use crate::tcb::sbox_mem::raw_ptr;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
 use crate::types::{NativeIoVec, NativeIoVecs};
 #[cfg(not(feature = "time_syscalls"))]
 use crate::verifier_interface::{push_syscall_result, start_timer, stop_timer};
-use crate::{effect, effects, path_effect};
+use crate::{effect, effects, net_effect, path_effect};
 use prusti_contracts::*;
 use syscall::syscall;
 use wave_macros::{external_call, external_method, with_ghost_var};
@@ -296,6 +296,60 @@ syscall_spec_gen! {
     syscall(sendto, fd: usize, buf: (&[u8]), cnt: usize, flags: i32, dest_addr: i32, addrlen: i32)
 }
 
+// recvfrom that also reports the source address of the datagram
+syscall_spec_gen! {
+    trace;
+    requires((buf.len() >= cnt));
+    ensures((effects!(old(trace), trace, effect!(FdAccess), effect!(WriteMem, addr, count) if addr == old(raw_ptr(buf)) && count == cnt)));
+    ensures((old(raw_ptr(buf)) == raw_ptr(buf)));
+    syscall(recvfrom ALIAS recvfrom_addr, fd: usize, buf: (&mut [u8]), cnt: usize, flags: i32, src: (&mut libc::sockaddr_storage), addrlen: (&mut u32))
+}
+
+//https://man7.org/linux/man-pages/man2/recvmsg.2.html
+// Hand-written since the msghdr has to be built around the iovecs. The kernel's
+// msg_flags (e.g. MSG_TRUNC) are returned through `msg_flags`.
+#[with_ghost_var(trace: &mut Trace)]
+#[trusted]
+#[ensures(trace.len() == old(trace.len() + buf.len()) &&
+    forall(|i: usize| (i < trace.len()) ==>
+    {
+        if i < old(trace.len())
+            { trace.lookup(i) == old(trace.lookup(i)) }
+        else
+        {
+            let this = buf.lookup(i - old(trace.len()));
+            let ev = trace.lookup(i);
+            iov_eq_write(ev, &this)
+        }
+    }
+))]
+pub fn os_recvmsg(
+    fd: usize,
+    buf: &NativeIoVecs,
+    iovcnt: usize,
+    flags: i32,
+    msg_flags: &mut i32,
+) -> isize {
+    let __start_ts = start_timer();
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    // NativeIoVec has the same layout as libc::iovec
+    msg.msg_iov = buf.iovs.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = iovcnt as _;
+    let result = unsafe { syscall!(RECVMSG, fd, &mut msg as *mut libc::msghdr, flags) as isize };
+    *msg_flags = msg.msg_flags;
+    let __end_ts = stop_timer();
+    push_syscall_result("recvmsg", __start_ts, __end_ts);
+    result
+}
+
+// sendto with an explicit destination, for unconnected datagram sockets
+syscall_spec_gen! {
+    trace;
+    requires((buf.len() >= cnt));
+    ensures((effects!(old(trace), trace, effect!(FdAccess), effect!(ReadMem, addr, count) if addr == old(raw_ptr(buf)) && count == cnt, net_effect!(NetAccess, protocol, ip, port) if ip == sockaddr_ip(dest_addr) && port == sockaddr_port(dest_addr) as usize)));
+    syscall(sendto ALIAS sendto_addr, fd: usize, buf: (&[u8]), cnt: usize, flags: i32, dest_addr: (&libc::sockaddr_storage), addrlen: u32)
+}
+
 //https://man7.org/linux/man-pages/man2/shutdown.2.html
 syscall_spec_gen! {
     trace;
@@ -320,8 +374,55 @@ syscall_spec_gen! {
 //https://man7.org/linux/man-pages/man2/connect.2.html
 syscall_spec_gen! {
     trace;
-    ensures((effects!(old(trace), trace, effect!(FdAccess), effect!(NetAccess, protocol, ip, port) if ip == addr.sin_addr.s_addr as usize && port == addr.sin_port as usize)));
-    syscall(connect, sockfd: usize, addr: (&libc::sockaddr_in), addrlen: u32)
+    ensures((effects!(old(trace), trace, effect!(FdAccess), net_effect!(NetAccess, protocol, ip, port) if ip == sockaddr_ip(addr) && port == sockaddr_port(addr) as usize)));
+    syscall(connect, sockfd: usize, addr: (&libc::sockaddr_storage), addrlen: u32)
+}
+
+//https://man7.org/linux/man-pages/man2/bind.2.html
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess), net_effect!(SockBind, protocol, ip, port) if ip == sockaddr_ip(addr) && port == sockaddr_port(addr) as usize)));
+    syscall(bind, sockfd: usize, addr: (&libc::sockaddr_storage), addrlen: u32)
+}
+
+//https://man7.org/linux/man-pages/man2/listen.2.html
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(listen, sockfd: usize, backlog: i32)
+}
+
+//https://man7.org/linux/man-pages/man2/accept.2.html
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(accept, sockfd: usize, addr: (&mut libc::sockaddr_storage), addrlen: (&mut u32))
+}
+
+//https://man7.org/linux/man-pages/man2/setsockopt.2.html
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(setsockopt ALIAS setsockopt_int, sockfd: usize, level: i32, optname: i32, optval: (&i32), optlen: u32)
+}
+
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(setsockopt ALIAS setsockopt_timeval, sockfd: usize, level: i32, optname: i32, optval: (&libc::timeval), optlen: u32)
+}
+
+//https://man7.org/linux/man-pages/man2/getsockopt.2.html
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(getsockopt ALIAS getsockopt_int, sockfd: usize, level: i32, optname: i32, optval: (&mut i32), optlen: (&mut u32))
+}
+
+syscall_spec_gen! {
+    trace;
+    ensures((effects!(old(trace), trace, effect!(FdAccess))));
+    syscall(getsockopt ALIAS getsockopt_timeval, sockfd: usize, level: i32, optname: i32, optval: (&mut libc::timeval), optlen: (&mut u32))
 }
 
 //https://man7.org/linux/man-pages/man2/ioctl.2.html
@@ -137,3 +137,65 @@ syscall_spec_gen! {
     ensures((effects!(old(trace), trace, effect!(FdAccess))));
     syscall(fstat, fd: usize, stat: (&mut libc::stat))
 }
+
+//https://man7.org/linux/man-pages/man2/openat2.2.html
+#[repr(C)]
+pub struct OpenHow {
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
+}
+
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+pub const RESOLVE_BENEATH: u64 = 0x08;
+
+#[with_ghost_var(trace: &mut Trace)]
+#[trusted]
+#[ensures(effects!(old(trace), trace, path_effect!(PathAccessAt, fd, p, f) if fd == dirfd && p == old(path) && f == !flag_set(how.flags as i32, libc::O_NOFOLLOW)))]
+pub fn os_openat2(dirfd: usize, path: [u8; 4096], how: &OpenHow) -> isize {
+    let __start_ts = start_timer();
+    // openat2 is newer than the syscall table we use elsewhere, so go through libc
+    // and turn its -1/errno back into a negative errno like the other os_* calls
+    let result = unsafe {
+        let r = libc::syscall(
+            libc::SYS_openat2,
+            dirfd as libc::c_int,
+            path.as_ptr(),
+            how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        );
+        if r < 0 {
+            -(*libc::__errno_location() as isize)
+        } else {
+            r as isize
+        }
+    };
+    let __end_ts = stop_timer();
+    push_syscall_result("openat2", __start_ts, __end_ts);
+    result
+}
+
+/// Whether the kernel can resolve paths for `PathResolution::Beneath` (openat2, Linux 5.6+)
+#[trusted]
+pub fn openat2_supported() -> bool {
+    let how = OpenHow {
+        flags: (libc::O_PATH | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            libc::AT_FDCWD,
+            b".\0".as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd < 0 {
+        return std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS);
+    }
+    unsafe { libc::close(fd as i32) };
+    true
+}
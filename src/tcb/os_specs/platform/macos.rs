@@ -219,3 +219,9 @@ syscall_spec_gen! {
     ensures((effects!(old(trace), trace, effect!(FdAccess))));
     syscall(fstat64 ALIAS fstat, fd: usize, stat: &mut libc::stat)
 }
+
+// openat2 is Linux only, `PathResolution::Beneath` is never used here
+#[trusted]
+pub fn openat2_supported() -> bool {
+    false
+}
@@ -0,0 +1,626 @@
+// Copy-on-write overlay of a preopened directory.
+//
+// The preopen's own directory is the lower layer and is never changed. Everything the
+// guest changes goes to a private upper directory instead:
+// - reads go to the upper layer if the path is there, and fall through to the lower
+//   one otherwise
+// - writes first copy the path (and its parents) up, then happen in the upper layer
+// - deleting something the lower layer has leaves a whiteout, an empty `.wh.<name>`
+//   file next to where the entry would be in the upper layer
+//
+// A whiteout hides the lower entry and everything below it. An entry created again
+// over a whiteout keeps it, so a directory created over a deleted one starts empty.
+// The guest can't name `.wh.` files itself (see `resolve_path`).
+//
+// This works on paths from translate_path, which have no symlinks left to expand,
+// and only ever touches the two layers through their directory fds, with the traced
+// os calls, so that PathResolution::Walk covers them like any other access.
+use crate::os::{
+    trace_close, trace_fstatat, trace_getdents64, trace_mkdirat, trace_openat, trace_read_host,
+    trace_readlinkat_host, trace_renameat, trace_symlinkat, trace_unlinkat, trace_write_host,
+};
+use crate::quota::{reserve_disk, settle_disk};
+use crate::tcb::misc::{fresh_stat, is_preopen_fd, normalized_components, open_host_dir};
+#[cfg(feature = "verify")]
+use crate::tcb::verifier::*;
+use crate::types::*;
+use prusti_contracts::*;
+use wave_macros::{external_calls, external_methods, with_ghost_var};
+use RuntimeError::*;
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+// how much of a directory listing or a copied file is read at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn join(components: &[&[u8]]) -> Vec<u8> {
+    if components.is_empty() {
+        return b".".to_vec();
+    }
+    components.join(&b'/')
+}
+
+fn host_path(path: &[u8]) -> RuntimeResult<HostPath> {
+    // components come from a NUL-terminated HostPath or a directory listing
+    if path.len() >= PATH_MAX {
+        return Err(Enametoolong);
+    }
+    let mut out = [0; PATH_MAX];
+    out[..path.len()].copy_from_slice(path);
+    Ok(out)
+}
+
+fn is_whiteout(name: &[u8]) -> bool {
+    name.starts_with(WHITEOUT_PREFIX)
+}
+
+// Path of the whiteout for the (non-root) path `components`
+fn whiteout_path(components: &[&[u8]]) -> Vec<u8> {
+    let (name, parent) = components.split_last().unwrap();
+    let mut path = Vec::new();
+    if !parent.is_empty() {
+        path.extend_from_slice(&join(parent));
+        path.push(b'/');
+    }
+    path.extend_from_slice(WHITEOUT_PREFIX);
+    path.extend_from_slice(name);
+    path
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(host_path, fresh_stat)]
+#[external_methods(is_err)]
+fn lstat_at(ctx: &VmCtx, dirfd: HostFd, path: &[u8]) -> Option<libc::stat> {
+    let path = host_path(path).ok()?;
+    let mut stat = fresh_stat();
+    if trace_fstatat(ctx, dirfd, path, &mut stat, libc::AT_SYMLINK_NOFOLLOW).is_err() {
+        return None;
+    }
+    Some(stat)
+}
+
+fn is_dir(stat: &libc::stat) -> bool {
+    stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+// Does a whiteout hide `components` or any of its parents?
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(whiteout_path)]
+#[external_methods(any, is_some)]
+fn whited_out(ctx: &VmCtx, upper: HostFd, components: &[&[u8]]) -> bool {
+    (1..=components.len())
+        .any(|end| lstat_at(ctx, upper, &whiteout_path(&components[..end])).is_some())
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(join)]
+#[external_methods(is_some)]
+fn in_upper(ctx: &VmCtx, upper: HostFd, components: &[&[u8]]) -> bool {
+    lstat_at(ctx, upper, &join(components)).is_some()
+}
+
+// The lower layer's entry at `components`, unless a whiteout hides it
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(join)]
+#[external_methods(is_some)]
+fn lower_stat(
+    ctx: &VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    components: &[&[u8]],
+) -> Option<libc::stat> {
+    if whited_out(ctx, upper, components) {
+        return None;
+    }
+    lstat_at(ctx, lower, &join(components))
+}
+
+// Names in the directory at `path` under `dirfd`, without `.` and `..`.
+// A directory that can't be read has no entries.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(host_path, parse)]
+#[external_methods(with_capacity, to_vec, push)]
+fn list_dir(ctx: &VmCtx, dirfd: HostFd, path: &[u8]) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let path = match host_path(path) {
+        Ok(path) => path,
+        Err(_) => return names,
+    };
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = match trace_openat(ctx, dirfd, path, flags) {
+        Ok(fd) => HostFd::from_raw(fd),
+        Err(_) => return names,
+    };
+    loop {
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        match trace_getdents64(ctx, fd, &mut buf, CHUNK_SIZE) {
+            Ok(len) if len > 0 => {}
+            _ => break,
+        }
+        let mut idx = 0;
+        while idx < buf.len() {
+            let dirent = match Dirent::parse(&buf, idx) {
+                Ok(dirent) => dirent,
+                Err(_) => break,
+            };
+            let start = idx + dirent.name_start;
+            let name = &buf[start..start + dirent.out_namlen];
+            if name != b"." && name != b".." {
+                names.push(name.to_vec());
+            }
+            idx += dirent.reclen as usize;
+        }
+    }
+    let _ = trace_close(ctx, fd);
+    names
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(host_path, whiteout_path)]
+fn create_whiteout(ctx: &VmCtx, upper: HostFd, components: &[&[u8]]) -> RuntimeResult<()> {
+    let path = host_path(&whiteout_path(components))?;
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = trace_openat(ctx, upper, path, flags)?;
+    trace_close(ctx, HostFd::from_raw(fd))?;
+    Ok(())
+}
+
+// Copy what is left to read of `src` to `dst`
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(vec)]
+fn copy_contents(ctx: &VmCtx, src: HostFd, dst: HostFd) -> RuntimeResult<()> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let len = trace_read_host(ctx, src, &mut buf, CHUNK_SIZE)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let mut done = 0;
+        while done < len {
+            let written = trace_write_host(ctx, dst, &buf[done..len], len - done)?;
+            if written == 0 {
+                return Err(Eio);
+            }
+            done += written;
+        }
+    }
+}
+
+// Copy a single lower entry into the upper layer, whose parent already has it.
+// Directories are copied without their contents, which stay visible below them.
+// Copied files count against the disk quota like any file the guest writes.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(host_path)]
+#[external_methods(truncate)]
+fn copy_entry(
+    ctx: &mut VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    path: &[u8],
+    stat: &libc::stat,
+) -> RuntimeResult<()> {
+    let path = host_path(path)?;
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => {
+            trace_mkdirat(ctx, upper, path, stat.st_mode & 0o7777)?;
+            Ok(())
+        }
+        libc::S_IFREG => {
+            reserve_disk(ctx, 0, stat.st_size as u64)?;
+            let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+            let src = HostFd::from_raw(trace_openat(ctx, lower, path, flags)?);
+            // WASI has no permissions, so the copy is created with the default mode
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+            let dst = match trace_openat(ctx, upper, path, flags | libc::O_CLOEXEC) {
+                Ok(dst) => HostFd::from_raw(dst),
+                Err(e) => {
+                    let _ = trace_close(ctx, src);
+                    return Err(e);
+                }
+            };
+            let copied = copy_contents(ctx, src, dst);
+            // charge whatever made it into the upper layer, even if the copy failed
+            settle_disk(ctx, dst, Some(0));
+            let _ = trace_close(ctx, src);
+            let _ = trace_close(ctx, dst);
+            copied
+        }
+        libc::S_IFLNK => {
+            let mut target = [0; PATH_MAX];
+            let len = trace_readlinkat_host(ctx, lower, path, &mut target, PATH_MAX)?;
+            let target = host_path(&target[..len])?;
+            trace_symlinkat(ctx, target, upper, path)?;
+            Ok(())
+        }
+        // devices, fifos and sockets can only be read in place
+        _ => Err(Enotsup),
+    }
+}
+
+// Copy `components` and its parents up, so that it can be changed in the upper layer.
+// Fails with Enoent if a parent does not exist in either layer. A final component that
+// does not exist is fine, as it is about to be created.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(join)]
+fn copy_up_components(
+    ctx: &mut VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    components: &[&[u8]],
+) -> RuntimeResult<()> {
+    for end in 1..=components.len() {
+        let prefix = &components[..end];
+        if in_upper(ctx, upper, prefix) {
+            continue;
+        }
+        match lower_stat(ctx, lower, upper, prefix) {
+            Some(stat) => copy_entry(ctx, lower, upper, &join(prefix), &stat)?,
+            None if end < components.len() => return Err(Enoent),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Is any component of `path` a name the overlay keeps for itself?
+#[trusted]
+pub fn is_reserved_path(path: &HostPath) -> bool {
+    normalized_components(path)
+        .iter()
+        .any(|component| is_whiteout(component))
+}
+
+/// The layer reads of `path` should go to: the upper one if it has `path` or hides it
+/// with a whiteout, the lower one otherwise.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(normalized_components)]
+#[trusted]
+pub fn read_layer(ctx: &VmCtx, lower: HostFd, upper: HostFd, path: &[u8]) -> HostFd {
+    let components = normalized_components(path);
+    if in_upper(ctx, upper, &components) || whited_out(ctx, upper, &components) {
+        upper
+    } else {
+        lower
+    }
+}
+
+/// Make `path` (and its parents) exist in the upper layer if they exist in the merged
+/// tree, so that it can be changed or created there.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(normalized_components)]
+#[trusted]
+pub fn copy_up(
+    ctx: &mut VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    path: &HostPath,
+) -> RuntimeResult<()> {
+    copy_up_components(ctx, lower, upper, &normalized_components(path))
+}
+
+/// The merged listing of the directory at `path`: `.` and `..`, then the upper layer's
+/// entries, then the lower layer's entries that the upper one doesn't shadow or hide.
+/// Each entry is (inode, wasi filetype, name).
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(normalized_components, join, is_whiteout, from)]
+#[external_methods(split_last, to_wasi, push, to_vec, unwrap_or, into_iter)]
+#[external_methods(filter, collect, contains, clone, as_slice, is_some, extend_from_slice)]
+#[trusted]
+pub fn merged_entries(
+    ctx: &VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    path: &[u8],
+) -> Vec<(u64, u8, Vec<u8>)> {
+    let components = normalized_components(path);
+    let dir_path = join(&components);
+    let mut entries = Vec::new();
+    let layer = read_layer(ctx, lower, upper, path);
+    if let Some(stat) = lstat_at(ctx, layer, &dir_path) {
+        let parent = match components.split_last() {
+            Some((_, parent)) => {
+                let parent_layer = read_layer(ctx, lower, upper, &join(parent));
+                lstat_at(ctx, parent_layer, &join(parent))
+            }
+            None => None,
+        };
+        let dir_type = Filetype::Directory.to_wasi();
+        entries.push((stat.st_ino, dir_type, b".".to_vec()));
+        entries.push((parent.unwrap_or(stat).st_ino, dir_type, b"..".to_vec()));
+    }
+    let upper_names: Vec<Vec<u8>> = if in_upper(ctx, upper, &components) {
+        list_dir(ctx, upper, &dir_path)
+            .into_iter()
+            .filter(|name| !is_whiteout(name))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let lower_names = if lower_stat(ctx, lower, upper, &components).is_some() {
+        list_dir(ctx, lower, &dir_path)
+            .into_iter()
+            .filter(|name| !upper_names.contains(name))
+            .filter(|name| {
+                let mut entry = components.clone();
+                entry.push(name.as_slice());
+                !whited_out(ctx, upper, &entry)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let layers = [(upper, upper_names), (lower, lower_names)];
+    for (dirfd, names) in layers {
+        for name in names {
+            let mut entry_path = dir_path.clone();
+            entry_path.push(b'/');
+            entry_path.extend_from_slice(&name);
+            if let Some(stat) = lstat_at(ctx, dirfd, &entry_path) {
+                let filetype = Filetype::from(stat.st_mode as libc::mode_t).to_wasi();
+                entries.push((stat.st_ino, filetype, name));
+            }
+        }
+    }
+    entries
+}
+
+/// The dirents fd_readdir returns for `entries` (from `merged_entries`), starting at
+/// entry `cookie`. Each entry's cookie is its index plus one, and the last one is cut
+/// off if it doesn't fit in `buf_len` bytes, so the guest knows to ask for more.
+#[trusted]
+pub fn merged_dirents(entries: &Vec<(u64, u8, Vec<u8>)>, cookie: u64, buf_len: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (idx, (ino, filetype, name)) in entries.iter().enumerate().skip(cookie as usize) {
+        out.extend_from_slice(&(idx as u64 + 1).to_le_bytes());
+        out.extend_from_slice(&ino.to_le_bytes());
+        out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(*filetype as u32).to_le_bytes());
+        out.extend_from_slice(name);
+        if out.len() >= buf_len {
+            out.truncate(buf_len);
+            break;
+        }
+    }
+    out
+}
+
+/// Remove the file (or, if `dir` is set, the empty directory) at `path` from the merged
+/// tree, leaving a whiteout if the lower layer has it.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(normalized_components, join, host_path, is_dir)]
+#[external_methods(is_empty, or, is_some, clone, push, extend_from_slice)]
+#[trusted]
+pub fn remove(
+    ctx: &mut VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    path: &HostPath,
+    dir: bool,
+) -> RuntimeResult<()> {
+    let components = normalized_components(path);
+    if components.is_empty() {
+        return Err(Ebusy);
+    }
+    let path = join(&components);
+    let upper_stat = lstat_at(ctx, upper, &path);
+    let lower_entry = lower_stat(ctx, lower, upper, &components);
+    let stat = match upper_stat.or(lower_entry) {
+        Some(stat) => stat,
+        None => return Err(Enoent),
+    };
+    match (dir, is_dir(&stat)) {
+        (true, false) => return Err(Enotdir),
+        (false, true) => return Err(Eisdir),
+        _ => {}
+    }
+    if dir && merged_entries(ctx, lower, upper, &path).len() > 2 {
+        return Err(Enotempty);
+    }
+    copy_up_components(ctx, lower, upper, &components[..components.len() - 1])?;
+    if upper_stat.is_some() {
+        let flags = if dir {
+            // all that can be left in it are whiteouts of lower entries
+            for name in list_dir(ctx, upper, &path) {
+                let mut whiteout = path.clone();
+                whiteout.push(b'/');
+                whiteout.extend_from_slice(&name);
+                let _ = trace_unlinkat(ctx, upper, host_path(&whiteout)?, 0);
+            }
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        trace_unlinkat(ctx, upper, host_path(&path)?, flags)?;
+    }
+    if lower_entry.is_some() {
+        create_whiteout(ctx, upper, &components)?;
+    }
+    Ok(())
+}
+
+/// Rename `old` to `new` in the merged tree. Renaming a directory the lower layer has
+/// would mean copying up the whole subtree, so that fails with Exdev (like overlayfs),
+/// and callers like `mv` fall back to copying.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(normalized_components, join, host_path, is_dir)]
+#[external_methods(is_empty, is_some, or)]
+#[trusted]
+pub fn rename(
+    ctx: &mut VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    old: &HostPath,
+    new: &HostPath,
+) -> RuntimeResult<()> {
+    let old = normalized_components(old);
+    let new = normalized_components(new);
+    if old.is_empty() || new.is_empty() {
+        return Err(Ebusy);
+    }
+    let old_lower = lower_stat(ctx, lower, upper, &old);
+    if let Some(stat) = old_lower {
+        if is_dir(&stat) {
+            return Err(Exdev);
+        }
+    }
+    let old_stat = match lstat_at(ctx, upper, &join(&old)).or(old_lower) {
+        Some(stat) => stat,
+        None => return Err(Enoent),
+    };
+    let new_lower = lower_stat(ctx, lower, upper, &new);
+    // like rename(2), a file can't replace a directory or the other way around
+    if let Some(stat) = lstat_at(ctx, upper, &join(&new)).or(new_lower) {
+        match (is_dir(&old_stat), is_dir(&stat)) {
+            (false, true) => return Err(Eisdir),
+            (true, false) => return Err(Enotdir),
+            _ => {}
+        }
+    }
+    if let Some(stat) = new_lower {
+        // rename only checks that the upper copy of the directory is empty
+        if is_dir(&stat) && merged_entries(ctx, lower, upper, &join(&new)).len() > 2 {
+            return Err(Enotempty);
+        }
+    }
+    copy_up_components(ctx, lower, upper, &old)?;
+    copy_up_components(ctx, lower, upper, &new)?;
+    let old_path = host_path(&join(&old))?;
+    let new_path = host_path(&join(&new))?;
+    trace_renameat(ctx, upper, old_path, upper, new_path)?;
+    if new_lower.is_some() {
+        // keep the lower entry's contents from showing through the renamed one
+        create_whiteout(ctx, upper, &new)?;
+    }
+    if old_lower.is_some() {
+        create_whiteout(ctx, upper, &old)?;
+    }
+    Ok(())
+}
+
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(join, is_whiteout, whiteout_path, is_dir, Deleted, Added, Modified)]
+#[external_methods(iter, map, collect, push, clone, pop, split_last, unwrap, is_some)]
+fn collect_changes(
+    ctx: &VmCtx,
+    lower: HostFd,
+    upper: HostFd,
+    dir: &mut Vec<Vec<u8>>,
+    changes: &mut Vec<OverlayChange>,
+) {
+    let dir_path = {
+        let components: Vec<&[u8]> = dir.iter().map(|c| c.as_slice()).collect();
+        join(&components)
+    };
+    for name in list_dir(ctx, upper, &dir_path) {
+        dir.push(name.clone());
+        let components: Vec<&[u8]> = dir.iter().map(|c| c.as_slice()).collect();
+        let path = join(&components);
+        if is_whiteout(&name) {
+            // a whiteout with nothing over it is a deletion
+            let mut deleted = components.clone();
+            deleted.pop();
+            deleted.push(&name[WHITEOUT_PREFIX.len()..]);
+            if !in_upper(ctx, upper, &deleted) {
+                changes.push(OverlayChange::Deleted(join(&deleted)));
+            }
+        } else if let Some(stat) = lstat_at(ctx, upper, &path) {
+            let (_, parent) = components.split_last().unwrap();
+            let lower_had =
+                !whited_out(ctx, upper, parent) && lstat_at(ctx, lower, &path).is_some();
+            let replaced = lstat_at(ctx, upper, &whiteout_path(&components)).is_some();
+            if is_dir(&stat) {
+                // a directory that was only copied up to hold changes isn't one itself
+                if !lower_had || replaced {
+                    changes.push(OverlayChange::Added(path.clone()));
+                }
+                collect_changes(ctx, lower, upper, dir, changes);
+            } else if lower_had {
+                changes.push(OverlayChange::Modified(path.clone()));
+            } else {
+                changes.push(OverlayChange::Added(path.clone()));
+            }
+        }
+        dir.pop();
+    }
+}
+
+/// Everything in the upper layer that differs from the lower one, with paths relative
+/// to the preopen's root. Files that were copied up count as modified, even if they
+/// were only opened for writing. The changed files themselves are in the upper directory.
+#[with_ghost_var(trace: &mut Trace)]
+#[trusted]
+pub fn changes(ctx: &VmCtx, lower: HostFd, upper: HostFd) -> Vec<OverlayChange> {
+    let mut changes = Vec::new();
+    collect_changes(ctx, lower, upper, &mut Vec::new(), &mut changes);
+    changes
+}
+
+// The opened upper dir of the preopen named `guest_name`, if it is copy-on-write.
+// An upper dir that can't be opened fails the whole sandbox instead of leaving the
+// preopen writable.
+#[trusted]
+pub fn preopen_upper(
+    guest_name: &String,
+    overlays: &Vec<(String, String)>,
+) -> RuntimeResult<Option<HostFd>> {
+    match overlays.iter().find(|(name, _)| name == guest_name) {
+        Some((_, upper_dir)) => Ok(Some(open_host_dir(upper_dir)?)),
+        None => Ok(None),
+    }
+}
+
+// Whether any of `overlays` names a guest directory that isn't one of `dirs`
+#[trusted]
+pub fn unknown_overlay(dirs: &Vec<(String, String)>, overlays: &Vec<(String, String)>) -> bool {
+    overlays
+        .iter()
+        .any(|(name, _)| !dirs.iter().any(|(_, guest_name)| guest_name == name))
+}
+
+impl VmCtx {
+    /// The upper directory of the preopen with host fd `dirfd`, if it is copy-on-write
+    #[with_ghost_var(trace: &Trace)]
+    #[trusted]
+    pub fn overlay_upper(&self, dirfd: HostFd) -> Option<HostFd> {
+        self.preopens
+            .iter()
+            .find(|p| p.host_fd == dirfd)
+            .and_then(|p| p.upper)
+    }
+
+    /// The host fd `path` (from `translate_path`) under the preopen with host fd `dirfd`
+    /// should be accessed through. That is `dirfd` itself unless the preopen is
+    /// copy-on-write, in which case `write` copies `path` up into the upper layer first.
+    #[with_ghost_var(trace: &mut Trace)]
+    #[trusted]
+    #[ensures(
+        match &result {
+            Ok(fd) => is_preopen_fd(&self.preopens, fd.to_raw()),
+            _ => true,
+        }
+    )]
+    pub fn layer_for(
+        &mut self,
+        dirfd: HostFd,
+        path: &HostPath,
+        write: bool,
+    ) -> RuntimeResult<HostFd> {
+        let upper = match self.overlay_upper(dirfd) {
+            Some(upper) => upper,
+            None => return Ok(dirfd),
+        };
+        if write {
+            copy_up(self, dirfd, upper, path)?;
+            return Ok(upper);
+        }
+        Ok(read_layer(self, dirfd, upper, path))
+    }
+
+    /// What the guest changed in the copy-on-write preopen named `guest_name`, or None
+    /// if there is no such preopen. The changed files themselves are in its upper dir.
+    #[trusted]
+    pub fn overlay_changes(&self, guest_name: &str) -> Option<Vec<OverlayChange>> {
+        let preopen = self.preopens.iter().find(|p| p.guest_name == guest_name)?;
+        let upper = preopen.upper?;
+        Some(changes(self, preopen.host_fd, upper))
+    }
+}
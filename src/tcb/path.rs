@@ -1,3 +1,6 @@
+use crate::os::trace_readlinkat_host;
+use crate::tcb::misc::normalized_components;
+use crate::tcb::overlay::read_layer;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
 use crate::types::*;
@@ -6,10 +9,11 @@ use std::ffi::OsString;
 use std::os::unix::ffi::OsStringExt;
 use std::path::{Component, Path, PathBuf};
 //use std::fs::read_link;
-use owned_components::{readlinkat, OwnedComponent, OwnedComponents};
+use owned_components::{OwnedComponent, OwnedComponents};
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::str;
+use wave_macros::{external_calls, external_methods, with_ghost_var};
 
 const DEPTH_ERR: isize = i32::MIN as isize;
 
@@ -82,11 +86,27 @@ pub fn get_components(path: &PathBuf) -> Vec<Component> {
     path.components().collect()
 }
 
-#[requires(idx < 4)]
+// Trusted because of the shifts and masks
+#[requires(idx < netlist.len())]
 #[pure]
 #[trusted]
-pub fn addr_matches_netlist_entry(netlist: &Netlist, addr: u32, port: u32, idx: usize) -> bool {
-    addr == netlist[idx].addr && port == netlist[idx].port
+pub fn addr_matches_netlist_entry(
+    netlist: &Netlist,
+    protocol: WasiProto,
+    addr: u128,
+    port: u32,
+    idx: usize,
+) -> bool {
+    let entry = &netlist[idx];
+    if entry.protocol != protocol {
+        return false;
+    }
+    let mask = match entry.prefix_len {
+        0 => 0,
+        len if len >= 128 => u128::MAX,
+        len => u128::MAX << (128 - len),
+    };
+    (addr & mask) == (entry.addr & mask) && entry.port_lo <= port && port <= entry.port_hi
 }
 
 // If the first component is not the rootdir or a prefix (like Windows C://) its relative
@@ -129,17 +149,54 @@ pub fn min_depth(components: &OwnedComponents) -> isize {
     curr_depth
 }
 
+// `is_symlink` is about the layer reads of `out_path` go to, which is the preopen's own
+// directory unless it is copy-on-write (see tcb/overlay.rs). Only a path that is there
+// and isn't a symlink, or isn't there at all, is Ok(None); anything else that stops the
+// link from being read is an error, so that nothing is taken to not be a symlink just
+// because it couldn't be checked.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(parse, from, from_bytes)]
+#[external_methods(as_pathbuf, unparse, overlay_upper, as_os_str, as_bytes)]
 #[trusted]
-#[ensures(result.is_none() ==> old(!is_symlink(out_path)) )]
-fn read_linkat_h(dirfd: HostFd, out_path: &OwnedComponents) -> Option<OwnedComponents> {
-    readlinkat(dirfd.to_raw(), &out_path.as_pathbuf())
-        .ok()
-        .map(|p| OwnedComponents::parse(p))
+#[ensures(
+    match &result {
+        Ok(None) => old(!is_symlink(out_path)),
+        _ => true,
+    }
+)]
+fn read_linkat_h(
+    ctx: &VmCtx,
+    dirfd: HostFd,
+    out_path: &OwnedComponents,
+) -> RuntimeResult<Option<OwnedComponents>> {
+    let path = out_path.as_pathbuf();
+    // in a copy-on-write preopen, the link may have been replaced or removed in the upper dir
+    let dirfd = match ctx.overlay_upper(dirfd) {
+        Some(upper) => read_layer(ctx, dirfd, upper, path.as_os_str().as_bytes()),
+        None => dirfd,
+    };
+    let host_path = match OwnedComponents::parse(path).unparse() {
+        Some(host_path) => host_path,
+        None => return Err(RuntimeError::Enametoolong),
+    };
+    let mut buf = [0u8; PATH_MAX];
+    match trace_readlinkat_host(ctx, dirfd, host_path, &mut buf, PATH_MAX) {
+        // the target was cut off
+        Ok(len) if len >= PATH_MAX => Err(RuntimeError::Enametoolong),
+        Ok(len) => {
+            let link = PathBuf::from(OsStr::from_bytes(&buf[..len]));
+            Ok(Some(OwnedComponents::parse(link)))
+        }
+        Err(RuntimeError::Einval) | Err(RuntimeError::Enoent) => Ok(None),
+        Err(e) => Err(e),
+    }
 }
 
 // Looks at a single component of a path:
 // if it is a symlink, return the linkpath.
 // else, we just append the value to out_path
+#[with_ghost_var(trace: &mut Trace)]
+#[external_methods(push, pop)]
 #[trusted]
 #[requires(!is_symlink(out_path) )]
 // require that out_path does not contain any symlinks going in
@@ -148,18 +205,45 @@ fn read_linkat_h(dirfd: HostFd, out_path: &OwnedComponents) -> Option<OwnedCompo
 // ensures that out_path contains no symlinks on exit
 #[ensures(forall(|i: usize| (i < out_path.len()) ==> !is_symlink(out_path.prefix(i)) ))]
 pub fn maybe_expand_component(
+    ctx: &VmCtx,
     dirfd: HostFd,
     out_path: &mut OwnedComponents,
     comp: OwnedComponent,
     num_symlinks: &mut isize,
-) -> Option<OwnedComponents> {
+) -> RuntimeResult<Option<OwnedComponents>> {
     out_path.inner.push(comp);
-    if let Some(linkpath) = read_linkat_h(dirfd, out_path) {
-        out_path.inner.pop(); // pop the component we just added, since it is a symlink
-        *num_symlinks += 1;
-        return Some(linkpath);
+    match read_linkat_h(ctx, dirfd, out_path) {
+        Ok(None) => Ok(None),
+        Ok(Some(linkpath)) => {
+            out_path.inner.pop(); // pop the component we just added, since it is a symlink
+            *num_symlinks += 1;
+            Ok(Some(linkpath))
+        }
+        Err(e) => {
+            // it may still be a symlink
+            out_path.inner.pop();
+            Err(e)
+        }
+    }
+}
+
+// Under a virtual root (see `VmCtx::virtual_root`), `/` means the preopen root, and `..`
+// there stays at it. Returns true if `comp` was one of those, which are handled here
+// instead of being appended to out_path.
+#[trusted]
+#[requires(!is_symlink(out_path) )]
+#[requires(forall(|i: usize| (i < out_path.len()) ==> !is_symlink(out_path.prefix(i)) ))]
+#[ensures(!is_symlink(out_path))]
+#[ensures(forall(|i: usize| (i < out_path.len()) ==> !is_symlink(out_path.prefix(i)) ))]
+pub fn apply_virtual_root(out_path: &mut OwnedComponents, comp: &OwnedComponent) -> bool {
+    match comp {
+        OwnedComponent::RootDir => {
+            out_path.inner.clear();
+            true
+        }
+        OwnedComponent::ParentDir => min_depth(out_path) == 0,
+        _ => false,
     }
-    return None;
 }
 
 // its an empty path, its not a symlink
@@ -171,6 +255,103 @@ pub fn fresh_components() -> OwnedComponents {
     OwnedComponents::new()
 }
 
+// Prepend `base` (the position of the directory fd the guest is resolving against,
+// relative to its preopen) to `path`. Since the result is resolved from the
+// preopen root, the depth checks in resolve_path also cover `base`.
+// Under a virtual root absolute paths are kept, for expand_path to start at the root.
+#[trusted]
+pub fn rebase_path(path: Vec<u8>, base: &Vec<u8>, virtual_root: bool) -> RuntimeResult<Vec<u8>> {
+    if path.len() == 0 {
+        // it would otherwise refer to `base` itself
+        return Err(RuntimeError::Enotcapable);
+    }
+    if path[0] == b'/' {
+        // otherwise it would be silently turned into a relative one
+        if virtual_root {
+            return Ok(path);
+        }
+        return Err(RuntimeError::Enotcapable);
+    }
+    if base.len() == 0 {
+        return Ok(path);
+    }
+    let mut out = base.clone();
+    out.push(b'/');
+    out.extend_from_slice(&path);
+    Ok(out)
+}
+
+// `path` rebased and checked like resolve_path's result, but with nothing expanded.
+// That is only the path resolve_path would have returned if it has no symlinks on it,
+// so `PathResolution::Beneath` uses it once the kernel has confirmed that.
+#[trusted]
+#[ensures(
+    match &result {
+        Ok(v) => path_safe(&v, should_follow),
+        _ => true,
+    }
+)]
+pub fn lexical_path(
+    path: Vec<u8>,
+    should_follow: bool,
+    base: &Vec<u8>,
+    virtual_root: bool,
+) -> RuntimeResult<HostPath> {
+    let path = rebase_path(path, base, virtual_root)?;
+    let p = PathBuf::from(OsString::from_vec(path));
+    let mut c = fresh_components();
+    for comp in get_components(&p) {
+        let comp = OwnedComponent::from_borrowed(&comp);
+        if !(virtual_root && apply_virtual_root(&mut c, &comp)) {
+            c.push(comp);
+        }
+    }
+    if virtual_root && c.len() == 0 {
+        c.push(OwnedComponent::CurDir);
+    }
+    if c.len() <= 0 || !is_relative(&c) || min_depth(&c) < 0 {
+        return Err(RuntimeError::Enotcapable);
+    }
+    OwnedComponents::unparse(c).ok_or(RuntimeError::Enametoolong)
+}
+
+// Whether relative symlink `target` climbs above the preopen root when read from the
+// directory `link` (from translate_path) is in
+#[trusted]
+pub fn climbs_out(link: &HostPath, target: &[u8]) -> bool {
+    let mut depth = normalized_components(link).len() as isize - 1;
+    for component in target.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => depth -= 1,
+            _ => depth += 1,
+        }
+        if depth < 0 {
+            return true;
+        }
+    }
+    false
+}
+
+impl VmCtx {
+    /// Whether the symlink target at `ptr`, as the guest wrote it, leaves the preopen when
+    /// read from the directory of `link` (from `translate_path`).
+    #[with_ghost_var(trace: &mut Trace)]
+    #[external_calls(climbs_out)]
+    #[trusted]
+    pub fn symlink_target_escapes(&self, ptr: SboxPtr, len: u32, link: &HostPath) -> bool {
+        // both `/` and `..` stay at the root
+        if self.virtual_root {
+            return false;
+        }
+        if !self.fits_in_lin_mem(ptr, len) {
+            return true;
+        }
+        let target = self.copy_buf_from_sandbox(ptr, len);
+        target.first() == Some(&b'/') || climbs_out(link, &target)
+    }
+}
+
 #[cfg(feature = "verify")]
 predicate! {
     pub fn has_no_symlink_prefixes(v: &OwnedComponents) -> bool {
@@ -111,7 +111,8 @@ impl VmCtx {
         old(raw_ptr(self.mem.as_slice()) + ptr as usize) == before_expiry(raw_ptr(result)) && 
         raw_ptr(self.mem.as_slice()) + ptr as usize == before_expiry(raw_ptr(result)) && 
         old(self.netlist) == self.netlist && 
-        old(self.homedir_host_fd) == self.homedir_host_fd)]
+        old(self.inbound) == self.inbound && 
+        old(self.preopens) == self.preopens)]
     #[trusted]
     pub fn slice_mem_mut(&mut self, ptr: SboxPtr, len: u32) -> &mut [u8] {
         let start = ptr as usize;
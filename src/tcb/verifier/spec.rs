@@ -1,9 +1,9 @@
-use crate::tcb::misc::netlist_unmodified;
+use crate::tcb::misc::{inbound_unmodified, is_preopen_fd, netlist_unmodified};
 #[cfg(feature = "verify")]
 use crate::tcb::path::path_safe;
 use crate::tcb::sbox_mem::{raw_ptr, valid_linmem};
 use crate::tcb::verifier::trace::{Effect, EffectType, Trace};
-use crate::types::{addr_in_netlist, VmCtx, HOMEDIR_FD, LINEAR_MEM_SIZE};
+use crate::types::{addr_in_netlist, VmCtx, WasiProto, LINEAR_MEM_SIZE};
 use prusti_contracts::*;
 
 #[cfg(feature = "verify")]
@@ -16,6 +16,7 @@ predicate! {
         ctx.arg_buffer.len() < 1024 * 1024 &&
         ctx.env_buffer.len() < 1024 * 1024 &&
         netlist_unmodified(&ctx.netlist) &&
+        inbound_unmodified(&ctx.inbound) &&
         valid_linmem(raw_ptr(ctx.mem.as_slice())) //&&
         //mem_ptr <= mem_ptr + count
     }
@@ -38,9 +39,11 @@ predicate! {
                     //Effect { typ: EffectType::WriteMem, f1: addr, f2: count, .. } => valid_linmem(raw_ptr(ctx.mem.as_slice())),//(addr < ctx.memlen) && (count < ctx.memlen) && (addr <= (addr + count)),
                     Effect { typ: EffectType::Shutdown, ..  } => true, // currently, all shutdowns are safe
                     Effect { typ: EffectType::FdAccess, ..  } => true,
-                    Effect { typ: EffectType::PathAccessAt, f1: dir_fd, f2:_, f3:_, p: Some(path), should_follow: Some(b) } => dir_fd == ctx.homedir_host_fd.to_raw() && path.len() == 4096 && path_safe(&path, b),
-                    Effect { typ: EffectType::NetAccess, f1: _proto, f2:addr, f3:port, .. } => addr_in_netlist(&ctx.netlist, addr as u32, port as u32),
-                    Effect { typ: EffectType::SockCreation, f1: domain, f2:ty, ..  } => domain == (libc::AF_INET as usize) && (ty == (libc::SOCK_STREAM as usize) || ty == (libc::SOCK_DGRAM as usize)),
+                    Effect { typ: EffectType::PathAccessAt, f1: dir_fd, f2:_, f3:_, p: Some(path), should_follow: Some(b) } => is_preopen_fd(&ctx.preopens, dir_fd) && path.len() == 4096 && path_safe(&path, b),
+                    // the os calls don't know the socket's protocol, the trace_* specs check it
+                    Effect { typ: EffectType::NetAccess, f1: _proto, f3:port, ip, .. } => addr_in_netlist(&ctx.netlist, WasiProto::Tcp, ip, port as u32) || addr_in_netlist(&ctx.netlist, WasiProto::Udp, ip, port as u32),
+                    Effect { typ: EffectType::SockBind, f1: _proto, f3:port, ip, .. } => addr_in_netlist(&ctx.inbound.bindlist, WasiProto::Tcp, ip, port as u32) || addr_in_netlist(&ctx.inbound.bindlist, WasiProto::Udp, ip, port as u32),
+                    Effect { typ: EffectType::SockCreation, f1: domain, f2:ty, ..  } => (domain == (libc::AF_INET as usize) || domain == (libc::AF_INET6 as usize)) && (ty == (libc::SOCK_STREAM as usize) || ty == (libc::SOCK_DGRAM as usize)),
                     _ => false,
                 }
             ))
@@ -84,6 +84,7 @@ pub enum EffectType {
     PathAccessAt,
     NetAccess,
     SockCreation,
+    SockBind,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -92,6 +93,7 @@ pub struct Effect {
     pub f1: usize,
     pub f2: usize,
     pub f3: usize,
+    pub ip: u128,
     pub p: Option<[u8; 4096]>,
     pub should_follow: Option<bool>,
 }
@@ -105,6 +107,7 @@ macro_rules! effect {
             f1: 0,
             f2: 0,
             f3: 0,
+            ip: 0,
             p: None,
             should_follow: None,
         }
@@ -115,6 +118,7 @@ macro_rules! effect {
             f1: $f1,
             f2: 0,
             f3: 0,
+            ip: 0,
             p: None,
             should_follow: None,
         }
@@ -125,6 +129,7 @@ macro_rules! effect {
             f1: $f1,
             f2: $f2,
             f3: 0,
+            ip: 0,
             p: None,
             should_follow: None,
         }
@@ -135,6 +140,24 @@ macro_rules! effect {
             f1: $f1,
             f2: $f2,
             f3: $f3,
+            ip: 0,
+            p: None,
+            should_follow: None,
+        }
+    };
+}
+
+// macro for network effects. ips are 128 bits (ipv4 is mapped into ipv6),
+// so they get their own field.
+#[macro_export]
+macro_rules! net_effect {
+    ($typ:ident, $f1:pat, $ip:pat, $port:pat) => {
+        Effect {
+            typ: EffectType::$typ,
+            f1: $f1,
+            f2: 0,
+            f3: $port,
+            ip: $ip,
             p: None,
             should_follow: None,
         }
@@ -150,6 +173,7 @@ macro_rules! path_effect {
             f1: $f1,
             f2: 0,
             f3: 0,
+            ip: 0,
             p: Some($f2),
             should_follow: Some($f3),
         }
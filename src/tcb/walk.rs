@@ -0,0 +1,192 @@
+// Race-free resolution of paths from translate_path, for `PathResolution::Walk` and
+// `PathResolution::Beneath`.
+//
+// translate_path expands symlinks by reading them, and checks the result never leaves
+// the preopen. Handing that path to the kernel in one piece re-resolves it, so a host
+// process that swaps a directory on it for a symlink in between can point the syscall
+// anywhere. Here instead each directory on the path is opened relative to the one
+// before with O_NOFOLLOW, and the syscall is made on the last one with the final name:
+// a component swapped for a symlink makes the walk (or the syscall, which then must
+// not follow its last component either) fail instead of leaving the preopen.
+// `..` goes back to the fd it came from rather than being looked up, so directories
+// moved out from under the walk can't be used to climb out either.
+//
+// Under `PathResolution::Beneath` the kernel does the walk instead: the directory the
+// last component is in is opened with a single openat2, whose RESOLVE_BENEATH keeps it
+// inside the preopen however the tree changes in between.
+use crate::types::*;
+use prusti_contracts::*;
+use std::ffi::CString;
+use RuntimeError::*;
+
+#[cfg(target_os = "linux")]
+const WALK_FLAGS: i32 = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+// no O_PATH, so the directories on the path must be readable
+#[cfg(target_os = "macos")]
+const WALK_FLAGS: i32 = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+
+/// The directory a path was walked to, and the name to make the syscall with in it.
+/// Closes the directory when dropped, unless it is the one the walk started from.
+pub struct PinnedPath {
+    pub dirfd: HostFd,
+    pub leaf: HostPath,
+    owned: bool,
+}
+
+impl Drop for PinnedPath {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { libc::close(self.dirfd.to_raw() as i32) };
+        }
+    }
+}
+
+fn close_all(fds: &[usize]) {
+    for fd in fds {
+        unsafe { libc::close(*fd as i32) };
+    }
+}
+
+fn last_error() -> RuntimeError {
+    let errno = std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO);
+    RuntimeError::from_syscall_ret(-(errno as isize))
+        .err()
+        .unwrap_or(Eio)
+}
+
+fn path_components(path: &HostPath) -> Vec<&[u8]> {
+    let end = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+    path[..end]
+        .split(|&b| b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect()
+}
+
+fn leaf_path(leaf: &[u8]) -> HostPath {
+    let mut leaf_path = [0u8; 4096];
+    leaf_path[..leaf.len()].copy_from_slice(leaf);
+    leaf_path
+}
+
+/// Pin `path` (from translate_path) under `dirfd` up to its last component, the way
+/// `mode` asks for. Under `PathResolution::Expand` nothing is pinned.
+#[trusted]
+pub fn pin_parent(
+    mode: PathResolution,
+    dirfd: HostFd,
+    path: &HostPath,
+) -> RuntimeResult<PinnedPath> {
+    match mode {
+        PathResolution::Walk => walk_parent(dirfd, path),
+        PathResolution::Beneath => open_parent_beneath(dirfd, path),
+        PathResolution::Expand => Ok(PinnedPath {
+            dirfd,
+            leaf: *path,
+            owned: false,
+        }),
+    }
+}
+
+fn walk_parent(dirfd: HostFd, path: &HostPath) -> RuntimeResult<PinnedPath> {
+    let components = path_components(path);
+    let (last, parents): (&[u8], &[&[u8]]) = match components.split_last() {
+        Some((last, parents)) => (last, parents),
+        None => (b".", &[]),
+    };
+    // the directories opened so far, innermost last
+    let mut opened: Vec<usize> = Vec::new();
+    for component in parents {
+        if *component == b".." {
+            // translate_path never lets a path climb above where it starts
+            if let Some(fd) = opened.pop() {
+                close_all(&[fd]);
+            }
+            continue;
+        }
+        let cur = opened.last().copied().unwrap_or(dirfd.to_raw());
+        // components end at the HostPath's NUL, so can't contain one
+        let name = CString::new(*component).unwrap();
+        let fd = unsafe { libc::openat(cur as i32, name.as_ptr(), WALK_FLAGS) };
+        if fd < 0 {
+            let err = last_error();
+            close_all(&opened);
+            return Err(err);
+        }
+        opened.push(fd as usize);
+    }
+    let leaf: &[u8] = if last == b".." {
+        if let Some(fd) = opened.pop() {
+            close_all(&[fd]);
+        }
+        b"."
+    } else {
+        last
+    };
+    let pinned = match opened.pop() {
+        Some(fd) => PinnedPath {
+            dirfd: HostFd::from_raw(fd),
+            leaf: leaf_path(leaf),
+            owned: true,
+        },
+        None => PinnedPath {
+            dirfd,
+            leaf: leaf_path(leaf),
+            owned: false,
+        },
+    };
+    close_all(&opened);
+    Ok(pinned)
+}
+
+#[cfg(target_os = "linux")]
+fn open_parent_beneath(dirfd: HostFd, path: &HostPath) -> RuntimeResult<PinnedPath> {
+    use crate::tcb::os_specs::{OpenHow, RESOLVE_BENEATH, RESOLVE_NO_SYMLINKS};
+    let components = path_components(path);
+    // `..` can't be made relative to the directory it is in without leaving it,
+    // so a path ending in one pins all of it
+    let (parents, leaf): (&[&[u8]], &[u8]) = match components.split_last() {
+        Some((last, _)) if *last == b".." => (&components, b"."),
+        Some((last, parents)) => (parents, last),
+        None => (&[], b"."),
+    };
+    if parents.is_empty() {
+        return Ok(PinnedPath {
+            dirfd,
+            leaf: leaf_path(leaf),
+            owned: false,
+        });
+    }
+    // components end at the HostPath's NUL, so can't contain one
+    let parent = CString::new(parents.join(&b'/')).unwrap();
+    // like the walk, nothing on a path from translate_path is a symlink any more
+    let how = OpenHow {
+        flags: (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd.to_raw() as i32,
+            parent.as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd < 0 {
+        return Err(last_error());
+    }
+    Ok(PinnedPath {
+        dirfd: HostFd::from_raw(fd as usize),
+        leaf: leaf_path(leaf),
+        owned: true,
+    })
+}
+
+// openat2 is Linux only, `PathResolution::Beneath` is never selected elsewhere
+#[cfg(target_os = "macos")]
+fn open_parent_beneath(_dirfd: HostFd, _path: &HostPath) -> RuntimeResult<PinnedPath> {
+    Err(Enotsup)
+}
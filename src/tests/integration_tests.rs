@@ -4,6 +4,7 @@ use std::path::Path;
 
 // execute an example using our example/make infrastructure
 // returns stdout
+// (WAVE_PATH_RESOLUTION is passed on to the example, see `make test`)
 fn run_and_capture(example_path: &str) -> String {
     let output = Command::new("make")
     .arg("-s") // silent
@@ -19,6 +20,19 @@ fn run_and_capture(example_path: &str) -> String {
     }
 }
 
+// like run_and_capture, but under waverunner, returning the json outcome it prints last
+fn run_under_waverunner(example_path: &str) -> String {
+    let output = Command::new("make")
+    .arg("-s")
+    .arg("-C")
+    .arg(example_path)
+    .arg("waverun")
+    .output()
+    .unwrap_or_else(|e| { panic!("failed to execute process: {}", e) });
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    stdout.lines().last().unwrap_or("").to_string()
+}
 
 #[test]
 fn example_cat() {
@@ -135,6 +149,12 @@ fn example_raise() {
     // all we expect is that it doesn't crash
 }
 
+#[test]
+fn example_oob_store() {
+    let s = run_under_waverunner("examples/oob_store");
+    assert_eq!(s, "{\"outcome\":\"trap\",\"trap\":\"out_of_bounds\"}");
+}
+
 #[test]
 fn example_random() {
     let s = run_and_capture("examples/random");
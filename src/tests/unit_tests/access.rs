@@ -0,0 +1,96 @@
+use super::{open_at_root, put_path, readdir_names, TestDir};
+use crate::runtime::{fresh_ctx, init_preopens};
+use crate::types::{
+    AccessMode, AccessRule, RuntimeError, VmCtx, RIGHTS_ALL, RIGHTS_FD_READ, RIGHTS_FD_READDIR,
+    RIGHTS_FD_WRITE,
+};
+use crate::wrappers::wasi_path_rename;
+use std::fs;
+
+// A sandbox with `dir` preopened as sandbox fd 3 under the access `rules`
+fn access_ctx(dir: &TestDir, rules: &[(&[u8], AccessMode)]) -> VmCtx {
+    let mut ctx = fresh_ctx(Vec::new());
+    let dirs = vec![(dir.name(), "data".to_owned())];
+    let access = rules
+        .iter()
+        .map(|(subtree, mode)| AccessRule {
+            preopen: "data".to_owned(),
+            subtree: subtree.to_vec(),
+            mode: *mode,
+        })
+        .collect();
+    ctx.preopens = init_preopens(&mut ctx.fdmap, &dirs, &access, &Vec::new(), &Vec::new()).unwrap();
+    ctx
+}
+
+#[test]
+fn test_hidden_entries_not_listed() {
+    let dir = TestDir::new("hidden_entries");
+    fs::create_dir(dir.join("secret")).unwrap();
+    fs::create_dir(dir.join("sub/secret")).unwrap();
+    let mut ctx = access_ctx(
+        &dir,
+        &[
+            (b"secret", AccessMode::Hidden),
+            (b"sub/secret", AccessMode::Hidden),
+        ],
+    );
+
+    // O_DIRECTORY
+    let fd = open_at_root(&mut ctx, b".", 1 << 1, RIGHTS_FD_READDIR).unwrap();
+    let names = readdir_names(&mut ctx, fd);
+    assert!(names.contains(&b"sub".to_vec()));
+    assert!(!names.contains(&b"secret".to_vec()));
+
+    let fd = open_at_root(&mut ctx, b"sub", 1 << 1, RIGHTS_FD_READDIR).unwrap();
+    let names = readdir_names(&mut ctx, fd);
+    assert!(names.contains(&b"file".to_vec()));
+    assert!(!names.contains(&b"secret".to_vec()));
+}
+
+#[test]
+fn test_rename_keeps_subtrees_in_place() {
+    let dir = TestDir::new("rename_subtrees");
+    fs::create_dir(dir.join("sub/ro")).unwrap();
+    let mut ctx = access_ctx(&dir, &[(b"sub/ro", AccessMode::ReadOnly)]);
+
+    // moving sub would take sub/ro out from under its read-only mode
+    let old_len = put_path(&mut ctx, 0, b"sub");
+    let new_len = put_path(&mut ctx, 1024, b"moved");
+    assert_eq!(
+        wasi_path_rename(&mut ctx, 3, 0, old_len, 3, 1024, new_len),
+        Err(RuntimeError::Enotcapable)
+    );
+    assert!(dir.join("sub/ro").exists());
+
+    // but what's next to it can still move
+    let old_len = put_path(&mut ctx, 0, b"sub/file");
+    let new_len = put_path(&mut ctx, 1024, b"file");
+    wasi_path_rename(&mut ctx, 3, 0, old_len, 3, 1024, new_len).unwrap();
+    assert!(dir.join("file").exists());
+}
+
+#[test]
+fn test_read_only_subtree() {
+    let dir = TestDir::new("read_only");
+    let mut ctx = access_ctx(&dir, &[(b"sub", AccessMode::ReadOnly)]);
+
+    // O_RDWR
+    assert!(matches!(
+        open_at_root(&mut ctx, b"sub/file", 1 << 5, RIGHTS_ALL),
+        Err(RuntimeError::Erofs)
+    ));
+    assert!(matches!(
+        open_at_root(&mut ctx, b"sub/new", 1, RIGHTS_ALL),
+        Err(RuntimeError::Erofs)
+    ));
+    // what is opened for reading can't be written through its fd either
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_ALL).unwrap();
+    assert!(matches!(
+        ctx.fdmap.check_rights(fd, RIGHTS_FD_WRITE),
+        Err(RuntimeError::Enotcapable)
+    ));
+    ctx.fdmap.check_rights(fd, RIGHTS_FD_READ).unwrap();
+    // while the rest of the preopen is still writable
+    open_at_root(&mut ctx, b"new", 1 | 1 << 5, RIGHTS_ALL).unwrap();
+}
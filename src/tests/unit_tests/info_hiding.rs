@@ -0,0 +1,71 @@
+use super::{path_stat_ino_dev, readlink, TestDir};
+use crate::runtime::fresh_ctx;
+use crate::types::{InfoHiding, RuntimeError, VmCtx};
+use crate::wrappers::wasi_fd_filestat_get;
+use std::fs;
+use std::os::unix::fs::{symlink, MetadataExt};
+
+// A sandbox with `dirs` preopened as sandbox fds 3, 4, ...
+fn hiding_ctx(dirs: &[&TestDir], remap_inodes: bool) -> VmCtx {
+    let preopens = dirs.iter().map(|dir| (dir.name(), dir.name())).collect();
+    let mut ctx = fresh_ctx(preopens);
+    ctx.info_hiding = Some(InfoHiding::new(remap_inodes));
+    ctx
+}
+
+#[test]
+fn test_devices_virtualized_per_preopen() {
+    let first = TestDir::new("devices_first");
+    let second = TestDir::new("devices_second");
+    let mut ctx = hiding_ctx(&[&first, &second], false);
+
+    assert_eq!(path_stat_ino_dev(&mut ctx, 3, b"sub/file").1, 1);
+    assert_eq!(path_stat_ino_dev(&mut ctx, 4, b"sub/file").1, 2);
+    assert_eq!(wasi_fd_filestat_get(&ctx, 3).unwrap().dev, 1);
+    // inodes are left alone unless asked for
+    let host_ino = fs::metadata(first.join("sub/file")).unwrap().ino();
+    assert_eq!(path_stat_ino_dev(&mut ctx, 3, b"sub/file").0, host_ino);
+}
+
+#[test]
+fn test_inodes_remapped() {
+    let dir = TestDir::new("inodes");
+    let mut ctx = hiding_ctx(&[&dir], true);
+
+    let host_ino = fs::metadata(dir.join("sub/file")).unwrap().ino();
+    let (ino, _) = path_stat_ino_dev(&mut ctx, 3, b"sub/file");
+    assert_ne!(ino, host_ino);
+    // the same file keeps the same number, whichever way it is looked at
+    assert_eq!(path_stat_ino_dev(&mut ctx, 3, b"sub/../sub/file").0, ino);
+    let (root_ino, _) = path_stat_ino_dev(&mut ctx, 3, b".");
+    assert_eq!(wasi_fd_filestat_get(&ctx, 3).unwrap().ino, root_ino);
+    assert_ne!(root_ino, ino);
+}
+
+#[test]
+fn test_readlink_targets_sanitized() {
+    let dir = TestDir::new("readlink");
+    let host_file = fs::canonicalize(&dir).unwrap().join("sub/file");
+    symlink("sub/file", dir.join("relative")).unwrap();
+    symlink(&host_file, dir.join("absolute")).unwrap();
+    symlink(&host_file, dir.join("sub/absolute")).unwrap();
+    symlink("../outside", dir.join("escaping")).unwrap();
+    symlink("/etc/passwd", dir.join("host")).unwrap();
+    let mut ctx = hiding_ctx(&[&dir], false);
+
+    assert_eq!(readlink(&mut ctx, b"relative").unwrap(), b"sub/file");
+    // absolute targets into the preopen become relative to the link
+    assert_eq!(readlink(&mut ctx, b"absolute").unwrap(), b"sub/file");
+    assert_eq!(readlink(&mut ctx, b"sub/absolute").unwrap(), b"../sub/file");
+    // and ones out of it are refused, without the target ever reaching the guest's buffer
+    ctx.mem[4096..4096 + 256].fill(0xaa);
+    assert!(matches!(
+        readlink(&mut ctx, b"escaping"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(matches!(
+        readlink(&mut ctx, b"host"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(ctx.mem[4096..4096 + 256].iter().all(|&b| b == 0xaa));
+}
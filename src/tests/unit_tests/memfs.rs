@@ -0,0 +1,119 @@
+use super::{path_stat_ino_dev, put_iov, put_path};
+use crate::runtime::{fresh_ctx, init_preopens};
+use crate::tcb::memfs::MemFs;
+use crate::types::{RuntimeError, VmCtx, RIGHTS_ALL};
+use crate::wrappers::{
+    wasi_fd_allocate, wasi_fd_filestat_get, wasi_fd_pwrite, wasi_fd_write, wasi_path_open,
+    wasi_path_unlink_file, wasi_poll_oneoff, wasi_sock_send,
+};
+
+// A sandbox with `fs` preopened as sandbox fd 3
+fn mem_ctx(fs: &MemFs) -> VmCtx {
+    let mut ctx = fresh_ctx(Vec::new());
+    let mem_preopens = vec![("mem".to_owned(), fs.clone())];
+    ctx.preopens = init_preopens(
+        &mut ctx.fdmap,
+        &Vec::new(),
+        &Vec::new(),
+        &Vec::new(),
+        &mem_preopens,
+    )
+    .unwrap();
+    ctx
+}
+
+// Create `path` under sandbox fd 3 for reading and writing, returning its fd
+fn mem_create(ctx: &mut VmCtx, path: &[u8]) -> u32 {
+    let len = put_path(ctx, 0, path);
+    // O_CREAT | O_RDWR
+    let oflags = 1 | 1 << 5;
+    wasi_path_open(ctx, 3, 0, 0, len, oflags, RIGHTS_ALL, RIGHTS_ALL, 0).unwrap()
+}
+
+#[test]
+fn test_memfs_capacity() {
+    let fs = MemFs::with_capacity(12);
+    let mut ctx = mem_ctx(&fs);
+    let fd = mem_create(&mut ctx, b"file");
+
+    let iov = put_iov(&mut ctx, b"01234567");
+    assert_eq!(wasi_fd_write(&mut ctx, fd, iov, 1).unwrap(), 8);
+    // a write that doesn't fit isn't done at all
+    assert!(matches!(
+        wasi_fd_write(&mut ctx, fd, iov, 1),
+        Err(RuntimeError::Enospc)
+    ));
+    assert_eq!(fs.read_file(b"file").unwrap(), b"01234567");
+    // and emptying a file frees its space
+    let other = mem_create(&mut ctx, b"other");
+    assert!(matches!(
+        wasi_fd_pwrite(&mut ctx, other, iov, 1, 0),
+        Err(RuntimeError::Enospc)
+    ));
+    fs.write_file(b"file", b"").unwrap();
+    assert_eq!(wasi_fd_pwrite(&mut ctx, other, iov, 1, 0).unwrap(), 8);
+}
+
+#[test]
+fn test_memfs_offsets_checked() {
+    let fs = MemFs::new();
+    let mut ctx = mem_ctx(&fs);
+    let fd = mem_create(&mut ctx, b"file");
+
+    let iov = put_iov(&mut ctx, b"data");
+    assert!(matches!(
+        wasi_fd_pwrite(&mut ctx, fd, iov, 1, u64::MAX - 1),
+        Err(RuntimeError::Efbig)
+    ));
+    assert!(matches!(
+        wasi_fd_allocate(&mut ctx, fd, i64::MAX as u64, 2),
+        Err(RuntimeError::Efbig)
+    ));
+    // well past the capacity, but representable
+    assert!(matches!(
+        wasi_fd_allocate(&mut ctx, fd, 1 << 40, 1),
+        Err(RuntimeError::Enospc)
+    ));
+    assert_eq!(wasi_fd_filestat_get(&ctx, fd).unwrap().size, 0);
+}
+
+#[test]
+fn test_memfs_fds_not_sockets() {
+    let fs = MemFs::new();
+    let mut ctx = mem_ctx(&fs);
+    let fd = mem_create(&mut ctx, b"file");
+
+    let iov = put_iov(&mut ctx, b"data");
+    assert!(matches!(
+        wasi_sock_send(&mut ctx, fd, iov, 1, 0),
+        Err(RuntimeError::Enotsock)
+    ));
+    // a single fd_read subscription
+    ctx.mem[0..48].fill(0);
+    ctx.mem[8] = 1;
+    ctx.mem[16..20].copy_from_slice(&fd.to_le_bytes());
+    assert!(matches!(
+        wasi_poll_oneoff(&mut ctx, 0, 512, 1),
+        Err(RuntimeError::Enotsup)
+    ));
+}
+
+#[test]
+fn test_memfs_empty_paths_and_inode_reuse() {
+    let fs = MemFs::new();
+    fs.write_file(b"file", b"data").unwrap();
+    // an empty path names nothing, rather than the directory it starts from
+    assert!(matches!(fs.read_dir(b""), Err(RuntimeError::Enoent)));
+    assert!(matches!(
+        fs.symlink(b"link", b""),
+        Err(RuntimeError::Enoent)
+    ));
+
+    // the inode of a file that is gone goes to the next one created
+    let mut ctx = mem_ctx(&fs);
+    let (ino, _) = path_stat_ino_dev(&mut ctx, 3, b"file");
+    let len = put_path(&mut ctx, 0, b"file");
+    wasi_path_unlink_file(&mut ctx, 3, 0, len).unwrap();
+    mem_create(&mut ctx, b"new");
+    assert_eq!(path_stat_ino_dev(&mut ctx, 3, b"new").0, ino);
+}
@@ -0,0 +1,162 @@
+// use super::*;
+// use crate::runtime::*;
+// use crate::types::*;
+// use crate::wrappers::*;
+// use std::time::Instant;
+
+// // some basic sanity tests
+// #[cfg(test)]
+// #[test]
+// fn test_time_get() -> RuntimeResult<()> {
+//     let mut ctx = fresh_ctx(String::from("."));
+//     let ret = wasi_clock_time_get(&mut ctx, ClockId::Realtime, Timestamp::new(0))?;
+//     let reference = Instant::now();
+
+//     assert_ne!(ret, Timestamp::new(0));
+//     assert_eq!(ctx.errno, RuntimeError::Success);
+//     Ok(())
+// }
+
+// #[cfg(test)]
+// #[test]
+// fn test_res_get() -> RuntimeResult<()> {
+//     let mut ctx = fresh_ctx(String::from("."));
+//     let ret = wasi_clock_res_get(&mut ctx, ClockId::Realtime)?;
+//     let reference = Instant::now();
+
+//     assert_ne!(ret, Timestamp::new(0));
+//     assert_eq!(ctx.errno, RuntimeError::Success);
+//     Ok(())
+// }
+
+mod access;
+mod info_hiding;
+mod memfs;
+mod netlist;
+mod overlay;
+mod path_resolution;
+mod preopens;
+mod quotas;
+mod rights;
+mod sockets;
+mod symlinks;
+
+use crate::runtime::fresh_ctx;
+use crate::types::{RuntimeResult, VmCtx};
+use crate::wrappers::{
+    wasi_fd_readdir, wasi_path_filestat_get, wasi_path_open, wasi_path_readlink,
+};
+use std::convert::TryInto;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// A host directory for a test to preopen, removed again once the test is done with it
+pub(super) struct TestDir(PathBuf);
+
+impl TestDir {
+    // An empty directory, unique to `name` and this test run
+    pub(super) fn empty(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("wave_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    // A directory containing sub/file
+    pub(super) fn new(name: &str) -> Self {
+        let dir = Self::empty(name);
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), b"contents").unwrap();
+        dir
+    }
+
+    // The directory's path, as preopen lists take it
+    pub(super) fn name(&self) -> String {
+        self.0.to_str().unwrap().to_owned()
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// A sandbox with `dir` preopened as sandbox fd 3, under its host path
+pub(super) fn dir_ctx(dir: &TestDir) -> VmCtx {
+    fresh_ctx(vec![(dir.name(), dir.name())])
+}
+
+// Put `path` into sandbox memory at `ptr`, returning its length
+pub(super) fn put_path(ctx: &mut VmCtx, ptr: u32, path: &[u8]) -> u32 {
+    ctx.mem[ptr as usize..ptr as usize + path.len()].copy_from_slice(path);
+    path.len() as u32
+}
+
+// Put `data` at 4096 and a single iovec for it at 1024, returning the iovec's address
+pub(super) fn put_iov(ctx: &mut VmCtx, data: &[u8]) -> u32 {
+    ctx.mem[4096..4096 + data.len()].copy_from_slice(data);
+    ctx.mem[1024..1028].copy_from_slice(&4096u32.to_le_bytes());
+    ctx.mem[1028..1032].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    1024
+}
+
+// Open `path` under sandbox fd 3 with `oflags` and `rights`
+pub(super) fn open_at_root(
+    ctx: &mut VmCtx,
+    path: &[u8],
+    oflags: u32,
+    rights: u64,
+) -> RuntimeResult<u32> {
+    let len = put_path(ctx, 0, path);
+    wasi_path_open(ctx, 3, 0, 0, len, oflags, rights, rights, 0)
+}
+
+// The names fd_readdir lists for the directory at sandbox fd `v_fd`
+pub(super) fn readdir_names(ctx: &mut VmCtx, v_fd: u32) -> Vec<Vec<u8>> {
+    let len = wasi_fd_readdir(ctx, v_fd, 8192, 4096, 0).unwrap() as usize;
+    let buf = &ctx.mem[8192..8192 + len];
+    let mut names = Vec::new();
+    let mut idx = 0;
+    while idx + 24 <= buf.len() {
+        let namlen = u32::from_le_bytes(buf[idx + 16..idx + 20].try_into().unwrap()) as usize;
+        names.push(buf[idx + 24..idx + 24 + namlen].to_vec());
+        idx += 24 + namlen;
+    }
+    names
+}
+
+pub(super) fn path_stat_ino_dev(ctx: &mut VmCtx, v_fd: u32, path: &[u8]) -> (u64, u64) {
+    let len = put_path(ctx, 0, path);
+    let stat = wasi_path_filestat_get(ctx, v_fd, 0, 0, len).unwrap();
+    (stat.ino, stat.dev)
+}
+
+// Stat `path` under sandbox fd 3, following symlinks
+pub(super) fn stat_following(ctx: &mut VmCtx, path: &[u8]) -> RuntimeResult<u64> {
+    let len = put_path(ctx, 0, path);
+    // LOOKUP_SYMLINK_FOLLOW
+    Ok(wasi_path_filestat_get(ctx, 3, 1, 0, len)?.ino)
+}
+
+// readlink `path` under sandbox fd 3, as the guest sees it
+pub(super) fn readlink(ctx: &mut VmCtx, path: &[u8]) -> RuntimeResult<Vec<u8>> {
+    let len = put_path(ctx, 0, path);
+    let n = wasi_path_readlink(ctx, 3, 0, len, 4096, 256)?;
+    Ok(ctx.mem[4096..4096 + n as usize].to_vec())
+}
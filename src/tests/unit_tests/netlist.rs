@@ -0,0 +1,141 @@
+use crate::runtime::fresh_ctx;
+use crate::tcb::misc::{sockaddr_ip, sockaddr_port};
+use crate::types::{addr_in_netlist, ipv4_mapped, NetEndpoint, WasiProto};
+
+fn endpoint(protocol: WasiProto, addr: u128, prefix_len: u32, ports: (u32, u32)) -> NetEndpoint {
+    NetEndpoint {
+        protocol,
+        addr,
+        prefix_len,
+        port_lo: ports.0,
+        port_hi: ports.1,
+    }
+}
+
+#[test]
+fn test_netlist_cidr() {
+    // 10.0.0.0/8, as an ipv4-mapped prefix
+    let netlist = vec![endpoint(
+        WasiProto::Tcp,
+        ipv4_mapped(0x0a00_0000),
+        96 + 8,
+        (0, 65535),
+    )];
+    assert!(addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        ipv4_mapped(0x0a01_0203),
+        80
+    ));
+    assert!(addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        ipv4_mapped(0x0aff_ffff),
+        80
+    ));
+    assert!(!addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        ipv4_mapped(0x0b00_0000),
+        80
+    ));
+    // the ipv6 address with the same low bits isn't ipv4
+    assert!(!addr_in_netlist(&netlist, WasiProto::Tcp, 0x0a01_0203, 80));
+
+    // 2001:db8::/32
+    let netlist = vec![endpoint(WasiProto::Udp, 0x2001_0db8 << 96, 32, (53, 53))];
+    assert!(addr_in_netlist(
+        &netlist,
+        WasiProto::Udp,
+        (0x2001_0db8 << 96) + 1,
+        53
+    ));
+    assert!(!addr_in_netlist(
+        &netlist,
+        WasiProto::Udp,
+        0x2001_0db9 << 96,
+        53
+    ));
+
+    // /0 is everything, /128 a single address
+    let netlist = vec![endpoint(WasiProto::Tcp, 0, 0, (0, 65535))];
+    assert!(addr_in_netlist(&netlist, WasiProto::Tcp, u128::MAX, 1));
+    let netlist = vec![endpoint(
+        WasiProto::Tcp,
+        ipv4_mapped(0x7f00_0001),
+        128,
+        (0, 65535),
+    )];
+    assert!(addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        ipv4_mapped(0x7f00_0001),
+        1
+    ));
+    assert!(!addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        ipv4_mapped(0x7f00_0002),
+        1
+    ));
+}
+
+#[test]
+fn test_netlist_ports_and_protocol() {
+    let addr = ipv4_mapped(0x7f00_0001);
+    let netlist = vec![
+        endpoint(WasiProto::Tcp, addr, 128, (8000, 8080)),
+        endpoint(WasiProto::Udp, addr, 128, (53, 53)),
+    ];
+    assert!(addr_in_netlist(&netlist, WasiProto::Tcp, addr, 8000));
+    assert!(addr_in_netlist(&netlist, WasiProto::Tcp, addr, 8080));
+    assert!(!addr_in_netlist(&netlist, WasiProto::Tcp, addr, 7999));
+    assert!(!addr_in_netlist(&netlist, WasiProto::Tcp, addr, 8081));
+    assert!(addr_in_netlist(&netlist, WasiProto::Udp, addr, 53));
+    // each entry only lets its own protocol through
+    assert!(!addr_in_netlist(&netlist, WasiProto::Tcp, addr, 53));
+    assert!(!addr_in_netlist(&netlist, WasiProto::Udp, addr, 8000));
+}
+
+#[test]
+fn test_netlist_byte_order() {
+    let mut ctx = fresh_ctx(Vec::new());
+    // the guest's sockaddr_in6 for [2001:db8::1]:8080: family, then the port and
+    // address in network order
+    ctx.mem[0..28].fill(0);
+    ctx.mem[0..2].copy_from_slice(&2u16.to_le_bytes());
+    ctx.mem[2..4].copy_from_slice(&8080u16.to_be_bytes());
+    ctx.mem[8..12].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+    ctx.mem[23] = 1;
+    let saddr = ctx.read_sockaddr(0, 28).unwrap();
+    let addr = (0x2001_0db8 << 96) + 1;
+    assert_eq!((sockaddr_ip(&saddr), sockaddr_port(&saddr)), (addr, 8080));
+    let netlist = vec![endpoint(WasiProto::Tcp, addr, 128, (8080, 8080))];
+    assert!(addr_in_netlist(
+        &netlist,
+        WasiProto::Tcp,
+        sockaddr_ip(&saddr),
+        8080
+    ));
+
+    // and for 192.168.1.2:80
+    ctx.mem[0..16].fill(0);
+    ctx.mem[0..2].copy_from_slice(&1u16.to_le_bytes());
+    ctx.mem[2..4].copy_from_slice(&80u16.to_be_bytes());
+    ctx.mem[4..8].copy_from_slice(&[192, 168, 1, 2]);
+    let saddr = ctx.read_sockaddr(0, 16).unwrap();
+    assert_eq!(sockaddr_ip(&saddr), ipv4_mapped(0xc0a8_0102));
+    assert_eq!(sockaddr_port(&saddr), 80);
+}
+
+#[test]
+fn test_netendpoint_layout_matches_c() {
+    // as in bindings/wave.h, where unsigned __int128 is 16-aligned
+    let entry = endpoint(WasiProto::Udp, 1, 128, (0, 0));
+    let base = &entry as *const NetEndpoint as usize;
+    assert_eq!(&entry.addr as *const u128 as usize - base, 0);
+    assert_eq!(&entry.protocol as *const WasiProto as usize - base, 16);
+    assert_eq!(&entry.port_hi as *const u32 as usize - base, 28);
+    assert_eq!(std::mem::size_of::<NetEndpoint>(), 32);
+    assert_eq!(std::mem::align_of::<NetEndpoint>(), 16);
+}
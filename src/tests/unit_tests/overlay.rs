@@ -0,0 +1,152 @@
+use super::{open_at_root, put_iov, put_path, readdir_names, TestDir};
+use crate::runtime::{fresh_ctx, init_preopens};
+use crate::types::{
+    OverlayChange, PathResolution, QuotaKind, RuntimeError, VmCtx, RIGHTS_ALL, RIGHTS_FD_READ,
+    RIGHTS_FD_READDIR,
+};
+use crate::wrappers::{
+    wasi_fd_close, wasi_fd_pwrite, wasi_fd_read, wasi_fd_write, wasi_path_rename,
+    wasi_path_unlink_file,
+};
+use std::fs;
+
+// A sandbox with `lower` preopened as sandbox fd 3, its changes going to `upper`
+fn overlay_ctx(lower: &TestDir, upper: &TestDir) -> VmCtx {
+    let mut ctx = fresh_ctx(Vec::new());
+    let dirs = vec![(lower.name(), "lower".to_owned())];
+    let overlays = vec![("lower".to_owned(), upper.name())];
+    ctx.preopens =
+        init_preopens(&mut ctx.fdmap, &dirs, &Vec::new(), &overlays, &Vec::new()).unwrap();
+    ctx
+}
+
+#[test]
+fn test_overlay_copy_up() {
+    let lower = TestDir::new("overlay_copy_lower");
+    let upper = TestDir::empty("overlay_copy_upper");
+    let mut ctx = overlay_ctx(&lower, &upper);
+
+    // reads go through to the lower layer without copying anything
+    let iov = put_iov(&mut ctx, &[0; 8]);
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ).unwrap();
+    assert_eq!(wasi_fd_read(&mut ctx, fd, iov, 1).unwrap(), 8);
+    assert_eq!(&ctx.mem[4096..4104], b"contents");
+    assert!(!upper.join("sub").exists());
+
+    // O_RDWR
+    let fd = open_at_root(&mut ctx, b"sub/file", 1 << 5, RIGHTS_ALL).unwrap();
+    let iov = put_iov(&mut ctx, b"new");
+    assert_eq!(wasi_fd_write(&mut ctx, fd, iov, 1).unwrap(), 3);
+    assert_eq!(fs::read(upper.join("sub/file")).unwrap(), b"newtents");
+    assert_eq!(fs::read(lower.join("sub/file")).unwrap(), b"contents");
+}
+
+#[test]
+fn test_overlay_remove_leaves_whiteout() {
+    let lower = TestDir::new("overlay_remove_lower");
+    let upper = TestDir::empty("overlay_remove_upper");
+    let mut ctx = overlay_ctx(&lower, &upper);
+
+    let len = put_path(&mut ctx, 0, b"sub/file");
+    wasi_path_unlink_file(&mut ctx, 3, 0, len).unwrap();
+    assert!(lower.join("sub/file").exists());
+    assert!(upper.join("sub/.wh.file").exists());
+    // O_DIRECTORY
+    let dir = open_at_root(&mut ctx, b"sub", 1 << 1, RIGHTS_FD_READDIR).unwrap();
+    assert_eq!(
+        readdir_names(&mut ctx, dir),
+        vec![b".".to_vec(), b"..".to_vec()]
+    );
+    assert!(matches!(
+        open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ),
+        Err(RuntimeError::Enoent)
+    ));
+    let changes = ctx.overlay_changes("lower").unwrap();
+    assert!(changes.contains(&OverlayChange::Deleted(b"sub/file".to_vec())));
+}
+
+#[test]
+fn test_overlay_copy_up_charges_disk() {
+    let lower = TestDir::new("overlay_quota_lower");
+    let upper = TestDir::empty("overlay_quota_upper");
+    let mut ctx = overlay_ctx(&lower, &upper);
+    ctx.quotas.disk_bytes = Some(10);
+
+    // sub/file is 8 bytes, so copying it up leaves room for 2 more
+    let fd = open_at_root(&mut ctx, b"sub/file", 1 << 5, RIGHTS_ALL).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 8);
+    fs::write(lower.join("big"), b"0123456789a").unwrap();
+    assert!(matches!(
+        open_at_root(&mut ctx, b"big", 1 << 5, RIGHTS_ALL),
+        Err(RuntimeError::Edquot)
+    ));
+    assert!(!upper.join("big").exists());
+    let iov = put_iov(&mut ctx, b"+");
+    assert_eq!(wasi_fd_pwrite(&mut ctx, fd, iov, 1, 8).unwrap(), 1);
+}
+
+#[test]
+fn test_overlay_upper_must_open() {
+    let lower = TestDir::new("overlay_missing_lower");
+    let dirs = vec![(lower.name(), "lower".to_owned())];
+    let mut ctx = fresh_ctx(Vec::new());
+    let missing = vec![("lower".to_owned(), "/nonexistent/upper".to_owned())];
+    assert!(matches!(
+        init_preopens(&mut ctx.fdmap, &dirs, &Vec::new(), &missing, &Vec::new()),
+        Err(RuntimeError::Enoent)
+    ));
+    // nor may an overlay name a directory the guest doesn't have
+    let unknown = vec![("other".to_owned(), lower.name())];
+    assert!(init_preopens(&mut ctx.fdmap, &dirs, &Vec::new(), &unknown, &Vec::new()).is_err());
+}
+
+#[test]
+fn test_overlay_under_walk() {
+    let lower = TestDir::new("overlay_walk_lower");
+    let upper = TestDir::empty("overlay_walk_upper");
+    let mut ctx = overlay_ctx(&lower, &upper);
+    ctx.set_path_resolution(PathResolution::Walk);
+    ctx.quotas.disk_bytes = Some(16);
+
+    // copying up, the whiteouts and the quota lookups all go through pinned parents
+    let fd = open_at_root(&mut ctx, b"sub/file", 1 << 5, RIGHTS_ALL).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 8);
+    wasi_fd_close(&mut ctx, fd).unwrap();
+    let len = put_path(&mut ctx, 0, b"sub/file");
+    wasi_path_unlink_file(&mut ctx, 3, 0, len).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 0);
+    assert!(upper.join("sub/.wh.file").exists());
+    assert!(lower.join("sub/file").exists());
+    let dir = open_at_root(&mut ctx, b"sub", 1 << 1, RIGHTS_FD_READDIR).unwrap();
+    assert_eq!(
+        readdir_names(&mut ctx, dir),
+        vec![b".".to_vec(), b"..".to_vec()]
+    );
+}
+
+#[test]
+fn test_overlay_rename_keeps_types() {
+    let lower = TestDir::new("overlay_rename_lower");
+    let upper = TestDir::empty("overlay_rename_upper");
+    fs::create_dir(lower.join("dir")).unwrap();
+    fs::create_dir(upper.join("new_dir")).unwrap();
+    let mut ctx = overlay_ctx(&lower, &upper);
+
+    // a file over a directory only the lower layer has
+    let old_len = put_path(&mut ctx, 0, b"sub/file");
+    let new_len = put_path(&mut ctx, 1024, b"dir");
+    assert_eq!(
+        wasi_path_rename(&mut ctx, 3, 0, old_len, 3, 1024, new_len),
+        Err(RuntimeError::Eisdir)
+    );
+    // and a directory over a file
+    let old_len = put_path(&mut ctx, 0, b"new_dir");
+    let new_len = put_path(&mut ctx, 1024, b"sub/file");
+    assert_eq!(
+        wasi_path_rename(&mut ctx, 3, 0, old_len, 3, 1024, new_len),
+        Err(RuntimeError::Enotdir)
+    );
+    assert!(lower.join("dir").is_dir());
+    assert!(!upper.join("dir").exists());
+    assert!(!upper.join("sub/file").exists());
+}
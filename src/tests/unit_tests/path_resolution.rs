@@ -0,0 +1,154 @@
+use super::{dir_ctx, open_at_root, path_stat_ino_dev, put_iov, put_path, stat_following, TestDir};
+use crate::os::{trace_fstatat, trace_openat};
+use crate::tcb::path::rebase_path;
+use crate::types::{PathResolution, RuntimeError, VmCtx, RIGHTS_ALL, RIGHTS_FD_READ};
+use crate::wrappers::{wasi_fd_read, wasi_path_filestat_get};
+use std::fs;
+use std::os::unix::fs::symlink;
+
+// A sandbox with `dir` preopened as sandbox fd 3 that resolves paths with `mode`, or None
+// if the host can't, in which case the sandbox must have fallen back to Expand
+fn mode_ctx(dir: &TestDir, mode: PathResolution) -> Option<VmCtx> {
+    let mut ctx = dir_ctx(dir);
+    let selected = ctx.set_path_resolution(mode);
+    if selected != mode {
+        assert_eq!(
+            (mode, selected),
+            (PathResolution::Beneath, PathResolution::Expand)
+        );
+        return None;
+    }
+    assert_eq!(ctx.path_resolution, mode);
+    Some(ctx)
+}
+
+#[test]
+fn test_path_open_under_each_mode() {
+    for mode in [
+        PathResolution::Expand,
+        PathResolution::Walk,
+        PathResolution::Beneath,
+    ] {
+        let dir = TestDir::new(&format!("modes_{:?}", mode));
+        let outside = TestDir::new(&format!("modes_outside_{:?}", mode));
+        symlink("sub", dir.join("link")).unwrap();
+        symlink(&outside, dir.join("out")).unwrap();
+        let mut ctx = match mode_ctx(&dir, mode) {
+            Some(ctx) => ctx,
+            None => continue,
+        };
+
+        let iov = put_iov(&mut ctx, &[0; 8]);
+        let fd = open_at_root(&mut ctx, b"link/file", 0, RIGHTS_FD_READ).unwrap();
+        assert_eq!(wasi_fd_read(&mut ctx, fd, iov, 1).unwrap(), 8);
+        assert_eq!(&ctx.mem[4096..4104], b"contents");
+        // O_CREAT
+        open_at_root(&mut ctx, b"link/new", 1, RIGHTS_ALL).unwrap();
+        assert!(dir.join("sub/new").exists());
+
+        for path in [&b"out/file"[..], b"../file", b"/etc/passwd"] {
+            assert!(
+                open_at_root(&mut ctx, path, 0, RIGHTS_FD_READ).is_err(),
+                "{:?} opened {:?}",
+                mode,
+                String::from_utf8_lossy(path)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_symlink_swap_race() {
+    for mode in [
+        PathResolution::Expand,
+        PathResolution::Walk,
+        PathResolution::Beneath,
+    ] {
+        let dir = TestDir::new(&format!("swap_{:?}", mode));
+        let outside = TestDir::new(&format!("swap_outside_{:?}", mode));
+        let mut ctx = match mode_ctx(&dir, mode) {
+            Some(ctx) => ctx,
+            None => continue,
+        };
+
+        // a host process swaps sub for a link out of the preopen after the path was
+        // resolved, but before the syscall is made with it
+        let len = put_path(&mut ctx, 0, b"sub/file");
+        let host_path = ctx.translate_path(0, len, true, 3).unwrap();
+        fs::rename(dir.join("sub"), dir.join("old")).unwrap();
+        symlink(outside.join("sub"), dir.join("sub")).unwrap();
+
+        let dirfd = ctx.translate_dir_fd(3).unwrap();
+        let opened = trace_openat(&ctx, dirfd, host_path, libc::O_RDONLY);
+        // only resolving the whole path at once lets the swap through
+        assert_eq!(opened.is_ok(), mode == PathResolution::Expand, "{:?}", mode);
+        // and the same goes for the calls that don't open the file
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let statted = trace_fstatat(&ctx, dirfd, host_path, &mut stat, 0);
+        assert_eq!(
+            statted.is_ok(),
+            mode == PathResolution::Expand,
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn test_rebase_path() {
+    let base = b"sub".to_vec();
+    assert_eq!(
+        rebase_path(b"file".to_vec(), &Vec::new(), false).unwrap(),
+        b"file"
+    );
+    assert_eq!(
+        rebase_path(b"file".to_vec(), &base, false).unwrap(),
+        b"sub/file"
+    );
+    // `..` is kept for resolve_path to check against the preopen root
+    assert_eq!(
+        rebase_path(b"../x".to_vec(), &base, false).unwrap(),
+        b"sub/../x"
+    );
+    assert!(matches!(
+        rebase_path(Vec::new(), &base, false),
+        Err(RuntimeError::Enotcapable)
+    ));
+    // and so are absolute paths
+    assert!(matches!(
+        rebase_path(b"/etc/passwd".to_vec(), &base, false),
+        Err(RuntimeError::Enotcapable)
+    ));
+}
+
+#[test]
+fn test_virtual_root() {
+    let dir = TestDir::new("virtual_root");
+    symlink("/sub/file", dir.join("sub/absolute")).unwrap();
+    let mut ctx = dir_ctx(&dir);
+    let (ino, _) = path_stat_ino_dev(&mut ctx, 3, b"sub/file");
+
+    assert!(matches!(
+        stat_following(&mut ctx, b"/sub/file"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(stat_following(&mut ctx, b"sub/absolute").is_err());
+
+    // `/` is the preopen root, which `..` can't climb above
+    assert_eq!(
+        rebase_path(b"/sub/file".to_vec(), &b"sub".to_vec(), true).unwrap(),
+        b"/sub/file"
+    );
+    ctx.virtual_root = true;
+    assert_eq!(stat_following(&mut ctx, b"/sub/file").unwrap(), ino);
+    assert_eq!(stat_following(&mut ctx, b"../../sub/file").unwrap(), ino);
+    assert_eq!(stat_following(&mut ctx, b"sub/absolute").unwrap(), ino);
+    let sub = open_at_root(&mut ctx, b"sub", 1 << 1, RIGHTS_ALL).unwrap();
+    let len = put_path(&mut ctx, 0, b"/sub/file");
+    assert_eq!(
+        wasi_path_filestat_get(&mut ctx, sub, 1, 0, len)
+            .unwrap()
+            .ino,
+        ino
+    );
+}
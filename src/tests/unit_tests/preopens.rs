@@ -0,0 +1,47 @@
+use super::{open_at_root, TestDir};
+use crate::runtime::{fresh_ctx, init_preopens};
+use crate::types::{FdMap, RuntimeError, DEFAULT_SBOX_FDS, RIGHTS_FD_READ};
+use crate::wrappers::wasi_fd_close;
+
+#[test]
+fn test_preopen_failures_reported() {
+    let dir = TestDir::new("preopen_failures");
+    let missing = dir.join("missing").to_str().unwrap().to_owned();
+    let dirs = vec![(missing, "missing".to_owned())];
+    let mut fdmap = FdMap::new(DEFAULT_SBOX_FDS);
+    assert!(matches!(
+        init_preopens(&mut fdmap, &dirs, &Vec::new(), &Vec::new(), &Vec::new()),
+        Err(RuntimeError::Enoent)
+    ));
+}
+
+#[test]
+fn test_fd_table_limit() {
+    let dir = TestDir::new("fd_table_limit");
+    let dirs = vec![(dir.name(), "dir".to_owned())];
+    // a preopen that doesn't fit in the fd table fails rather than going missing
+    let mut fdmap = FdMap::new(3);
+    fdmap.init_std_fds().unwrap();
+    assert!(matches!(
+        init_preopens(&mut fdmap, &dirs, &Vec::new(), &Vec::new(), &Vec::new()),
+        Err(RuntimeError::Emfile)
+    ));
+
+    // the table grows up to its limit, and closed fds are handed out again
+    let mut ctx = fresh_ctx(Vec::new());
+    ctx.fdmap = FdMap::new(5);
+    ctx.fdmap.init_std_fds().unwrap();
+    ctx.preopens =
+        init_preopens(&mut ctx.fdmap, &dirs, &Vec::new(), &Vec::new(), &Vec::new()).unwrap();
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ).unwrap();
+    assert_eq!(fd, 4);
+    assert!(matches!(
+        open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ),
+        Err(RuntimeError::Emfile)
+    ));
+    wasi_fd_close(&mut ctx, fd).unwrap();
+    assert_eq!(
+        open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ).unwrap(),
+        4
+    );
+}
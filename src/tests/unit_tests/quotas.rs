@@ -0,0 +1,89 @@
+use super::sockets::{bound_socket, local_port, loopback};
+use super::{dir_ctx, open_at_root, put_iov, put_path, TestDir};
+use crate::runtime::fresh_ctx;
+use crate::types::{QuotaKind, RuntimeError, RIGHTS_ALL};
+use crate::wrappers::{
+    wasi_fd_close, wasi_fd_renumber, wasi_fd_write, wasi_path_unlink_file, wasi_sock_accept,
+    wasi_sock_listen, wasi_sock_send,
+};
+use std::io::Read;
+use std::net::TcpStream;
+
+#[test]
+fn test_unlink_reclaims_on_last_close() {
+    let dir = TestDir::new("unlink_open");
+    let mut ctx = dir_ctx(&dir);
+    ctx.quotas.disk_bytes = Some(100);
+
+    // O_CREAT | O_RDWR
+    let fd = open_at_root(&mut ctx, b"new", 1 | 1 << 5, RIGHTS_ALL).unwrap();
+    let other = open_at_root(&mut ctx, b"new", 1 << 5, RIGHTS_ALL).unwrap();
+    let spare = open_at_root(&mut ctx, b"new", 1 << 5, RIGHTS_ALL).unwrap();
+    let iov = put_iov(&mut ctx, b"0123456789");
+    assert_eq!(wasi_fd_write(&mut ctx, fd, iov, 1).unwrap(), 10);
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 10);
+
+    // the guest can still write to it, so it holds on to its space
+    let len = put_path(&mut ctx, 0, b"new");
+    wasi_path_unlink_file(&mut ctx, 3, 0, len).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 10);
+    wasi_fd_close(&mut ctx, fd).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 10);
+    // renumbering over another fd to the file leaves one fd to it
+    wasi_fd_renumber(&mut ctx, other, spare).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 10);
+    wasi_fd_close(&mut ctx, spare).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::DiskBytes), 0);
+}
+
+#[test]
+fn test_files_created_only_for_new_files() {
+    let dir = TestDir::new("files_created");
+    let mut ctx = dir_ctx(&dir);
+    ctx.quotas.files_created = Some(1);
+
+    // O_CREAT
+    open_at_root(&mut ctx, b"sub/file", 1, RIGHTS_ALL).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::FilesCreated), 0);
+    open_at_root(&mut ctx, b"new", 1, RIGHTS_ALL).unwrap();
+    open_at_root(&mut ctx, b"new", 1, RIGHTS_ALL).unwrap();
+    assert_eq!(ctx.usage.used(QuotaKind::FilesCreated), 1);
+    // files that exist can still be opened once the quota is used up
+    assert!(matches!(
+        open_at_root(&mut ctx, b"other", 1, RIGHTS_ALL),
+        Err(RuntimeError::Edquot)
+    ));
+    open_at_root(&mut ctx, b"sub/file", 1, RIGHTS_ALL).unwrap();
+    // O_CREAT | O_EXCL
+    ctx.quotas.files_created = Some(2);
+    assert!(matches!(
+        open_at_root(&mut ctx, b"new", 1 | 1 << 2, RIGHTS_ALL),
+        Err(RuntimeError::Eexist)
+    ));
+    assert_eq!(ctx.usage.used(QuotaKind::FilesCreated), 1);
+}
+
+#[test]
+fn test_sock_send_over_quota_refused_whole() {
+    let mut ctx = fresh_ctx(Vec::new());
+    ctx.inbound.bindlist.push(loopback());
+    ctx.inbound.peerlist.push(loopback());
+    let server = bound_socket(&mut ctx).unwrap();
+    wasi_sock_listen(&mut ctx, server, 1).unwrap();
+    let mut client = TcpStream::connect(("127.0.0.1", local_port(&ctx, server))).unwrap();
+    let conn = wasi_sock_accept(&mut ctx, server, 0).unwrap();
+    ctx.quotas.bytes_written = Some(6);
+
+    // two 4 byte buffers, like fd_write nothing is sent if they don't both fit
+    let iov = put_iov(&mut ctx, b"abcd");
+    ctx.mem.copy_within(1024..1032, 1032);
+    assert!(matches!(
+        wasi_sock_send(&mut ctx, conn, iov, 2, 0),
+        Err(RuntimeError::Edquot)
+    ));
+    assert_eq!(ctx.usage.used(QuotaKind::BytesWritten), 0);
+    assert_eq!(wasi_sock_send(&mut ctx, conn, iov, 1, 0).unwrap(), 4);
+    let mut buf = [0; 8];
+    assert_eq!(client.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"abcd");
+}
@@ -0,0 +1,74 @@
+use super::{open_at_root, TestDir};
+use crate::runtime::fresh_ctx;
+use crate::types::{
+    FdRights, RuntimeError, RIGHTS_ALL, RIGHTS_FD_READ, RIGHTS_FD_TELL, RIGHTS_FD_WRITE,
+    RIGHTS_POLL_FD_READWRITE,
+};
+use crate::wrappers::{wasi_fd_fdstat_set_rights, wasi_fd_seek, wasi_poll_oneoff};
+
+#[test]
+fn test_tell_and_poll_rights() {
+    let dir = TestDir::new("tell_poll");
+    let mut ctx = fresh_ctx(vec![(dir.name(), "dir".to_owned())]);
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_TELL).unwrap();
+    // only a zero SEEK_CUR is a tell
+    assert_eq!(wasi_fd_seek(&ctx, fd, 0, 1).unwrap(), 0);
+    for (delta, whence) in [(0, 0), (0, 2), (1, 1)] {
+        assert!(matches!(
+            wasi_fd_seek(&ctx, fd, delta, whence),
+            Err(RuntimeError::Enotcapable)
+        ));
+    }
+
+    // a single fd_read subscription on `fd`
+    ctx.mem[0..48].fill(0);
+    ctx.mem[8] = 1;
+    ctx.mem[16..20].copy_from_slice(&fd.to_le_bytes());
+    assert!(matches!(
+        wasi_poll_oneoff(&mut ctx, 0, 512, 1),
+        Err(RuntimeError::Enotcapable)
+    ));
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_POLL_FD_READWRITE).unwrap();
+    ctx.mem[16..20].copy_from_slice(&fd.to_le_bytes());
+    assert!(!matches!(
+        wasi_poll_oneoff(&mut ctx, 0, 512, 1),
+        Err(RuntimeError::Enotcapable)
+    ));
+}
+
+#[test]
+fn test_check_and_narrow_rights() {
+    let dir = TestDir::new("rights");
+    let mut ctx = fresh_ctx(vec![(dir.name(), "dir".to_owned())]);
+    let fd = open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ | RIGHTS_FD_TELL).unwrap();
+    ctx.fdmap.check_rights(fd, RIGHTS_FD_READ).unwrap();
+    ctx.fdmap.check_rights(fd, 0).unwrap();
+    assert!(matches!(
+        ctx.fdmap.check_rights(fd, RIGHTS_FD_READ | RIGHTS_FD_WRITE),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(matches!(
+        ctx.fdmap.check_rights(fd + 1, 0),
+        Err(RuntimeError::Ebadf)
+    ));
+
+    // rights can be dropped but never regained
+    wasi_fd_fdstat_set_rights(&mut ctx, fd, RIGHTS_FD_READ, 0).unwrap();
+    assert!(matches!(
+        ctx.fdmap.check_rights(fd, RIGHTS_FD_TELL),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(matches!(
+        wasi_fd_fdstat_set_rights(&mut ctx, fd, RIGHTS_FD_READ | RIGHTS_FD_TELL, 0),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert_eq!(ctx.fdmap.fd_to_rights(fd), FdRights::new(RIGHTS_FD_READ, 0));
+
+    // and what a directory opens is bounded by what it lets its children inherit
+    wasi_fd_fdstat_set_rights(&mut ctx, 3, RIGHTS_ALL, RIGHTS_FD_READ).unwrap();
+    assert!(matches!(
+        open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ | RIGHTS_FD_WRITE),
+        Err(RuntimeError::Enotcapable)
+    ));
+    open_at_root(&mut ctx, b"sub/file", 0, RIGHTS_FD_READ).unwrap();
+}
@@ -0,0 +1,123 @@
+use crate::runtime::fresh_ctx;
+use crate::types::{
+    ipv4_mapped, NetEndpoint, RuntimeError, RuntimeResult, VmCtx, WasiProto, RIGHTS_ALL,
+    RIGHTS_FD_FDSTAT_SET_FLAGS, RIGHTS_FD_FILESTAT_GET, RIGHTS_SOCK_LISTEN,
+};
+use crate::wrappers::{
+    wasi_fd_fdstat_set_rights, wasi_sock_accept, wasi_sock_bind, wasi_sock_getsockopt,
+    wasi_sock_listen, wasi_sock_recv_from, wasi_sock_setsockopt, wasi_socket,
+};
+use std::net::TcpStream;
+
+// 127.0.0.1/32, on any port
+pub(super) fn loopback() -> NetEndpoint {
+    NetEndpoint {
+        protocol: WasiProto::Tcp,
+        addr: ipv4_mapped(0x7f00_0001),
+        prefix_len: 128,
+        port_lo: 0,
+        port_hi: u16::MAX as u32,
+    }
+}
+
+// A tcp socket bound to 127.0.0.1 on an ephemeral port, using sandbox memory at 0
+pub(super) fn bound_socket(ctx: &mut VmCtx) -> RuntimeResult<u32> {
+    let sockfd = wasi_socket(ctx, 1, 6, 0)?;
+    // the guest's sockaddr_in: family, then the port and address in network order
+    ctx.mem[0..16].fill(0);
+    ctx.mem[0..2].copy_from_slice(&1u16.to_le_bytes());
+    ctx.mem[4..8].copy_from_slice(&[127, 0, 0, 1]);
+    wasi_sock_bind(ctx, sockfd, 0, 16)?;
+    Ok(sockfd)
+}
+
+pub(super) fn local_port(ctx: &VmCtx, sockfd: u32) -> u16 {
+    let fd = ctx.fdmap.fd_to_native(sockfd).unwrap().to_raw() as i32;
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let res =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    assert_eq!(res, 0);
+    u16::from_be(addr.sin_port)
+}
+
+#[test]
+fn test_listen_needs_bind() {
+    let mut ctx = fresh_ctx(Vec::new());
+    // nothing may be bound by default
+    assert!(matches!(
+        bound_socket(&mut ctx),
+        Err(RuntimeError::Enotcapable)
+    ));
+
+    ctx.inbound.bindlist.push(loopback());
+    let unbound = wasi_socket(&mut ctx, 1, 6, 0).unwrap();
+    assert!(matches!(
+        wasi_sock_listen(&mut ctx, unbound, 1),
+        Err(RuntimeError::Enotcapable)
+    ));
+    let bound = bound_socket(&mut ctx).unwrap();
+    wasi_sock_listen(&mut ctx, bound, 1).unwrap();
+}
+
+#[test]
+fn test_accept_checks_peers() {
+    let mut ctx = fresh_ctx(Vec::new());
+    ctx.inbound.bindlist.push(loopback());
+    let server = bound_socket(&mut ctx).unwrap();
+    wasi_sock_listen(&mut ctx, server, 2).unwrap();
+    let port = local_port(&ctx, server);
+
+    // no peers are accepted by default
+    let _refused = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(matches!(
+        wasi_sock_accept(&mut ctx, server, 0),
+        Err(RuntimeError::Econnaborted)
+    ));
+
+    ctx.inbound.peerlist.push(loopback());
+    let _accepted = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let conn = wasi_sock_accept(&mut ctx, server, 0).unwrap();
+    // the connection can't be turned into another server
+    assert!(matches!(
+        ctx.fdmap.check_rights(conn, RIGHTS_SOCK_LISTEN),
+        Err(RuntimeError::Enotcapable)
+    ));
+}
+
+#[test]
+fn test_recv_from_needs_room_for_v6() {
+    let mut ctx = fresh_ctx(Vec::new());
+    let sockfd = wasi_socket(&mut ctx, 1, 5, 0).unwrap();
+    // a sockaddr_in fits, but the datagram could come from an ipv6 peer
+    assert!(matches!(
+        wasi_sock_recv_from(&mut ctx, sockfd, 0, 64, 0, 64, 16),
+        Err(RuntimeError::Einval)
+    ));
+}
+
+#[test]
+fn test_sockopts_check_rights() {
+    let mut ctx = fresh_ctx(Vec::new());
+    let sockfd = wasi_socket(&mut ctx, 1, 6, 0).unwrap();
+    // SO_KEEPALIVE
+    ctx.mem[0..4].copy_from_slice(&1u32.to_le_bytes());
+    wasi_sock_setsockopt(&mut ctx, sockfd, 1, 0, 4).unwrap();
+    assert_eq!(wasi_sock_getsockopt(&mut ctx, sockfd, 1, 0, 4).unwrap(), 4);
+    // buffers are bounded below i32::MAX, like the host's socklen_t
+    assert!(matches!(
+        wasi_sock_getsockopt(&mut ctx, sockfd, 1, 0, u32::MAX),
+        Err(RuntimeError::Einval)
+    ));
+
+    let rights = RIGHTS_ALL ^ RIGHTS_FD_FDSTAT_SET_FLAGS ^ RIGHTS_FD_FILESTAT_GET;
+    wasi_fd_fdstat_set_rights(&mut ctx, sockfd, rights, rights).unwrap();
+    assert!(matches!(
+        wasi_sock_setsockopt(&mut ctx, sockfd, 1, 0, 4),
+        Err(RuntimeError::Enotcapable)
+    ));
+    assert!(matches!(
+        wasi_sock_getsockopt(&mut ctx, sockfd, 1, 0, 4),
+        Err(RuntimeError::Enotcapable)
+    ));
+}
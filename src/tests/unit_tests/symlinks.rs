@@ -0,0 +1,74 @@
+use super::{dir_ctx, put_path, readlink, stat_following, TestDir};
+use crate::types::{RuntimeError, RuntimeResult, VmCtx};
+use crate::wrappers::wasi_path_symlink;
+use std::os::unix::fs::symlink;
+
+#[test]
+fn test_symlink_hop_limit() {
+    let dir = TestDir::new("symlink_hops");
+    // l<n> gets to sub/file through n + 1 symlinks
+    symlink("sub/file", dir.join("l0")).unwrap();
+    for n in 1..45 {
+        symlink(format!("l{}", n - 1), dir.join(format!("l{}", n))).unwrap();
+    }
+    let mut ctx = dir_ctx(&dir);
+
+    // a path fails once it reaches max_hops symlinks
+    assert!(stat_following(&mut ctx, b"l8").is_ok());
+    assert!(matches!(
+        stat_following(&mut ctx, b"l9"),
+        Err(RuntimeError::Eloop)
+    ));
+    // limits past what Linux allows are held to it
+    ctx.symlinks.max_hops = 1000;
+    assert!(stat_following(&mut ctx, b"l38").is_ok());
+    assert!(matches!(
+        stat_following(&mut ctx, b"l39"),
+        Err(RuntimeError::Eloop)
+    ));
+    // and ones below 1 to 1, which lets no symlinks through
+    ctx.symlinks.max_hops = 0;
+    assert!(stat_following(&mut ctx, b"sub/file").is_ok());
+    assert!(matches!(
+        stat_following(&mut ctx, b"l0"),
+        Err(RuntimeError::Eloop)
+    ));
+}
+
+// Make `link` under sandbox fd 3 a symlink to `target`
+fn make_symlink(ctx: &mut VmCtx, target: &[u8], link: &[u8]) -> RuntimeResult<()> {
+    let target_len = put_path(ctx, 0, target);
+    let link_len = put_path(ctx, 512, link);
+    wasi_path_symlink(ctx, 0, target_len, 3, 512, link_len)
+}
+
+#[test]
+fn test_symlink_follow_and_create() {
+    let dir = TestDir::new("symlink_policy");
+    symlink("sub/file", dir.join("link")).unwrap();
+    let mut ctx = dir_ctx(&dir);
+
+    ctx.symlinks.follow = false;
+    assert!(matches!(
+        stat_following(&mut ctx, b"link"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    // a link the path ends at can still be looked at without following it
+    assert_eq!(readlink(&mut ctx, b"link").unwrap(), b"sub/file");
+    ctx.symlinks.follow = true;
+    assert!(stat_following(&mut ctx, b"link").is_ok());
+
+    ctx.symlinks.create = false;
+    assert!(matches!(
+        make_symlink(&mut ctx, b"sub/file", b"new"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    ctx.symlinks.create = true;
+    ctx.symlinks.contained_targets = true;
+    assert!(matches!(
+        make_symlink(&mut ctx, b"../../outside", b"sub/escaping"),
+        Err(RuntimeError::Enotcapable)
+    ));
+    make_symlink(&mut ctx, b"sub/file", b"new").unwrap();
+    assert_eq!(readlink(&mut ctx, b"new").unwrap(), b"sub/file");
+}
@@ -1,9 +1,11 @@
 use crate::effects;
+use crate::tcb::memfs::MemFs;
 use crate::tcb::misc::*;
 use crate::tcb::path::addr_matches_netlist_entry;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::*;
 use prusti_contracts::*;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ops::Sub;
 use wave_macros::{external_calls, external_methods, with_ghost_var};
@@ -30,14 +32,20 @@ pub use platform::*;
 
 
 
-pub const MAX_SBOX_FDS: u32 = 8; // up to 16 or 32?
+// Hard cap on the size of any sandbox's fd table. The limit for a particular
+// sandbox is set when its FdMap is created and may not exceed this.
+pub const MAX_SBOX_FDS: u32 = 1 << 16;
+pub const DEFAULT_SBOX_FDS: u32 = 1024;
 pub const MAX_HOST_FDS: usize = 1024;
 pub const PATH_MAX: usize = 4096;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const LINEAR_MEM_SIZE: usize = 4294965096; //4GB
 
-pub const HOMEDIR_FD: SboxFd = 3; //4GB
+// the sizes of the sockaddr_in and sockaddr_in6 layouts guest addresses are read and
+// written in (see `read_sockaddr`)
+pub const SBOX_SOCKADDR_IN_LEN: u32 = 16;
+pub const SBOX_SOCKADDR_IN6_LEN: u32 = 28;
 
 // Note: prusti does not like derive(Debug)
 
@@ -301,13 +309,423 @@ impl RuntimeError {
 #[repr(transparent)]
 pub struct SyscallRet(usize);
 
+/// Where an fd opened through path_open lives: the host fd of the preopen it was
+/// opened under, and its (symlink-free) path relative to that preopen's root.
+/// `rel_path` is the path the fd was opened with, not looked up again: once the fd's
+/// directory or one of its ancestors is renamed, paths relative to the fd resolve
+/// against where it used to be.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct PathPos {
+    pub preopen_fd: HostFd,
+    pub rel_path: Vec<u8>,
+}
+
 #[derive(PartialEq, Eq)]
 #[cfg_attr(not(feature = "verify"), derive(Debug))]
 pub struct FdMap {
     pub m: Vec<RuntimeResult<HostFd>>,
     pub sockinfo: Vec<RuntimeResult<WasiProto>>,
+    pub pathinfo: Vec<RuntimeResult<PathPos>>,
+    pub rights: Vec<FdRights>,
+    // Sockets bound through sock_bind, the only ones sock_listen allows
+    pub bound: Vec<bool>,
+    // (st_dev, st_ino) of the regular file behind each fd, recorded while the sandbox
+    // has a disk quota (see quota.rs), and how many fds refer to each of those files
+    pub inodes: Vec<Option<(u64, u64)>>,
+    pub open_inodes: HashMap<(u64, u64), usize>,
     pub reserve: Vec<SboxFd>,
     pub counter: SboxFd,
+    // The table grows on demand up to this many entries
+    pub max_fds: SboxFd,
+}
+
+/// A host directory exposed to the sandbox under `guest_name`
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct Preopen {
+    pub sbox_fd: SboxFd,
+    pub host_fd: HostFd,
+    pub guest_name: String,
+    pub mode: AccessMode,
+    // (path relative to the preopen's root, mode) pairs overriding `mode` below that path
+    pub subtrees: Vec<(Vec<u8>, AccessMode)>,
+    // for copy-on-write preopens, the directory changes go to (see tcb/overlay.rs)
+    pub upper: Option<HostFd>,
+    // for preopens backed by an in-memory filesystem instead of a host directory
+    pub mem: Option<MemFs>,
+    // the absolute path of the host directory, to recognize links into it by
+    pub host_path: Option<Vec<u8>>,
+}
+
+/// A difference between a copy-on-write preopen and the directory it overlays.
+/// Paths are relative to the preopen's root.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum OverlayChange {
+    Added(Vec<u8>),
+    Modified(Vec<u8>),
+    Deleted(Vec<u8>),
+}
+
+/// What the guest may do with the files under a preopen or one of its subtrees
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum AccessMode {
+    ReadWrite,
+    /// Anything that would change what is on disk fails with EROFS
+    ReadOnly,
+    /// Every access fails with ENOTCAPABLE
+    Hidden,
+}
+
+/// Sets the access mode of the preopen named `preopen` (its guest name), or of the
+/// subtree at `subtree` under it. An empty `subtree` means the whole preopen.
+/// The longest matching subtree wins, so e.g. `.git` can be hidden inside a
+/// read-only dataset.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct AccessRule {
+    pub preopen: String,
+    pub subtree: Vec<u8>,
+    pub mode: AccessMode,
+}
+
+/// How a sandboxed program finished
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum RunOutcome {
+    /// The guest called proc_exit with this status, or `_start` returned (status 0)
+    Exited(u32),
+    /// The guest trapped
+    Trap(TrapKind),
+    /// The runtime itself failed, e.g. stopped the guest without saying why
+    HostFailure(String),
+    /// The guest was denied by the sandbox policy and then exited with a failing `status`
+    PolicyViolation { violation: Violation, status: u32 },
+    /// The guest raised this (wasi) signal under `SignalPolicy::Terminate`
+    Signaled(u32),
+    /// The sandbox ran past its deadline
+    TimedOut,
+    /// The sandbox was stopped through its `CancelHandle`
+    Cancelled,
+    /// The sandbox ran out of a quota (see `Quotas::terminate`)
+    QuotaExceeded(QuotaKind),
+}
+
+impl RunOutcome {
+    /// The status the embedding process should exit with. Traps and host failures
+    /// are reported the way a shell reports a process killed by SIGABRT.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunOutcome::Exited(status) => *status as i32,
+            RunOutcome::PolicyViolation { status, .. } => *status as i32,
+            RunOutcome::Trap(_) | RunOutcome::HostFailure(_) => 128 + libc::SIGABRT,
+            RunOutcome::Signaled(signal) => 128 + *signal as i32,
+            // as reported by coreutils' timeout
+            RunOutcome::TimedOut => 124,
+            RunOutcome::Cancelled => 128 + libc::SIGTERM,
+            // as if the process had gone over its RLIMIT_CPU or RLIMIT_FSIZE
+            RunOutcome::QuotaExceeded(QuotaKind::Hostcalls) => 128 + libc::SIGXCPU,
+            RunOutcome::QuotaExceeded(_) => 128 + libc::SIGXFSZ,
+        }
+    }
+}
+
+/// Wasm trap kinds, numbered as wasm2c's `wasm_rt_trap_t`
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum TrapKind {
+    OutOfBounds,
+    IntOverflow,
+    DivByZero,
+    InvalidConversion,
+    Unreachable,
+    CallIndirect,
+    StackExhaustion,
+    Unknown(u32),
+}
+
+impl From<u32> for TrapKind {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => TrapKind::OutOfBounds,
+            2 => TrapKind::IntOverflow,
+            3 => TrapKind::DivByZero,
+            4 => TrapKind::InvalidConversion,
+            5 => TrapKind::Unreachable,
+            6 => TrapKind::CallIndirect,
+            7 => TrapKind::StackExhaustion,
+            _ => TrapKind::Unknown(code),
+        }
+    }
+}
+
+/// Why a sandbox is being stopped early
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum CancelReason {
+    Cancelled,
+    TimedOut,
+}
+
+impl From<CancelReason> for RunOutcome {
+    fn from(reason: CancelReason) -> Self {
+        match reason {
+            CancelReason::Cancelled => RunOutcome::Cancelled,
+            CancelReason::TimedOut => RunOutcome::TimedOut,
+        }
+    }
+}
+
+/// Lets any thread stop a running sandbox. Clones share the same state. The request
+/// is acted on the next time the guest makes a hostcall; embedders that also need to
+/// stop guests that make no hostcalls must interrupt them (see waverunner's --timeout).
+#[derive(Clone)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct CancelHandle {
+    // 0 while running, otherwise 1 + the CancelReason
+    state: std::sync::Arc<std::sync::atomic::AtomicU8>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        CancelHandle {
+            state: std::sync::Arc::new(std::sync::atomic::AtomicU8::new(0)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.request(CancelReason::Cancelled)
+    }
+
+    pub fn time_out(&self) {
+        self.request(CancelReason::TimedOut)
+    }
+
+    // The first request wins
+    fn request(&self, reason: CancelReason) {
+        let v = match reason {
+            CancelReason::Cancelled => 1,
+            CancelReason::TimedOut => 2,
+        };
+        let _ = self.state.compare_exchange(
+            0,
+            v,
+            std::sync::atomic::Ordering::SeqCst,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+    }
+
+    pub fn pending(&self) -> Option<CancelReason> {
+        match self.state.load(std::sync::atomic::Ordering::SeqCst) {
+            0 => None,
+            1 => Some(CancelReason::Cancelled),
+            _ => Some(CancelReason::TimedOut),
+        }
+    }
+}
+
+impl PartialEq for CancelHandle {
+    fn eq(&self, other: &Self) -> bool {
+        std::sync::Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for CancelHandle {}
+
+/// A resource limited by `Quotas`
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum QuotaKind {
+    /// Bytes written through fd_write, fd_pwrite, sock_send and sock_send_to
+    BytesWritten,
+    /// Bytes read through fd_read, fd_pread, sock_recv and sock_recv_from
+    BytesRead,
+    /// Files, directories and links created through the path_* hostcalls
+    FilesCreated,
+    Hostcalls,
+    /// Net growth of the regular files the guest writes to, see `crate::quota`
+    DiskBytes,
+}
+
+/// Per-sandbox resource limits, `None` means unlimited.
+/// Writes and creations that would go over a limit fail with EDQUOT. Reads fail once
+/// the limit has been reached, so the last read may go over it by up to its buffer size.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct Quotas {
+    pub bytes_written: Option<u64>,
+    pub bytes_read: Option<u64>,
+    pub files_created: Option<u64>,
+    pub hostcalls: Option<u64>,
+    pub disk_bytes: Option<u64>,
+    /// Stop the sandbox with `RunOutcome::QuotaExceeded` instead of failing the hostcall.
+    /// Running out of hostcalls always stops it.
+    pub terminate: bool,
+}
+
+impl Quotas {
+    pub fn limit(&self, kind: QuotaKind) -> Option<u64> {
+        match kind {
+            QuotaKind::BytesWritten => self.bytes_written,
+            QuotaKind::BytesRead => self.bytes_read,
+            QuotaKind::FilesCreated => self.files_created,
+            QuotaKind::Hostcalls => self.hostcalls,
+            QuotaKind::DiskBytes => self.disk_bytes,
+        }
+    }
+}
+
+/// How much of each `QuotaKind` a sandbox has used so far
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct Usage {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub files_created: u64,
+    pub hostcalls: u64,
+    pub disk_bytes: u64,
+}
+
+impl Usage {
+    pub fn used(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::BytesWritten => self.bytes_written,
+            QuotaKind::BytesRead => self.bytes_read,
+            QuotaKind::FilesCreated => self.files_created,
+            QuotaKind::Hostcalls => self.hostcalls,
+            QuotaKind::DiskBytes => self.disk_bytes,
+        }
+    }
+
+    pub fn charge(&mut self, kind: QuotaKind, amount: u64) {
+        self.set(kind, self.used(kind).saturating_add(amount));
+    }
+
+    pub fn reclaim(&mut self, kind: QuotaKind, amount: u64) {
+        self.set(kind, self.used(kind).saturating_sub(amount));
+    }
+
+    fn set(&mut self, kind: QuotaKind, used: u64) {
+        match kind {
+            QuotaKind::BytesWritten => self.bytes_written = used,
+            QuotaKind::BytesRead => self.bytes_read = used,
+            QuotaKind::FilesCreated => self.files_created = used,
+            QuotaKind::Hostcalls => self.hostcalls = used,
+            QuotaKind::DiskBytes => self.disk_bytes = used,
+        }
+    }
+}
+
+/// What to do when the guest calls proc_raise. The host process is never signalled.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum SignalPolicy {
+    /// proc_raise returns success and nothing happens
+    Ignore,
+    /// The sandbox is stopped with a `RunOutcome::Signaled`
+    Terminate,
+    /// The (wasi) signal number is passed to the embedder's callback, then the guest continues
+    Callback(extern "C" fn(u32)),
+}
+
+/// How the host paths of guest path accesses are handed to the kernel
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum PathResolution {
+    /// As a whole, relative to the preopen. Symlinks on the path have been expanded
+    /// already, but could have been swapped in since by something outside the sandbox.
+    Expand,
+    /// One directory at a time, so that racing changes on the host can't redirect the
+    /// access outside the preopen (see tcb/walk.rs)
+    Walk,
+    /// By the kernel (openat2 with RESOLVE_BENEATH, Linux 5.6+): files are opened with a
+    /// single openat2, and every other access is made in the directory it names, opened
+    /// the same way (see tcb/walk.rs). The kernel also confirms paths are free of
+    /// symlinks, so that most of them skip the userspace expansion.
+    /// Set with `VmCtx::set_path_resolution`, which falls back to `Expand` without it.
+    Beneath,
+}
+
+/// The most `SymlinkPolicy::max_hops` may be, like Linux's own limit
+pub const MAX_SYMLINK_HOPS: isize = 40;
+
+/// What guest paths may do with symlinks. Paths the policy refuses fail with ENOTCAPABLE.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct SymlinkPolicy {
+    /// A path fails with ELOOP once it has gone through this many symlinks, so the
+    /// default of 10 lets it through 9. Taken to be within 1..=MAX_SYMLINK_HOPS.
+    pub max_hops: isize,
+    /// Whether paths may go through symlinks at all. A symlink the path ends at and
+    /// doesn't follow (e.g. for unlink or readlink) is fine either way.
+    pub follow: bool,
+    /// Whether path_symlink may create symlinks
+    pub create: bool,
+    /// Refuse to create symlinks whose target, as written and read from where the link
+    /// is, leaves the preopen. Following such links is always refused; this keeps them
+    /// from being made for tools on the host to trip over later.
+    pub contained_targets: bool,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        SymlinkPolicy {
+            max_hops: 10,
+            follow: true,
+            create: true,
+            contained_targets: false,
+        }
+    }
+}
+
+impl SymlinkPolicy {
+    /// How many symlinks a path may go through before it fails with ELOOP, counting
+    /// the one it fails at
+    pub fn hop_limit(&self) -> isize {
+        if !self.follow || self.max_hops < 1 {
+            // the first symlink is already too many
+            return 1;
+        }
+        if self.max_hops > MAX_SYMLINK_HOPS {
+            return MAX_SYMLINK_HOPS;
+        }
+        self.max_hops
+    }
+}
+
+/// Keeps host details out of the metadata and link targets the guest can read.
+/// Device ids are replaced with one per preopen (1 for the first, ...), and readlink
+/// rewrites absolute targets into a preopen to relative ones and refuses targets that
+/// lead out of the sandbox.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct InfoHiding {
+    /// Also replace inode numbers (in stats and fd_readdir) with ones made up per sandbox
+    pub remap_inodes: bool,
+    // mixed into the made up inode numbers, so they can't be matched across sandboxes
+    pub(crate) inode_key: u64,
+}
+
+/// A hostcall the sandbox policy refused (the guest saw ENOTCAPABLE)
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub enum Violation {
+    /// A path outside of the preopens, or without the needed rights
+    PathDenied { hostcall: &'static str },
+    /// A connect, bind, accept or send_to not allowed by the netlist or inbound policy
+    NetDenied { hostcall: &'static str },
+}
+
+impl Violation {
+    pub fn path(hostcall: &'static str) -> Self {
+        Violation::PathDenied { hostcall }
+    }
+
+    pub fn net(hostcall: &'static str) -> Self {
+        Violation::NetDenied { hostcall }
+    }
 }
 
 #[derive(PartialEq, Eq)]
@@ -315,8 +733,7 @@ pub struct VmCtx {
     pub mem: Vec<u8>,
     pub memlen: usize,
     pub fdmap: FdMap,
-    pub homedir: String,
-    pub homedir_host_fd: HostFd,
+    pub preopens: Vec<Preopen>,
     // pub errno: RuntimeError,
     pub arg_buffer: Vec<u8>,
     pub argc: usize,
@@ -324,6 +741,27 @@ pub struct VmCtx {
     pub envc: usize,
     // pub log_path: String,
     pub netlist: Netlist,
+    pub inbound: InboundPolicy,
+    // The first hostcall the policy denied, reported in the run's outcome
+    pub violation: Option<Violation>,
+    // Set while the guest runs under `wasm2c_frontend::run_start`: the way back to it,
+    // and why the guest was stopped
+    pub guest_exit: Option<unsafe extern "C" fn(i32)>,
+    pub stop: Option<RunOutcome>,
+    pub signal_policy: SignalPolicy,
+    pub cancel: CancelHandle,
+    // checked on every hostcall, see `CancelHandle`
+    pub deadline: Option<std::time::Instant>,
+    pub quotas: Quotas,
+    pub usage: Usage,
+    // The first quota the sandbox ran out of
+    pub quota_exceeded: Option<QuotaKind>,
+    pub path_resolution: PathResolution,
+    // `/` in guest paths and absolute symlink targets means the preopen root, and `..`
+    // above it stays there, rather than such paths being refused
+    pub virtual_root: bool,
+    pub symlinks: SymlinkPolicy,
+    pub info_hiding: Option<InfoHiding>,
 }
 
 // #[cfg_attr(not(feature = "verify"), derive(Debug))]
@@ -494,6 +932,23 @@ impl From<Timestamp> for libc::timespec {
     }
 }
 
+impl From<libc::timeval> for Timestamp {
+    fn from(tv: libc::timeval) -> Timestamp {
+        Timestamp::from_sec_nsec(tv.tv_sec as u64, tv.tv_usec as u64 * 1000)
+    }
+}
+
+impl From<Timestamp> for libc::timeval {
+    fn from(timestamp: Timestamp) -> Self {
+        let sec = timestamp.0 / 1000000000;
+        let usec = (timestamp.0 % 1000000000) / 1000;
+        libc::timeval {
+            tv_sec: sec as libc::time_t,
+            tv_usec: usec as libc::suseconds_t,
+        }
+    }
+}
+
 impl From<Timestamp> for u64 {
     fn from(timestamp: Timestamp) -> u64 {
         timestamp.0
@@ -560,7 +1015,89 @@ impl From<libc::mode_t> for Filetype {
     }
 }
 
-type Rights = u64;
+pub type Rights = u64;
+
+// See: https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#rights
+pub const RIGHTS_FD_DATASYNC: Rights = 1 << 0;
+pub const RIGHTS_FD_READ: Rights = 1 << 1;
+pub const RIGHTS_FD_SEEK: Rights = 1 << 2;
+pub const RIGHTS_FD_FDSTAT_SET_FLAGS: Rights = 1 << 3;
+pub const RIGHTS_FD_SYNC: Rights = 1 << 4;
+pub const RIGHTS_FD_TELL: Rights = 1 << 5;
+pub const RIGHTS_FD_WRITE: Rights = 1 << 6;
+pub const RIGHTS_FD_ADVISE: Rights = 1 << 7;
+pub const RIGHTS_FD_ALLOCATE: Rights = 1 << 8;
+pub const RIGHTS_PATH_CREATE_DIRECTORY: Rights = 1 << 9;
+pub const RIGHTS_PATH_CREATE_FILE: Rights = 1 << 10;
+pub const RIGHTS_PATH_LINK_SOURCE: Rights = 1 << 11;
+pub const RIGHTS_PATH_LINK_TARGET: Rights = 1 << 12;
+pub const RIGHTS_PATH_OPEN: Rights = 1 << 13;
+pub const RIGHTS_FD_READDIR: Rights = 1 << 14;
+pub const RIGHTS_PATH_READLINK: Rights = 1 << 15;
+pub const RIGHTS_PATH_RENAME_SOURCE: Rights = 1 << 16;
+pub const RIGHTS_PATH_RENAME_TARGET: Rights = 1 << 17;
+pub const RIGHTS_PATH_FILESTAT_GET: Rights = 1 << 18;
+pub const RIGHTS_PATH_FILESTAT_SET_SIZE: Rights = 1 << 19;
+pub const RIGHTS_PATH_FILESTAT_SET_TIMES: Rights = 1 << 20;
+pub const RIGHTS_FD_FILESTAT_GET: Rights = 1 << 21;
+pub const RIGHTS_FD_FILESTAT_SET_SIZE: Rights = 1 << 22;
+pub const RIGHTS_FD_FILESTAT_SET_TIMES: Rights = 1 << 23;
+pub const RIGHTS_PATH_SYMLINK: Rights = 1 << 24;
+pub const RIGHTS_PATH_REMOVE_DIRECTORY: Rights = 1 << 25;
+pub const RIGHTS_PATH_UNLINK_FILE: Rights = 1 << 26;
+pub const RIGHTS_POLL_FD_READWRITE: Rights = 1 << 27;
+pub const RIGHTS_SOCK_SHUTDOWN: Rights = 1 << 28;
+pub const RIGHTS_SOCK_ACCEPT: Rights = 1 << 29;
+// Not in WASI: wave's own rights for the other calls of a listening socket
+pub const RIGHTS_SOCK_BIND: Rights = 1 << 30;
+pub const RIGHTS_SOCK_LISTEN: Rights = 1 << 31;
+pub const RIGHTS_ALL: Rights = (1 << 32) - 1;
+
+// Rights for serving on a socket, which connections handed out by sock_accept don't get
+pub const RIGHTS_SOCK_SERVE: Rights = RIGHTS_SOCK_BIND | RIGHTS_SOCK_LISTEN | RIGHTS_SOCK_ACCEPT;
+
+// Rights that change a file through the fd itself, rather than through a path
+// (which is checked against the access mode instead)
+pub const RIGHTS_FD_MUTATE: Rights = RIGHTS_FD_WRITE
+    | RIGHTS_FD_ALLOCATE
+    | RIGHTS_FD_FILESTAT_SET_SIZE
+    | RIGHTS_FD_FILESTAT_SET_TIMES;
+
+/// The rights held by a single sandbox fd. `base` gates operations on the fd
+/// itself, `inheriting` bounds the rights of fds opened through it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct FdRights {
+    pub base: Rights,
+    pub inheriting: Rights,
+}
+
+impl FdRights {
+    pub fn new(base: Rights, inheriting: Rights) -> Self {
+        FdRights { base, inheriting }
+    }
+
+    pub fn all() -> Self {
+        FdRights::new(RIGHTS_ALL, RIGHTS_ALL)
+    }
+
+    pub fn empty() -> Self {
+        FdRights::new(0, 0)
+    }
+
+    // these rights, minus anything that could change a file through the fd
+    pub fn read_only(&self) -> Self {
+        FdRights::new(
+            bitwise_and_u64(self.base, RIGHTS_ALL ^ RIGHTS_FD_MUTATE),
+            bitwise_and_u64(self.inheriting, RIGHTS_ALL ^ RIGHTS_FD_MUTATE),
+        )
+    }
+
+    // true if these rights are no stronger than `other`
+    pub fn within(&self, other: &FdRights) -> bool {
+        rights_subset(self.base, other.base) && rights_subset(self.inheriting, other.inheriting)
+    }
+}
 
 // internal representation is the wasi representation
 #[cfg_attr(not(feature = "verify"), derive(Debug))]
@@ -712,6 +1249,19 @@ impl OFlags {
         }
         flags
     }
+
+    pub fn creat(&self) -> bool {
+        nth_bit_set_u32(self.0, 0)
+    }
+
+    pub fn trunc(&self) -> bool {
+        nth_bit_set_u32(self.0, 3)
+    }
+
+    // opened for writing (O_WRONLY or O_RDWR)
+    pub fn writes(&self) -> bool {
+        nth_bit_set_u32(self.0, 4) || nth_bit_set_u32(self.0, 5)
+    }
 }
 
 pub struct FstFlags(u16);
@@ -816,6 +1366,24 @@ impl TryFrom<u32> for RiFlags {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RoFlags(u16);
+
+impl RoFlags {
+    pub fn from_posix(msg_flags: i32) -> Self {
+        // recv_data_truncated is the only roflag
+        if flag_set(msg_flags, libc::MSG_TRUNC) {
+            RoFlags(1)
+        } else {
+            RoFlags(0)
+        }
+    }
+
+    pub fn to_wasi(&self) -> u16 {
+        self.0
+    }
+}
+
 pub struct Subscription {
     pub userdata: u64,
     pub subscription_u: SubscriptionInner,
@@ -1055,6 +1623,9 @@ pub fn sock_domain_to_posix(domain: u32) -> RuntimeResult<i32> {
     if domain == 1 {
         return Ok(libc::AF_INET);
     }
+    if domain == 2 {
+        return Ok(libc::AF_INET6);
+    }
     Err(RuntimeError::Enotsup)
 }
 
@@ -1069,36 +1640,132 @@ pub fn sock_type_to_posix(ty: u32) -> RuntimeResult<i32> {
     Err(RuntimeError::Enotsup)
 }
 
+/// The socket options a guest may get or set; anything else is rejected.
+/// Boolean and size options are a u32 in wasm memory, timeouts a u64 of nanoseconds.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SockOpt {
+    TcpNoDelay,
+    KeepAlive,
+    RecvTimeout,
+    SendTimeout,
+    RecvBufSize,
+    SendBufSize,
+}
+
+impl SockOpt {
+    /// (level, optname) for getsockopt/setsockopt
+    pub fn to_posix(&self) -> (i32, i32) {
+        match self {
+            SockOpt::TcpNoDelay => (libc::IPPROTO_TCP, libc::TCP_NODELAY),
+            SockOpt::KeepAlive => (libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+            SockOpt::RecvTimeout => (libc::SOL_SOCKET, libc::SO_RCVTIMEO),
+            SockOpt::SendTimeout => (libc::SOL_SOCKET, libc::SO_SNDTIMEO),
+            SockOpt::RecvBufSize => (libc::SOL_SOCKET, libc::SO_RCVBUF),
+            SockOpt::SendBufSize => (libc::SOL_SOCKET, libc::SO_SNDBUF),
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, SockOpt::RecvTimeout | SockOpt::SendTimeout)
+    }
+
+    /// Size of the option's value in wasm memory
+    pub fn wasi_len(&self) -> u32 {
+        if self.is_timeout() {
+            8
+        } else {
+            4
+        }
+    }
+
+    pub fn tcp_only(&self) -> bool {
+        matches!(self, SockOpt::TcpNoDelay | SockOpt::KeepAlive)
+    }
+}
+
+impl TryFrom<u32> for SockOpt {
+    type Error = RuntimeError;
+
+    fn try_from(opt: u32) -> RuntimeResult<SockOpt> {
+        match opt {
+            0 => Ok(SockOpt::TcpNoDelay),
+            1 => Ok(SockOpt::KeepAlive),
+            2 => Ok(SockOpt::RecvTimeout),
+            3 => Ok(SockOpt::SendTimeout),
+            4 => Ok(SockOpt::RecvBufSize),
+            5 => Ok(SockOpt::SendBufSize),
+            _ => Err(RuntimeError::Enoprotoopt),
+        }
+    }
+}
+
 // protocol 1 = TCP 2 = UDP
+// Matches any address within `prefix_len` bits of `addr`, on any port in
+// `port_lo..=port_hi`.
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(not(feature = "verify"), derive(Debug))]
-#[repr(C)]
+// addr comes first and the struct is 16-aligned so that the layout matches C's, where
+// unsigned __int128 is 16-aligned even on toolchains whose u128 is only 8-aligned.
+#[repr(C, align(16))]
 pub struct NetEndpoint {
+    /// ipv6 address as a native-endian integer (::1 is 1), not in network byte order.
+    /// ipv4 addresses are stored ipv4-mapped (::ffff:a.b.c.d)
+    pub addr: u128,
     // domain: u32,
     // ty: u32,
     pub protocol: WasiProto,
-    pub addr: u32,
-    pub port: u32,
+    /// 0..=128, so an ipv4 /8 is a prefix of 96 + 8
+    pub prefix_len: u32,
+    pub port_lo: u32,
+    pub port_hi: u32,
 }
 
-pub type Netlist = [NetEndpoint; 4];
+pub type Netlist = Vec<NetEndpoint>;
 
+/// `ip` as an ipv4-mapped ipv6 address
 #[pure]
-pub fn addr_in_netlist(netlist: &Netlist, addr: u32, port: u32) -> bool {
-    if addr_matches_netlist_entry(&netlist, addr, port, 0) {
-        return true;
-    }
-    if addr_matches_netlist_entry(&netlist, addr, port, 1) {
-        return true;
-    }
-    if addr_matches_netlist_entry(&netlist, addr, port, 2) {
-        return true;
-    }
-    if addr_matches_netlist_entry(&netlist, addr, port, 3) {
-        return true;
-    }
+pub fn ipv4_mapped(ip: u32) -> u128 {
+    0xffff_0000_0000 + ip as u128
+}
+
+#[pure]
+pub fn addr_in_netlist(netlist: &Netlist, protocol: WasiProto, addr: u128, port: u32) -> bool {
+    addr_in_netlist_from(netlist, protocol, addr, port, 0)
+}
 
-    false
+// Does any entry at or after `idx` match?
+// Written recursively rather than as a loop so that it can stay pure.
+#[pure]
+#[requires(idx <= netlist.len())]
+pub fn addr_in_netlist_from(
+    netlist: &Netlist,
+    protocol: WasiProto,
+    addr: u128,
+    port: u32,
+    idx: usize,
+) -> bool {
+    if idx >= netlist.len() {
+        return false;
+    }
+    addr_matches_netlist_entry(netlist, protocol, addr, port, idx)
+        || addr_in_netlist_from(netlist, protocol, addr, port, idx + 1)
+}
+
+/// Policy for sockets the sandbox serves on. `bindlist` holds the local
+/// endpoints the guest may bind. If `restrict_peers` is set, incoming
+/// connections are only accepted from peers matching `peerlist`, so an empty
+/// peerlist refuses every accept. `empty_inbound_policy` sets it.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "verify"), derive(Debug))]
+pub struct InboundPolicy {
+    pub bindlist: Netlist,
+    pub peerlist: Netlist,
+    pub restrict_peers: bool,
+}
+
+#[pure]
+pub fn peer_allowed(policy: &InboundPolicy, protocol: WasiProto, addr: u128, port: u32) -> bool {
+    !policy.restrict_peers || addr_in_netlist(&policy.peerlist, protocol, addr, port)
 }
 
 // Higher level protocols
@@ -1114,9 +1781,10 @@ pub enum WasiProto {
 impl WasiProto {
     // domain and type are enough to identify tcp and udp, the only protocols allowed
     pub fn new(domain: i32, ty: i32, _family: i32) -> Self {
-        if domain as i32 == libc::AF_INET && ty as i32 == libc::SOCK_STREAM {
+        let inet = domain == libc::AF_INET || domain == libc::AF_INET6;
+        if inet && ty as i32 == libc::SOCK_STREAM {
             WasiProto::Tcp
-        } else if domain as i32 == libc::AF_INET && ty as i32 == libc::SOCK_DGRAM {
+        } else if inet && ty as i32 == libc::SOCK_DGRAM {
             WasiProto::Udp
         } else {
             WasiProto::Unknown
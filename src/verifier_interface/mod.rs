@@ -12,6 +12,12 @@ macro_rules! path_effect {
     ($trace:expr, $input:expr) => {};
 }
 
+#[cfg(not(any(feature = "verify", test)))]
+#[macro_export]
+macro_rules! net_effect {
+    ($trace:expr, $input:expr) => {};
+}
+
 // #[cfg(not(feature = "verify"))]
 // #[macro_export]
 // macro_rules! do_effect {
@@ -1,4 +1,7 @@
+use crate::runtime::init_preopens;
 use crate::tcb::ffi::*;
+use crate::tcb::memfs::MemFs;
+use crate::tcb::misc::empty_inbound_policy;
 use crate::types::*;
 use crate::wrappers::*;
 use crate::writeback::*;
@@ -30,26 +33,36 @@ use crate::stats::stats::output_syscall_perf_results;
 
 trace::init_depth_var!();
 
+/// `preopens` is a list of (host directory, guest name) pairs. They are handed to
+/// the sandbox in order, starting at fd 3.
+/// `max_fds` bounds the size of the sandbox's fd table (at most `MAX_SBOX_FDS`).
+/// `signal_policy` decides what the guest's proc_raise does.
+/// `quotas` limits how much the sandbox may do.
+/// `access` makes preopens, or subtrees of them, read-only or hidden.
+/// `overlays` makes preopens copy-on-write, as (guest name, host upper dir) pairs.
+/// `mem_preopens` are in-memory directories handed to the sandbox after `preopens`, as
+/// (guest name, filesystem) pairs. Keep a clone of the filesystem to read it back.
+/// Fails if a preopen can't be set up as asked, e.g. an upper dir can't be opened.
 pub fn create_ctx(
     memptr: *mut u8,
-    homedir: &str,
+    preopens: &Vec<(String, String)>,
+    access: &Vec<AccessRule>,
+    overlays: &Vec<(String, String)>,
+    mem_preopens: &Vec<(String, MemFs)>,
+    max_fds: u32,
     mut arg_buffer: Vec<u8>,
     argc: usize,
     mut env_buffer: Vec<u8>,
     envc: usize,
     netlist: Netlist,
-) -> VmCtx {
+    inbound: InboundPolicy,
+    signal_policy: SignalPolicy,
+    quotas: Quotas,
+) -> RuntimeResult<VmCtx> {
     let memlen = LINEAR_MEM_SIZE;
-    let mut fdmap = FdMap::new();
+    let mut fdmap = FdMap::new(std::cmp::min(max_fds, MAX_SBOX_FDS));
     fdmap.init_std_fds();
-    let homedir_file = std::fs::File::open(homedir).unwrap();
-    let homedir_host_fd = homedir_file.as_raw_fd() as usize;
-    if homedir_host_fd >= 0 {
-        fdmap.create(HostFd::from_raw(homedir_host_fd));
-    }
-    // Need to forget file to make sure it does not get auto-closed
-    // when it gets out of scope
-    std::mem::forget(homedir_file);
+    let preopens = init_preopens(&mut fdmap, preopens, access, overlays, mem_preopens)?;
     // replace all space with null.
     // This makes it easy to return the arg_buffer later
     for i in 0..arg_buffer.len() {
@@ -68,18 +81,31 @@ pub fn create_ctx(
 
     let mem = ffi_load_vec(memptr, memlen);
 
-    VmCtx {
+    Ok(VmCtx {
         mem,
         memlen,
         fdmap,
-        homedir: homedir.to_owned(),
-        homedir_host_fd: HostFd::from_raw(homedir_host_fd),
+        preopens,
         arg_buffer,
         argc,
         env_buffer,
         envc,
         netlist,
-    }
+        inbound,
+        violation: None,
+        guest_exit: None,
+        stop: None,
+        signal_policy,
+        cancel: CancelHandle::new(),
+        deadline: None,
+        quotas,
+        usage: Usage::default(),
+        quota_exceeded: None,
+        path_resolution: PathResolution::Expand,
+        virtual_root: false,
+        symlinks: SymlinkPolicy::default(),
+        info_hiding: None,
+    })
 }
 
 /// Used for FFI. (wasm2c frontend)
@@ -96,18 +122,54 @@ fn ctx_from_memptr(
     env: *mut u8,
     envc: usize,
     log_path: *mut c_char,
-    netlist: *const Netlist,
-) -> VmCtx {
-    let netlist = transmut_netlist(netlist);
+    netlist: *const NetEndpoint,
+    netlist_len: usize,
+) -> RuntimeResult<VmCtx> {
+    let netlist = transmut_netlist(netlist, netlist_len);
     let log_path = ffi_load_cstr(log_path).to_owned().clone();
     let homedir = &(*ffi_load_cstr(homedir).clone()); // Actually copy the inner string
 
+    // the C interface only supports a single preopen, which the guest sees under its host name
+    let preopens = vec![(homedir.to_owned(), homedir.to_owned())];
+
     let arg_buffer = ffi_load_cstr_as_vec(args).clone();
     let env_buffer = ffi_load_cstr_as_vec(env).clone();
 
-    create_ctx(memptr, homedir, arg_buffer, argc, env_buffer, envc, netlist)
+    let mut ctx = create_ctx(
+        memptr,
+        &preopens,
+        &Vec::new(),
+        &Vec::new(),
+        &Vec::new(),
+        DEFAULT_SBOX_FDS,
+        arg_buffer,
+        argc,
+        env_buffer,
+        envc,
+        netlist,
+        // the C interface has no way to pass an inbound policy, so nothing may be served
+        empty_inbound_policy(),
+        SignalPolicy::Ignore,
+        Quotas::default(),
+    )?;
+    // nor to pass options, so the path resolution can be picked from the environment
+    // (e.g. to run the same binaries under each one)
+    let mode = match std::env::var("WAVE_PATH_RESOLUTION").as_deref() {
+        Ok("walk") => PathResolution::Walk,
+        Ok("beneath") => PathResolution::Beneath,
+        _ => PathResolution::Expand,
+    };
+    // a run meant for one mode must not pass silently under another
+    if ctx.set_path_resolution(mode) != mode {
+        eprintln!("wave: openat2 is unavailable, resolving paths with expand instead of beneath");
+    }
+    Ok(ctx)
 }
 
+/// Set up a sandbox for a module whose linear memory is at `memptr`, with `homedir`
+/// preopened as fd 3 and the `netlist_len` endpoints at `netlist` as its netlist.
+/// Returns NULL if the sandbox can't be set up (e.g. `homedir` can't be opened), so
+/// callers must check the result before running the module.
 #[no_mangle]
 #[trace(logging)]
 pub extern "C" fn wave_init(
@@ -119,12 +181,26 @@ pub extern "C" fn wave_init(
     env: *mut u8,
     envc: usize,
     log_path: *mut c_char,
-    netlist: *const Netlist,
+    netlist: *const NetEndpoint,
+    netlist_len: usize,
 ) -> *mut VmCtx {
     env_logger::init(); // removing this line kills tracing
-    let ctx = ctx_from_memptr(
-        memptr, memsize, homedir, args, argc, env, envc, log_path, netlist,
-    );
+    let ctx = match ctx_from_memptr(
+        memptr,
+        memsize,
+        homedir,
+        args,
+        argc,
+        env,
+        envc,
+        log_path,
+        netlist,
+        netlist_len,
+    ) {
+        Ok(ctx) => ctx,
+        // the preopens couldn't be set up, which callers must check for
+        Err(_) => return std::ptr::null_mut(),
+    };
     // convert the ctx into a raw pointer for the runtime
     // must manually destruct later
     Box::into_raw(Box::new(ctx))
@@ -144,7 +220,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_args_getZ_iii(
     argv_buf: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_args_get(ctx_ref, argv, argv_buf);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -160,7 +236,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_args_sizes_getZ_iii(
     pargv_buf_size: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_args_sizes_get(ctx_ref);
     let retval =
         wasm2c_marshal_and_writeback_u32_pair(ctx_ref, pargc as usize, pargv_buf_size as usize, r);
@@ -169,14 +245,103 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_args_sizes_getZ_iii(
     retval
 }
 
-// TODO: this needs to invoke the cleanup function
+pub use crate::tcb::ffi::GuestEntry;
+
+// What `stop_guest` jumps back to `run_start` with, above all of wasm_rt_trap_t's codes
+pub const STOP_CODE: i32 = 0x100;
+
+/// Run the guest's entry point, returning how it finished. proc_exit, terminating
+/// proc_raises, cancellation and quotas longjmp back to here through the wasm2c
+/// runtime's jmp_buf (see `stop_guest`), as the runtime's own wasm_rt_trap does, so
+/// that nothing has to unwind through the guest's frames. A panic in a hostcall can't
+/// unwind out of it either, and aborts the process.
+pub fn run_start(ctx: &mut VmCtx, entry: GuestEntry) -> RunOutcome {
+    ctx.guest_exit = Some(entry.exit);
+    ctx.stop = None;
+    let code = run_guest(entry);
+    ctx.guest_exit = None;
+    let outcome = match code {
+        0 => RunOutcome::Exited(0),
+        // Without a reason the guest was stopped from outside of a hostcall, which
+        // embedders only do to cancel it (e.g. waverunner's timeout signal)
+        STOP_CODE => match (ctx.stop.take(), ctx.cancel.pending()) {
+            (Some(outcome), _) => outcome,
+            (None, Some(reason)) => RunOutcome::from(reason),
+            (None, None) => RunOutcome::HostFailure("guest stopped without a reason".to_string()),
+        },
+        trap => RunOutcome::Trap(TrapKind::from(trap as u32)),
+    };
+    match (outcome, ctx.violation) {
+        (RunOutcome::Exited(status), Some(violation)) if status != 0 => {
+            RunOutcome::PolicyViolation { violation, status }
+        }
+        (outcome, _) => outcome,
+    }
+}
+
+/// Stop the guest with `outcome` by longjmp-ing back to `run_start`. This skips the
+/// frames of the guest and of the hostcall it is in, so callers must not hold anything
+/// that needs dropping. Without a `run_start` to go back to (e.g. under wasm2c-runner),
+/// exit the process with the outcome's status instead.
+fn stop_guest(ctx: &mut VmCtx, outcome: RunOutcome) -> ! {
+    match ctx.guest_exit {
+        Some(exit) => {
+            ctx.stop = Some(outcome);
+            exit_guest(exit, STOP_CODE);
+            // wave_exit doesn't return
+            std::process::abort()
+        }
+        None => std::process::exit(outcome.exit_code()),
+    }
+}
+
+/// Every hostcall starts here. If the sandbox has been cancelled, is past its
+/// deadline or is out of hostcalls, stop the guest (see `stop_guest`) instead of
+/// running the hostcall.
+fn enter_hostcall(ctx: *const *mut VmCtx) -> &'static mut VmCtx {
+    let ctx_ref = ptr_to_ref(ctx);
+    if ctx_ref.check_quota(QuotaKind::Hostcalls, 1).is_err() {
+        stop_guest(ctx_ref, RunOutcome::QuotaExceeded(QuotaKind::Hostcalls));
+    }
+    ctx_ref.charge_quota(QuotaKind::Hostcalls, 1);
+    if let Some(deadline) = ctx_ref.deadline {
+        if Instant::now() >= deadline {
+            ctx_ref.cancel.time_out();
+        }
+    }
+    if let Some(reason) = ctx_ref.cancel.pending() {
+        stop_guest(ctx_ref, RunOutcome::from(reason));
+    }
+    ctx_ref
+}
+
+/// Under `Quotas::terminate`, stop the sandbox once it has run out of a quota
+fn enforce_quotas(ctx: &mut VmCtx) {
+    if ctx.quotas.terminate {
+        if let Some(kind) = ctx.quota_exceeded {
+            stop_guest(ctx, RunOutcome::QuotaExceeded(kind));
+        }
+    }
+}
+
+/// Remember the first hostcall that the sandbox policy refused
+fn record_violation<T>(ctx: &mut VmCtx, r: &RuntimeResult<T>, violation: Violation) {
+    if matches!(r, Err(Enotcapable)) && ctx.violation.is_none() {
+        ctx.violation = Some(violation);
+    }
+}
+
+// Goes back to `run_start` rather than exiting the process, so the embedder can
+// clean up
 #[no_mangle]
 #[trace(logging)]
 pub extern "C" fn Z_wasi_snapshot_preview1Z_proc_exitZ_vi(ctx: *const *mut VmCtx, x: u32) {
-    std::process::exit(x as i32);
-    // let start = start_timer();
-    //let ctx_ref = ptr_to_ref(ctx);
-    // wasi_proc_exit(ctx_ref, x);
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_proc_exit(ctx_ref, x);
+    let end = stop_timer();
+    push_hostcall_result("proc_exit", start, end);
+    stop_guest(ctx_ref, RunOutcome::Exited(x));
 }
 
 #[no_mangle]
@@ -187,7 +352,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_environ_sizes_getZ_iii(
     pbuf_size: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_environ_sizes_get(ctx_ref);
     let retval =
         wasm2c_marshal_and_writeback_u32_pair(ctx_ref, pcount as usize, pbuf_size as usize, r);
@@ -204,7 +369,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_environ_getZ_iii(
     environ_buf: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_environ_get(ctx_ref, __environ, environ_buf);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -221,7 +386,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_prestat_getZ_iii(
 ) -> u32 {
     // Wasm2c implementation
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_prestat_get(ctx_ref, fd);
     let retval = wasm2c_marshal_and_writeback_prestat(ctx_ref, prestat as usize, r);
     let end = stop_timer();
@@ -239,8 +404,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_writeZ_iiiii(
     pnum: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_write(ctx_ref, fd, iov, iovcnt);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, pnum as usize, r);
     let end = stop_timer();
     push_hostcall_result("fd_write", start, end);
@@ -257,8 +423,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_readZ_iiiii(
     pnum: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_read(ctx_ref, fd, iov, iovcnt);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, pnum as usize, r);
     let end = stop_timer();
     push_hostcall_result("fd_read", start, end);
@@ -269,7 +436,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_readZ_iiiii(
 #[trace(logging)]
 pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_closeZ_ii(ctx: *const *mut VmCtx, fd: u32) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_close(ctx_ref, fd);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -287,7 +454,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_seekZ_iijii(
     new_offset: u32, // output
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_seek(ctx_ref, fd, offset as i64, whence);
     let retval = wasm2c_marshal_and_writeback_u64(ctx_ref, new_offset as usize, r);
     let end = stop_timer();
@@ -304,7 +471,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_clock_time_getZ_iiji(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_clock_time_get(ctx_ref, clock_id, 0);
     let retval = wasm2c_marshal_and_writeback_timestamp(ctx_ref, out as usize, r);
     let end = stop_timer();
@@ -320,7 +487,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_clock_res_getZ_iii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_clock_res_get(ctx_ref, clock_id);
     let retval = wasm2c_marshal_and_writeback_timestamp(ctx_ref, out as usize, r);
     let end = stop_timer();
@@ -346,7 +513,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_adviseZ_iijji(
     advice: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_advise(ctx_ref, v_fd, offset, len, advice);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -363,8 +530,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_allocateZ_iijj(
     len: u64,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_allocate(ctx_ref, v_fd, offset, len);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("fd_allocate", start, end);
@@ -378,7 +546,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_datasyncZ_ii(
     v_fd: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_datasync(ctx_ref, v_fd);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -394,7 +562,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_fdstat_getZ_iii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_fdstat_get(ctx_ref, v_fd);
     let retval = wasm2c_marshal_and_writeback_fdstat(ctx_ref, out as usize, r);
     let end = stop_timer();
@@ -410,7 +578,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_fdstat_set_flagsZ_iii(
     flags: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_fdstat_set_flags(ctx_ref, v_fd, flags);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -418,16 +586,21 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_fdstat_set_flagsZ_iii(
     retval
 }
 
-// Not supporting this because rights are getting removed
 #[no_mangle]
 #[trace(logging)]
-pub extern "C" fn _Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(
+pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_fdstat_set_rightsZ_iijj(
     ctx: *const *mut VmCtx,
-    a: u32,
-    b: u64,
-    c: u64,
+    v_fd: u32,
+    fs_rights_base: u64,
+    fs_rights_inheriting: u64,
 ) -> u32 {
-    unimplemented!()
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_fd_fdstat_set_rights(ctx_ref, v_fd, fs_rights_base, fs_rights_inheriting);
+    let retval = wasm2c_marshal(r);
+    let end = stop_timer();
+    push_hostcall_result("fd_fdstat_set_rights", start, end);
+    retval
 }
 
 #[no_mangle]
@@ -438,7 +611,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_filestat_getZ_iii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_filestat_get(ctx_ref, v_fd);
     let retval = wasm2c_marshal_and_writeback_filestat(ctx_ref, out as usize, r);
     let end = stop_timer();
@@ -454,8 +627,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_filestat_set_sizeZ_iij(
     size: u64,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_filestat_set_size(ctx_ref, v_fd, size as i64);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("fd_filestat_set_size", start, end);
@@ -472,7 +646,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_filestat_set_timesZ_iijji(
     fst_flags: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_filestat_set_times(ctx_ref, v_fd, atim, mtim, fst_flags);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -491,8 +665,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_preadZ_iiiiji(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_pread(ctx_ref, fd, iovs, iov_len, offset);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
     let end = stop_timer();
     push_hostcall_result("fd_pread", start, end);
@@ -508,7 +683,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_prestat_dir_nameZ_iiii(
     path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_prestat_dirname(ctx_ref, fd, path, path_len);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -527,8 +702,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_pwriteZ_iiiiji(
     retptr: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_pwrite(ctx_ref, fd, iovs, iov_len, offset);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, retptr as usize, r);
     let end = stop_timer();
     push_hostcall_result("fd_pwrite", start, end);
@@ -546,7 +722,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_readdirZ_iiiiji(
     retptr: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_readdir(ctx_ref, fd, buf, buf_len as usize, cookie);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, retptr as usize, r);
     let end = stop_timer();
@@ -562,7 +738,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_renumberZ_iii(
     to: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_renumber(ctx_ref, from, to);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -574,7 +750,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_renumberZ_iii(
 #[trace(logging)]
 pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_syncZ_ii(ctx: *const *mut VmCtx, fd: u32) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_sync(ctx_ref, fd);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -590,7 +766,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_fd_tellZ_iii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_fd_tell(ctx_ref, fd);
     let retval = wasm2c_marshal_and_writeback_u64(ctx_ref, out as usize, r);
     let end = stop_timer();
@@ -607,8 +783,10 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_create_directoryZ_iiii(
     path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_create_directory(ctx_ref, fd, pathname, path_len);
+    enforce_quotas(ctx_ref);
+    record_violation(ctx_ref, &r, Violation::path("path_create_directory"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_create_directory", start, end);
@@ -627,8 +805,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_filestat_getZ_iiiiii(
     out: u32, // wasm2c and wasi-libc disagree about 4 vs 5 arguments
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_filestat_get(ctx_ref, fd, flags, path, path_len);
+    record_violation(ctx_ref, &r, Violation::path("path_filestat_get"));
     let retval = wasm2c_marshal_and_writeback_filestat(ctx_ref, out as usize, r);
     let end = stop_timer();
     push_hostcall_result("path_filestat_get", start, end);
@@ -648,8 +827,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_filestat_set_timesZ_iiiiijji(
     fst_flags: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_filestat_set_times(ctx_ref, fd, flags, path, path_len, atim, mtim, fst_flags);
+    record_violation(ctx_ref, &r, Violation::path("path_filestat_set_times"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_filestat_set_times", start, end);
@@ -669,7 +849,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_linkZ_iiiiiiii(
     new_path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_link(
         ctx_ref,
         old_fd,
@@ -680,6 +860,8 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_linkZ_iiiiiiii(
         new_path,
         new_path_len,
     );
+    enforce_quotas(ctx_ref);
+    record_violation(ctx_ref, &r, Violation::path("path_link"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_link", start, end);
@@ -708,12 +890,12 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_openZ_iiiiiijjii(
     path_len: u32,
     oflags: u32,
     fs_rights_base: u64,
-    _fs_rights_inheriting: u64,
+    fs_rights_inheriting: u64,
     fdflags: u32,
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     // adjust oflags by adding O_WRONLY & O_RDWR as bits 4 and 5
     // after wasi-libc put them in fs_rights_base
     let new_flags = adjust_oflags(oflags, fs_rights_base);
@@ -724,8 +906,12 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_openZ_iiiiiijjii(
         path,
         path_len,
         new_flags,
+        fs_rights_base,
+        fs_rights_inheriting,
         fdflags as i32,
     );
+    enforce_quotas(ctx_ref);
+    record_violation(ctx_ref, &r, Violation::path("path_open"));
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
     let end = stop_timer();
     push_hostcall_result("path_open", start, end);
@@ -744,8 +930,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_readlinkZ_iiiiiii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_readlink(ctx_ref, fd, path, path_len, buf, buf_len);
+    record_violation(ctx_ref, &r, Violation::path("path_readlink"));
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
     let end = stop_timer();
     push_hostcall_result("path_readlink", start, end);
@@ -762,8 +949,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_remove_directoryZ_iiii(
     path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_remove_directory(ctx_ref, fd, path, path_len);
+    record_violation(ctx_ref, &r, Violation::path("path_remove_directory"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_remove_directory", start, end);
@@ -782,7 +970,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_renameZ_iiiiiii(
     new_path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_rename(
         ctx_ref,
         old_fd,
@@ -792,6 +980,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_renameZ_iiiiiii(
         new_path,
         new_path_len,
     );
+    record_violation(ctx_ref, &r, Violation::path("path_rename"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_rename", start, end);
@@ -809,8 +998,10 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_symlinkZ_iiiiii(
     path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_symlink(ctx_ref, old_path, old_path_len, fd, path, path_len);
+    enforce_quotas(ctx_ref);
+    record_violation(ctx_ref, &r, Violation::path("path_symlink"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_symlink", start, end);
@@ -826,8 +1017,9 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_path_unlink_fileZ_iiii(
     path_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_path_unlink_file(ctx_ref, fd, path, path_len);
+    record_violation(ctx_ref, &r, Violation::path("path_unlink_file"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("path_unlink_file", start, end);
@@ -844,7 +1036,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_poll_oneoffZ_iiiii(
     retptr: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_poll_oneoff(ctx_ref, in_ptr, out_ptr, nsubscriptions);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, retptr as usize, r);
     let end = stop_timer();
@@ -859,11 +1051,15 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_proc_raiseZ_ii(
     signal: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_proc_raise(ctx_ref, signal);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("proc_raise", start, end);
+    // Like proc_exit, go back to `run_start`
+    if retval == 0 && ctx_ref.signal_policy == SignalPolicy::Terminate {
+        stop_guest(ctx_ref, RunOutcome::Signaled(signal));
+    }
     retval
 }
 
@@ -875,7 +1071,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_random_getZ_iii(
     buf_len: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_random_get(ctx_ref, buf, buf_len);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -887,7 +1083,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_random_getZ_iii(
 #[trace(logging)]
 pub extern "C" fn Z_wasi_snapshot_preview1Z_sched_yieldZ_iv(ctx: *const *mut VmCtx) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_sched_yield(ctx_ref);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -907,9 +1103,10 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_recvZ_iiiiiii(
     out1: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_sock_recv(ctx_ref, fd, ri_data, ri_data_count, ri_flags);
-    let retval = wasm2c_marshal_and_writeback_u32_pair(ctx_ref, out0 as usize, out1 as usize, r);
+    enforce_quotas(ctx_ref);
+    let retval = wasm2c_marshal_and_writeback_u32_u16(ctx_ref, out0 as usize, out1 as usize, r);
     let end = stop_timer();
     push_hostcall_result("sock_recv", start, end);
     retval
@@ -926,14 +1123,61 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_sendZ_iiiiii(
     out: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_sock_send(ctx_ref, fd, si_data, si_data_count, si_flags);
+    enforce_quotas(ctx_ref);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
     let end = stop_timer();
     push_hostcall_result("sock_send", start, end);
     retval
 }
 
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_recv_fromZ_iiiiiiiii(
+    ctx: *const *mut VmCtx,
+    fd: u32,
+    buf: u32,
+    buf_len: u32,
+    ri_flags: u32,
+    addr_buf: u32,
+    addr_buf_len: u32,
+    out0: u32,
+    out1: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_recv_from(ctx_ref, fd, buf, buf_len, ri_flags, addr_buf, addr_buf_len);
+    enforce_quotas(ctx_ref);
+    let retval = wasm2c_marshal_and_writeback_u32_pair(ctx_ref, out0 as usize, out1 as usize, r);
+    let end = stop_timer();
+    push_hostcall_result("sock_recv_from", start, end);
+    retval
+}
+
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_send_toZ_iiiiiiii(
+    ctx: *const *mut VmCtx,
+    fd: u32,
+    buf: u32,
+    buf_len: u32,
+    si_flags: u32,
+    addr: u32,
+    addrlen: u32,
+    out: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_send_to(ctx_ref, fd, buf, buf_len, si_flags, addr, addrlen);
+    enforce_quotas(ctx_ref);
+    record_violation(ctx_ref, &r, Violation::net("sock_send_to"));
+    let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
+    let end = stop_timer();
+    push_hostcall_result("sock_send_to", start, end);
+    retval
+}
+
 #[no_mangle]
 #[trace(logging)]
 pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_shutdownZ_iii(
@@ -942,7 +1186,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_shutdownZ_iii(
     how: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_sock_shutdown(ctx_ref, fd, how);
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
@@ -950,6 +1194,24 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_shutdownZ_iii(
     retval
 }
 
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_acceptZ_iiii(
+    ctx: *const *mut VmCtx,
+    fd: u32,
+    flags: u32,
+    retptr: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_accept(ctx_ref, fd, flags);
+    record_violation(ctx_ref, &r, Violation::net("sock_accept"));
+    let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, retptr as usize, r);
+    let end = stop_timer();
+    push_hostcall_result("sock_accept", start, end);
+    retval
+}
+
 /*
  New Calls
 */
@@ -964,7 +1226,7 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_socketZ_iiiii(
     retptr: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_socket(ctx_ref, domain, ty, protocol);
     let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, retptr as usize, r);
     let end = stop_timer();
@@ -981,10 +1243,83 @@ pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_connectZ_iiii(
     addrlen: u32,
 ) -> u32 {
     let start = start_timer();
-    let ctx_ref = ptr_to_ref(ctx);
+    let ctx_ref = enter_hostcall(ctx);
     let r = wasi_sock_connect(ctx_ref, sockfd, addr, addrlen);
+    record_violation(ctx_ref, &r, Violation::net("sock_connect"));
     let retval = wasm2c_marshal(r);
     let end = stop_timer();
     push_hostcall_result("sock_connect", start, end);
     retval
 }
+
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_bindZ_iiii(
+    ctx: *const *mut VmCtx,
+    sockfd: u32,
+    addr: u32,
+    addrlen: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_bind(ctx_ref, sockfd, addr, addrlen);
+    record_violation(ctx_ref, &r, Violation::net("sock_bind"));
+    let retval = wasm2c_marshal(r);
+    let end = stop_timer();
+    push_hostcall_result("sock_bind", start, end);
+    retval
+}
+
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_listenZ_iii(
+    ctx: *const *mut VmCtx,
+    sockfd: u32,
+    backlog: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_listen(ctx_ref, sockfd, backlog);
+    record_violation(ctx_ref, &r, Violation::net("sock_listen"));
+    let retval = wasm2c_marshal(r);
+    let end = stop_timer();
+    push_hostcall_result("sock_listen", start, end);
+    retval
+}
+
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_setsockoptZ_iiiii(
+    ctx: *const *mut VmCtx,
+    fd: u32,
+    opt: u32,
+    value: u32,
+    value_len: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_setsockopt(ctx_ref, fd, opt, value, value_len);
+    let retval = wasm2c_marshal(r);
+    let end = stop_timer();
+    push_hostcall_result("sock_setsockopt", start, end);
+    retval
+}
+
+#[no_mangle]
+#[trace(logging)]
+pub extern "C" fn Z_wasi_snapshot_preview1Z_sock_getsockoptZ_iiiiii(
+    ctx: *const *mut VmCtx,
+    fd: u32,
+    opt: u32,
+    value: u32,
+    value_len: u32,
+    out: u32,
+) -> u32 {
+    let start = start_timer();
+    let ctx_ref = enter_hostcall(ctx);
+    let r = wasi_sock_getsockopt(ctx_ref, fd, opt, value, value_len);
+    let retval = wasm2c_marshal_and_writeback_u32(ctx_ref, out as usize, r);
+    let end = stop_timer();
+    push_hostcall_result("sock_getsockopt", start, end);
+    retval
+}
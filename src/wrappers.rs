@@ -1,6 +1,11 @@
 use crate::os::*;
 use crate::runtime::*;
-use crate::tcb::misc::{bitwise_or, first_null, flag_set, fresh_stat, push_dirent_name};
+use crate::tcb::misc::{
+    bitwise_or, call_signal_callback, first_null, flag_set, fresh_sockaddr, fresh_stat,
+    hostpath_to_vec, push_dirent_name, rights_subset, sockaddr_ip, sockaddr_len, sockaddr_port,
+    wasm_iovs_total_len,
+};
+use crate::tcb::overlay;
 #[cfg(feature = "verify")]
 use crate::tcb::verifier::external_specs::result::*;
 #[cfg(feature = "verify")]
@@ -9,6 +14,10 @@ use crate::tcb::verifier::*;
 // use crate::poll::{parse_subscriptions, writeback_timeouts, writeback_fds};
 use crate::iov::parse_iovs;
 use crate::poll::*;
+use crate::quota::{
+    disk_file_size, disk_path_size, disk_unlink_size, openat_creating, reserve_disk, settle_close,
+    settle_disk, track_open, write_end,
+};
 use crate::types::*;
 use crate::{effect, effects, unwrap_result};
 use prusti_contracts::*;
@@ -18,9 +27,9 @@ use std::str;
 use wave_macros::{external_calls, external_methods, with_ghost_var};
 use RuntimeError::*;
 
-// TODO: support arbitrary *at calls
-// Currently they can only be based off our preopened dir
-// (I've never seen a call that has attempted otherwise)
+// *at calls may be based off any fd opened through path_open (or a preopen).
+// The fdmap remembers where each such fd lives under its preopen, so paths are
+// always resolved from the preopen root and `..` cannot escape it.
 // We also don't need to handle the magic AT_FDCWD constant, because wasi-libc
 // auto adjusts it to our home directory
 
@@ -29,8 +38,21 @@ use RuntimeError::*;
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_open
 // Modifies: fdmap
 #[with_ghost_var(trace: &mut Trace)]
-#[external_methods(create, to_posix, should_follow, to_openat_posix)]
-#[external_calls(from, bitwise_or, flag_set)]
+#[external_methods(
+    create_at,
+    to_posix,
+    should_follow,
+    to_openat_posix,
+    creat,
+    trunc,
+    fd_to_rights,
+    within,
+    to_stat_posix,
+    writes,
+    read_only,
+    overlay_upper
+)]
+#[external_calls(from, bitwise_or, flag_set, hostpath_to_vec, new, rights_subset)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -42,6 +64,8 @@ pub fn wasi_path_open(
     pathname: u32,
     path_len: u32,
     oflags: u32,
+    rights_base: u64,
+    rights_inheriting: u64,
     fdflags: i32,
 ) -> RuntimeResult<u32> {
     let dirflags = LookupFlags::new(dirflags);
@@ -49,13 +73,31 @@ pub fn wasi_path_open(
     let fdflags = FdFlags::from(fdflags);
     let should_follow = dirflags.should_follow();
 
-    if v_dir_fd != HOMEDIR_FD {
+    ctx.fdmap.check_rights(v_dir_fd, RIGHTS_PATH_OPEN)?;
+    if oflags.creat() {
+        ctx.fdmap.check_rights(v_dir_fd, RIGHTS_PATH_CREATE_FILE)?;
+    }
+    if oflags.trunc() {
+        ctx.fdmap
+            .check_rights(v_dir_fd, RIGHTS_PATH_FILESTAT_SET_SIZE)?;
+    }
+    // the new fd can hold at most what the directory lets its children inherit
+    let dir_rights = ctx.fdmap.fd_to_rights(v_dir_fd);
+    let ceiling = FdRights::new(dir_rights.inheriting, dir_rights.inheriting);
+    let rights = FdRights::new(rights_base, rights_inheriting);
+    if !rights.within(&ceiling) {
         return Err(Enotcapable);
     }
-    let fd = ctx.homedir_host_fd;
 
-    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, fd);
+    let fd = ctx.translate_dir_fd(v_dir_fd)?;
+
+    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, v_dir_fd);
     unwrap_result!(host_pathname);
+    let writes = oflags.creat() || oflags.trunc() || oflags.writes();
+    let mode = ctx.check_access(fd, &host_pathname, writes)?;
+    // in a copy-on-write preopen, anything that may be written is copied up first
+    let copy_up = writes || rights_subset(RIGHTS_FD_WRITE, rights.base);
+    let layer = ctx.layer_for(fd, &host_pathname, copy_up)?;
 
     let dflags = dirflags.to_openat_posix();
     let flags = bitwise_or(bitwise_or(dflags, oflags.to_posix()), fdflags.to_posix());
@@ -67,26 +109,56 @@ pub fn wasi_path_open(
         return Err(Einval);
     }
 
-    let fd = trace_openat(ctx, fd, host_pathname, flags)?;
-    ctx.fdmap.create(HostFd::from_raw(fd))
+    let truncated = if oflags.trunc() {
+        disk_path_size(ctx, layer, host_pathname, dirflags.to_stat_posix())
+    } else {
+        None
+    };
+    let new_fd = if oflags.creat() {
+        openat_creating(ctx, layer, host_pathname, flags)?
+    } else {
+        trace_openat(ctx, layer, host_pathname, flags)?
+    };
+    if let Some(size) = truncated {
+        ctx.reclaim_quota(QuotaKind::DiskBytes, size);
+    }
+    let pos = PathPos {
+        preopen_fd: fd,
+        rel_path: hostpath_to_vec(&host_pathname),
+    };
+    // nothing opened under a read-only subtree may change a file through its fd
+    let rights = if mode == AccessMode::ReadOnly {
+        rights.read_only()
+    } else if layer == fd && ctx.overlay_upper(fd).is_some() {
+        // and neither may anything opened in the lower layer of a copy-on-write preopen,
+        // though what is opened through it (which is copied up) still may
+        FdRights::new(rights.read_only().base, rights.inheriting)
+    } else {
+        rights
+    };
+    let v_fd = ctx.fdmap.create_at(HostFd::from_raw(new_fd), pos, rights)?;
+    track_open(ctx, v_fd, HostFd::from_raw(new_fd));
+    Ok(v_fd)
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_close
 // modifies: fdmap
 #[with_ghost_var(trace: &mut Trace)]
-#[external_methods(delete)]
+#[external_methods(delete, len)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_close(ctx: &mut VmCtx, v_fd: u32) -> RuntimeResult<u32> {
     // can't replace with fd_to_native until we fix question mark operator
-    if v_fd >= MAX_SBOX_FDS {
+    if v_fd as usize >= ctx.fdmap.len() {
         return Err(Ebadf);
     }
     let fd = ctx.fdmap.m[v_fd as usize]?;
 
     ctx.fdmap.delete(v_fd);
+    // a file unlinked while open only frees its space now
+    settle_close(ctx, fd);
     let result = trace_close(ctx, fd)?;
     Ok(result as u32)
 }
@@ -116,26 +188,40 @@ pub fn wasi_fd_close(ctx: &mut VmCtx, v_fd: u32) -> RuntimeResult<u32> {
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_read(ctx: &mut VmCtx, v_fd: u32, iovs: u32, iovcnt: u32) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_READ)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let wasm_iovs = parse_iovs(ctx, iovs, iovcnt);
     unwrap_result!(wasm_iovs);
 
+    ctx.check_quota(QuotaKind::BytesRead, 1)?;
     let result = trace_readv(ctx, fd, &wasm_iovs, iovcnt as usize)?;
+    ctx.charge_quota(QuotaKind::BytesRead, result as u64);
     Ok(result as u32)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[external_methods(push)]
+#[external_calls(wasm_iovs_total_len)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_write(ctx: &mut VmCtx, v_fd: u32, iovs: u32, iovcnt: u32) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_WRITE)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let wasm_iovs = parse_iovs(ctx, iovs, iovcnt);
     unwrap_result!(wasm_iovs);
 
+    let len = wasm_iovs_total_len(&wasm_iovs);
+    ctx.check_quota(QuotaKind::BytesWritten, len)?;
+    let disk_before = disk_file_size(ctx, fd)?;
+    if let Some(size) = disk_before {
+        let end = write_end(ctx, fd, size, len)?;
+        reserve_disk(ctx, size, end)?;
+    }
     let result = trace_writev(ctx, fd, &wasm_iovs, iovcnt as usize)?;
+    ctx.charge_quota(QuotaKind::BytesWritten, result as u64);
+    settle_disk(ctx, fd, disk_before);
     Ok(result as u32)
 }
 
@@ -147,12 +233,19 @@ pub fn wasi_fd_write(ctx: &mut VmCtx, v_fd: u32, iovs: u32, iovcnt: u32) -> Runt
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-// #[ensures(v_fd < MAX_SBOX_FDS && ctx.fdmap.contains(v_fd) ==> effects!(old(trace), trace, Effect::FdAccess))]
-// #[ensures(v_fd >= MAX_SBOX_FDS ==> effects!(old(trace), trace))]
+// #[ensures((v_fd as usize) < ctx.fdmap.len() && ctx.fdmap.contains(v_fd) ==> effects!(old(trace), trace, Effect::FdAccess))]
+// #[ensures((v_fd as usize) >= ctx.fdmap.len() ==> effects!(old(trace), trace))]
 pub fn wasi_fd_seek(ctx: &VmCtx, v_fd: u32, v_filedelta: i64, v_whence: u32) -> RuntimeResult<u64> {
     let whence = Whence::from_u32(v_whence).ok_or(Einval)?;
+    let whence: i32 = whence.into();
+    // a seek that does not move the offset is just a tell
+    if v_filedelta == 0 && whence == libc::SEEK_CUR {
+        ctx.fdmap.check_rights(v_fd, RIGHTS_FD_TELL)?;
+    } else {
+        ctx.fdmap.check_rights(v_fd, RIGHTS_FD_SEEK)?;
+    }
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    let ret = trace_seek(ctx, fd, v_filedelta, whence.into())?;
+    let ret = trace_seek(ctx, fd, v_filedelta, whence)?;
     Ok(ret as u64)
 }
 
@@ -183,6 +276,7 @@ pub fn wasi_fd_advise(
     v_advice: u32,
 ) -> RuntimeResult<u32> {
     let advice = Advice::try_from(v_advice as i32)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_ADVISE)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
     let ret = trace_advise(ctx, fd, offset as i64, len as i64, advice.into())?;
@@ -196,10 +290,16 @@ pub fn wasi_fd_advise(
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-pub fn wasi_fd_allocate(ctx: &VmCtx, v_fd: u32, offset: u64, len: u64) -> RuntimeResult<u32> {
+pub fn wasi_fd_allocate(ctx: &mut VmCtx, v_fd: u32, offset: u64, len: u64) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_ALLOCATE)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
+    let disk_before = disk_file_size(ctx, fd)?;
+    if let Some(size) = disk_before {
+        reserve_disk(ctx, size, offset.saturating_add(len))?;
+    }
     let ret = trace_allocate(ctx, fd, offset as i64, len as i64)?;
+    settle_disk(ctx, fd, disk_before);
     Ok(ret as u32)
 }
 
@@ -211,6 +311,7 @@ pub fn wasi_fd_allocate(ctx: &VmCtx, v_fd: u32, offset: u64, len: u64) -> Runtim
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_sync(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_SYNC)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let ret = trace_sync(ctx, fd)?;
     Ok(())
@@ -224,6 +325,7 @@ pub fn wasi_fd_sync(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<()> {
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_datasync(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_DATASYNC)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let ret = trace_datasync(ctx, fd)?;
     Ok(ret as u32)
@@ -233,6 +335,7 @@ pub fn wasi_fd_datasync(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<u32> {
 //modifies: none
 #[with_ghost_var(trace: &mut Trace)]
 #[external_calls(fresh_stat, from_posix)]
+#[external_methods(fd_to_rights)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -244,12 +347,13 @@ pub fn wasi_fd_fdstat_get(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<FdStat> {
     let filetype = stat.st_mode;
 
     let mode_flags = trace_fgetfl(ctx, fd)?;
+    let rights = ctx.fdmap.fd_to_rights(v_fd);
 
     let result = FdStat {
         fs_filetype: (filetype as libc::mode_t).into(),
         fs_flags: FdFlags::from_posix(mode_flags as i32),
-        fs_rights_base: 0, // We don't bother to implement rights
-        fs_rights_inheriting: u64::MAX,
+        fs_rights_base: rights.base,
+        fs_rights_inheriting: rights.inheriting,
     };
     Ok(result)
 }
@@ -265,6 +369,7 @@ pub fn wasi_fd_fdstat_get(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<FdStat> {
 // can only adjust Fdflags using set_flags, not O_flags or any other flags
 pub fn wasi_fd_fdstat_set_flags(ctx: &mut VmCtx, v_fd: u32, v_flags: u32) -> RuntimeResult<()> {
     let flags = FdFlags::from(v_flags as i32);
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_FDSTAT_SET_FLAGS)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
     let posix_flags = flags.to_posix();
@@ -272,19 +377,48 @@ pub fn wasi_fd_fdstat_set_flags(ctx: &mut VmCtx, v_fd: u32, v_flags: u32) -> Run
     Ok(())
 }
 
+// https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_fdstat_set_rights
+// modifies: fdmap
+// Rights can only be narrowed, never widened
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(new)]
+#[external_methods(narrow_rights)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+#[ensures(effects!(old(trace), trace))]
+pub fn wasi_fd_fdstat_set_rights(
+    ctx: &mut VmCtx,
+    v_fd: u32,
+    rights_base: u64,
+    rights_inheriting: u64,
+) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(v_fd, 0)?;
+    let rights = FdRights::new(rights_base, rights_inheriting);
+    ctx.fdmap.narrow_rights(v_fd, rights)
+}
+
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_filestat_get
 // modifies: None
 #[with_ghost_var(trace: &mut Trace)]
 #[external_calls(fresh_stat)]
+#[external_methods(fd_to_pos)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_filestat_get(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<FileStat> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_FILESTAT_GET)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let mut stat = fresh_stat();
     let filetype = trace_fstat(ctx, fd, &mut stat)?;
-    Ok(stat.into())
+    // the std fds are under no preopen
+    let preopen = match ctx.fdmap.fd_to_pos(v_fd) {
+        Ok(pos) => Some(pos.preopen_fd),
+        Err(_) => None,
+    };
+    Ok(ctx.hide_stat(preopen, stat.into()))
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_filestat_set_size
@@ -297,9 +431,16 @@ pub fn wasi_fd_filestat_get(ctx: &VmCtx, v_fd: u32) -> RuntimeResult<FileStat> {
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
-pub fn wasi_fd_filestat_set_size(ctx: &VmCtx, v_fd: u32, size: i64) -> RuntimeResult<()> {
+pub fn wasi_fd_filestat_set_size(ctx: &mut VmCtx, v_fd: u32, size: i64) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_FILESTAT_SET_SIZE)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
+    let disk_before = disk_file_size(ctx, fd)?;
+    if let Some(current) = disk_before {
+        // a negative size is left for ftruncate to reject
+        reserve_disk(ctx, current, size.max(0) as u64)?;
+    }
     let ret = trace_ftruncate(ctx, fd, size)?;
+    settle_disk(ctx, fd, disk_before);
     Ok(())
 }
 
@@ -323,6 +464,7 @@ pub fn wasi_fd_filestat_set_times(
     let atim = Timestamp::new(v_atim);
     let mtim = Timestamp::new(v_mtim);
     let fst_flags = FstFlags::try_from(v_fst_flags as u16)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_FILESTAT_SET_TIMES)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
     let mut specs: Vec<libc::timespec> = Vec::new();
@@ -348,19 +490,23 @@ pub fn wasi_fd_pread(
     iovcnt: u32,
     offset: u64,
 ) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_READ)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_SEEK)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let wasm_iovs = parse_iovs(ctx, iovs, iovcnt);
     unwrap_result!(wasm_iovs);
 
+    ctx.check_quota(QuotaKind::BytesRead, 1)?;
     let result = trace_preadv(ctx, fd, &wasm_iovs, iovcnt as usize, offset as usize)?;
+    ctx.charge_quota(QuotaKind::BytesRead, result as u64);
     Ok(result as u32)
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#prestat_dirname
 // modifies: mem
-// If v_fd refers to a preopened directory (fd == 3), write the name to path
+// If v_fd refers to a preopened directory, write its guest name to path
 #[with_ghost_var(trace: &mut Trace)]
-#[external_methods(get_homedir)]
+#[external_methods(get_preopen_name, ok_or)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -371,12 +517,15 @@ pub fn wasi_prestat_dirname(
     path: u32,
     path_len: u32,
 ) -> RuntimeResult<()> {
-    if v_fd >= MAX_SBOX_FDS {
+    if v_fd as usize >= ctx.fdmap.len() {
         return Err(Ebadf);
     }
 
-    let dirname = ctx.get_homedir();
+    let dirname = ctx.get_preopen_name(v_fd).ok_or(Ebadf)?;
     let dirname_len = dirname.len() as u32;
+    if dirname_len > path_len {
+        return Err(Enametoolong);
+    }
     if !ctx.fits_in_lin_mem(path, dirname_len) {
         return Err(Efault);
     }
@@ -389,19 +538,21 @@ pub fn wasi_prestat_dirname(
 /// Must return ebadf if the file doesn't exist:
 /// https://github.com/WebAssembly/wasi-libc/blob/ad5133410f66b93a2381db5b542aad5e0964db96/libc-bottom-half/sources/preopens.c#L212
 #[with_ghost_var(trace: &mut Trace)]
+#[external_methods(get_preopen_name)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_fd_prestat_get(ctx: &mut VmCtx, v_fd: u32) -> RuntimeResult<u32> {
-    if v_fd == HOMEDIR_FD {
-        return Ok(ctx.homedir.len() as u32);
+    match ctx.get_preopen_name(v_fd) {
+        Some(name) => Ok(name.len() as u32),
+        None => Err(Ebadf),
     }
-    Err(Ebadf)
 }
 
 #[with_ghost_var(trace: &mut Trace)]
 #[external_methods(push)]
+#[external_calls(wasm_iovs_total_len)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -413,11 +564,21 @@ pub fn wasi_fd_pwrite(
     iovcnt: u32,
     offset: u64,
 ) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_WRITE)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_SEEK)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
     let wasm_iovs = parse_iovs(ctx, iovs, iovcnt);
     unwrap_result!(wasm_iovs);
 
+    let len = wasm_iovs_total_len(&wasm_iovs);
+    ctx.check_quota(QuotaKind::BytesWritten, len)?;
+    let disk_before = disk_file_size(ctx, fd)?;
+    if let Some(size) = disk_before {
+        reserve_disk(ctx, size, offset.saturating_add(len))?;
+    }
     let result = trace_pwritev(ctx, fd, &wasm_iovs, iovcnt as usize, offset as usize)?;
+    ctx.charge_quota(QuotaKind::BytesWritten, result as u64);
+    settle_disk(ctx, fd, disk_before);
     Ok(result as u32)
 }
 
@@ -437,18 +598,19 @@ pub fn wasi_path_create_directory(
 ) -> RuntimeResult<()> {
     // let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_CREATE_DIRECTORY)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     // create directory follows symlinks
-    let host_pathname = ctx.translate_path(pathname, path_len, true, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, true, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, true)?;
+    let layer = ctx.layer_for(fd, &host_pathname, true)?;
     // wasi doesn't specify what permissions should be
     // We use rw------- cause it seems sane.
-    let res = trace_mkdirat(ctx, fd, host_pathname, 0o766)?;
+    ctx.check_quota(QuotaKind::FilesCreated, 1)?;
+    let res = trace_mkdirat(ctx, layer, host_pathname, 0o766)?;
+    ctx.charge_quota(QuotaKind::FilesCreated, 1);
     Ok(())
 }
 
@@ -462,24 +624,22 @@ pub fn wasi_path_create_directory(
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_path_filestat_get(
-    ctx: &VmCtx,
+    ctx: &mut VmCtx,
     v_fd: u32,
     flags: u32,
     pathname: u32,
     path_len: u32,
 ) -> RuntimeResult<FileStat> {
     let flags = LookupFlags::new(flags);
-    let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_FILESTAT_GET)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     let should_follow = flags.should_follow();
 
-    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, false)?;
+    let layer = ctx.layer_for(fd, &host_pathname, false)?;
 
     let mut stat = fresh_stat();
 
@@ -492,8 +652,8 @@ pub fn wasi_path_filestat_get(
         return Err(Einval);
     }
 
-    let res = trace_fstatat(ctx, fd, host_pathname, &mut stat, n_flags)?;
-    Ok(stat.into())
+    let res = trace_fstatat(ctx, layer, host_pathname, &mut stat, n_flags)?;
+    Ok(ctx.hide_stat(Some(fd), stat.into()))
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_filestat_set_times
@@ -507,7 +667,7 @@ pub fn wasi_path_filestat_get(
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_path_filestat_set_times(
-    ctx: &VmCtx,
+    ctx: &mut VmCtx,
     v_fd: u32,
     flags: u32,
     pathname: u32,
@@ -527,15 +687,15 @@ pub fn wasi_path_filestat_set_times(
     }
 
     // let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap
+        .check_rights(v_fd, RIGHTS_PATH_FILESTAT_SET_TIMES)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     let should_follow = flags.should_follow();
-    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, true)?;
+    let layer = ctx.layer_for(fd, &host_pathname, true)?;
 
     let mut specs: Vec<libc::timespec> = Vec::new();
     let atim_spec = atim.ts_to_native(fst_flags.atim(), fst_flags.atim_now());
@@ -552,7 +712,7 @@ pub fn wasi_path_filestat_set_times(
         return Err(Einval);
     }
 
-    let res = trace_utimensat(ctx, fd, host_pathname, &specs, n_flags)?;
+    let res = trace_utimensat(ctx, layer, host_pathname, &specs, n_flags)?;
 
     Ok(())
 }
@@ -567,7 +727,7 @@ pub fn wasi_path_filestat_set_times(
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_path_link(
-    ctx: &VmCtx,
+    ctx: &mut VmCtx,
     v_old_fd: u32,
     flags: u32,
     old_pathname: u32,
@@ -581,25 +741,26 @@ pub fn wasi_path_link(
     // let old_fd = ctx.fdmap.fd_to_native(v_old_fd)?;
     // let new_fd = ctx.fdmap.fd_to_native(v_new_fd)?;
 
-    if v_old_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_old_fd == HOMEDIR_FD);
-    let old_fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_old_fd, RIGHTS_PATH_LINK_SOURCE)?;
+    let old_fd = ctx.translate_dir_fd(v_old_fd)?;
 
-    if v_new_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_new_fd == HOMEDIR_FD);
-    let new_fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_new_fd, RIGHTS_PATH_LINK_TARGET)?;
+    let new_fd = ctx.translate_dir_fd(v_new_fd)?;
 
     let should_follow = flags.should_follow();
 
     // when resolveing paths for path_link, we resolve the final symlink
-    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, should_follow, old_fd);
+    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, should_follow, v_old_fd);
     unwrap_result!(old_host_pathname);
-    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, should_follow, new_fd);
+    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, should_follow, v_new_fd);
     unwrap_result!(new_host_pathname);
+    // a new link to a read-only file would let it be written through the link
+    ctx.check_access(old_fd, &old_host_pathname, true)?;
+    ctx.check_access(new_fd, &new_host_pathname, true)?;
+    ctx.check_no_subtrees_below(old_fd, &old_host_pathname)?;
+    ctx.check_no_subtrees_below(new_fd, &new_host_pathname)?;
+    let old_layer = ctx.layer_for(old_fd, &old_host_pathname, true)?;
+    let new_layer = ctx.layer_for(new_fd, &new_host_pathname, true)?;
 
     let n_flags = flags.to_linkat_posix();
 
@@ -610,21 +771,23 @@ pub fn wasi_path_link(
         return Err(Einval);
     }
 
+    ctx.check_quota(QuotaKind::FilesCreated, 1)?;
     let res = trace_linkat(
         ctx,
-        old_fd,
+        old_layer,
         old_host_pathname,
-        new_fd,
+        new_layer,
         new_host_pathname,
         n_flags,
     )?;
+    ctx.charge_quota(QuotaKind::FilesCreated, 1);
     Ok(())
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_readlink
 // modifies: mem
 #[with_ghost_var(trace: &mut Trace)]
-#[external_methods(reserve_exact, push)]
+#[external_methods(reserve_exact, push, is_some)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -638,29 +801,34 @@ pub fn wasi_path_readlink(
     len: u32,
 ) -> RuntimeResult<u32> {
     // let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_READLINK)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     let should_follow = false; // readlink never follows symlink (it reads it!)
                                // TODO: replace once we can support the ? again
-    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, should_follow, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, false)?;
+    let layer = ctx.layer_for(fd, &host_pathname, false)?;
 
     if !ctx.fits_in_lin_mem(ptr, len) {
         return Err(Efault);
     }
 
-    let res = trace_readlinkat(ctx, fd, host_pathname, ptr, len as usize)?;
-    let res = res as u32;
-    Ok(res)
+    if ctx.info_hiding.is_some() {
+        // the raw target must not reach the sandbox, so it is read into a host buffer
+        let mut target = [0u8; PATH_MAX];
+        let res = trace_readlinkat_host(ctx, layer, host_pathname, &mut target, PATH_MAX)?;
+        return ctx.hide_readlink(fd, &host_pathname, &target[..res], ptr, len);
+    }
+    let res = trace_readlinkat(ctx, layer, host_pathname, ptr, len as usize)?;
+    Ok(res as u32)
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_remove_directory
 // modifies: none
 #[with_ghost_var(trace: &mut Trace)]
+#[external_methods(overlay_upper)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -672,15 +840,16 @@ pub fn wasi_path_remove_directory(
     path_len: u32,
 ) -> RuntimeResult<()> {
     // let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_REMOVE_DIRECTORY)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     // unlinkat operates on symlinks
-    let host_pathname = ctx.translate_path(pathname, path_len, false, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, false, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, true)?;
+    if let Some(upper) = ctx.overlay_upper(fd) {
+        return overlay::remove(ctx, fd, upper, &host_pathname, true);
+    }
 
     let res = trace_unlinkat(ctx, fd, host_pathname, libc::AT_REMOVEDIR);
     // posix spec allows unlinkat to return EEXIST for a non-empty directory
@@ -696,12 +865,13 @@ pub fn wasi_path_remove_directory(
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_rename
 // modifies: none
 #[with_ghost_var(trace: &mut Trace)]
+#[external_methods(overlay_upper)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_path_rename(
-    ctx: &VmCtx,
+    ctx: &mut VmCtx,
     v_old_fd: u32,
     old_pathname: u32,
     old_path_len: u32,
@@ -709,22 +879,34 @@ pub fn wasi_path_rename(
     new_pathname: u32,
     new_path_len: u32,
 ) -> RuntimeResult<()> {
-    if v_old_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    let old_fd = ctx.homedir_host_fd;
+    ctx.fdmap
+        .check_rights(v_old_fd, RIGHTS_PATH_RENAME_SOURCE)?;
+    let old_fd = ctx.translate_dir_fd(v_old_fd)?;
 
-    if v_new_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    let new_fd = ctx.homedir_host_fd;
+    ctx.fdmap
+        .check_rights(v_new_fd, RIGHTS_PATH_RENAME_TARGET)?;
+    let new_fd = ctx.translate_dir_fd(v_new_fd)?;
     // let old_fd = ctx.fdmap.fd_to_native(v_old_fd)?;
     // let new_fd = ctx.fdmap.fd_to_native(v_old_fd)?;
     // rename does not follow terminal symlinks - it operates on symlinks directly
-    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, false, old_fd);
+    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, false, v_old_fd);
     unwrap_result!(old_host_pathname);
-    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, false, new_fd);
+    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, false, v_new_fd);
     unwrap_result!(new_host_pathname);
+    ctx.check_access(old_fd, &old_host_pathname, true)?;
+    ctx.check_access(new_fd, &new_host_pathname, true)?;
+    ctx.check_no_subtrees_below(old_fd, &old_host_pathname)?;
+    ctx.check_no_subtrees_below(new_fd, &new_host_pathname)?;
+    let old_upper = ctx.overlay_upper(old_fd);
+    if old_upper.is_some() || ctx.overlay_upper(new_fd).is_some() {
+        // like a rename across filesystems
+        if old_fd != new_fd {
+            return Err(Exdev);
+        }
+        if let Some(upper) = old_upper {
+            return overlay::rename(ctx, old_fd, upper, &old_host_pathname, &new_host_pathname);
+        }
+    }
 
     let res = trace_renameat(ctx, old_fd, old_host_pathname, new_fd, new_host_pathname)?;
     Ok(())
@@ -739,7 +921,7 @@ pub fn wasi_path_rename(
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_path_symlink(
-    ctx: &VmCtx,
+    ctx: &mut VmCtx,
     old_pathname: u32,
     old_path_len: u32,
     v_fd: u32,
@@ -747,24 +929,34 @@ pub fn wasi_path_symlink(
     new_path_len: u32,
 ) -> RuntimeResult<()> {
     //let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_SYMLINK)?;
+    if !ctx.symlinks.create {
         return Err(Enotcapable);
     }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     // when evaluating paths for path_symlink, we follow symlinks
-    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, true, fd);
+    let old_host_pathname = ctx.translate_path(old_pathname, old_path_len, true, v_fd);
     unwrap_result!(old_host_pathname);
-    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, true, fd);
+    let new_host_pathname = ctx.translate_path(new_pathname, new_path_len, true, v_fd);
     unwrap_result!(new_host_pathname);
+    if ctx.symlinks.contained_targets
+        && ctx.symlink_target_escapes(old_pathname, old_path_len, &new_host_pathname)
+    {
+        return Err(Enotcapable);
+    }
+    ctx.check_access(fd, &new_host_pathname, true)?;
+    let layer = ctx.layer_for(fd, &new_host_pathname, true)?;
 
-    let res = trace_symlinkat(ctx, old_host_pathname, fd, new_host_pathname)?;
+    ctx.check_quota(QuotaKind::FilesCreated, 1)?;
+    let res = trace_symlinkat(ctx, old_host_pathname, layer, new_host_pathname)?;
+    ctx.charge_quota(QuotaKind::FilesCreated, 1);
     Ok(())
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#path_unlink_file
 #[with_ghost_var(trace: &mut Trace)]
+#[external_methods(overlay_upper)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -776,17 +968,29 @@ pub fn wasi_path_unlink_file(
     path_len: u32,
 ) -> RuntimeResult<()> {
     // let fd = ctx.fdmap.fd_to_native(v_fd)?;
-    if v_fd != HOMEDIR_FD {
-        return Err(Enotcapable);
-    }
-    assert!(v_fd == HOMEDIR_FD);
-    let fd = ctx.homedir_host_fd;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_PATH_UNLINK_FILE)?;
+    let fd = ctx.translate_dir_fd(v_fd)?;
 
     // unlink operates on symlinks (it is in fact the main way to delete symlinks)
-    let host_pathname = ctx.translate_path(pathname, path_len, false, fd);
+    let host_pathname = ctx.translate_path(pathname, path_len, false, v_fd);
     unwrap_result!(host_pathname);
+    ctx.check_access(fd, &host_pathname, true)?;
+
+    if let Some(upper) = ctx.overlay_upper(fd) {
+        // only what was written to the upper layer took up space
+        let unlinked = disk_unlink_size(ctx, upper, host_pathname);
+        overlay::remove(ctx, fd, upper, &host_pathname, false)?;
+        if let Some(size) = unlinked {
+            ctx.reclaim_quota(QuotaKind::DiskBytes, size);
+        }
+        return Ok(());
+    }
 
+    let unlinked = disk_unlink_size(ctx, fd, host_pathname);
     let res = trace_unlinkat(ctx, fd, host_pathname, 0)?;
+    if let Some(size) = unlinked {
+        ctx.reclaim_quota(QuotaKind::DiskBytes, size);
+    }
     Ok(())
 }
 
@@ -827,6 +1031,8 @@ pub fn wasi_clock_time_get(
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#proc_exit
+// Nothing to do here: proc_exit does not return to the guest, so the frontends stop
+// it once this returns (see `wasm2c_frontend::stop_guest`).
 #[with_ghost_var(trace: &mut Trace)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
@@ -839,12 +1045,22 @@ pub fn wasi_proc_exit(ctx: &VmCtx, rval: u32) -> RuntimeResult<()> {
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#proc_raise
 #[with_ghost_var(trace: &mut Trace)]
+#[external_calls(call_signal_callback)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 #[ensures(effects!(old(trace), trace))]
 pub fn wasi_proc_raise(ctx: &VmCtx, signal: u32) -> RuntimeResult<()> {
+    // wasi signals are 1 (hup) through 30 (sys), 0 is reserved
+    if signal == 0 || signal > 30 {
+        return Err(Einval);
+    }
+    // SignalPolicy::Terminate is handled by the frontends, which stop the guest
+    // once this returns. Nothing here ever signals the host process.
+    if let SignalPolicy::Callback(callback) = ctx.signal_policy {
+        call_signal_callback(callback, signal);
+    }
     Ok(())
 }
 
@@ -878,14 +1094,14 @@ pub fn wasi_random_get(ctx: &mut VmCtx, ptr: u32, len: u32) -> RuntimeResult<()>
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_renumber
 #[with_ghost_var(trace: &mut Trace)]
-#[external_methods(shift)]
+#[external_methods(shift, len)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
 #[ensures(trace_safe(trace, ctx))]
 #[ensures(effects!(old(trace), trace))]
 pub fn wasi_fd_renumber(ctx: &mut VmCtx, v_from: u32, v_to: u32) -> RuntimeResult<()> {
-    if v_from >= MAX_SBOX_FDS || v_to >= MAX_SBOX_FDS {
+    if v_from as usize >= ctx.fdmap.len() || v_to as usize >= ctx.fdmap.len() {
         return Err(Ebadf);
     }
     ctx.fdmap.shift(v_from, v_to);
@@ -1024,37 +1240,35 @@ pub fn wasi_sock_recv(
     ri_data: u32,
     ri_data_count: u32,
     ri_flags: u32,
-) -> RuntimeResult<(u32, u32)> {
-    let fd = ctx.fdmap.fd_to_native(v_fd)?;
+) -> RuntimeResult<(u32, u16)> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_READ)?;
+    let fd = ctx.fdmap.fd_to_sock(v_fd)?;
     let ri_flags = RiFlags::try_from(ri_flags)?;
 
-    let mut num: u32 = 0;
-    let mut i = 0;
-    while i < ri_data_count {
-        body_invariant!(ctx_safe(ctx));
-        body_invariant!(trace_safe(trace, ctx));
-
-        let start = (ri_data + i * 8) as usize;
-
-        let (ptr, len) = ctx.read_u32_pair(start)?;
-        if !ctx.fits_in_lin_mem(ptr, len) {
-            return Err(Efault);
-        }
-
-        let result = trace_recv(ctx, fd, ptr, len as usize, ri_flags.to_posix())?;
-        let result = result as u32;
+    let wasm_iovs = parse_iovs(ctx, ri_data, ri_data_count);
+    unwrap_result!(wasm_iovs);
 
-        num += result;
-        i += 1;
-    }
-    // TODO: handle ro_flags
-    //       It doesn't look like there is a good way to handle them other than to use linux
-    //       recvmsg instead of recv, which is more complex
-    Ok((num, 0))
+    // A single recvmsg, so that recv_peek and recv_waitall apply across all of the iovecs
+    // and a datagram is never split over several receives
+    ctx.check_quota(QuotaKind::BytesRead, 1)?;
+    let mut msg_flags: i32 = 0;
+    let result = trace_recvmsg(
+        ctx,
+        fd,
+        &wasm_iovs,
+        ri_data_count as usize,
+        ri_flags.to_posix(),
+        &mut msg_flags,
+    )?;
+    ctx.charge_quota(QuotaKind::BytesRead, result as u64);
+    let ro_flags = RoFlags::from_posix(msg_flags);
+    Ok((result as u32, ro_flags.to_wasi()))
 }
 
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#sock_send
 #[with_ghost_var(trace: &mut Trace)]
+#[external_methods(lookup)]
+#[external_calls(wasm_iovs_total_len)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -1066,30 +1280,138 @@ pub fn wasi_sock_send(
     si_data_count: u32,
     si_flags: u32,
 ) -> RuntimeResult<u32> {
-    let fd = ctx.fdmap.fd_to_native(v_fd)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_WRITE)?;
+    let fd = ctx.fdmap.fd_to_sock(v_fd)?;
+    let wasm_iovs = parse_iovs(ctx, si_data, si_data_count);
+    unwrap_result!(wasm_iovs);
+
+    // like fd_write, a send that would go over the quota is refused as a whole
+    let total = wasm_iovs_total_len(&wasm_iovs);
+    ctx.check_quota(QuotaKind::BytesWritten, total)?;
 
     let mut num: u32 = 0;
     let mut i = 0;
-    while i < si_data_count {
+    while i < wasm_iovs.len() {
         body_invariant!(ctx_safe(ctx));
         body_invariant!(trace_safe(trace, ctx));
 
-        let start = (si_data + i * 8) as usize;
-
-        let (ptr, len) = ctx.read_u32_pair(start)?;
-        if !ctx.fits_in_lin_mem(ptr, len) {
-            return Err(Efault);
-        }
+        let iov = wasm_iovs.lookup(i);
         // Currently the flags field of trace_send must be set to 0:
         // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md/#siflags
         let flags = 0;
-        let result = trace_send(ctx, fd, ptr, len as usize, flags)?;
+        let result = trace_send(ctx, fd, iov.iov_base, iov.iov_len as usize, flags)?;
+        ctx.charge_quota(QuotaKind::BytesWritten, result as u64);
         num += result as u32;
         i += 1;
     }
     Ok(num)
 }
 
+// Unconnected datagram hostcalls. One call carries exactly one datagram, so unlike
+// sock_send/sock_recv these take a single buffer rather than an array of iovecs.
+// No spec for these since we added them
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(addr_in_netlist, sockaddr_ip, sockaddr_port, sockaddr_len)]
+#[external_methods(read_sockaddr)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_send_to(
+    ctx: &mut VmCtx,
+    v_fd: u32,
+    buf: u32,
+    buf_len: u32,
+    si_flags: u32,
+    addr: u32,
+    addrlen: u32,
+) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_WRITE)?;
+    let fd = ctx.fdmap.fd_to_sock(v_fd)?;
+    if !matches!(ctx.fdmap.sockinfo[v_fd as usize]?, WasiProto::Udp) {
+        return Err(Enotsup);
+    }
+
+    // addrlen is 16 for inet4 and 28 for inet6
+    let saddr = ctx.read_sockaddr(addr, addrlen)?;
+    // every datagram is checked, there is no connection to have checked once
+    let (ip, port) = (sockaddr_ip(&saddr), sockaddr_port(&saddr));
+    if !addr_in_netlist(&ctx.netlist, WasiProto::Udp, ip, port) {
+        return Err(Enotcapable);
+    }
+
+    if !ctx.fits_in_lin_mem(buf, buf_len) {
+        return Err(Efault);
+    }
+    ctx.check_quota(QuotaKind::BytesWritten, buf_len as u64)?;
+    // As with sock_send, si_flags must currently be 0
+    let flags = 0;
+    let result = trace_sendto(
+        ctx,
+        fd,
+        WasiProto::Udp,
+        buf,
+        buf_len as usize,
+        flags,
+        &saddr,
+        sockaddr_len(&saddr),
+    )?;
+    ctx.charge_quota(QuotaKind::BytesWritten, result as u64);
+    Ok(result as u32)
+}
+
+// Returns the number of bytes received and the length of the source address
+// written to `addr_buf`.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(fresh_sockaddr, try_from)]
+#[external_methods(write_sockaddr)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_recv_from(
+    ctx: &mut VmCtx,
+    v_fd: u32,
+    buf: u32,
+    buf_len: u32,
+    ri_flags: u32,
+    addr_buf: u32,
+    addr_buf_len: u32,
+) -> RuntimeResult<(u32, u32)> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_READ)?;
+    let fd = ctx.fdmap.fd_to_sock(v_fd)?;
+    if !matches!(ctx.fdmap.sockinfo[v_fd as usize]?, WasiProto::Udp) {
+        return Err(Enotsup);
+    }
+    let ri_flags = RiFlags::try_from(ri_flags)?;
+
+    // Check both buffers before receiving so a datagram is never dropped on a bad pointer
+    if !ctx.fits_in_lin_mem(buf, buf_len) || !ctx.fits_in_lin_mem(addr_buf, addr_buf_len) {
+        return Err(Efault);
+    }
+    // the source isn't known until the datagram is received, so there must be room for
+    // either kind of address
+    if addr_buf_len < SBOX_SOCKADDR_IN6_LEN {
+        return Err(Einval);
+    }
+
+    ctx.check_quota(QuotaKind::BytesRead, 1)?;
+    let mut src = fresh_sockaddr();
+    let mut src_len = std::mem::size_of::<libc::sockaddr_storage>() as u32;
+    let result = trace_recvfrom(
+        ctx,
+        fd,
+        buf,
+        buf_len as usize,
+        ri_flags.to_posix(),
+        &mut src,
+        &mut src_len,
+    )?;
+    ctx.charge_quota(QuotaKind::BytesRead, result as u64);
+    let written = ctx.write_sockaddr(addr_buf, addr_buf_len, &src)?;
+    Ok((result as u32, written))
+}
+
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#sock_shutdown
 // ensures: valid(v_fd) => trace = old(shutdown :: trace)
 #[with_ghost_var(trace: &mut Trace)]
@@ -1100,7 +1422,8 @@ pub fn wasi_sock_send(
 #[ensures(trace_safe(trace, ctx))]
 
 pub fn wasi_sock_shutdown(ctx: &VmCtx, v_fd: u32, v_how: u32) -> RuntimeResult<()> {
-    let fd = ctx.fdmap.fd_to_native(v_fd)?;
+    ctx.fdmap.check_rights(v_fd, RIGHTS_SOCK_SHUTDOWN)?;
+    let fd = ctx.fdmap.fd_to_sock(v_fd)?;
     let how = SdFlags::new(v_how);
     let posix_how = how.try_into()?;
 
@@ -1179,8 +1502,9 @@ pub fn wasi_poll_oneoff(
 // https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#fd_readdir
 #[with_ghost_var(trace: &mut Trace)]
 #[external_methods(extend_from_slice, reserve_exact)]
-#[external_methods(to_le_bytes, to_wasi)]
+#[external_methods(to_le_bytes, to_wasi, fd_to_pos, overlay_upper)]
 #[external_calls(from_le_bytes, from, first_null, push_dirent_name, parse)]
+#[external_calls(merged_dirents)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -1194,12 +1518,28 @@ pub fn wasi_fd_readdir(
     buf_len: usize,
     cookie: u64,
 ) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(v_fd, RIGHTS_FD_READDIR)?;
     let fd = ctx.fdmap.fd_to_native(v_fd)?;
 
+    // a directory in a copy-on-write preopen lists both layers, whichever one it is in
+    if let Ok(pos) = ctx.fdmap.fd_to_pos(v_fd) {
+        if let Some(upper) = ctx.overlay_upper(pos.preopen_fd) {
+            let entries = overlay::merged_entries(ctx, pos.preopen_fd, upper, &pos.rel_path);
+            let entries = ctx.hide_entries(pos.preopen_fd, &pos.rel_path, entries);
+            let out_buf = overlay::merged_dirents(&entries, cookie, buf_len);
+            ctx.copy_buf_to_sandbox(buf, &out_buf, out_buf.len() as u32)?;
+            return Ok(out_buf.len() as u32);
+        }
+    }
+
     let mut host_buf: Vec<u8> = Vec::new();
     host_buf.reserve_exact(buf_len as usize);
 
     let res = trace_getdents64(ctx, fd, &mut host_buf, buf_len)?;
+    let (preopen, rel_path) = match ctx.fdmap.fd_to_pos(v_fd) {
+        Ok(pos) => (Some(pos.preopen_fd), pos.rel_path),
+        Err(_) => (None, Vec::new()),
+    };
 
     // the number of entries we have read so far. If less than cookie, don't output the directory
     let mut entry_idx = 0;
@@ -1222,6 +1562,17 @@ pub fn wasi_fd_readdir(
             continue;
         }
 
+        // entries in hidden subtrees are left out, as if they weren't there
+        let name_start = in_idx + dirent.name_start;
+        let name = &host_buf[name_start..name_start + dirent.out_namlen];
+        if let Some(dirfd) = preopen {
+            if ctx.entry_hidden(dirfd, &rel_path, name) {
+                in_idx += dirent.reclen as usize;
+                entry_idx += 1;
+                continue;
+            }
+        }
+
         let out_next = in_idx + 24 + dirent.out_namlen;
 
         // If we would overflow - don't :)
@@ -1233,8 +1584,8 @@ pub fn wasi_fd_readdir(
         let out_next_bytes: [u8; 8] = out_next.to_le_bytes();
         out_buf.extend_from_slice(&out_next_bytes);
 
-        // Copy in Inode verbatim
-        let d_ino_bytes: [u8; 8] = dirent.ino.to_le_bytes();
+        // Copy in Inode verbatim, unless info hiding remaps it
+        let d_ino_bytes: [u8; 8] = ctx.hide_ino(preopen, dirent.ino).to_le_bytes();
         out_buf.extend_from_slice(&d_ino_bytes);
 
         // Copy namlen
@@ -1265,7 +1616,7 @@ pub fn wasi_fd_readdir(
 
 // No spec for this one since we added it
 #[with_ghost_var(trace: &mut Trace)]
-#[external_calls(sock_domain_to_posix, sock_type_to_posix)]
+#[external_calls(sock_domain_to_posix, sock_type_to_posix, all)]
 #[external_methods(create_sock)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
@@ -1273,7 +1624,7 @@ pub fn wasi_fd_readdir(
 #[ensures(trace_safe(trace, ctx))]
 pub fn wasi_socket(ctx: &mut VmCtx, domain: u32, ty: u32, protocol: u32) -> RuntimeResult<u32> {
     // We only allow TCP and UDP, which can both be identified using protocol=0 when
-    // domain.ty are (AF_INET,SOCK_STREAM) or (AF_INET,SOCK_DGRAM) respectively
+    // domain.ty are (AF_INET(6),SOCK_STREAM) or (AF_INET(6),SOCK_DGRAM) respectively
     if protocol != 0 {
         return Err(Einval);
     }
@@ -1287,20 +1638,23 @@ pub fn wasi_socket(ctx: &mut VmCtx, domain: u32, ty: u32, protocol: u32) -> Runt
     if matches!(wasi_proto, WasiProto::Unknown) {
         return Err(Einval);
     }
-    if !(domain == libc::AF_INET && (ty == libc::SOCK_STREAM || ty == libc::SOCK_DGRAM)) {
+    if !((domain == libc::AF_INET || domain == libc::AF_INET6)
+        && (ty == libc::SOCK_STREAM || ty == libc::SOCK_DGRAM))
+    {
         return Err(Einval);
     }
 
     let res = trace_socket(ctx, domain, ty, protocol)?;
 
-    ctx.fdmap.create_sock(HostFd::from_raw(res), wasi_proto)
+    ctx.fdmap
+        .create_sock(HostFd::from_raw(res), wasi_proto, FdRights::all())
     // ctx.fdmap.create(res.into())
 }
 
 // No spec for this one since we added it
 #[with_ghost_var(trace: &mut Trace)]
-#[external_calls(sock_domain_to_posix, from, addr_in_netlist)]
-//#[external_calls(addr_in_netlist)]
+#[external_calls(addr_in_netlist, sockaddr_ip, sockaddr_port, sockaddr_len)]
+#[external_methods(read_sockaddr)]
 #[requires(ctx_safe(ctx))]
 #[requires(trace_safe(trace, ctx))]
 #[ensures(ctx_safe(ctx))]
@@ -1311,48 +1665,234 @@ pub fn wasi_sock_connect(
     addr: u32,
     addrlen: u32,
 ) -> RuntimeResult<()> {
-    let fd = ctx.fdmap.fd_to_native(sockfd)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
 
-    if addrlen != 16 {
-        return Err(Einval);
-    }
+    // addrlen is 16 for inet4 and 28 for inet6
+    let saddr = ctx.read_sockaddr(addr, addrlen)?;
 
-    // since we only support inet4, addrlen will always be 16
-    if addrlen != 16 {
-        return Err(Einval);
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
+    if matches!(protocol, WasiProto::Unknown) {
+        return Err(Enotcapable);
     }
 
-    if !ctx.fits_in_lin_mem(addr, addrlen) {
-        return Err(Eoverflow);
+    let (ip, port) = (sockaddr_ip(&saddr), sockaddr_port(&saddr));
+    if !addr_in_netlist(&ctx.netlist, protocol, ip, port) {
+        return Err(Enotcapable);
     }
 
-    let sin_family = ctx.read_u16(addr as usize);
-    let sin_port = ctx.read_u16(addr as usize + 2);
-    let sin_addr_in = ctx.read_u32(addr as usize + 4);
-    let sin_family = sock_domain_to_posix(sin_family as u32)? as libc::sa_family_t;
-    // We can directly use sockaddr_in since we already know all socks are inet
-    let sin_addr = libc::in_addr {
-        s_addr: sin_addr_in,
-    };
-    let saddr = libc::sockaddr_in {
-        // i'll be lazy, should refactor to os-specific code...
-        #[cfg(target_os = "macos")]
-        sin_len: 0,
-        sin_family,
-        sin_port,
-        sin_addr,
-        sin_zero: [0; 8],
-    };
+    let res = trace_connect(ctx, fd, protocol, &saddr, sockaddr_len(&saddr))?;
+    Ok(())
+}
 
-    let protocol = ctx.fdmap.sockinfo[fd.to_raw()]?;
+// No spec for this one since we added it
+// The local address must be in the inbound bindlist
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(addr_in_netlist, sockaddr_ip, sockaddr_port, sockaddr_len)]
+#[external_methods(read_sockaddr, mark_bound)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_bind(ctx: &mut VmCtx, sockfd: u32, addr: u32, addrlen: u32) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(sockfd, RIGHTS_SOCK_BIND)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
+
+    let saddr = ctx.read_sockaddr(addr, addrlen)?;
+
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
     if matches!(protocol, WasiProto::Unknown) {
         return Err(Enotcapable);
     }
 
-    if !addr_in_netlist(&ctx.netlist, sin_addr_in, sin_port as u32) {
+    let (ip, port) = (sockaddr_ip(&saddr), sockaddr_port(&saddr));
+    if !addr_in_netlist(&ctx.inbound.bindlist, protocol, ip, port) {
         return Err(Enotcapable);
     }
 
-    let res = trace_connect(ctx, fd, &saddr, addrlen)?;
+    let res = trace_bind(ctx, fd, protocol, &saddr, sockaddr_len(&saddr))?;
+    ctx.fdmap.mark_bound(sockfd);
+    Ok(())
+}
+
+// No spec for this one since we added it
+// Only sockets bound through wasi_sock_bind, and so within the bindlist, may listen
+#[with_ghost_var(trace: &mut Trace)]
+#[external_methods(is_bound)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_listen(ctx: &mut VmCtx, sockfd: u32, backlog: u32) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(sockfd, RIGHTS_SOCK_LISTEN)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
+    if !matches!(protocol, WasiProto::Tcp) {
+        return Err(Enotsup);
+    }
+    // the host would bind an unbound socket to an ephemeral port on every address,
+    // none of which the bindlist was checked against
+    if !ctx.fdmap.is_bound(sockfd) {
+        return Err(Enotcapable);
+    }
+    if backlog > i32::MAX as u32 {
+        return Err(Einval);
+    }
+    let res = trace_listen(ctx, fd, backlog as i32)?;
     Ok(())
 }
+
+// No spec for this one since we added it
+// Only the options in SockOpt are allowed, and `value_len` must match the option.
+// Changing an option needs the same right as changing the fd's flags.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(try_from, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_setsockopt(
+    ctx: &mut VmCtx,
+    sockfd: u32,
+    opt: u32,
+    value: u32,
+    value_len: u32,
+) -> RuntimeResult<()> {
+    ctx.fdmap.check_rights(sockfd, RIGHTS_FD_FDSTAT_SET_FLAGS)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
+    let opt = SockOpt::try_from(opt)?;
+    if matches!(protocol, WasiProto::Unknown) {
+        return Err(Enotsup);
+    }
+    if opt.tcp_only() && !matches!(protocol, WasiProto::Tcp) {
+        return Err(Enoprotoopt);
+    }
+    if value_len != opt.wasi_len() {
+        return Err(Einval);
+    }
+    if !ctx.fits_in_lin_mem(value, value_len) {
+        return Err(Efault);
+    }
+
+    let (level, optname) = opt.to_posix();
+    if opt.is_timeout() {
+        let timeout = Timestamp::new(ctx.read_u64(value as usize));
+        let tv = libc::timeval::from(timeout);
+        let res = trace_setsockopt_timeval(ctx, fd, level, optname, &tv)?;
+    } else {
+        let v = ctx.read_u32(value as usize);
+        if v > i32::MAX as u32 {
+            return Err(Einval);
+        }
+        let res = trace_setsockopt_int(ctx, fd, level, optname, &(v as i32))?;
+    }
+    Ok(())
+}
+
+// No spec for this one since we added it
+// Writes the option's value to `value` and returns its length.
+// Reading an option needs the same right as fd_filestat_get.
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(try_from, from)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_getsockopt(
+    ctx: &mut VmCtx,
+    sockfd: u32,
+    opt: u32,
+    value: u32,
+    value_len: u32,
+) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(sockfd, RIGHTS_FD_FILESTAT_GET)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
+    let opt = SockOpt::try_from(opt)?;
+    if matches!(protocol, WasiProto::Unknown) {
+        return Err(Enotsup);
+    }
+    if opt.tcp_only() && !matches!(protocol, WasiProto::Tcp) {
+        return Err(Enoprotoopt);
+    }
+    if value_len < opt.wasi_len() || value_len > i32::MAX as u32 {
+        return Err(Einval);
+    }
+    if !ctx.fits_in_lin_mem(value, value_len) {
+        return Err(Efault);
+    }
+
+    let (level, optname) = opt.to_posix();
+    if opt.is_timeout() {
+        let mut tv = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        let res = trace_getsockopt_timeval(ctx, fd, level, optname, &mut tv)?;
+        ctx.write_u64(value as usize, Timestamp::from(tv).nsec());
+    } else {
+        let mut v: i32 = 0;
+        let res = trace_getsockopt_int(ctx, fd, level, optname, &mut v)?;
+        ctx.write_u32(value as usize, v as u32);
+    }
+    Ok(opt.wasi_len())
+}
+
+// https://github.com/WebAssembly/WASI/blob/main/phases/snapshot/docs.md#sock_accept
+// modifies: fdmap
+// Connections from peers outside the inbound policy are closed immediately
+#[with_ghost_var(trace: &mut Trace)]
+#[external_calls(
+    fresh_sockaddr,
+    peer_allowed,
+    sockaddr_ip,
+    sockaddr_port,
+    from,
+    from_raw,
+    size_of,
+    new
+)]
+#[external_methods(create_sock, to_posix, is_err)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasi_sock_accept(ctx: &mut VmCtx, sockfd: u32, flags: u32) -> RuntimeResult<u32> {
+    ctx.fdmap.check_rights(sockfd, RIGHTS_SOCK_ACCEPT)?;
+    let fd = ctx.fdmap.fd_to_sock(sockfd)?;
+    let protocol = ctx.fdmap.sockinfo[sockfd as usize]?;
+    if !matches!(protocol, WasiProto::Tcp) {
+        return Err(Enotsup);
+    }
+
+    let mut peer = fresh_sockaddr();
+    let mut peer_len = mem::size_of::<libc::sockaddr_storage>() as u32;
+    let new_fd = trace_accept(ctx, fd, &mut peer, &mut peer_len)?;
+    let new_fd = HostFd::from_raw(new_fd);
+
+    let (ip, port) = (sockaddr_ip(&peer), sockaddr_port(&peer));
+    if !peer_allowed(&ctx.inbound, protocol, ip, port) {
+        trace_close(ctx, new_fd)?;
+        return Err(Econnaborted);
+    }
+
+    if flags != 0 {
+        let fdflags = FdFlags::from(flags as i32);
+        if let Err(e) = trace_fsetfl(ctx, new_fd, fdflags.to_posix()) {
+            trace_close(ctx, new_fd)?;
+            return Err(e);
+        }
+    }
+
+    // don't leak the connection if the sandbox is out of fds. It is already
+    // connected, so it can't be used to serve.
+    let rights = FdRights::new(
+        RIGHTS_ALL ^ RIGHTS_SOCK_SERVE,
+        RIGHTS_ALL ^ RIGHTS_SOCK_SERVE,
+    );
+    let res = ctx.fdmap.create_sock(new_fd, protocol, rights);
+    if res.is_err() {
+        trace_close(ctx, new_fd)?;
+    }
+    res
+}
@@ -188,3 +188,31 @@ pub fn wasm2c_marshal_and_writeback_u32_pair(
         Err(err) => err.into(),
     }
 }
+
+// (u32, u16) results such as sock_recv's (bytes received, roflags)
+#[with_ghost_var(trace: &mut Trace)]
+#[requires(ctx_safe(ctx))]
+#[requires(trace_safe(trace, ctx))]
+#[ensures(ctx_safe(ctx))]
+#[ensures(trace_safe(trace, ctx))]
+pub fn wasm2c_marshal_and_writeback_u32_u16(
+    ctx: &mut VmCtx,
+    addr0: usize,
+    addr1: usize,
+    res: RuntimeResult<(u32, u16)>,
+) -> u32 {
+    if !ctx.fits_in_lin_mem_usize(addr0, 4) {
+        return RuntimeError::Eoverflow.into();
+    }
+    if !ctx.fits_in_lin_mem_usize(addr1, 2) {
+        return RuntimeError::Eoverflow.into();
+    }
+    match res {
+        Ok((v0, v1)) => {
+            ctx.write_u32(addr0, v0);
+            ctx.write_u16(addr1, v1);
+            0
+        }
+        Err(err) => err.into(),
+    }
+}
@@ -5,9 +5,15 @@ mod waverunner;
 use crate::types::WaveConfig;
 use anyhow::{anyhow, Result};
 use clap::{App, Arg};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
-use wave::tcb::misc::empty_netlist;
-use wave::types::{NetEndpoint, Netlist, WasiProto};
+use std::time::Duration;
+use wave::tcb::misc::{empty_inbound_policy, empty_netlist};
+use wave::types::{
+    AccessMode, AccessRule, InboundPolicy, InfoHiding, NetEndpoint, Netlist, PathResolution,
+    Quotas, SignalPolicy, SymlinkPolicy, WasiProto, DEFAULT_SBOX_FDS, MAX_SBOX_FDS,
+    MAX_SYMLINK_HOPS,
+};
 
 // Converts a space seperated string into a null-seperated Vec<u8>
 // also counts the number of words
@@ -30,28 +36,118 @@ fn parse_argenv(s: String) -> (Vec<u8>, usize) {
     (buf, count)
 }
 
+// Parses an ipv4 address, or an ipv6 address in brackets (e.g. [::1]),
+// optionally followed by a prefix length (e.g. 10.0.0.0/8 or [fd00::]/8).
+// Both are returned as ipv6, with ipv4 addresses ipv4-mapped, along with the
+// prefix length in ipv6 bits.
+fn parse_ip_range(s: &str) -> Result<(u128, u32)> {
+    let (ip_s, prefix_s) = match s.rsplit_once('/') {
+        Some((ip_s, prefix_s)) => (ip_s, Some(prefix_s)),
+        None => (s, None),
+    };
+    let prefix = match prefix_s {
+        Some(prefix_s) => Some(u32::from_str(prefix_s)?),
+        None => None,
+    };
+    if let Some(ip6_s) = ip_s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let prefix = prefix.unwrap_or(128);
+        if prefix > 128 {
+            return Err(anyhow!("Prefix too long: {}", s));
+        }
+        return Ok((Ipv6Addr::from_str(ip6_s)?.into(), prefix));
+    }
+    let prefix = prefix.unwrap_or(32);
+    if prefix > 32 {
+        return Err(anyhow!("Prefix too long: {}", s));
+    }
+    let addr: u128 = Ipv4Addr::from_str(ip_s)?.to_ipv6_mapped().into();
+    Ok((addr, 96 + prefix))
+}
+
+// Parses a port (e.g. 80) or inclusive port range (e.g. 5432-5439)
+fn parse_port_range(s: &str) -> Result<(u32, u32)> {
+    let (lo, hi) = match s.split_once('-') {
+        Some((lo_s, hi_s)) => (u16::from_str(lo_s)?, u16::from_str(hi_s)?),
+        None => (u16::from_str(s)?, u16::from_str(s)?),
+    };
+    if lo > hi {
+        return Err(anyhow!("Empty port range: {}", s));
+    }
+    Ok((lo as u32, hi as u32))
+}
+
 // Parses a triple of the form protocol:ip:port
+// The ip may be followed by a prefix length, and the port may be a range,
+// e.g. tcp:10.0.0.0/8:5432-5439
+// ipv6 addresses must be bracketed, e.g. tcp:[::1]:8080
 fn parse_net_triple(s: &str) -> Result<NetEndpoint> {
-    // let (protocol_s, ip_s, port_s) = s.split(":").collect();
-    let triple: Vec<&str> = s.split(':').collect();
-    if triple.len() != 3 {
-        return Err(anyhow!("Not 3 entries in net_triple: {}", s));
-    }
-    let protocol_s = triple[0];
-    let ip_s = triple[1];
-    let port_s = triple[2];
+    let (protocol_s, rest) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Not 3 entries in net_triple: {}", s))?;
+    let (ip_s, port_s) = rest
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Not 3 entries in net_triple: {}", s))?;
 
     let protocol = match protocol_s.to_lowercase().as_str() {
         "tcp" => WasiProto::Tcp,
         "udp" => WasiProto::Udp,
         _ => return Err(anyhow!("Unknown protocol: {}", protocol_s)),
     };
-    let addr: u32 = std::net::Ipv4Addr::from_str(ip_s)?.into();
-    let port = u32::from_str(port_s)?;
+    let (addr, prefix_len) = parse_ip_range(ip_s)?;
+    let (port_lo, port_hi) = parse_port_range(port_s)?;
     Ok(NetEndpoint {
         protocol,
         addr,
-        port,
+        prefix_len,
+        port_lo,
+        port_hi,
+    })
+}
+
+// Parses a preopen of the form host_dir:guest_name, splitting on the last ':' so the
+// host dir may contain one. If no guest name is given, the guest sees the directory
+// under its host name
+fn parse_preopen(s: &str) -> Result<(String, String)> {
+    match s.rsplit_once(':') {
+        Some((host_dir, guest_name)) => {
+            if host_dir.is_empty() || guest_name.is_empty() {
+                return Err(anyhow!("Malformed preopen: {}", s));
+            }
+            Ok((host_dir.to_string(), guest_name.to_string()))
+        }
+        None => Ok((s.to_string(), s.to_string())),
+    }
+}
+
+// Parses a copy-on-write overlay of the form guest_name:upper_dir
+fn parse_overlay(s: &str) -> Result<(String, String)> {
+    match s.split_once(':') {
+        Some((guest_name, upper_dir)) if !guest_name.is_empty() && !upper_dir.is_empty() => {
+            Ok((guest_name.to_string(), upper_dir.to_string()))
+        }
+        _ => Err(anyhow!("Malformed overlay: {}", s)),
+    }
+}
+
+// Parses an access rule of the form mode:guest_name or mode:guest_name:subtree,
+// where mode is one of rw, ro or hidden
+fn parse_access_rule(s: &str) -> Result<AccessRule> {
+    let mut parts = s.splitn(3, ':');
+    let mode = match parts.next() {
+        Some("rw") => AccessMode::ReadWrite,
+        Some("ro") => AccessMode::ReadOnly,
+        Some("hidden") => AccessMode::Hidden,
+        _ => return Err(anyhow!("Unknown access mode: {}", s)),
+    };
+    let preopen = match parts.next() {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => return Err(anyhow!("Malformed access rule: {}", s)),
+    };
+    let subtree = parts.next().unwrap_or("").as_bytes().to_vec();
+    Ok(AccessRule {
+        preopen,
+        subtree,
+        mode,
     })
 }
 
@@ -61,15 +157,59 @@ fn parse_netlist(s: String) -> Result<Netlist> {
     if s.is_empty() {
         return Ok(netlist);
     }
-    for (idx, triple_str) in s.split(',').enumerate() {
-        if idx >= netlist.len() {
-            return Err(anyhow!("Too many net endpoints for the allow list"));
-        };
-        netlist[idx] = parse_net_triple(triple_str)?;
+    for triple_str in s.split(',') {
+        netlist.push(parse_net_triple(triple_str)?);
     }
     Ok(netlist)
 }
 
+// Parses the inbound policy. The bindlist has the same format as the netlist.
+// The peerlist is a comma-seperated list of ips, with optional prefix lengths
+// (ipv6 in brackets). Peers are refused unless they are in the peerlist, which is
+// empty by default
+fn parse_inbound(bindlist: String, peerlist: String) -> Result<InboundPolicy> {
+    let mut policy = empty_inbound_policy();
+    policy.bindlist = parse_netlist(bindlist)?;
+    if peerlist.is_empty() {
+        return Ok(policy);
+    }
+    for ip_s in peerlist.split(',') {
+        let (addr, prefix_len) = parse_ip_range(ip_s)?;
+        // peers connect from arbitrary ports
+        policy.peerlist.push(NetEndpoint {
+            protocol: WasiProto::Tcp,
+            addr,
+            prefix_len,
+            port_lo: 0,
+            port_hi: u16::MAX as u32,
+        });
+    }
+    Ok(policy)
+}
+
+extern "C" fn log_guest_signal(signal: u32) {
+    eprintln!("guest raised signal {}", signal);
+}
+
+// What the guest's proc_raise does: "ignore" it, "terminate" the sandbox, or "log" it
+fn parse_signal_policy(s: &str) -> Result<SignalPolicy> {
+    match s {
+        "ignore" => Ok(SignalPolicy::Ignore),
+        "terminate" => Ok(SignalPolicy::Terminate),
+        "log" => Ok(SignalPolicy::Callback(log_guest_signal)),
+        _ => Err(anyhow!("Unknown signal policy: {}", s)),
+    }
+}
+
+// A timeout in (possibly fractional) seconds
+fn parse_timeout(s: &str) -> Result<Duration> {
+    let secs = f64::from_str(s)?;
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err(anyhow!("Invalid timeout: {}", s));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
 fn main() {
     let matches = App::new("Wave Runner")
         .version("0.1.0")
@@ -84,8 +224,93 @@ fn main() {
             Arg::new("homedir")
                 .long("homedir")
                 .takes_value(true)
-                .help("Home directory")
-                .required(true),
+                .help("Home directory (preopened before any --preopen directories)"),
+        )
+        .arg(
+            Arg::new("preopen")
+                .long("preopen")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Additional directory to preopen, of the form host_dir:guest_name"),
+        )
+        .arg(
+            Arg::new("access")
+                .long("access")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Make a preopen or subtree rw, ro or hidden, as mode:guest_name[:subtree]"),
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Make a preopen copy-on-write, as guest_name:upper_dir"),
+        )
+        .arg(
+            Arg::new("mem_preopen")
+                .long("mem-preopen")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Give the guest an empty in-memory directory, as guest_name"),
+        )
+        .arg(
+            Arg::new("overlay_diff")
+                .long("overlay-diff")
+                .help("Print what the guest changed in each --overlay preopen once it is done"),
+        )
+        .arg(
+            Arg::new("walk_paths")
+                .long("walk-paths")
+                .help("Open paths a directory at a time, safe from symlink races on the host"),
+        )
+        .arg(
+            Arg::new("resolve_beneath")
+                .long("resolve-beneath")
+                .conflicts_with("walk_paths")
+                .help("Have the kernel keep paths inside the preopens (Linux 5.6+)"),
+        )
+        .arg(
+            Arg::new("virtual_root")
+                .long("virtual-root")
+                .help("Resolve absolute paths and symlink targets from the root of the preopen"),
+        )
+        .arg(
+            Arg::new("max_symlink_hops")
+                .long("max-symlink-hops")
+                .takes_value(true)
+                .help("Number of symlinks at which a guest path fails with ELOOP (default 10)"),
+        )
+        .arg(
+            Arg::new("no_follow_symlinks")
+                .long("no-follow-symlinks")
+                .help("Refuse guest paths that go through a symlink"),
+        )
+        .arg(
+            Arg::new("no_create_symlinks")
+                .long("no-create-symlinks")
+                .help("Refuse to let the guest create symlinks"),
+        )
+        .arg(
+            Arg::new("contain_symlink_targets")
+                .long("contain-symlink-targets")
+                .help("Refuse to create symlinks whose target leaves the preopen"),
+        )
+        .arg(
+            Arg::new("hide_host_info")
+                .long("hide-host-info")
+                .help("Hide host device ids and symlink targets outside the preopens"),
+        )
+        .arg(
+            Arg::new("remap_inodes")
+                .long("remap-inodes")
+                .help("Also hide host inode numbers (implies --hide-host-info)"),
+        )
+        .arg(
+            Arg::new("max_fds")
+                .long("max-fds")
+                .takes_value(true)
+                .help("Maximum number of file descriptors the sandbox may have open at once"),
         )
         .arg(
             Arg::new("netlist")
@@ -93,6 +318,18 @@ fn main() {
                 .takes_value(true)
                 .help("Allow-list for net endpoints that the Wasm application"),
         )
+        .arg(
+            Arg::new("bindlist")
+                .long("bindlist")
+                .takes_value(true)
+                .help("Allow-list for local endpoints the Wasm application may bind"),
+        )
+        .arg(
+            Arg::new("peerlist")
+                .long("peerlist")
+                .takes_value(true)
+                .help("Allow-list for peer ips the Wasm application may accept connections from (none by default)"),
+        )
         .arg(
             Arg::new("args")
                 .long("args")
@@ -105,22 +342,161 @@ fn main() {
                 .takes_value(true)
                 .help("Environment to pass to sandbox (space seperated key-value pairs)"),
         )
+        .arg(
+            Arg::new("on_raise")
+                .long("on-raise")
+                .takes_value(true)
+                .help("What proc_raise does: ignore (default), terminate or log"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .help("Wall-clock time limit in seconds, after which the sandbox is stopped"),
+        )
+        .arg(
+            Arg::new("max_write_bytes")
+                .long("max-write-bytes")
+                .takes_value(true)
+                .help("Maximum number of bytes the sandbox may write to files and sockets"),
+        )
+        .arg(
+            Arg::new("max_read_bytes")
+                .long("max-read-bytes")
+                .takes_value(true)
+                .help("Maximum number of bytes the sandbox may read from files and sockets"),
+        )
+        .arg(
+            Arg::new("max_files_created")
+                .long("max-files-created")
+                .takes_value(true)
+                .help("Maximum number of files, directories and links the sandbox may create"),
+        )
+        .arg(
+            Arg::new("max_hostcalls")
+                .long("max-hostcalls")
+                .takes_value(true)
+                .help("Maximum number of hostcalls the sandbox may make"),
+        )
+        .arg(
+            Arg::new("max_disk_bytes")
+                .long("max-disk-bytes")
+                .takes_value(true)
+                .help("Maximum number of bytes the files the sandbox writes may grow by in total"),
+        )
+        .arg(
+            Arg::new("quota_terminate")
+                .long("quota-terminate")
+                .help("Stop the sandbox when it exceeds a quota instead of failing the hostcall"),
+        )
+        .arg(
+            Arg::new("outcome_json")
+                .long("outcome-json")
+                .help("Print how the sandbox finished as JSON once it is done"),
+        )
         .get_matches();
 
     let module_path = matches.value_of("module path").unwrap().to_string();
-    let homedir = matches.value_of("homedir").unwrap().to_string();
+    let mut preopens = Vec::new();
+    if let Some(homedir) = matches.value_of("homedir") {
+        preopens.push((homedir.to_string(), homedir.to_string()));
+    }
+    for preopen_str in matches.values_of("preopen").into_iter().flatten() {
+        preopens.push(parse_preopen(preopen_str).unwrap());
+    }
+    if preopens.is_empty() {
+        panic!("At least one of --homedir or --preopen must be given");
+    }
+    let mut access = Vec::new();
+    for rule_str in matches.values_of("access").into_iter().flatten() {
+        access.push(parse_access_rule(rule_str).unwrap());
+    }
+    let mut overlays = Vec::new();
+    for overlay_str in matches.values_of("overlay").into_iter().flatten() {
+        overlays.push(parse_overlay(overlay_str).unwrap());
+    }
+    let mem_preopens: Vec<String> = matches
+        .values_of("mem_preopen")
+        .into_iter()
+        .flatten()
+        .map(|s| s.to_string())
+        .collect();
+    let mut symlinks = SymlinkPolicy {
+        follow: !matches.is_present("no_follow_symlinks"),
+        create: !matches.is_present("no_create_symlinks"),
+        contained_targets: matches.is_present("contain_symlink_targets"),
+        ..SymlinkPolicy::default()
+    };
+    if let Some(s) = matches.value_of("max_symlink_hops") {
+        symlinks.max_hops = isize::from_str(s).unwrap();
+        if !(1..=MAX_SYMLINK_HOPS).contains(&symlinks.max_hops) {
+            panic!(
+                "--max-symlink-hops must be between 1 and {}",
+                MAX_SYMLINK_HOPS
+            );
+        }
+    }
+    let info_hiding = if matches.is_present("hide_host_info") || matches.is_present("remap_inodes")
+    {
+        Some(InfoHiding::new(matches.is_present("remap_inodes")))
+    } else {
+        None
+    };
+    let max_fds = match matches.value_of("max_fds") {
+        Some(s) => u32::from_str(s).unwrap(),
+        None => DEFAULT_SBOX_FDS,
+    };
+    if max_fds > MAX_SBOX_FDS {
+        panic!("--max-fds may be at most {}", MAX_SBOX_FDS);
+    }
     let netlist_str = matches.value_of("netlist").unwrap_or("").to_string();
+    let bindlist_str = matches.value_of("bindlist").unwrap_or("").to_string();
+    let peerlist_str = matches.value_of("peerlist").unwrap_or("").to_string();
     let args_str = matches.value_of("args").unwrap_or("").to_string();
     let env_str = matches.value_of("env").unwrap_or("").to_string();
 
     let (arg_buffer, argc) = parse_argenv(args_str);
     let (env_buffer, envc) = parse_argenv(env_str);
     let netlist = parse_netlist(netlist_str).unwrap();
+    let inbound = parse_inbound(bindlist_str, peerlist_str).unwrap();
+    let signal_policy =
+        parse_signal_policy(matches.value_of("on_raise").unwrap_or("ignore")).unwrap();
+    let timeout = matches
+        .value_of("timeout")
+        .map(|s| parse_timeout(s).unwrap());
+    let quota = |name: &str| matches.value_of(name).map(|s| u64::from_str(s).unwrap());
+    let quotas = Quotas {
+        bytes_written: quota("max_write_bytes"),
+        bytes_read: quota("max_read_bytes"),
+        files_created: quota("max_files_created"),
+        hostcalls: quota("max_hostcalls"),
+        disk_bytes: quota("max_disk_bytes"),
+        terminate: matches.is_present("quota_terminate"),
+    };
 
     let config = WaveConfig {
         module_path,
-        homedir,
+        preopens,
+        access,
+        overlays,
+        overlay_diff: matches.is_present("overlay_diff"),
+        mem_preopens,
+        path_resolution: if matches.is_present("walk_paths") {
+            PathResolution::Walk
+        } else if matches.is_present("resolve_beneath") {
+            PathResolution::Beneath
+        } else {
+            PathResolution::Expand
+        },
+        virtual_root: matches.is_present("virtual_root"),
+        symlinks,
+        info_hiding,
+        max_fds,
         netlist,
+        inbound,
+        signal_policy,
+        timeout,
+        quotas,
         args: arg_buffer,
         argc,
         env: env_buffer,
@@ -129,6 +505,65 @@ fn main() {
 
     println!("{:?}", config);
 
-    waverunner::run(&config);
+    let outcome = waverunner::run(&config);
+    if matches.is_present("outcome_json") {
+        println!("{}", waverunner::outcome_to_json(&outcome));
+    }
+    std::process::exit(outcome.exit_code());
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ipv6() {
+        // ipv6 addresses have to be bracketed
+        assert_eq!(parse_ip_range("[::1]").unwrap(), (1, 128));
+        assert!(parse_ip_range("::1").is_err());
+        let entry = parse_net_triple("tcp:[::1]:8080").unwrap();
+        assert_eq!((entry.addr, entry.prefix_len), (1, 128));
+        assert_eq!((entry.port_lo, entry.port_hi), (8080, 8080));
+    }
+
+    #[test]
+    fn test_parse_net_triple() {
+        // ipv4 is ipv4-mapped, with the prefix in ipv6 bits
+        assert_eq!(
+            parse_ip_range("10.0.0.0/8").unwrap(),
+            (0xffff_0a00_0000, 104)
+        );
+        assert_eq!(parse_ip_range("127.0.0.1").unwrap().1, 128);
+        assert_eq!(parse_ip_range("[fd00::]/8").unwrap(), (0xfd << 120, 8));
+        assert!(parse_ip_range("10.0.0.0/33").is_err());
+        assert!(parse_ip_range("[::1]/129").is_err());
+        assert!(parse_ip_range("10.0.0").is_err());
+
+        let entry = parse_net_triple("udp:10.0.0.0/8:5432-5439").unwrap();
+        assert!(matches!(entry.protocol, WasiProto::Udp));
+        assert_eq!((entry.addr, entry.prefix_len), (0xffff_0a00_0000, 104));
+        assert_eq!((entry.port_lo, entry.port_hi), (5432, 5439));
+        assert!(parse_net_triple("tcp:127.0.0.1:80-79").is_err());
+        assert!(parse_net_triple("icmp:127.0.0.1:80").is_err());
+        assert!(parse_net_triple("tcp:127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_parse_preopen() {
+        assert_eq!(
+            parse_preopen("/srv/data:data").unwrap(),
+            ("/srv/data".to_string(), "data".to_string())
+        );
+        assert_eq!(
+            parse_preopen("/srv/data").unwrap(),
+            ("/srv/data".to_string(), "/srv/data".to_string())
+        );
+        // only the last ':' separates the guest name
+        assert_eq!(
+            parse_preopen("/mnt/c:/data:data").unwrap(),
+            ("/mnt/c:/data".to_string(), "data".to_string())
+        );
+        assert!(parse_preopen(":data").is_err());
+        assert!(parse_preopen("/srv/data:").is_err());
+    }
+}
@@ -1,12 +1,41 @@
 use dlopen::wrapper::{Container, WrapperApi};
-use wave::types::{Netlist, VmCtx};
+use std::time::Duration;
+use wave::types::{
+    AccessRule, InboundPolicy, InfoHiding, Netlist, PathResolution, Quotas, SignalPolicy,
+    SymlinkPolicy, VmCtx,
+};
+use wave::wasm2c_frontend::GuestEntry;
 
 
 #[derive(Debug)]
 pub struct WaveConfig {
     pub module_path: String,
-    pub homedir: String,
+    // (host directory, guest name) pairs
+    pub preopens: Vec<(String, String)>,
+    // read-only and hidden preopens and subtrees
+    pub access: Vec<AccessRule>,
+    // (guest name, host upper dir) pairs of copy-on-write preopens
+    pub overlays: Vec<(String, String)>,
+    // print the changes made to the overlays after the run
+    pub overlay_diff: bool,
+    // guest names of empty in-memory preopens, thrown away after the run
+    pub mem_preopens: Vec<String>,
+    // how guest paths are resolved on the host
+    pub path_resolution: PathResolution,
+    // `/` in guest paths means the preopen root
+    pub virtual_root: bool,
+    pub symlinks: SymlinkPolicy,
+    // keep host device ids, inode numbers and link targets from the guest
+    pub info_hiding: Option<InfoHiding>,
+    // size limit of the sandbox's fd table
+    pub max_fds: u32,
     pub netlist: Netlist,
+    pub inbound: InboundPolicy,
+    // what the guest's proc_raise does
+    pub signal_policy: SignalPolicy,
+    // wall-clock limit on the whole run
+    pub timeout: Option<Duration>,
+    pub quotas: Quotas,
     pub args: Vec<u8>,
     pub argc: usize,
     pub env: Vec<u8>,
@@ -23,5 +52,18 @@ pub struct WaveSandbox {
 #[derive(WrapperApi, Debug)]
 pub struct Wasm2cBinary {
     w2c__start: unsafe extern "C" fn() -> i32,
+    // from bindings/wave_run.c
+    wave_run: unsafe extern "C" fn(start: unsafe extern "C" fn() -> i32) -> i32,
+    wave_exit: unsafe extern "C" fn(code: i32),
+}
+
+impl Wasm2cBinary {
+    pub fn guest_entry(&self) -> GuestEntry {
+        GuestEntry {
+            start: self.w2c__start,
+            run: self.wave_run,
+            exit: self.wave_exit,
+        }
+    }
 }
 
@@ -1,9 +1,15 @@
 use crate::types::{Wasm2cBinary, WaveConfig, WaveSandbox};
 use dlopen::wrapper::Container;
 use libc::{mprotect, munmap};
+use std::ffi::CString;
 use std::ptr;
-use wave::wasm2c_frontend::{create_ctx, wave_cleanup}; // TODO: fix path in wave
-use wave::types::VmCtx;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use wave::tcb::memfs::MemFs;
+use wave::wasm2c_frontend::{create_ctx, run_start, wave_cleanup, STOP_CODE}; // TODO: fix path in wave
+use wave::types::{CancelHandle, OverlayChange, QuotaKind, RunOutcome, TrapKind, Violation, VmCtx};
 use wave::setup_teardown::wave_alloc_linmem;
 // handles wasm2c files
 
@@ -20,14 +26,316 @@ const EIGHT_GB: usize = 1 << 33;
 
 
 
-fn wave_setup_signals() {
-    // For now, do nothings, since we just fail on any signal
+// Bounds of the running sandbox's linear memory plus guard region, [start, end).
+// Read by the signal handlers, so these are plain atomics rather than part of WaveSandbox.
+static LINMEM_START: AtomicUsize = AtomicUsize::new(0);
+static LINMEM_END: AtomicUsize = AtomicUsize::new(0);
+
+// Code of the loaded module, [start, end). Timeout interrupts only stop the guest
+// while it is running its own code, never inside a hostcall or the runtime.
+static MODULE_CODE_START: AtomicUsize = AtomicUsize::new(0);
+static MODULE_CODE_END: AtomicUsize = AtomicUsize::new(0);
+
+// The module's wave_exit (see bindings/wave_run.c), which the handlers use to get
+// back to run_start
+static GUEST_EXIT: AtomicUsize = AtomicUsize::new(0);
+
+// What was installed before setup, see `SavedSignals`
+static SAVED_SIGNALS: AtomicPtr<SavedSignals> = AtomicPtr::new(ptr::null_mut());
+
+// How often the watchdog re-interrupts a guest that has run past its deadline
+const INTERRUPT_INTERVAL: Duration = Duration::from_millis(10);
+
+// WASM_RT_TRAP_OOB, which run_start reports as an out of bounds trap
+const TRAP_OOB: i32 = 1;
+
+// The handlers run on a stack of their own, so that they still work once the guest
+// has used up its stack
+const SIGNAL_STACK_SIZE: usize = 1 << 16;
+
+const HANDLED_SIGNALS: [libc::c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGALRM];
+
+// The actions and alternate stack from before setup. Teardown puts them back, and
+// the handlers pass on the signals that aren't the sandbox's to the old actions.
+// SIGALRM's action is only replaced (and so saved) when there is a timeout.
+struct SavedSignals {
+    actions: [Option<libc::sigaction>; HANDLED_SIGNALS.len()],
+    stack: libc::stack_t,
+    our_stack: *mut libc::c_void,
+}
+
+// Jump back to run_start through the module's jmp_buf, as the runtime's own traps
+// do. The handlers are installed with SA_NODEFER, so no signal is left blocked
+// whether or not the runtime uses siglongjmp.
+unsafe fn exit_guest(code: i32) {
+    let exit = GUEST_EXIT.load(Ordering::SeqCst);
+    if exit != 0 {
+        let exit: unsafe extern "C" fn(i32) = std::mem::transmute(exit);
+        exit(code);
+    }
+}
+
+// Pass a signal that isn't the sandbox's on to the action from before setup
+unsafe fn chain_signal(signum: libc::c_int, info: *mut libc::siginfo_t, uctx: *mut libc::c_void) {
+    let saved = SAVED_SIGNALS.load(Ordering::SeqCst);
+    let idx = HANDLED_SIGNALS.iter().position(|s| *s == signum);
+    let mut old: libc::sigaction = std::mem::zeroed();
+    old.sa_sigaction = libc::SIG_DFL;
+    if let (false, Some(idx)) = (saved.is_null(), idx) {
+        if let Some(action) = (*saved).actions[idx] {
+            old = action;
+        }
+    }
+    if old.sa_sigaction == libc::SIG_IGN && signum == libc::SIGALRM {
+        return;
+    }
+    if old.sa_sigaction == libc::SIG_DFL || old.sa_sigaction == libc::SIG_IGN {
+        // A fault can't be ignored: it happens again as soon as we return, so let that
+        // one get the default action
+        libc::signal(signum, libc::SIG_DFL);
+        if signum == libc::SIGALRM {
+            libc::raise(signum);
+        }
+    } else if old.sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: SignalHandler = std::mem::transmute(old.sa_sigaction);
+        handler(signum, info, uctx);
+    } else {
+        let handler: extern "C" fn(libc::c_int) = std::mem::transmute(old.sa_sigaction);
+        handler(signum);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn interrupted_pc(uctx: *mut libc::ucontext_t) -> Option<usize> {
+    Some((*uctx).uc_mcontext.gregs[libc::REG_RIP as usize] as usize)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn interrupted_pc(uctx: *mut libc::ucontext_t) -> Option<usize> {
+    Some((*uctx).uc_mcontext.pc as usize)
+}
+
+// Elsewhere the guest isn't interrupted, so timeouts only stop it in hostcalls
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+unsafe fn interrupted_pc(_uctx: *mut libc::ucontext_t) -> Option<usize> {
+    None
+}
+
+type SignalHandler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+// Hostcalls stay within the linear memory itself, so a fault in it or its guard
+// region comes from the guest or the runtime's C helpers, and there are no Rust
+// frames on the way back out to run_start.
+extern "C" fn linmem_fault_handler(
+    signum: libc::c_int,
+    info: *mut libc::siginfo_t,
+    uctx: *mut libc::c_void,
+) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let start = LINMEM_START.load(Ordering::SeqCst);
+        let end = LINMEM_END.load(Ordering::SeqCst);
+        if addr >= start && addr < end {
+            exit_guest(TRAP_OOB);
+        }
+        chain_signal(signum, info, uctx);
+    }
+}
+
+// Sent by the watchdog once the deadline has passed. If the guest is in a hostcall
+// instead, the hostcall's deadline check stops it (a blocking syscall returns EINTR).
+extern "C" fn timeout_handler(
+    signum: libc::c_int,
+    info: *mut libc::siginfo_t,
+    uctx: *mut libc::c_void,
+) {
+    unsafe {
+        if (*info).si_pid() != libc::getpid() {
+            return chain_signal(signum, info, uctx);
+        }
+        let start = MODULE_CODE_START.load(Ordering::SeqCst);
+        let end = MODULE_CODE_END.load(Ordering::SeqCst);
+        match interrupted_pc(uctx as *mut libc::ucontext_t) {
+            // run_start sees that the sandbox has timed out
+            Some(pc) if pc >= start && pc < end => exit_guest(STOP_CODE),
+            _ => (),
+        }
+    }
+}
+
+unsafe fn install_handler(signum: libc::c_int, handler: SignalHandler) -> libc::sigaction {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_NODEFER;
+    libc::sigemptyset(&mut action.sa_mask);
+    let mut old: libc::sigaction = std::mem::zeroed();
+    libc::sigaction(signum, &action, &mut old);
+    old
+}
+
+// Faults in the linear memory or its guard region become out of bounds traps, and,
+// given the module's code (only needed with a timeout), SIGALRM from the watchdog
+// stops a guest that has run past its deadline. Anything else is left to the
+// actions that were there before.
+fn wave_setup_signals(
+    linmem: *mut u8,
+    module_code: Option<(usize, usize)>,
+    exit: unsafe extern "C" fn(i32),
+) {
+    LINMEM_START.store(linmem as usize, Ordering::SeqCst);
+    LINMEM_END.store(linmem as usize + EIGHT_GB, Ordering::SeqCst);
+    if let Some((start, end)) = module_code {
+        MODULE_CODE_START.store(start, Ordering::SeqCst);
+        MODULE_CODE_END.store(end, Ordering::SeqCst);
+    }
+    GUEST_EXIT.store(exit as usize, Ordering::SeqCst);
+    unsafe {
+        let our_stack = libc::mmap(
+            ptr::null_mut(),
+            SIGNAL_STACK_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if our_stack == libc::MAP_FAILED {
+            panic!("Could not allocate a signal stack");
+        }
+        let stack = libc::stack_t {
+            ss_sp: our_stack,
+            ss_flags: 0,
+            ss_size: SIGNAL_STACK_SIZE,
+        };
+        let saved = Box::into_raw(Box::new(SavedSignals {
+            actions: [None; HANDLED_SIGNALS.len()],
+            stack: std::mem::zeroed(),
+            our_stack,
+        }));
+        libc::sigaltstack(&stack, &mut (*saved).stack);
+        // before any of the handlers can look for it
+        SAVED_SIGNALS.store(saved, Ordering::SeqCst);
+        for (idx, signum) in HANDLED_SIGNALS.iter().enumerate() {
+            let handler: SignalHandler = match *signum {
+                libc::SIGALRM if module_code.is_none() => continue,
+                libc::SIGALRM => timeout_handler,
+                _ => linmem_fault_handler,
+            };
+            (*saved).actions[idx] = Some(install_handler(*signum, handler));
+        }
+    }
+}
+
+fn wave_teardown_signals() {
+    let saved = SAVED_SIGNALS.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !saved.is_null() {
+        unsafe {
+            let saved = Box::from_raw(saved);
+            for (idx, signum) in HANDLED_SIGNALS.iter().enumerate() {
+                if let Some(action) = &saved.actions[idx] {
+                    libc::sigaction(*signum, action, ptr::null_mut());
+                }
+            }
+            libc::sigaltstack(&saved.stack, ptr::null_mut());
+            munmap(saved.our_stack, SIGNAL_STACK_SIZE);
+        }
+    }
+    LINMEM_START.store(0, Ordering::SeqCst);
+    LINMEM_END.store(0, Ordering::SeqCst);
+    MODULE_CODE_START.store(0, Ordering::SeqCst);
+    MODULE_CODE_END.store(0, Ordering::SeqCst);
+    GUEST_EXIT.store(0, Ordering::SeqCst);
+}
+
+struct CodeSearch {
+    addr: usize,
+    range: (usize, usize),
+}
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" fn find_code_segment(
+    info: *mut libc::dl_phdr_info,
+    _size: usize,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let search = &mut *(data as *mut CodeSearch);
+    let info = &*info;
+    for i in 0..info.dlpi_phnum as usize {
+        let phdr = &*info.dlpi_phdr.add(i);
+        if phdr.p_type != libc::PT_LOAD || phdr.p_flags & libc::PF_X == 0 {
+            continue;
+        }
+        let start = info.dlpi_addr as usize + phdr.p_vaddr as usize;
+        let end = start + phdr.p_memsz as usize;
+        if search.addr >= start && search.addr < end {
+            search.range = (start, end);
+            return 1;
+        }
+    }
+    0
+}
+
+// The executable segment of the module at `module_path`, which must already be loaded
+#[cfg(target_os = "linux")]
+fn find_module_code(module_path: &str) -> Result<(usize, usize), String> {
+    let path = CString::new(module_path).map_err(|e| e.to_string())?;
+    let mut search = CodeSearch {
+        addr: 0,
+        range: (0, 0),
+    };
+    unsafe {
+        let handle = libc::dlopen(path.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+        if handle.is_null() {
+            return Err(format!("{} is not loaded", module_path));
+        }
+        search.addr = libc::dlsym(handle, b"w2c__start\0".as_ptr() as *const libc::c_char) as usize;
+        libc::dlclose(handle);
+        if search.addr == 0 {
+            return Err(format!("{} has no w2c__start", module_path));
+        }
+        libc::dl_iterate_phdr(
+            Some(find_code_segment),
+            &mut search as *mut CodeSearch as *mut libc::c_void,
+        );
+    }
+    if search.range == (0, 0) {
+        return Err(format!(
+            "no executable segment of {} holds w2c__start",
+            module_path
+        ));
+    }
+    Ok(search.range)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_module_code(_module_path: &str) -> Result<(usize, usize), String> {
+    Err("finding the module's code is only supported on Linux".to_string())
+}
+
+// Once `timeout` has passed, time out the sandbox, then keep interrupting the guest
+// thread until told that it is done (by sending on or dropping the returned Sender).
+fn spawn_watchdog(cancel: CancelHandle, timeout: Duration) -> (Sender<()>, JoinHandle<()>) {
+    let guest = unsafe { libc::pthread_self() };
+    let (done_tx, done_rx) = mpsc::channel();
+    let watchdog = thread::spawn(move || {
+        if done_rx.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+        cancel.time_out();
+        while done_rx.recv_timeout(INTERRUPT_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+            unsafe { libc::pthread_kill(guest, libc::SIGALRM) };
+        }
+    });
+    (done_tx, watchdog)
 }
 
 // Setup has 3 steps
 // 1. load the AOT-compiled Wasm module via DlOpen
 // 2. MMap and mprotect the linear memory
-// 3. Setup signals (maybe?)
+// 3. Setup signals, so guest faults in linear memory become traps (and, with a
+//    timeout, the watchdog can interrupt the guest)
 // It then returns a wave_runtime object with all these things set up
 fn setup(config: &WaveConfig) -> WaveSandbox {
     // 1. Load AOT-compiled Wasm module
@@ -40,17 +348,45 @@ fn setup(config: &WaveConfig) -> WaveSandbox {
     let linmem = wave_alloc_linmem() as *mut u8;
 
     // 3. Setup signals
-    wave_setup_signals();
+    let module_code = config.timeout.map(|_| {
+        find_module_code(&config.module_path)
+            .unwrap_or_else(|e| panic!("--timeout can't interrupt the guest: {}", e))
+    });
+    wave_setup_signals(linmem, module_code, module.guest_entry().exit);
 
-    let vmctx = create_ctx(
+    let mem_preopens = config
+        .mem_preopens
+        .iter()
+        .map(|guest_name| (guest_name.clone(), MemFs::new()))
+        .collect();
+    let mut vmctx = create_ctx(
         linmem,
-        &config.homedir,
+        &config.preopens,
+        &config.access,
+        &config.overlays,
+        &mem_preopens,
+        config.max_fds,
         config.args.clone(),
         config.argc,
         config.env.clone(),
         config.envc,
-        config.netlist,
-    );
+        config.netlist.clone(),
+        config.inbound.clone(),
+        config.signal_policy,
+        config.quotas,
+    )
+    .unwrap_or_else(|e| panic!("can't set up the sandbox's preopens: {:?}", e));
+    // Beneath falls back to Expand where openat2 isn't available
+    let path_resolution = vmctx.set_path_resolution(config.path_resolution);
+    if path_resolution != config.path_resolution {
+        eprintln!(
+            "openat2 is unavailable, resolving paths with {:?} instead of {:?}",
+            path_resolution, config.path_resolution
+        );
+    }
+    vmctx.virtual_root = config.virtual_root;
+    vmctx.symlinks = config.symlinks;
+    vmctx.info_hiding = config.info_hiding;
 
     WaveSandbox {
         module,
@@ -59,16 +395,27 @@ fn setup(config: &WaveConfig) -> WaveSandbox {
     }
 }
 
-// The execute stage simply consists of calling the __start function
-// returns the result code of executing the sandbox
-fn execute(sandbox: &WaveSandbox) -> i32 {
-    unsafe { sandbox.module.w2c__start() }
+// The execute stage simply consists of calling the __start function, under a
+// watchdog if there is a timeout.
+// returns how the sandbox finished, including any proc_exit status
+fn execute(sandbox: &mut WaveSandbox, timeout: Option<Duration>) -> RunOutcome {
+    let watchdog = timeout.map(|timeout| {
+        sandbox.vmctx.deadline = Some(Instant::now() + timeout);
+        spawn_watchdog(sandbox.vmctx.cancel.clone(), timeout)
+    });
+    let outcome = run_start(&mut sandbox.vmctx, sandbox.module.guest_entry());
+    if let Some((done, watchdog)) = watchdog {
+        let _ = done.send(());
+        watchdog.join().unwrap();
+    }
+    outcome
 }
 
 // // Here, we just:
 // // 1. Unmap linear memory
 // // 2. Drop the sandbox
 fn teardown(mut sandbox: WaveSandbox) {
+    wave_teardown_signals();
     unsafe {
     // release file descriptors and such
     wave_cleanup(&(&mut sandbox.vmctx as *mut VmCtx) as *const *mut VmCtx);
@@ -83,9 +430,101 @@ fn teardown(mut sandbox: WaveSandbox) {
 // 1. Set up runtime
 // 2. Execute AOT-compiled Wasm binary
 // 3. Teardown runtime
-pub fn run(config: &WaveConfig) {
-    let sandbox = setup(config);
-    let _result = execute(&sandbox);
+pub fn run(config: &WaveConfig) -> RunOutcome {
+    let mut sandbox = setup(config);
+    let outcome = execute(&mut sandbox, config.timeout);
+    if config.overlay_diff {
+        print_overlay_diff(&sandbox.vmctx, config);
+    }
     teardown(sandbox);
+    outcome
+}
+
+// One line per change, e.g. "home: M src/main.c", with A, M and D for added,
+// modified and deleted
+fn print_overlay_diff(vmctx: &VmCtx, config: &WaveConfig) {
+    for (guest_name, _) in config.overlays.iter() {
+        for change in vmctx.overlay_changes(guest_name).into_iter().flatten() {
+            let (kind, path) = match change {
+                OverlayChange::Added(path) => ("A", path),
+                OverlayChange::Modified(path) => ("M", path),
+                OverlayChange::Deleted(path) => ("D", path),
+            };
+            let path = String::from_utf8_lossy(&path);
+            println!("{}: {} {}", guest_name, kind, path);
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
 
+fn trap_name(kind: TrapKind) -> String {
+    match kind {
+        TrapKind::OutOfBounds => "out_of_bounds".to_string(),
+        TrapKind::IntOverflow => "int_overflow".to_string(),
+        TrapKind::DivByZero => "div_by_zero".to_string(),
+        TrapKind::InvalidConversion => "invalid_conversion".to_string(),
+        TrapKind::Unreachable => "unreachable".to_string(),
+        TrapKind::CallIndirect => "call_indirect".to_string(),
+        TrapKind::StackExhaustion => "stack_exhaustion".to_string(),
+        TrapKind::Unknown(code) => format!("unknown_{}", code),
+    }
+}
+
+fn quota_name(kind: QuotaKind) -> &'static str {
+    match kind {
+        QuotaKind::BytesWritten => "bytes_written",
+        QuotaKind::BytesRead => "bytes_read",
+        QuotaKind::FilesCreated => "files_created",
+        QuotaKind::Hostcalls => "hostcalls",
+        QuotaKind::DiskBytes => "disk_bytes",
+    }
+}
+
+// e.g. {"outcome":"exited","status":0}
+pub fn outcome_to_json(outcome: &RunOutcome) -> String {
+    match outcome {
+        RunOutcome::Exited(status) => format!("{{\"outcome\":\"exited\",\"status\":{}}}", status),
+        RunOutcome::Trap(kind) => format!(
+            "{{\"outcome\":\"trap\",\"trap\":{}}}",
+            json_string(&trap_name(*kind))
+        ),
+        RunOutcome::HostFailure(msg) => format!(
+            "{{\"outcome\":\"host_failure\",\"message\":{}}}",
+            json_string(msg)
+        ),
+        RunOutcome::TimedOut => "{\"outcome\":\"timed_out\"}".to_string(),
+        RunOutcome::Cancelled => "{\"outcome\":\"cancelled\"}".to_string(),
+        RunOutcome::QuotaExceeded(kind) => format!(
+            "{{\"outcome\":\"quota_exceeded\",\"quota\":{}}}",
+            json_string(quota_name(*kind))
+        ),
+        RunOutcome::Signaled(signal) => {
+            format!("{{\"outcome\":\"signaled\",\"signal\":{}}}", signal)
+        }
+        RunOutcome::PolicyViolation { violation, status } => {
+            let (kind, hostcall) = match violation {
+                Violation::PathDenied { hostcall } => ("path_denied", hostcall),
+                Violation::NetDenied { hostcall } => ("net_denied", hostcall),
+            };
+            format!(
+                "{{\"outcome\":\"policy_violation\",\"violation\":{},\"hostcall\":{},\"status\":{}}}",
+                json_string(kind),
+                json_string(hostcall),
+                status
+            )
+        }
+    }
+}